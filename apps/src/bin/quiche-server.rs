@@ -29,8 +29,6 @@ extern crate log;
 
 use std::io;
 
-use std::net;
-
 use std::io::prelude::*;
 
 use std::collections::HashMap;
@@ -168,6 +166,11 @@ fn main() {
     let conn_id_seed =
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
+    let mut token_secret = [0; 32];
+    rng.fill(&mut token_secret).unwrap();
+
+    let token_key = quiche::TokenKey::new(&token_secret);
+
     let mut next_client_id = 0;
     let mut clients_ids = ClientIdMap::new();
     let mut clients = ClientMap::new();
@@ -288,6 +291,8 @@ fn main() {
 
                 let mut odcid = None;
 
+                let mut peer_address_verified = false;
+
                 if !args.no_retry {
                     // Token is always present in Initial packets.
                     let token = hdr.token.as_ref().unwrap();
@@ -297,7 +302,8 @@ fn main() {
                         warn!("Doing stateless retry");
 
                         let scid = quiche::ConnectionId::from_ref(&scid);
-                        let new_token = mint_token(&hdr, &from);
+                        let new_token =
+                            token_key.seal_retry_token(&from, &hdr.dcid).unwrap();
 
                         let len = quiche::retry(
                             &hdr.scid,
@@ -322,23 +328,31 @@ fn main() {
                         continue 'read;
                     }
 
-                    odcid = validate_token(&from, token);
-
                     // The token was not valid, meaning the retry failed, so
                     // drop the packet.
-                    if odcid.is_none() {
-                        error!("Invalid address validation token");
-                        continue;
-                    }
+                    odcid = match token_key.open(&from, token) {
+                        Ok(v) => v,
 
-                    if scid.len() != hdr.dcid.len() {
-                        error!("Invalid destination connection ID");
-                        continue 'read;
-                    }
+                        Err(_) => {
+                            error!("Invalid address validation token");
+                            continue;
+                        },
+                    };
 
-                    // Reuse the source connection ID we sent in the Retry
-                    // packet, instead of changing it again.
-                    scid.copy_from_slice(&hdr.dcid);
+                    if odcid.is_some() {
+                        if scid.len() != hdr.dcid.len() {
+                            error!("Invalid destination connection ID");
+                            continue 'read;
+                        }
+
+                        // Reuse the source connection ID we sent in the Retry
+                        // packet, instead of changing it again.
+                        scid.copy_from_slice(&hdr.dcid);
+                    } else {
+                        // The token was sent in a NEW_TOKEN frame, so the
+                        // client's address doesn't need to be validated again.
+                        peer_address_verified = true;
+                    }
                 }
 
                 let scid = quiche::ConnectionId::from_vec(scid.to_vec());
@@ -355,6 +369,10 @@ fn main() {
                 )
                 .unwrap();
 
+                if peer_address_verified {
+                    conn.set_peer_address_verified().unwrap();
+                }
+
                 if let Some(keylog) = &mut keylog {
                     if let Ok(keylog) = keylog.try_clone() {
                         conn.set_keylog(Box::new(keylog));
@@ -409,6 +427,7 @@ fn main() {
             let recv_info = quiche::RecvInfo {
                 to: local_addr,
                 from,
+                ecn: quiche::Ecn::NotEct,
             };
 
             // Process potentially coalesced packets.
@@ -632,62 +651,6 @@ fn main() {
     }
 }
 
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
-
-/// Validates a stateless retry token.
-///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
-
 fn handle_path_events(client: &mut Client) {
     while let Some(qe) = client.conn.path_event_next() {
         match qe {
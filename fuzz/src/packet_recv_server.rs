@@ -47,7 +47,11 @@ fuzz_target!(|data: &[u8]| {
         quiche::accept(&SCID, None, to, from, &mut CONFIG.lock().unwrap())
             .unwrap();
 
-    let info = quiche::RecvInfo { from, to };
+    let info = quiche::RecvInfo {
+        from,
+        to,
+        ecn: quiche::Ecn::NotEct,
+    };
 
     conn.recv(&mut buf, info).ok();
 });
@@ -169,12 +169,18 @@ impl From<EventType> for EventImportance {
             EventType::ConnectivityEventType(
                 ConnectivityEventType::ConnectionStateUpdated,
             ) => EventImportance::Base,
+            EventType::ConnectivityEventType(
+                ConnectivityEventType::MtuUpdated,
+            ) => EventImportance::Extra,
 
             EventType::SecurityEventType(SecurityEventType::KeyUpdated) =>
                 EventImportance::Base,
             EventType::SecurityEventType(SecurityEventType::KeyDiscarded) =>
                 EventImportance::Base,
 
+            EventType::TransportEventType(
+                TransportEventType::VersionInformation,
+            ) => EventImportance::Core,
             EventType::TransportEventType(TransportEventType::ParametersSet) =>
                 EventImportance::Core,
             EventType::TransportEventType(
@@ -328,6 +334,9 @@ impl From<&EventData> for EventType {
                 EventType::ConnectivityEventType(
                     ConnectivityEventType::ConnectionStateUpdated,
                 ),
+            EventData::MtuUpdated { .. } => EventType::ConnectivityEventType(
+                ConnectivityEventType::MtuUpdated,
+            ),
 
             EventData::KeyUpdated { .. } =>
                 EventType::SecurityEventType(SecurityEventType::KeyUpdated),
@@ -476,6 +485,9 @@ pub enum EventData {
     #[serde(rename = "connectivity:connection_state_updated")]
     ConnectionStateUpdated(connectivity::ConnectionStateUpdated),
 
+    #[serde(rename = "connectivity:mtu_updated")]
+    MtuUpdated(connectivity::MtuUpdated),
+
     // Security
     #[serde(rename = "security:key_updated")]
     KeyUpdated(security::KeyUpdated),
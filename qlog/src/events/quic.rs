@@ -372,6 +372,12 @@ pub enum QuicFrameTypeName {
     ApplicationClose,
     HandshakeDone,
     Datagram,
+    AckFrequency,
+    ImmediateAck,
+    AckMp,
+    PathAbandon,
+    PathStatus,
+    ResetStreamAt,
     Unknown,
 }
 
@@ -493,6 +499,46 @@ pub enum QuicFrame {
         raw: Option<Bytes>,
     },
 
+    AckFrequency {
+        sequence_number: u64,
+        ack_eliciting_threshold: u64,
+        request_max_ack_delay: u64,
+        reordering_threshold: u64,
+    },
+
+    ImmediateAck,
+
+    AckMp {
+        space_identifier: u64,
+        ack_delay: Option<f32>,
+        acked_ranges: Option<AckedRanges>,
+
+        ect1: Option<u64>,
+
+        ect0: Option<u64>,
+
+        ce: Option<u64>,
+    },
+
+    PathAbandon {
+        dcid_sequence_number: u64,
+        error_code: u64,
+        reason: Option<String>,
+    },
+
+    PathStatus {
+        dcid_sequence_number: u64,
+        sequence_number: u64,
+        status: u64,
+    },
+
+    ResetStreamAt {
+        stream_id: u64,
+        error_code: u64,
+        final_size: u64,
+        reliable_size: u64,
+    },
+
     Unknown {
         raw_frame_type: u64,
         frame_type_value: Option<u64>,
@@ -558,6 +604,10 @@ pub struct TransportParametersSet {
     pub initial_max_streams_uni: Option<u64>,
 
     pub preferred_address: Option<PreferredAddress>,
+
+    pub min_ack_delay: Option<u64>,
+
+    pub enable_multipath: Option<bool>,
 }
 
 #[serde_with::skip_serializing_none]
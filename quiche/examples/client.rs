@@ -174,6 +174,7 @@ fn main() {
             let recv_info = quiche::RecvInfo {
                 to: socket.local_addr().unwrap(),
                 from,
+                ecn: quiche::Ecn::NotEct,
             };
 
             // Process potentially coalesced packets.
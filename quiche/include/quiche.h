@@ -58,6 +58,12 @@ extern "C" {
 // The current QUIC wire version.
 #define QUICHE_PROTOCOL_VERSION 0x00000001
 
+// QUIC version 1 (RFC 9000).
+#define QUICHE_PROTOCOL_VERSION_V1 0x00000001
+
+// QUIC version 2 (RFC 9369).
+#define QUICHE_PROTOCOL_VERSION_V2 0x6b3343cf
+
 // The maximum length of a connection ID.
 #define QUICHE_MAX_CONN_ID_LEN 20
 
@@ -124,6 +130,24 @@ enum quiche_error {
 
     // Error in key update.
     QUICHE_ERR_KEY_UPDATE = -19,
+
+    // Error in version negotiation.
+    QUICHE_ERR_VERSION_NEGOTIATION = -20,
+
+    // The address validation token could not be authenticated.
+    QUICHE_ERR_INVALID_TOKEN = -21,
+
+    // The address validation token has expired.
+    QUICHE_ERR_TOKEN_EXPIRED = -22,
+
+    // The AEAD limits were reached.
+    QUICHE_ERR_AEAD_LIMIT_REACHED = -23,
+
+    // The multipath scheduler name is not valid.
+    QUICHE_ERR_INVALID_MULTIPATH_SCHEDULER = -24,
+
+    // The QUIC-LB configuration is not valid.
+    QUICHE_ERR_INVALID_LB_CONFIG = -25,
 };
 
 // Returns a human readable string with the quiche version number.
@@ -182,6 +206,28 @@ void quiche_config_set_max_recv_udp_payload_size(quiche_config *config, size_t v
 // Sets the maximum outgoing UDP payload size.
 void quiche_config_set_max_send_udp_payload_size(quiche_config *config, size_t v);
 
+// Configures whether to enable Path MTU Discovery.
+void quiche_config_discover_pmtu(quiche_config *config, bool v);
+
+// Configures whether to enable the latency spin bit.
+void quiche_config_enable_spin_bit(quiche_config *config, bool v);
+
+// Configures whether to enable the multipath extension.
+void quiche_config_set_multipath(quiche_config *config, bool v);
+
+// Configures whether to enable the reliable stream reset extension.
+void quiche_config_enable_reset_stream_at(quiche_config *config, bool v);
+
+enum quiche_path_scheduler {
+    QUICHE_PATH_SCHEDULER_MINRTT = 0,
+    QUICHE_PATH_SCHEDULER_ROUNDROBIN = 1,
+    QUICHE_PATH_SCHEDULER_BACKUP = 2,
+};
+
+// Sets the scheduler used to select paths when multipath is in use.
+void quiche_config_set_path_scheduler(quiche_config *config,
+                                      enum quiche_path_scheduler scheduler);
+
 // Sets the `initial_max_data` transport parameter.
 void quiche_config_set_initial_max_data(quiche_config *config, uint64_t v);
 
@@ -206,6 +252,9 @@ void quiche_config_set_ack_delay_exponent(quiche_config *config, uint64_t v);
 // Sets the `max_ack_delay` transport parameter.
 void quiche_config_set_max_ack_delay(quiche_config *config, uint64_t v);
 
+// Sets the `min_ack_delay` transport parameter, in microseconds.
+void quiche_config_set_min_ack_delay(quiche_config *config, uint64_t v);
+
 // Sets the `disable_active_migration` transport parameter.
 void quiche_config_set_disable_active_migration(quiche_config *config, bool v);
 
@@ -234,6 +283,9 @@ void quiche_config_enable_pacing(quiche_config *config, bool v);
 // Configures max pacing rate to be used.
 void quiche_config_set_max_pacing_rate(quiche_config *config, uint64_t v);
 
+// Configures whether to enable ECN marking and validation.
+void quiche_config_enable_ecn(quiche_config *config, bool v);
+
 // Configures whether to enable receiving DATAGRAM frames.
 void quiche_config_enable_dgram(quiche_config *config, bool enabled,
                                 size_t recv_queue_len,
@@ -245,12 +297,26 @@ void quiche_config_set_max_connection_window(quiche_config *config, uint64_t v);
 // Sets the maximum stream window.
 void quiche_config_set_max_stream_window(quiche_config *config, uint64_t v);
 
+// Sets the fraction of the AEAD confidentiality limit after which a key update
+// is automatically initiated.
+void quiche_config_set_key_update_threshold(quiche_config *config, double v);
+
 // Sets the limit of active connection IDs.
 void quiche_config_set_active_connection_id_limit(quiche_config *config, uint64_t v);
 
 // Sets the initial stateless reset token. |v| must contain 16 bytes, otherwise the behaviour is undefined.
 void quiche_config_set_stateless_reset_token(quiche_config *config, const uint8_t *v);
 
+// Sets the server's preferred IPv4 and IPv6 addresses. Either can be NULL.
+int quiche_config_set_preferred_address(quiche_config *config,
+                                        const struct sockaddr *v4, socklen_t v4_len,
+                                        const struct sockaddr *v6, socklen_t v6_len);
+
+// Configures the list of versions available for compatible version negotiation.
+int quiche_config_set_available_versions(quiche_config *config,
+                                         const uint32_t *versions,
+                                         size_t versions_len);
+
 // Frees the config object.
 void quiche_config_free(quiche_config *config);
 
@@ -291,6 +357,61 @@ ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
                      const uint8_t *token, size_t token_len,
                      uint32_t version, uint8_t *out, size_t out_len);
 
+// Writes a stateless reset packet in response to a received packet of
+// |received_len| bytes with the given destination connection ID.
+ssize_t quiche_stateless_reset(const uint8_t *dcid, size_t dcid_len,
+                               const uint8_t *key, size_t key_len,
+                               size_t received_len,
+                               uint8_t *out, size_t out_len);
+
+// Writes the 16 bytes stateless reset token for the given connection ID to
+// |out|.
+void quiche_stateless_reset_token(const uint8_t *cid, size_t cid_len,
+                                  const uint8_t *key, size_t key_len,
+                                  uint8_t *out);
+
+// Stores the key used to seal and open address validation tokens.
+typedef struct quiche_token_key quiche_token_key;
+
+// Creates a token key from the given secret.
+quiche_token_key *quiche_token_key_new(const uint8_t *secret, size_t secret_len);
+
+// Replaces the secret used to seal new tokens. Tokens sealed using the
+// current secret are still accepted until the next rotation.
+void quiche_token_key_rotate(quiche_token_key *key,
+                             const uint8_t *secret, size_t secret_len);
+
+// Sets how long tokens sent in Retry packets remain valid, in milliseconds.
+void quiche_token_key_set_retry_token_lifetime(quiche_token_key *key, uint64_t v);
+
+// Sets how long tokens sent in NEW_TOKEN frames remain valid, in milliseconds.
+void quiche_token_key_set_new_token_lifetime(quiche_token_key *key, uint64_t v);
+
+// Seals a token to be sent in a Retry packet, binding the peer address and
+// the original destination connection ID.
+ssize_t quiche_token_key_seal_retry_token(const quiche_token_key *key,
+                                          const struct sockaddr *peer,
+                                          size_t peer_len,
+                                          const uint8_t *odcid, size_t odcid_len,
+                                          uint8_t *out, size_t out_len);
+
+// Seals a token to be sent in a NEW_TOKEN frame, binding the peer address.
+ssize_t quiche_token_key_seal_new_token(const quiche_token_key *key,
+                                        const struct sockaddr *peer,
+                                        size_t peer_len,
+                                        uint8_t *out, size_t out_len);
+
+// Opens a token received in an Initial packet. On success, the original
+// destination connection ID of Retry tokens is written to |odcid|, while
+// |odcid_len| is set to 0 for NEW_TOKEN tokens.
+int quiche_token_key_open(const quiche_token_key *key,
+                          const struct sockaddr *peer, size_t peer_len,
+                          const uint8_t *token, size_t token_len,
+                          uint8_t *odcid, size_t *odcid_len);
+
+// Frees the token key object.
+void quiche_token_key_free(quiche_token_key *key);
+
 // Returns true if the given protocol version is supported.
 bool quiche_version_is_supported(uint32_t version);
 
@@ -318,6 +439,17 @@ void quiche_conn_set_qlog_fd(quiche_conn *conn, int fd, const char *log_title,
 // Configures the given session for resumption.
 int quiche_conn_set_session(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
 
+// Configures the address validation token sent in the client's Initial packets.
+int quiche_conn_set_token(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
+
+// Marks the client's address as verified, e.g. after validating its token.
+int quiche_conn_set_peer_address_verified(quiche_conn *conn);
+
+// Writes the next token received in a NEW_TOKEN frame into the provided
+// buffer. The token is dropped if the buffer is too short.
+ssize_t quiche_conn_new_token_next(quiche_conn *conn, uint8_t *out,
+                                   size_t out_len);
+
 typedef struct {
     // The remote address the packet was received from.
     struct sockaddr *from;
@@ -326,12 +458,21 @@ typedef struct {
     // The local address the packet was received on.
     struct sockaddr *to;
     socklen_t to_len;
+
+    // The ECN codepoint of the IP header the packet was received with.
+    uint8_t ecn;
 } quiche_recv_info;
 
 // Processes QUIC packets received from the peer.
 ssize_t quiche_conn_recv(quiche_conn *conn, uint8_t *buf, size_t buf_len,
                          const quiche_recv_info *info);
 
+// Processes a buffer of GRO-coalesced QUIC packets received from the peer,
+// each one segment_size bytes long except for the last.
+ssize_t quiche_conn_recv_segments(quiche_conn *conn, uint8_t *buf,
+                                  size_t buf_len, size_t segment_size,
+                                  const quiche_recv_info *info);
+
 typedef struct {
     // The local address the packet should be sent from.
     struct sockaddr_storage from;
@@ -343,12 +484,21 @@ typedef struct {
 
     // The time to send the packet out.
     struct timespec at;
+
+    // The ECN codepoint to set on the IP header of the packet.
+    uint8_t ecn;
 } quiche_send_info;
 
 // Writes a single QUIC packet to be sent to the peer.
 ssize_t quiche_conn_send(quiche_conn *conn, uint8_t *out, size_t out_len,
                          quiche_send_info *out_info);
 
+// Writes a batch of QUIC packets of the same size to be sent to the peer with
+// GSO, and sets segment_size to the size of each packet.
+ssize_t quiche_conn_send_batch(quiche_conn *conn, uint8_t *out, size_t out_len,
+                               size_t max_segments, quiche_send_info *out_info,
+                               size_t *segment_size);
+
 // Returns the size of the send quantum, in bytes.
 size_t quiche_conn_send_quantum(const quiche_conn *conn);
 
@@ -374,6 +524,11 @@ int quiche_conn_stream_priority(quiche_conn *conn, uint64_t stream_id,
 int quiche_conn_stream_shutdown(quiche_conn *conn, uint64_t stream_id,
                                 enum quiche_shutdown direction, uint64_t err);
 
+// Resets the sending side of the specified stream, but still delivers data
+// up to the given reliable size to the peer.
+int quiche_conn_stream_shutdown_at(quiche_conn *conn, uint64_t stream_id,
+                                   uint64_t err, uint64_t reliable_size);
+
 // Returns the stream's send capacity in bytes.
 ssize_t quiche_conn_stream_capacity(const quiche_conn *conn, uint64_t stream_id);
 
@@ -416,6 +571,9 @@ uint64_t quiche_conn_timeout_as_millis(const quiche_conn *conn);
 // Processes a timeout event.
 void quiche_conn_on_timeout(quiche_conn *conn);
 
+// Initiates a key update.
+int quiche_conn_initiate_key_update(quiche_conn *conn);
+
 // Closes the connection with the given error and reason.
 int quiche_conn_close(quiche_conn *conn, bool app, uint64_t err,
                       const uint8_t *reason, size_t reason_len);
@@ -482,6 +640,97 @@ bool quiche_conn_local_error(const quiche_conn *conn,
                              const uint8_t **reason,
                              size_t *reason_len);
 
+enum quiche_conn_event_type {
+    QUICHE_CONN_EVENT_HANDSHAKE_COMPLETED,
+    QUICHE_CONN_EVENT_RESUMED,
+    QUICHE_CONN_EVENT_EARLY_DATA_REJECTED,
+    QUICHE_CONN_EVENT_STREAM_READABLE,
+    QUICHE_CONN_EVENT_STREAM_WRITABLE,
+    QUICHE_CONN_EVENT_STREAM_RESET,
+    QUICHE_CONN_EVENT_STREAM_STOPPED,
+    QUICHE_CONN_EVENT_DATAGRAM_RECEIVED,
+    QUICHE_CONN_EVENT_PATH,
+    QUICHE_CONN_EVENT_SCID_RETIRED,
+    QUICHE_CONN_EVENT_PEER_CLOSED,
+    QUICHE_CONN_EVENT_DATAGRAM_ACKED,
+    QUICHE_CONN_EVENT_DATAGRAM_LOST,
+};
+
+typedef struct quiche_conn_event quiche_conn_event;
+
+// Returns the next event raised on the connection, or NULL if there are no
+// events to report.
+quiche_conn_event *quiche_conn_poll_event(quiche_conn *conn);
+
+// Returns the type of the event.
+enum quiche_conn_event_type quiche_conn_event_type(const quiche_conn_event *ev);
+
+// Returns true if the event is related to a stream, and updates the provided
+// parameters accordingly. The error code is only set for stream reset and
+// stopped events.
+bool quiche_conn_event_stream(const quiche_conn_event *ev, uint64_t *stream_id,
+                              uint64_t *error_code);
+
+// Returns true if the event reports a tracked DATAGRAM being acknowledged or
+// lost, and updates the provided ID accordingly.
+bool quiche_conn_event_dgram(const quiche_conn_event *ev, uint64_t *id);
+
+// Returns true if the event reports a retired source connection ID, and
+// updates the provided parameters accordingly.
+bool quiche_conn_event_retired_scid(const quiche_conn_event *ev,
+                                    const uint8_t **out, size_t *out_len);
+
+// Returns true if the event reports the connection being closed by the peer,
+// and updates the provided parameters accordingly.
+bool quiche_conn_event_peer_error(const quiche_conn_event *ev,
+                                  bool *is_app,
+                                  uint64_t *error_code,
+                                  const uint8_t **reason,
+                                  size_t *reason_len);
+
+enum quiche_path_event_type {
+    QUICHE_PATH_EVENT_NEW,
+    QUICHE_PATH_EVENT_VALIDATED,
+    QUICHE_PATH_EVENT_FAILED_VALIDATION,
+    QUICHE_PATH_EVENT_CLOSED,
+    QUICHE_PATH_EVENT_REUSED_SOURCE_CONNECTION_ID,
+    QUICHE_PATH_EVENT_PEER_MIGRATED,
+};
+
+typedef struct quiche_path_event quiche_path_event;
+
+// Returns the path event carried by a QUICHE_CONN_EVENT_PATH event, or NULL
+// for any other event type. The returned object must be freed with
+// quiche_path_event_free().
+quiche_path_event *quiche_conn_event_path(const quiche_conn_event *ev);
+
+// Frees the event object.
+void quiche_conn_event_free(quiche_conn_event *ev);
+
+// Returns the type of the path event.
+enum quiche_path_event_type quiche_path_event_type(const quiche_path_event *ev);
+
+// Fills the local and peer addresses of the path the event relates to. For
+// QUICHE_PATH_EVENT_REUSED_SOURCE_CONNECTION_ID these are the addresses the
+// connection ID is now used on.
+void quiche_path_event_addrs(const quiche_path_event *ev,
+                             struct sockaddr_storage *local,
+                             socklen_t *local_len,
+                             struct sockaddr_storage *peer,
+                             socklen_t *peer_len);
+
+// Returns true if the event reports a reused source connection ID, and
+// updates the provided sequence number and previous addresses accordingly.
+bool quiche_path_event_reused_source_connection_id(const quiche_path_event *ev,
+                                                   uint64_t *cid_seq,
+                                                   struct sockaddr_storage *old_local,
+                                                   socklen_t *old_local_len,
+                                                   struct sockaddr_storage *old_peer,
+                                                   socklen_t *old_peer_len);
+
+// Frees the path event object.
+void quiche_path_event_free(quiche_path_event *ev);
+
 // Fetches the next stream from the given iterator. Returns false if there are
 // no more elements in the iterator.
 bool quiche_stream_iter_next(quiche_stream_iter *iter, uint64_t *stream_id);
@@ -528,6 +777,10 @@ typedef struct {
 
     // The number of streams stopped by remote.
     uint64_t stopped_stream_count_remote;
+
+    // The time stream data was blocked by the congestion window (in
+    // nanoseconds).
+    uint64_t congestion_blocked;
 } quiche_stats;
 
 // Collects and returns statistics about the connection.
@@ -637,6 +890,36 @@ typedef struct {
 // field of `quiche_stats`).
 int quiche_conn_path_stats(const quiche_conn *conn, size_t idx, quiche_path_stats *out);
 
+typedef struct {
+    // The number of stream bytes sent, including retransmissions.
+    uint64_t sent_bytes;
+
+    // The number of stream bytes acknowledged by the peer.
+    uint64_t acked_bytes;
+
+    // The number of stream bytes retransmitted.
+    uint64_t retrans_bytes;
+
+    // The number of stream bytes received, including duplicates.
+    uint64_t recv_bytes;
+
+    // The time between the creation of the stream and the first byte of data
+    // being sent (in nanoseconds), or -1 if no data was sent yet.
+    int64_t first_byte_sent;
+
+    // The time between the creation of the stream and the first byte of data
+    // being acknowledged (in nanoseconds), or -1 if no data was acked yet.
+    int64_t first_byte_acked;
+
+    // The time the application was blocked from writing to the stream by flow
+    // control (in nanoseconds).
+    uint64_t flow_control_blocked;
+} quiche_stream_stats;
+
+// Collects and returns statistics about the specified stream. The final
+// statistics of a collected stream are retained until they are returned once.
+int quiche_conn_stream_stats(quiche_conn *conn, uint64_t stream_id, quiche_stream_stats *out);
+
 // Returns whether or not this is a server-side connection.
 bool quiche_conn_is_server(const quiche_conn *conn);
 
@@ -666,6 +949,11 @@ ssize_t quiche_conn_dgram_recv(quiche_conn *conn, uint8_t *buf,
 ssize_t quiche_conn_dgram_send(quiche_conn *conn, const uint8_t *buf,
                                size_t buf_len);
 
+// Sends data in a DATAGRAM frame, and tracks its delivery. The ID reported by
+// the DATAGRAM acked and lost events is written to the provided pointer.
+ssize_t quiche_conn_dgram_send_tracked(quiche_conn *conn, const uint8_t *buf,
+                                       size_t buf_len, uint64_t *id);
+
 // Purges queued outgoing DATAGRAMs matching the predicate.
 void quiche_conn_dgram_purge_outgoing(quiche_conn *conn,
                                       bool (*f)(uint8_t *, size_t));
@@ -678,6 +966,36 @@ ssize_t quiche_conn_send_ack_eliciting_on_path(quiche_conn *conn,
                            const struct sockaddr *local, size_t local_len,
                            const struct sockaddr *peer, size_t peer_len);
 
+// Requests the peer to change how often it sends ACKs.
+int quiche_conn_set_ack_frequency(quiche_conn *conn,
+                                  uint64_t ack_eliciting_threshold,
+                                  uint64_t max_ack_delay_us,
+                                  uint64_t reordering_threshold);
+
+// Requests the peer to send an ACK immediately.
+int quiche_conn_send_immediate_ack(quiche_conn *conn);
+
+// Returns true if the multipath extension was negotiated.
+bool quiche_conn_is_multipath_enabled(const quiche_conn *conn);
+
+enum quiche_path_status {
+    QUICHE_PATH_STATUS_STANDBY = 1,
+    QUICHE_PATH_STATUS_AVAILABLE = 2,
+};
+
+// Advertises the preferred status of the specified path to the peer.
+int quiche_conn_set_path_status(quiche_conn *conn,
+                                const struct sockaddr *local, size_t local_len,
+                                const struct sockaddr *peer, size_t peer_len,
+                                enum quiche_path_status status);
+
+// Abandons the specified path.
+int quiche_conn_abandon_path(quiche_conn *conn,
+                             const struct sockaddr *local, size_t local_len,
+                             const struct sockaddr *peer, size_t peer_len,
+                             uint64_t error_code, const uint8_t *reason,
+                             size_t reason_len);
+
 // Frees the connection object.
 void quiche_conn_free(quiche_conn *conn);
 
@@ -824,6 +1142,24 @@ enum quiche_h3_error {
 
     // See QUICHE_ERR_KEY_UPDATE.
     QUICHE_H3_TRANSPORT_ERR_KEY_UPDATE = QUICHE_ERR_KEY_UPDATE - 1000,
+
+    // See QUICHE_ERR_VERSION_NEGOTIATION.
+    QUICHE_H3_TRANSPORT_ERR_VERSION_NEGOTIATION = QUICHE_ERR_VERSION_NEGOTIATION - 1000,
+
+    // See QUICHE_ERR_INVALID_TOKEN.
+    QUICHE_H3_TRANSPORT_ERR_INVALID_TOKEN = QUICHE_ERR_INVALID_TOKEN - 1000,
+
+    // See QUICHE_ERR_TOKEN_EXPIRED.
+    QUICHE_H3_TRANSPORT_ERR_TOKEN_EXPIRED = QUICHE_ERR_TOKEN_EXPIRED - 1000,
+
+    // See QUICHE_ERR_AEAD_LIMIT_REACHED.
+    QUICHE_H3_TRANSPORT_ERR_AEAD_LIMIT_REACHED = QUICHE_ERR_AEAD_LIMIT_REACHED - 1000,
+
+    // See QUICHE_ERR_INVALID_MULTIPATH_SCHEDULER.
+    QUICHE_H3_TRANSPORT_ERR_INVALID_MULTIPATH_SCHEDULER = QUICHE_ERR_INVALID_MULTIPATH_SCHEDULER - 1000,
+
+    // See QUICHE_ERR_INVALID_LB_CONFIG.
+    QUICHE_H3_TRANSPORT_ERR_INVALID_LB_CONFIG = QUICHE_ERR_INVALID_LB_CONFIG - 1000,
 };
 
 // Stores configuration shared between multiple connections.
@@ -0,0 +1,332 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! QUIC Acknowledgment Frequency extension.
+//!
+//! This implementation is based on the following draft:
+//!
+//! <https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency>
+
+use std::time::Duration;
+
+use crate::frame;
+use crate::ranges;
+
+/// The ACK frequency parameters of one side of the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Params {
+    seq_num: u64,
+
+    ack_eliciting_threshold: u64,
+
+    /// The requested maximum ACK delay, in microseconds.
+    max_ack_delay: u64,
+
+    reordering_threshold: u64,
+}
+
+#[derive(Default)]
+pub struct AckFreq {
+    /// The parameters requested by the peer, if any.
+    recv: Option<Params>,
+
+    /// The parameters requested from the peer, if any.
+    sent: Option<Params>,
+
+    /// Whether the last requested parameters need to be (re)transmitted.
+    send_pending: bool,
+
+    /// Whether an IMMEDIATE_ACK frame needs to be sent.
+    immediate_ack_pending: bool,
+}
+
+impl AckFreq {
+    /// Handles an ACK_FREQUENCY frame received from the peer.
+    ///
+    /// Frames that are older than the last one processed are ignored.
+    pub fn on_ack_frequency_received(
+        &mut self, seq_num: u64, ack_eliciting_threshold: u64,
+        max_ack_delay: u64, reordering_threshold: u64,
+    ) {
+        if self.recv.map_or(false, |p| seq_num <= p.seq_num) {
+            return;
+        }
+
+        self.recv = Some(Params {
+            seq_num,
+            ack_eliciting_threshold,
+            max_ack_delay,
+            reordering_threshold,
+        });
+    }
+
+    /// Returns the maximum ACK delay requested by the peer, if any.
+    pub fn max_ack_delay(&self) -> Option<Duration> {
+        self.recv.map(|p| Duration::from_micros(p.max_ack_delay))
+    }
+
+    /// Returns whether the ACK for the ack-eliciting packet that was just
+    /// received can be delayed.
+    ///
+    /// `unacked` is the number of ack-eliciting packets received since the
+    /// last ACK was sent, `prev_largest` is the largest packet number received
+    /// before this packet, and `largest_reported` is the largest packet number
+    /// acknowledged by the last ACK that was sent.
+    pub fn delay_ack(
+        &self, pn: u64, unacked: u64, recv_pkt_need_ack: &ranges::RangeSet,
+        prev_largest: u64, largest_reported: Option<u64>,
+    ) -> bool {
+        // Without any request from the peer, every ack-eliciting packet is
+        // acknowledged immediately.
+        let p = match self.recv {
+            Some(p) => p,
+
+            None => return false,
+        };
+
+        if unacked > p.ack_eliciting_threshold {
+            return false;
+        }
+
+        if p.reordering_threshold == 0 {
+            return true;
+        }
+
+        // The packet filled a gap.
+        if pn < prev_largest {
+            return false;
+        }
+
+        let largest = match recv_pkt_need_ack.last() {
+            Some(v) => v,
+
+            None => return true,
+        };
+
+        // Find the smallest missing packet number that wasn't reported as
+        // missing yet.
+        let mut missing = match largest_reported {
+            Some(v) => v + 1,
+
+            None => recv_pkt_need_ack.first().unwrap_or(largest),
+        };
+
+        for r in recv_pkt_need_ack.iter() {
+            if r.end <= missing {
+                continue;
+            }
+
+            if r.start > missing {
+                break;
+            }
+
+            missing = r.end;
+        }
+
+        if missing > largest {
+            return true;
+        }
+
+        largest - missing < p.reordering_threshold
+    }
+
+    /// Queues a new ACK_FREQUENCY frame with the given parameters.
+    pub fn request(
+        &mut self, ack_eliciting_threshold: u64, max_ack_delay: u64,
+        reordering_threshold: u64,
+    ) {
+        let seq_num = self.sent.map_or(0, |p| p.seq_num + 1);
+
+        self.sent = Some(Params {
+            seq_num,
+            ack_eliciting_threshold,
+            max_ack_delay,
+            reordering_threshold,
+        });
+
+        self.send_pending = true;
+    }
+
+    /// Queues an IMMEDIATE_ACK frame.
+    pub fn request_immediate_ack(&mut self) {
+        self.immediate_ack_pending = true;
+    }
+
+    /// Returns whether there are frames to be sent.
+    pub fn has_pending(&self) -> bool {
+        self.send_pending || self.immediate_ack_pending
+    }
+
+    /// Returns the ACK_FREQUENCY frame to send, if any.
+    pub fn ack_frequency_frame(&self) -> Option<frame::Frame> {
+        if !self.send_pending {
+            return None;
+        }
+
+        self.sent.map(|p| frame::Frame::AckFrequency {
+            seq_num: p.seq_num,
+            ack_eliciting_threshold: p.ack_eliciting_threshold,
+            request_max_ack_delay: p.max_ack_delay,
+            reordering_threshold: p.reordering_threshold,
+        })
+    }
+
+    pub fn on_ack_frequency_sent(&mut self) {
+        self.send_pending = false;
+    }
+
+    /// Handles the loss of an ACK_FREQUENCY frame. It is only retransmitted
+    /// if it wasn't superseded by a newer request.
+    pub fn on_ack_frequency_lost(&mut self, seq_num: u64) {
+        if self.sent.map_or(false, |p| p.seq_num == seq_num) {
+            self.send_pending = true;
+        }
+    }
+
+    /// Handles the acknowledgment of an ACK_FREQUENCY frame, and returns the
+    /// maximum ACK delay the peer now uses, if it changed.
+    pub fn on_ack_frequency_acked(&mut self, seq_num: u64) -> Option<Duration> {
+        self.sent
+            .filter(|p| p.seq_num == seq_num)
+            .map(|p| Duration::from_micros(p.max_ack_delay))
+    }
+
+    pub fn immediate_ack_pending(&self) -> bool {
+        self.immediate_ack_pending
+    }
+
+    pub fn on_immediate_ack_sent(&mut self) {
+        self.immediate_ack_pending = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(pns: &[u64]) -> ranges::RangeSet {
+        let mut r = ranges::RangeSet::default();
+
+        for &pn in pns {
+            r.push_item(pn);
+        }
+
+        r
+    }
+
+    #[test]
+    fn no_request() {
+        let ack_freq = AckFreq::default();
+
+        assert_eq!(ack_freq.max_ack_delay(), None);
+        assert!(!ack_freq.delay_ack(0, 1, &ranges(&[0]), 0, None));
+    }
+
+    #[test]
+    fn ack_eliciting_threshold() {
+        let mut ack_freq = AckFreq::default();
+
+        ack_freq.on_ack_frequency_received(0, 2, 50_000, 0);
+        assert_eq!(ack_freq.max_ack_delay(), Some(Duration::from_millis(50)));
+
+        assert!(ack_freq.delay_ack(0, 1, &ranges(&[0]), 0, None));
+        assert!(ack_freq.delay_ack(1, 2, &ranges(&[0, 1]), 0, None));
+        assert!(!ack_freq.delay_ack(2, 3, &ranges(&[0, 1, 2]), 1, None));
+
+        // Older requests are ignored.
+        ack_freq.on_ack_frequency_received(0, 0, 25_000, 0);
+        assert_eq!(ack_freq.max_ack_delay(), Some(Duration::from_millis(50)));
+        assert!(ack_freq.delay_ack(0, 1, &ranges(&[0]), 0, None));
+    }
+
+    #[test]
+    fn reordering_threshold() {
+        let mut ack_freq = AckFreq::default();
+
+        ack_freq.on_ack_frequency_received(0, 10, 25_000, 3);
+
+        // Packet 3 is missing.
+        let r = ranges(&[0, 1, 2, 4, 5]);
+        assert!(ack_freq.delay_ack(5, 5, &r, 4, None));
+
+        let r = ranges(&[0, 1, 2, 4, 5, 6]);
+        assert!(!ack_freq.delay_ack(6, 6, &r, 5, None));
+
+        // Packet 3 was already reported as missing.
+        let r = ranges(&[0, 1, 2, 4, 5, 6]);
+        assert!(ack_freq.delay_ack(6, 1, &r, 5, Some(5)));
+
+        // Packet 3 arrived out of order.
+        let r = ranges(&[0, 1, 2, 3, 4, 5, 6]);
+        assert!(!ack_freq.delay_ack(3, 1, &r, 6, Some(6)));
+
+        // Reordering is ignored.
+        ack_freq.on_ack_frequency_received(1, 10, 25_000, 0);
+
+        let r = ranges(&[0, 1, 2, 4, 5, 6]);
+        assert!(ack_freq.delay_ack(6, 6, &r, 5, None));
+    }
+
+    #[test]
+    fn request() {
+        let mut ack_freq = AckFreq::default();
+
+        assert!(!ack_freq.has_pending());
+        assert_eq!(ack_freq.ack_frequency_frame(), None);
+
+        ack_freq.request(10, 50_000, 1);
+        assert!(ack_freq.has_pending());
+        assert_eq!(
+            ack_freq.ack_frequency_frame(),
+            Some(frame::Frame::AckFrequency {
+                seq_num: 0,
+                ack_eliciting_threshold: 10,
+                request_max_ack_delay: 50_000,
+                reordering_threshold: 1,
+            })
+        );
+
+        ack_freq.on_ack_frequency_sent();
+        assert!(!ack_freq.has_pending());
+
+        ack_freq.on_ack_frequency_lost(0);
+        assert!(ack_freq.has_pending());
+        ack_freq.on_ack_frequency_sent();
+
+        ack_freq.request(20, 100_000, 1);
+        ack_freq.on_ack_frequency_sent();
+
+        // Superseded requests are not retransmitted.
+        ack_freq.on_ack_frequency_lost(0);
+        assert!(!ack_freq.has_pending());
+
+        assert_eq!(ack_freq.on_ack_frequency_acked(0), None);
+        assert_eq!(
+            ack_freq.on_ack_frequency_acked(1),
+            Some(Duration::from_millis(100))
+        );
+    }
+}
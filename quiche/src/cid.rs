@@ -29,9 +29,53 @@ use crate::Result;
 
 use crate::frame;
 use crate::packet::ConnectionId;
+use crate::rand;
 
 use std::collections::VecDeque;
 
+/// A generator of source connection IDs.
+///
+/// When configured with [`set_connection_id_generator()`], quiche uses it to
+/// issue new connection IDs to the peer once the handshake completes, up to
+/// the negotiated active connection ID limit, and to replace the ones the
+/// peer retires.
+///
+/// Implementations can encode routing information into the generated
+/// connection IDs, such as a server or worker ID, so that load balancers can
+/// route packets to the right server without keeping per-connection state.
+/// Generated connection IDs must be unique, and must not be longer than
+/// [`MAX_CONN_ID_LEN`]. Otherwise no more connection IDs are issued until the
+/// peer retires one of the active ones.
+///
+/// [`set_connection_id_generator()`]:
+/// struct.Config.html#method.set_connection_id_generator
+/// [`MAX_CONN_ID_LEN`]: constant.MAX_CONN_ID_LEN.html
+pub trait ConnectionIdGenerator: Send + Sync {
+    /// Returns a new connection ID.
+    fn new_connection_id(&self) -> ConnectionId<'static>;
+
+    /// Returns the length of the connection IDs returned by
+    /// [`new_connection_id()`].
+    ///
+    /// [`new_connection_id()`]: trait.ConnectionIdGenerator.html#tymethod.new_connection_id
+    fn cid_len(&self) -> usize;
+
+    /// Returns the stateless reset token associated with the given
+    /// connection ID.
+    ///
+    /// By default a random token is generated. Implementations that need to
+    /// send stateless resets can instead derive it from the connection ID,
+    /// for example using [`stateless_reset_token()`].
+    ///
+    /// [`stateless_reset_token()`]: fn.stateless_reset_token.html
+    fn stateless_reset_token(&self, _cid: &ConnectionId) -> u128 {
+        let mut token = [0; 16];
+        rand::rand_bytes(&mut token);
+
+        u128::from_be_bytes(token)
+    }
+}
+
 /// A structure holding a `ConnectionId` and all its related metadata.
 #[derive(Debug, Default)]
 pub struct ConnectionIdEntry {
@@ -413,6 +457,44 @@ impl ConnectionIdentifiers {
         Ok(seq)
     }
 
+    /// Issues new source Connection IDs using the given generator until
+    /// `limit` of them are active, without exceeding the limit set by the
+    /// peer.
+    ///
+    /// New Connection IDs are advertised to the peer through
+    /// `NEW_CONNECTION_ID` frames. If the generator returns an invalid
+    /// Connection ID, or one already in use, no more Connection IDs are issued
+    /// until the next time this is called.
+    pub fn replenish_scids(
+        &mut self, generator: &dyn ConnectionIdGenerator, limit: usize,
+    ) {
+        if self.zero_length_scid {
+            return;
+        }
+
+        let limit = std::cmp::min(limit, self.source_conn_id_limit);
+
+        while self.active_source_cids() < limit {
+            let cid = generator.new_connection_id();
+
+            if cid.is_empty() ||
+                cid.len() > crate::MAX_CONN_ID_LEN ||
+                self.scids.iter().any(|e| e.cid == cid)
+            {
+                break;
+            }
+
+            let reset_token = generator.stateless_reset_token(&cid);
+
+            if self
+                .new_scid(cid, Some(reset_token), true, None, false)
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
     /// Sets the initial destination identifier.
     pub fn set_initial_dcid(
         &mut self, cid: ConnectionId<'static>, reset_token: Option<u128>,
@@ -813,6 +895,73 @@ mod tests {
     use super::*;
     use crate::testing::create_cid_and_reset_token;
 
+    struct TestCidGenerator;
+
+    impl ConnectionIdGenerator for TestCidGenerator {
+        fn new_connection_id(&self) -> ConnectionId<'static> {
+            create_cid_and_reset_token(16).0
+        }
+
+        fn cid_len(&self) -> usize {
+            16
+        }
+    }
+
+    struct FixedCidGenerator;
+
+    impl ConnectionIdGenerator for FixedCidGenerator {
+        fn new_connection_id(&self) -> ConnectionId<'static> {
+            ConnectionId::from_vec(vec![0xba; 16])
+        }
+
+        fn cid_len(&self) -> usize {
+            16
+        }
+    }
+
+    #[test]
+    fn ids_replenish_scids() {
+        let (scid, _) = create_cid_and_reset_token(16);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_source_conn_id_limit(3);
+
+        ids.replenish_scids(&TestCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 3);
+        assert_eq!(ids.next_advertise_new_scid_seq(), Some(1));
+
+        // Nothing to do when all the Connection IDs are in use.
+        ids.replenish_scids(&TestCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 3);
+
+        let pkt_dcid = ids.get_scid(1).unwrap().cid.clone();
+        assert_eq!(ids.retire_scid(0, &pkt_dcid), Ok(Some(0)));
+        assert_eq!(ids.active_source_cids(), 2);
+
+        ids.replenish_scids(&TestCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 3);
+        assert!(ids.get_scid(3).is_ok());
+
+        // Replenishing stops when the generator returns a Connection ID that
+        // is already in use.
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_source_conn_id_limit(4);
+
+        ids.replenish_scids(&FixedCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 2);
+
+        ids.replenish_scids(&FixedCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 2);
+
+        // Nothing is issued when using zero-length Connection IDs.
+        let mut ids =
+            ConnectionIdentifiers::new(2, &ConnectionId::default(), 0, None);
+        ids.set_source_conn_id_limit(3);
+
+        ids.replenish_scids(&TestCidGenerator, 8);
+        assert_eq!(ids.active_source_cids(), 1);
+    }
+
     #[test]
     fn ids_new_scids() {
         let (scid, _) = create_cid_and_reset_token(16);
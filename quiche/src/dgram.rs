@@ -30,9 +30,12 @@ use crate::Result;
 use std::collections::VecDeque;
 
 /// Keeps track of DATAGRAM frames.
+///
+/// Each DATAGRAM is stored along with the optional ID the application uses to
+/// track its delivery.
 #[derive(Default)]
 pub struct DatagramQueue {
-    queue: Option<VecDeque<Vec<u8>>>,
+    queue: Option<VecDeque<(Vec<u8>, Option<u64>)>>,
     queue_max_len: usize,
     queue_bytes_size: usize,
 }
@@ -47,6 +50,10 @@ impl DatagramQueue {
     }
 
     pub fn push(&mut self, data: Vec<u8>) -> Result<()> {
+        self.push_with_id(data, None)
+    }
+
+    pub fn push_with_id(&mut self, data: Vec<u8>, id: Option<u64>) -> Result<()> {
         if self.is_full() {
             return Err(Error::Done);
         }
@@ -54,18 +61,20 @@ impl DatagramQueue {
         self.queue_bytes_size += data.len();
         self.queue
             .get_or_insert_with(Default::default)
-            .push_back(data);
+            .push_back((data, id));
 
         Ok(())
     }
 
     pub fn peek_front_len(&self) -> Option<usize> {
-        self.queue.as_ref().and_then(|q| q.front().map(|d| d.len()))
+        self.queue
+            .as_ref()
+            .and_then(|q| q.front().map(|(d, _)| d.len()))
     }
 
     pub fn peek_front_bytes(&self, buf: &mut [u8], len: usize) -> Result<usize> {
         match self.queue.as_ref().and_then(|q| q.front()) {
-            Some(d) => {
+            Some((d, _)) => {
                 let len = std::cmp::min(len, d.len());
                 if buf.len() < len {
                     return Err(Error::BufferTooShort);
@@ -80,9 +89,13 @@ impl DatagramQueue {
     }
 
     pub fn pop(&mut self) -> Option<Vec<u8>> {
-        if let Some(d) = self.queue.as_mut().and_then(|q| q.pop_front()) {
+        self.pop_with_id().map(|(d, _)| d)
+    }
+
+    pub fn pop_with_id(&mut self) -> Option<(Vec<u8>, Option<u64>)> {
+        if let Some((d, id)) = self.queue.as_mut().and_then(|q| q.pop_front()) {
             self.queue_bytes_size = self.queue_bytes_size.saturating_sub(d.len());
-            return Some(d);
+            return Some((d, id));
         }
 
         None
@@ -92,11 +105,24 @@ impl DatagramQueue {
         !self.queue.as_ref().map(|q| q.is_empty()).unwrap_or(true)
     }
 
-    pub fn purge<F: Fn(&[u8]) -> bool>(&mut self, f: F) {
+    pub fn purge<F: Fn(&[u8]) -> bool>(&mut self, f: F) -> usize {
+        let mut tracked = 0;
+
         if let Some(q) = self.queue.as_mut() {
-            q.retain(|d| !f(d));
-            self.queue_bytes_size = q.iter().fold(0, |total, d| total + d.len());
+            q.retain(|(d, id)| {
+                let purge = f(d);
+
+                if purge && id.is_some() {
+                    tracked += 1;
+                }
+
+                !purge
+            });
+            self.queue_bytes_size =
+                q.iter().fold(0, |total, (d, _)| total + d.len());
         }
+
+        tracked
     }
 
     pub fn is_full(&self) -> bool {
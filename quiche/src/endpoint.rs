@@ -0,0 +1,890 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Server-side management of many connections.
+//!
+//! An [`Endpoint`] owns the connections accepted by a server, and takes care
+//! of routing incoming datagrams to them based on their destination
+//! connection ID, of version negotiation and of stateless retry. Socket I/O
+//! is still left to the application.
+//!
+//! [`Endpoint`]: struct.Endpoint.html
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use std::time;
+
+use crate::rand;
+
+use crate::Config;
+use crate::Connection;
+use crate::ConnectionId;
+use crate::Ecn;
+use crate::Error;
+use crate::Header;
+use crate::RecvInfo;
+use crate::Result;
+use crate::SendInfo;
+use crate::TokenKey;
+use crate::Type;
+
+/// The maximum number of stateless packets waiting to be sent.
+///
+/// Packets generated once the queue is full are dropped, so that an
+/// application that doesn't call [`send_stateless()`] doesn't make the queue
+/// grow unbounded.
+///
+/// [`send_stateless()`]: struct.Endpoint.html#method.send_stateless
+const MAX_STATELESS_QUEUE_LEN: usize = 1024;
+
+/// An opaque identifier of a connection owned by an [`Endpoint`].
+///
+/// [`Endpoint`]: struct.Endpoint.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionHandle(u64);
+
+struct Entry {
+    conn: Connection,
+
+    /// The source connection IDs that are currently routed to the connection.
+    cids: Vec<ConnectionId<'static>>,
+
+    /// The destination connection ID picked by the client, which is routed
+    /// to the connection until the handshake is confirmed.
+    odcid: Option<ConnectionId<'static>>,
+}
+
+/// A QUIC server endpoint.
+///
+/// The endpoint owns the connections accepted by the server. Datagrams
+/// received from the socket are passed to [`recv()`], which routes them to
+/// the right connection based on their destination connection ID, or creates
+/// a new connection for Initial packets sent by new clients.
+///
+/// Clients advertising an unsupported version are answered with a Version
+/// Negotiation packet, and if a [`TokenKey`] is set using [`enable_retry()`]
+/// clients are asked to validate their address with a Retry packet before a
+/// connection is created. These packets are queued and need to be sent by the
+/// application using [`send_stateless()`].
+///
+/// Connections that have work to do, for example because they received
+/// packets or their timer expired, are returned by [`ready()`], after which
+/// the application is expected to process them as usual, and to call
+/// [`send()`] on them until [`Done`] is returned.
+///
+/// Connections are never removed implicitly, the application needs to call
+/// [`remove_closed()`] to collect the ones that are closed.
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:4433").unwrap();
+/// # let local = socket.local_addr().unwrap();
+/// let mut endpoint = quiche::Endpoint::new(config);
+///
+/// let mut buf = [0; 65535];
+/// let mut out = [0; 1350];
+///
+/// loop {
+///     socket.set_read_timeout(endpoint.timeout()).unwrap();
+///
+///     match socket.recv_from(&mut buf) {
+///         Ok((len, from)) => {
+///             let info = quiche::RecvInfo {
+///                 from,
+///                 to: local,
+///                 ecn: quiche::Ecn::NotEct,
+///             };
+///
+///             endpoint.recv(&mut buf[..len], info).ok();
+///         },
+///
+///         Err(_) => endpoint.on_timeout(),
+///     }
+///
+///     while let Ok((len, send_info)) = endpoint.send_stateless(&mut out) {
+///         socket.send_to(&out[..len], send_info.to).unwrap();
+///     }
+///
+///     for (_, conn) in endpoint.ready() {
+///         // Process streams...
+///
+///         while let Ok((len, send_info)) = conn.send(&mut out) {
+///             socket.send_to(&out[..len], send_info.to).unwrap();
+///         }
+///     }
+///
+///     endpoint.remove_closed();
+/// }
+/// # Ok::<(), quiche::Error>(())
+/// ```
+///
+/// [`recv()`]: struct.Endpoint.html#method.recv
+/// [`TokenKey`]: struct.TokenKey.html
+/// [`enable_retry()`]: struct.Endpoint.html#method.enable_retry
+/// [`send_stateless()`]: struct.Endpoint.html#method.send_stateless
+/// [`ready()`]: struct.Endpoint.html#method.ready
+/// [`send()`]: struct.Connection.html#method.send
+/// [`Done`]: enum.Error.html#variant.Done
+/// [`remove_closed()`]: struct.Endpoint.html#method.remove_closed
+pub struct Endpoint {
+    config: Config,
+
+    retry_key: Option<TokenKey>,
+
+    /// The length of the connection IDs generated by the endpoint.
+    cid_len: usize,
+
+    conns: HashMap<ConnectionHandle, Entry>,
+
+    /// Maps connection IDs to the connection they are routed to.
+    routes: HashMap<ConnectionId<'static>, ConnectionHandle>,
+
+    next_handle: u64,
+
+    /// Version Negotiation and Retry packets waiting to be sent.
+    stateless: VecDeque<(Vec<u8>, SendInfo)>,
+
+    /// Connections that have work to do.
+    ready: HashSet<ConnectionHandle>,
+
+    /// Connections that were handed out to the application, and whose
+    /// source connection IDs might have changed since.
+    dirty: HashSet<ConnectionHandle>,
+}
+
+impl Endpoint {
+    /// Creates a new endpoint accepting connections using the given
+    /// configuration.
+    ///
+    /// If a connection ID generator was set using
+    /// [`set_connection_id_generator()`] it is used to generate the source
+    /// connection IDs of new connections, in which case all the connection
+    /// IDs it generates must have the length returned by its `cid_len()`
+    /// method. Otherwise random
+    /// connection IDs of [`MAX_CONN_ID_LEN`] bytes are used.
+    ///
+    /// [`set_connection_id_generator()`]:
+    /// struct.Config.html#method.set_connection_id_generator
+    /// [`MAX_CONN_ID_LEN`]: constant.MAX_CONN_ID_LEN.html
+    pub fn new(config: Config) -> Endpoint {
+        let cid_len = match &config.cid_generator {
+            Some(generator) => generator.cid_len(),
+
+            None => crate::MAX_CONN_ID_LEN,
+        };
+
+        Endpoint {
+            config,
+
+            retry_key: None,
+
+            cid_len,
+
+            conns: HashMap::new(),
+
+            routes: HashMap::new(),
+
+            next_handle: 0,
+
+            stateless: VecDeque::new(),
+
+            ready: HashSet::new(),
+
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// Enables stateless retry using the given token key.
+    ///
+    /// Clients are then required to echo back a token sealed by the key,
+    /// either sent in a Retry packet or in a NEW_TOKEN frame, before a
+    /// connection is created for them.
+    pub fn enable_retry(&mut self, key: TokenKey) {
+        self.retry_key = Some(key);
+    }
+
+    /// Returns a mutable reference to the token key used for stateless
+    /// retry, if enabled.
+    ///
+    /// This can be used to rotate the key's secret.
+    pub fn retry_key_mut(&mut self) -> Option<&mut TokenKey> {
+        self.retry_key.as_mut()
+    }
+
+    /// Processes a datagram received from the network.
+    ///
+    /// On success the handle of the connection the datagram was routed to is
+    /// returned, which might be a newly created one.
+    ///
+    /// [`Done`] is returned if the datagram wasn't processed by any
+    /// connection, either because it was dropped or because it was answered
+    /// with a stateless packet that can be retrieved using
+    /// [`send_stateless()`].
+    ///
+    /// Errors returned by the connection while processing the datagram are
+    /// not reported here, as the connection itself takes care of closing when
+    /// needed.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`send_stateless()`]: struct.Endpoint.html#method.send_stateless
+    pub fn recv(
+        &mut self, buf: &mut [u8], info: RecvInfo,
+    ) -> Result<ConnectionHandle> {
+        self.sync_dirty();
+
+        let len = buf.len();
+
+        let hdr = match Header::from_slice(buf, self.cid_len) {
+            Ok(v) => v,
+
+            Err(_) => return Err(Error::Done),
+        };
+
+        let handle = match self.routes.get(&hdr.dcid) {
+            Some(handle) => *handle,
+
+            None => self.accept(&hdr, len, &info)?,
+        };
+
+        let entry = self.conns.get_mut(&handle).ok_or(Error::Done)?;
+
+        if let Err(e) = entry.conn.recv(buf, info) {
+            trace!("{} recv failed: {:?}", entry.conn.trace_id(), e);
+        }
+
+        self.ready.insert(handle);
+
+        self.sync_cids(handle);
+
+        Ok(handle)
+    }
+
+    /// Writes a stateless packet, such as a Version Negotiation or a Retry
+    /// packet, that needs to be sent to a client.
+    ///
+    /// On success the number of bytes written to the output buffer is
+    /// returned, along with the addresses the packet should be sent from and
+    /// to. [`Done`] is returned when there are no more packets to send.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn send_stateless(
+        &mut self, out: &mut [u8],
+    ) -> Result<(usize, SendInfo)> {
+        let (pkt, _) = self.stateless.front().ok_or(Error::Done)?;
+
+        if out.len() < pkt.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        let (pkt, info) = self.stateless.pop_front().ok_or(Error::Done)?;
+
+        out[..pkt.len()].copy_from_slice(&pkt);
+
+        Ok((pkt.len(), info))
+    }
+
+    /// Returns the earliest time at which [`on_timeout()`] should be called.
+    ///
+    /// [`on_timeout()`]: struct.Endpoint.html#method.on_timeout
+    pub fn timeout_instant(&self) -> Option<time::Instant> {
+        self.conns
+            .values()
+            .filter_map(|e| e.conn.timeout_instant())
+            .min()
+    }
+
+    /// Returns the amount of time until [`on_timeout()`] should be called.
+    ///
+    /// As with [`Connection::timeout()`], `None` is returned when there are
+    /// no timers armed on any connection.
+    ///
+    /// [`on_timeout()`]: struct.Endpoint.html#method.on_timeout
+    /// [`Connection::timeout()`]: struct.Connection.html#method.timeout
+    pub fn timeout(&self) -> Option<time::Duration> {
+        self.timeout_instant().map(|timeout| {
+            let now = time::Instant::now();
+
+            timeout.saturating_duration_since(now)
+        })
+    }
+
+    /// Processes the expired timers of all connections.
+    ///
+    /// Connections whose timer expired are marked as ready.
+    pub fn on_timeout(&mut self) {
+        let now = time::Instant::now();
+
+        for (handle, entry) in self.conns.iter_mut() {
+            match entry.conn.timeout_instant() {
+                Some(timeout) if timeout <= now => {
+                    entry.conn.on_timeout();
+
+                    self.ready.insert(*handle);
+                },
+
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns an iterator over the connections that have work to do.
+    ///
+    /// A connection has work to do if it received packets, if its timer
+    /// expired, or if it was accessed using [`connection_mut()`] since the
+    /// last call to this method.
+    ///
+    /// [`connection_mut()`]: struct.Endpoint.html#method.connection_mut
+    pub fn ready(
+        &mut self,
+    ) -> impl Iterator<Item = (ConnectionHandle, &mut Connection)> {
+        let ready = std::mem::take(&mut self.ready);
+
+        self.dirty.extend(ready.iter().copied());
+
+        self.conns
+            .iter_mut()
+            .filter(move |(handle, _)| ready.contains(handle))
+            .map(|(handle, entry)| (*handle, &mut entry.conn))
+    }
+
+    /// Returns a reference to the connection with the given handle.
+    pub fn connection(&self, handle: ConnectionHandle) -> Option<&Connection> {
+        self.conns.get(&handle).map(|e| &e.conn)
+    }
+
+    /// Returns a mutable reference to the connection with the given handle.
+    ///
+    /// The connection is marked as ready, so that it is returned by the next
+    /// call to [`ready()`].
+    ///
+    /// [`ready()`]: struct.Endpoint.html#method.ready
+    pub fn connection_mut(
+        &mut self, handle: ConnectionHandle,
+    ) -> Option<&mut Connection> {
+        let entry = self.conns.get_mut(&handle)?;
+
+        self.ready.insert(handle);
+        self.dirty.insert(handle);
+
+        Some(&mut entry.conn)
+    }
+
+    /// Returns an iterator over all the connections owned by the endpoint.
+    pub fn connections(
+        &self,
+    ) -> impl Iterator<Item = (ConnectionHandle, &Connection)> {
+        self.conns.iter().map(|(handle, e)| (*handle, &e.conn))
+    }
+
+    /// Returns the number of connections owned by the endpoint.
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns true if the endpoint doesn't own any connection.
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// Removes the connections that are closed, and returns them.
+    pub fn remove_closed(&mut self) -> Vec<(ConnectionHandle, Connection)> {
+        let closed: Vec<ConnectionHandle> = self
+            .conns
+            .iter()
+            .filter(|(_, e)| e.conn.is_closed())
+            .map(|(handle, _)| *handle)
+            .collect();
+
+        if closed.is_empty() {
+            return Vec::new();
+        }
+
+        self.routes.retain(|_, handle| !closed.contains(handle));
+
+        closed
+            .into_iter()
+            .filter_map(|handle| {
+                self.ready.remove(&handle);
+                self.dirty.remove(&handle);
+
+                self.conns.remove(&handle).map(|e| (handle, e.conn))
+            })
+            .collect()
+    }
+
+    /// Handles a datagram that couldn't be routed to an existing connection,
+    /// and returns the handle of the connection created for it, if any.
+    fn accept(
+        &mut self, hdr: &Header, len: usize, info: &RecvInfo,
+    ) -> Result<ConnectionHandle> {
+        // Only datagrams large enough to carry a client's first Initial
+        // packet are answered, to limit amplification.
+        if hdr.ty == Type::Short || len < crate::MIN_CLIENT_INITIAL_LEN {
+            return Err(Error::Done);
+        }
+
+        if !crate::version_is_supported(hdr.version) {
+            let mut out = [0; crate::MIN_CLIENT_INITIAL_LEN];
+
+            let len = crate::negotiate_version(&hdr.scid, &hdr.dcid, &mut out)?;
+
+            self.queue_stateless(&out[..len], info);
+
+            return Err(Error::Done);
+        }
+
+        if hdr.ty != Type::Initial {
+            return Err(Error::Done);
+        }
+
+        let mut verified = false;
+
+        let (scid, odcid) = match &self.retry_key {
+            Some(key) => match key
+                .open(&info.from, hdr.token.as_deref().unwrap_or(&[]))
+            {
+                // The token was sent in a Retry packet, so the client is
+                // using the connection ID the server picked.
+                Ok(Some(odcid)) => (hdr.dcid.clone().into_owned(), Some(odcid)),
+
+                // The token was sent in a NEW_TOKEN frame.
+                Ok(None) => {
+                    verified = true;
+
+                    (self.new_cid(), None)
+                },
+
+                // Either there is no token, or it can't be used to validate
+                // the client's address, e.g. because it expired or was sealed
+                // by a different server. Ask the client to validate its
+                // address using a Retry packet.
+                Err(_) => {
+                    let new_scid = self.new_cid();
+
+                    let token = key.seal_retry_token(&info.from, &hdr.dcid)?;
+
+                    let mut out = [0; crate::MIN_CLIENT_INITIAL_LEN];
+
+                    let len = crate::retry(
+                        &hdr.scid,
+                        &hdr.dcid,
+                        &new_scid,
+                        &token,
+                        hdr.version,
+                        &mut out,
+                    )?;
+
+                    self.queue_stateless(&out[..len], info);
+
+                    return Err(Error::Done);
+                },
+            },
+
+            None => (self.new_cid(), None),
+        };
+
+        let mut conn = crate::accept(
+            &scid,
+            odcid.as_ref(),
+            info.to,
+            info.from,
+            &mut self.config,
+        )?;
+
+        if verified {
+            conn.set_peer_address_verified()?;
+        }
+
+        let handle = ConnectionHandle(self.next_handle);
+        self.next_handle += 1;
+
+        // Until the client receives the server's first packet it keeps
+        // using the destination connection ID it picked, so route it as
+        // well. When a Retry was used, this is the same as `scid`.
+        let odcid = hdr.dcid.clone().into_owned();
+
+        self.routes.insert(odcid.clone(), handle);
+
+        self.conns.insert(handle, Entry {
+            conn,
+            cids: Vec::new(),
+            odcid: Some(odcid),
+        });
+
+        self.sync_cids(handle);
+
+        Ok(handle)
+    }
+
+    /// Generates a new source connection ID.
+    fn new_cid(&self) -> ConnectionId<'static> {
+        match &self.config.cid_generator {
+            Some(generator) => generator.new_connection_id(),
+
+            None => {
+                let mut cid = vec![0; self.cid_len];
+                rand::rand_bytes(&mut cid);
+
+                cid.into()
+            },
+        }
+    }
+
+    fn queue_stateless(&mut self, pkt: &[u8], info: &RecvInfo) {
+        if self.stateless.len() >= MAX_STATELESS_QUEUE_LEN {
+            return;
+        }
+
+        let send_info = SendInfo {
+            from: info.to,
+            to: info.from,
+            at: time::Instant::now(),
+            ecn: Ecn::NotEct,
+        };
+
+        self.stateless.push_back((pkt.to_vec(), send_info));
+    }
+
+    /// Updates the routes of the connections that were handed out to the
+    /// application.
+    fn sync_dirty(&mut self) {
+        let dirty = std::mem::take(&mut self.dirty);
+
+        for handle in dirty {
+            self.sync_cids(handle);
+        }
+    }
+
+    /// Updates the routes of a connection to match its current source
+    /// connection IDs, as they might have been issued or retired.
+    fn sync_cids(&mut self, handle: ConnectionHandle) {
+        let entry = match self.conns.get_mut(&handle) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let current: Vec<ConnectionId<'static>> = entry
+            .conn
+            .source_ids()
+            .map(|cid| cid.clone().into_owned())
+            .collect();
+
+        for cid in entry.cids.iter().filter(|cid| !current.contains(cid)) {
+            if self.routes.get(cid) == Some(&handle) {
+                self.routes.remove(cid);
+            }
+        }
+
+        for cid in current.iter().filter(|cid| !entry.cids.contains(cid)) {
+            self.routes.insert(cid.clone(), handle);
+        }
+
+        entry.cids = current;
+
+        // Once the handshake is confirmed the client doesn't use the
+        // destination connection ID it picked anymore.
+        if entry.conn.handshake_confirmed {
+            if let Some(odcid) = entry.odcid.take() {
+                if !entry.cids.contains(&odcid) &&
+                    self.routes.get(&odcid) == Some(&handle)
+                {
+                    self.routes.remove(&odcid);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    fn client(version: u32) -> Connection {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.version = version;
+
+        let mut scid = [0; 16];
+        rand::rand_bytes(&mut scid);
+
+        crate::connect(
+            Some("quic.tech"),
+            &ConnectionId::from_ref(&scid),
+            testing::Pipe::client_addr(),
+            testing::Pipe::server_addr(),
+            &mut config,
+        )
+        .unwrap()
+    }
+
+    fn recv_info() -> RecvInfo {
+        RecvInfo {
+            from: testing::Pipe::client_addr(),
+            to: testing::Pipe::server_addr(),
+            ecn: Ecn::NotEct,
+        }
+    }
+
+    /// Exchanges packets between the client and the endpoint until neither
+    /// has anything left to send.
+    fn advance(client: &mut Connection, endpoint: &mut Endpoint) {
+        let mut buf = [0; 65535];
+
+        loop {
+            let mut sent = false;
+
+            while let Ok((len, _)) = client.send(&mut buf) {
+                endpoint.recv(&mut buf[..len], recv_info()).ok();
+
+                sent = true;
+            }
+
+            while let Ok((len, info)) = endpoint.send_stateless(&mut buf) {
+                let info = RecvInfo {
+                    from: info.from,
+                    to: info.to,
+                    ecn: Ecn::NotEct,
+                };
+
+                client.recv(&mut buf[..len], info).ok();
+
+                sent = true;
+            }
+
+            let mut out = Vec::new();
+
+            for (_, conn) in endpoint.ready() {
+                while let Ok((len, info)) = conn.send(&mut buf) {
+                    out.push((buf[..len].to_vec(), info));
+                }
+            }
+
+            for (mut pkt, info) in out {
+                let info = RecvInfo {
+                    from: info.from,
+                    to: info.to,
+                    ecn: Ecn::NotEct,
+                };
+
+                client.recv(&mut pkt, info).unwrap();
+
+                sent = true;
+            }
+
+            if !sent {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let mut endpoint =
+            Endpoint::new(testing::Pipe::default_config().unwrap());
+        let mut client = client(crate::PROTOCOL_VERSION);
+        let odcid = client.destination_id().into_owned();
+
+        advance(&mut client, &mut endpoint);
+
+        assert!(client.is_established());
+        assert_eq!(endpoint.len(), 1);
+
+        let (handle, conn) = endpoint.connections().next().unwrap();
+        assert!(conn.is_established());
+
+        // The destination connection ID picked by the client isn't routed
+        // anymore once the handshake is confirmed.
+        assert!(!endpoint.routes.contains_key(&odcid));
+
+        // Stream data is routed to the same connection.
+        assert_eq!(client.stream_send(0, b"hello", true), Ok(5));
+        advance(&mut client, &mut endpoint);
+
+        let conn = endpoint.connection_mut(handle).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(conn.stream_recv(0, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"hello");
+
+        assert!(endpoint.remove_closed().is_empty());
+    }
+
+    #[test]
+    fn version_negotiation() {
+        let mut endpoint =
+            Endpoint::new(testing::Pipe::default_config().unwrap());
+        let mut client = client(0xbabababa);
+
+        let mut buf = [0; 65535];
+
+        let (len, _) = client.send(&mut buf).unwrap();
+        assert_eq!(
+            endpoint.recv(&mut buf[..len], recv_info()),
+            Err(Error::Done)
+        );
+        assert!(endpoint.is_empty());
+
+        let (len, _) = endpoint.send_stateless(&mut buf).unwrap();
+        let hdr = Header::from_slice(&mut buf[..len], 16).unwrap();
+        assert_eq!(hdr.ty, Type::VersionNegotiation);
+
+        assert_eq!(endpoint.send_stateless(&mut buf), Err(Error::Done));
+
+        // The client then switches to a supported version.
+        let info = RecvInfo {
+            from: testing::Pipe::server_addr(),
+            to: testing::Pipe::client_addr(),
+            ecn: Ecn::NotEct,
+        };
+        assert_eq!(client.recv(&mut buf[..len], info), Ok(len));
+
+        advance(&mut client, &mut endpoint);
+
+        assert!(client.is_established());
+        assert_eq!(endpoint.len(), 1);
+    }
+
+    #[test]
+    fn retry() {
+        let mut endpoint =
+            Endpoint::new(testing::Pipe::default_config().unwrap());
+        endpoint.enable_retry(TokenKey::new(b"endpoint secret"));
+
+        let mut client = client(crate::PROTOCOL_VERSION);
+
+        let mut buf = [0; 65535];
+
+        let (len, _) = client.send(&mut buf).unwrap();
+        assert_eq!(
+            endpoint.recv(&mut buf[..len], recv_info()),
+            Err(Error::Done)
+        );
+        assert!(endpoint.is_empty());
+
+        let (len, info) = endpoint.send_stateless(&mut buf).unwrap();
+        assert_eq!(info.to, testing::Pipe::client_addr());
+
+        let hdr = Header::from_slice(&mut buf[..len], 16).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+
+        let info = RecvInfo {
+            from: testing::Pipe::server_addr(),
+            to: testing::Pipe::client_addr(),
+            ecn: Ecn::NotEct,
+        };
+        assert_eq!(client.recv(&mut buf[..len], info), Ok(len));
+
+        advance(&mut client, &mut endpoint);
+
+        assert!(client.is_established());
+        assert_eq!(endpoint.len(), 1);
+
+        let (_, conn) = endpoint.connections().next().unwrap();
+        assert!(conn.is_established());
+    }
+
+    #[test]
+    fn drop_unknown() {
+        let mut endpoint =
+            Endpoint::new(testing::Pipe::default_config().unwrap());
+        let mut client = client(crate::PROTOCOL_VERSION);
+
+        let mut buf = [0; 65535];
+
+        let (len, _) = client.send(&mut buf).unwrap();
+
+        // Datagrams that are too small to carry a client's first Initial
+        // packet are not answered.
+        assert_eq!(
+            endpoint.recv(&mut buf[..1000], recv_info()),
+            Err(Error::Done)
+        );
+        assert!(endpoint.is_empty());
+
+        assert_eq!(
+            endpoint.recv(&mut buf[..len], recv_info()),
+            Ok(ConnectionHandle(0))
+        );
+        assert_eq!(endpoint.len(), 1);
+
+        // Short header packets for unknown connections are dropped.
+        let mut pkt = [0x40; 1200];
+        assert_eq!(endpoint.recv(&mut pkt, recv_info()), Err(Error::Done));
+        assert_eq!(endpoint.len(), 1);
+    }
+
+    #[test]
+    fn timeout_and_remove_closed() {
+        let mut endpoint =
+            Endpoint::new(testing::Pipe::default_config().unwrap());
+        let mut client = client(crate::PROTOCOL_VERSION);
+
+        assert_eq!(endpoint.timeout(), None);
+
+        advance(&mut client, &mut endpoint);
+
+        assert!(client.is_established());
+        assert!(endpoint.timeout().is_some());
+
+        let (handle, _) = endpoint.connections().next().unwrap();
+
+        assert_eq!(
+            endpoint
+                .connection_mut(handle)
+                .unwrap()
+                .close(false, 0, b""),
+            Ok(())
+        );
+        assert_eq!(endpoint.ready.len(), 1);
+
+        advance(&mut client, &mut endpoint);
+
+        assert!(endpoint.ready.is_empty());
+        assert!(client.is_draining());
+
+        // Expire the closing period rather than waiting for it to end.
+        endpoint.conns.get_mut(&handle).unwrap().conn.draining_timer =
+            Some(time::Instant::now());
+
+        endpoint.on_timeout();
+
+        assert!(endpoint.connection(handle).unwrap().is_closed());
+        assert!(endpoint.ready.contains(&handle));
+
+        let closed = endpoint.remove_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, handle);
+
+        assert!(endpoint.is_empty());
+        assert!(endpoint.routes.is_empty());
+        assert_eq!(endpoint.timeout(), None);
+    }
+}
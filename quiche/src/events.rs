@@ -0,0 +1,407 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::collections::VecDeque;
+
+use crate::stream::StreamIdHashMap;
+use crate::stream::StreamIdHashSet;
+
+use crate::ConnectionError;
+use crate::ConnectionId;
+use crate::PathEvent;
+
+/// A connection event, as returned by [`poll_event()`].
+///
+/// [`poll_event()`]: struct.Connection.html#method.poll_event
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The handshake completed, and the connection is now established.
+    HandshakeCompleted,
+
+    /// The connection was resumed from a previous session. This is raised
+    /// right after [`HandshakeCompleted`].
+    ///
+    /// [`HandshakeCompleted`]: enum.ConnectionEvent.html#variant.HandshakeCompleted
+    Resumed,
+
+    /// The early data sent by the client was rejected by the server, so any
+    /// stream data sent in 0-RTT packets will be sent again. This is only
+    /// raised on the client, right after [`HandshakeCompleted`].
+    ///
+    /// [`HandshakeCompleted`]: enum.ConnectionEvent.html#variant.HandshakeCompleted
+    EarlyDataRejected,
+
+    /// The stream with the given ID has data to be read, or its receiving
+    /// side is finished, after not being readable before.
+    StreamReadable(u64),
+
+    /// The stream with the given ID can be written to again, after being
+    /// blocked by the peer's flow control limits.
+    StreamWritable(u64),
+
+    /// The peer reset the receiving side of the stream with the given ID by
+    /// sending a `RESET_STREAM` or `RESET_STREAM_AT` frame, with the given
+    /// error code. In the latter case, data up to the frame's reliable size
+    /// can still be read before the reset is reported by [`stream_recv()`].
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    StreamReset {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The error code carried by the `RESET_STREAM` or `RESET_STREAM_AT`
+        /// frame.
+        error_code: u64,
+    },
+
+    /// The peer asked for the sending side of the stream with the given ID to
+    /// be stopped by sending a `STOP_SENDING` frame, with the given error
+    /// code.
+    StreamStopped {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The error code carried by the `STOP_SENDING` frame.
+        error_code: u64,
+    },
+
+    /// One or more DATAGRAM frames were received, and can be read with
+    /// [`dgram_recv()`].
+    ///
+    /// [`dgram_recv()`]: struct.Connection.html#method.dgram_recv
+    DatagramReceived,
+
+    /// The DATAGRAM with the given ID, as returned by [`dgram_send_tracked()`],
+    /// was acknowledged by the peer.
+    ///
+    /// [`dgram_send_tracked()`]: struct.Connection.html#method.dgram_send_tracked
+    DatagramAcked(u64),
+
+    /// The DATAGRAM with the given ID, as returned by [`dgram_send_tracked()`],
+    /// was declared lost, or dropped before being sent because it didn't fit
+    /// in a packet anymore. DATAGRAMs are never retransmitted by the
+    /// connection.
+    ///
+    /// [`dgram_send_tracked()`]: struct.Connection.html#method.dgram_send_tracked
+    DatagramLost(u64),
+
+    /// A path-specific event, as would otherwise be returned by
+    /// [`path_event_next()`].
+    ///
+    /// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+    Path(PathEvent),
+
+    /// A source connection ID was retired by the peer, as would otherwise be
+    /// returned by [`retired_scid_next()`].
+    ///
+    /// [`retired_scid_next()`]: struct.Connection.html#method.retired_scid_next
+    ScidRetired(ConnectionId<'static>),
+
+    /// The peer closed the connection with the given error. This is always
+    /// the last event raised on a connection.
+    PeerClosed(ConnectionError),
+}
+
+/// The number of tracked DATAGRAMs awaiting a delivery event to be polled, as a
+/// multiple of the DATAGRAM send queue length.
+const DGRAM_DELIVERY_QUEUE_FACTOR: usize = 4;
+
+/// Keeps track of the events that haven't been polled by the application yet.
+///
+/// Stream events are only recorded once per stream until they are polled, and
+/// are discarded when the stream is collected, so the amount of pending events
+/// is bounded by the number of streams alive. DATAGRAM delivery events are
+/// only recorded for DATAGRAMs the application explicitly asked to track, and
+/// no more DATAGRAMs can be tracked once a few times the DATAGRAM send queue
+/// length are awaiting their delivery event to be polled.
+pub struct EventQueue {
+    /// Handshake events, in the order they were raised.
+    handshake: VecDeque<ConnectionEvent>,
+
+    /// Set of streams that became readable.
+    readable: StreamIdHashSet,
+
+    /// Set of streams that became writable.
+    writable: StreamIdHashSet,
+
+    /// Set of streams reset by the peer, along with the error code.
+    reset: StreamIdHashMap<u64>,
+
+    /// Set of streams stopped by the peer, along with the error code.
+    stopped: StreamIdHashMap<u64>,
+
+    /// Whether DATAGRAM frames were received.
+    dgram_received: bool,
+
+    /// Delivery events of tracked DATAGRAMs, in the order they were raised.
+    dgram_delivery: VecDeque<ConnectionEvent>,
+
+    /// The number of tracked DATAGRAMs whose delivery event wasn't polled yet.
+    dgram_tracked: usize,
+
+    /// The maximum number of tracked DATAGRAMs whose delivery event wasn't
+    /// polled yet.
+    dgram_tracked_max_len: usize,
+
+    /// The error the peer closed the connection with.
+    peer_closed: Option<ConnectionError>,
+}
+
+impl EventQueue {
+    pub fn new(dgram_send_max_queue_len: usize) -> Self {
+        EventQueue {
+            handshake: VecDeque::new(),
+            readable: StreamIdHashSet::default(),
+            writable: StreamIdHashSet::default(),
+            reset: StreamIdHashMap::default(),
+            stopped: StreamIdHashMap::default(),
+            dgram_received: false,
+            dgram_delivery: VecDeque::new(),
+            dgram_tracked: 0,
+            dgram_tracked_max_len: dgram_send_max_queue_len
+                .saturating_mul(DGRAM_DELIVERY_QUEUE_FACTOR),
+            peer_closed: None,
+        }
+    }
+
+    pub fn on_handshake_completed(&mut self, resumed: bool, rejected: bool) {
+        self.handshake
+            .push_back(ConnectionEvent::HandshakeCompleted);
+
+        if resumed {
+            self.handshake.push_back(ConnectionEvent::Resumed);
+        }
+
+        if rejected {
+            self.handshake.push_back(ConnectionEvent::EarlyDataRejected);
+        }
+    }
+
+    pub fn on_stream_readable(&mut self, stream_id: u64) {
+        self.readable.insert(stream_id);
+    }
+
+    pub fn on_stream_writable(&mut self, stream_id: u64) {
+        self.writable.insert(stream_id);
+    }
+
+    pub fn on_stream_reset(&mut self, stream_id: u64, error_code: u64) {
+        self.reset.entry(stream_id).or_insert(error_code);
+    }
+
+    pub fn on_stream_stopped(&mut self, stream_id: u64, error_code: u64) {
+        self.stopped.entry(stream_id).or_insert(error_code);
+    }
+
+    /// Discards the pending events of a stream that was collected.
+    pub fn on_stream_collected(&mut self, stream_id: u64) {
+        self.readable.remove(&stream_id);
+        self.writable.remove(&stream_id);
+        self.reset.remove(&stream_id);
+        self.stopped.remove(&stream_id);
+    }
+
+    pub fn on_dgram_received(&mut self) {
+        self.dgram_received = true;
+    }
+
+    /// Returns true if another DATAGRAM can be tracked, i.e. if there aren't
+    /// too many tracked DATAGRAMs whose delivery event wasn't polled yet.
+    pub fn dgram_trackable(&self) -> bool {
+        self.dgram_tracked < self.dgram_tracked_max_len
+    }
+
+    pub fn on_dgram_tracked(&mut self) {
+        self.dgram_tracked += 1;
+    }
+
+    /// Stops accounting for tracked DATAGRAMs that won't raise any delivery
+    /// event, as they were purged before being sent.
+    pub fn on_dgram_purged(&mut self, count: usize) {
+        self.dgram_tracked = self.dgram_tracked.saturating_sub(count);
+    }
+
+    pub fn on_dgram_acked(&mut self, id: u64) {
+        self.dgram_delivery
+            .push_back(ConnectionEvent::DatagramAcked(id));
+    }
+
+    pub fn on_dgram_lost(&mut self, id: u64) {
+        self.dgram_delivery
+            .push_back(ConnectionEvent::DatagramLost(id));
+    }
+
+    pub fn on_peer_closed(&mut self, err: &ConnectionError) {
+        if self.peer_closed.is_none() {
+            self.peer_closed = Some(err.clone());
+        }
+    }
+
+    /// Returns the next handshake event.
+    pub fn pop_handshake(&mut self) -> Option<ConnectionEvent> {
+        self.handshake.pop_front()
+    }
+
+    /// Returns the next stream or DATAGRAM event, and then the peer's closing
+    /// event once there are no other events left.
+    pub fn pop(&mut self) -> Option<ConnectionEvent> {
+        if let Some((&stream_id, &error_code)) = self.reset.iter().next() {
+            self.reset.remove(&stream_id);
+
+            return Some(ConnectionEvent::StreamReset {
+                stream_id,
+                error_code,
+            });
+        }
+
+        if let Some((&stream_id, &error_code)) = self.stopped.iter().next() {
+            self.stopped.remove(&stream_id);
+
+            return Some(ConnectionEvent::StreamStopped {
+                stream_id,
+                error_code,
+            });
+        }
+
+        if let Some(&stream_id) = self.readable.iter().next() {
+            self.readable.remove(&stream_id);
+
+            return Some(ConnectionEvent::StreamReadable(stream_id));
+        }
+
+        if let Some(&stream_id) = self.writable.iter().next() {
+            self.writable.remove(&stream_id);
+
+            return Some(ConnectionEvent::StreamWritable(stream_id));
+        }
+
+        if self.dgram_received {
+            self.dgram_received = false;
+
+            return Some(ConnectionEvent::DatagramReceived);
+        }
+
+        if let Some(ev) = self.dgram_delivery.pop_front() {
+            self.dgram_tracked = self.dgram_tracked.saturating_sub(1);
+
+            return Some(ev);
+        }
+
+        self.peer_closed.take().map(ConnectionEvent::PeerClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_events_dedup() {
+        let mut events = EventQueue::new(10);
+
+        events.on_stream_readable(4);
+        events.on_stream_readable(4);
+        events.on_stream_reset(8, 42);
+        events.on_stream_reset(8, 43);
+
+        assert_eq!(
+            events.pop(),
+            Some(ConnectionEvent::StreamReset {
+                stream_id: 8,
+                error_code: 42
+            })
+        );
+        assert_eq!(events.pop(), Some(ConnectionEvent::StreamReadable(4)));
+        assert_eq!(events.pop(), None);
+
+        // Collected streams don't raise events anymore.
+        events.on_stream_writable(4);
+        events.on_stream_collected(4);
+        assert_eq!(events.pop(), None);
+    }
+
+    #[test]
+    fn peer_closed_last() {
+        let mut events = EventQueue::new(10);
+
+        let err = ConnectionError {
+            is_app: true,
+            error_code: 1,
+            reason: b"bye".to_vec(),
+        };
+
+        events.on_peer_closed(&err);
+        events.on_dgram_received();
+        events.on_dgram_received();
+        events.on_dgram_lost(1);
+        events.on_dgram_acked(0);
+        events.on_handshake_completed(true, false);
+
+        assert_eq!(
+            events.pop_handshake(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(events.pop_handshake(), Some(ConnectionEvent::Resumed));
+        assert_eq!(events.pop_handshake(), None);
+
+        assert_eq!(events.pop(), Some(ConnectionEvent::DatagramReceived));
+        assert_eq!(events.pop(), Some(ConnectionEvent::DatagramLost(1)));
+        assert_eq!(events.pop(), Some(ConnectionEvent::DatagramAcked(0)));
+        assert_eq!(events.pop(), Some(ConnectionEvent::PeerClosed(err)));
+        assert_eq!(events.pop(), None);
+    }
+
+    #[test]
+    fn dgram_tracked_bounded() {
+        let mut events = EventQueue::new(2);
+
+        for id in 0..8 {
+            assert!(events.dgram_trackable());
+
+            events.on_dgram_tracked();
+            events.on_dgram_acked(id);
+        }
+
+        // No more DATAGRAMs can be tracked until events are polled.
+        assert!(!events.dgram_trackable());
+
+        assert_eq!(events.pop(), Some(ConnectionEvent::DatagramAcked(0)));
+        assert!(events.dgram_trackable());
+
+        // Purged DATAGRAMs don't hold a slot anymore.
+        events.on_dgram_tracked();
+        assert!(!events.dgram_trackable());
+
+        events.on_dgram_purged(1);
+        assert!(events.dgram_trackable());
+
+        for id in 1..8 {
+            assert_eq!(events.pop(), Some(ConnectionEvent::DatagramAcked(id)));
+        }
+
+        assert_eq!(events.pop(), None);
+    }
+}
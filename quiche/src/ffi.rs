@@ -248,6 +248,33 @@ pub extern fn quiche_config_set_max_recv_udp_payload_size(
     config.set_max_recv_udp_payload_size(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_discover_pmtu(config: &mut Config, v: bool) {
+    config.discover_pmtu(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_spin_bit(config: &mut Config, v: bool) {
+    config.enable_spin_bit(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_multipath(config: &mut Config, v: bool) {
+    config.set_multipath(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_reset_stream_at(config: &mut Config, v: bool) {
+    config.enable_reset_stream_at(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_path_scheduler(
+    config: &mut Config, scheduler: PathScheduler,
+) {
+    config.set_path_scheduler(scheduler);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_initial_max_data(config: &mut Config, v: u64) {
     config.set_initial_max_data(v);
@@ -298,6 +325,11 @@ pub extern fn quiche_config_set_max_ack_delay(config: &mut Config, v: u64) {
     config.set_max_ack_delay(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_min_ack_delay(config: &mut Config, v: u64) {
+    config.set_min_ack_delay(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_disable_active_migration(
     config: &mut Config, v: bool,
@@ -346,6 +378,11 @@ pub extern fn quiche_config_set_max_pacing_rate(config: &mut Config, v: u64) {
     config.set_max_pacing_rate(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_enable_ecn(config: &mut Config, v: bool) {
+    config.enable_ecn(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_dgram(
     config: &mut Config, enabled: bool, recv_queue_len: size_t,
@@ -373,6 +410,13 @@ pub extern fn quiche_config_set_max_stream_window(config: &mut Config, v: u64) {
     config.set_max_stream_window(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_key_update_threshold(
+    config: &mut Config, v: f64,
+) {
+    config.set_key_update_threshold(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_active_connection_id_limit(
     config: &mut Config, v: u64,
@@ -393,6 +437,49 @@ pub extern fn quiche_config_set_stateless_reset_token(
     config.set_stateless_reset_token(Some(reset_token));
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_preferred_address(
+    config: &mut Config, v4: *const sockaddr, v4_len: socklen_t,
+    v6: *const sockaddr, v6_len: socklen_t,
+) -> c_int {
+    let v4 = if v4.is_null() {
+        None
+    } else {
+        match std_addr_from_c(unsafe { &*v4 }, v4_len) {
+            SocketAddr::V4(addr) => Some(addr),
+
+            SocketAddr::V6(_) => return Error::InvalidState.to_c() as c_int,
+        }
+    };
+
+    let v6 = if v6.is_null() {
+        None
+    } else {
+        match std_addr_from_c(unsafe { &*v6 }, v6_len) {
+            SocketAddr::V6(addr) => Some(addr),
+
+            SocketAddr::V4(_) => return Error::InvalidState.to_c() as c_int,
+        }
+    };
+
+    config.set_preferred_address(v4, v6);
+
+    0
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_available_versions(
+    config: &mut Config, versions: *const u32, versions_len: size_t,
+) -> c_int {
+    let versions = unsafe { slice::from_raw_parts(versions, versions_len) };
+
+    match config.set_available_versions(versions) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_free(config: *mut Config) {
     drop(unsafe { Box::from_raw(config) });
@@ -565,6 +652,150 @@ pub extern fn quiche_retry(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_stateless_reset(
+    dcid: *const u8, dcid_len: size_t, key: *const u8, key_len: size_t,
+    received_len: size_t, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let dcid = unsafe { slice::from_raw_parts(dcid, dcid_len) };
+    let dcid = ConnectionId::from_ref(dcid);
+
+    let key = unsafe { slice::from_raw_parts(key, key_len) };
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match stateless_reset(&dcid, key, received_len, out) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_stateless_reset_token(
+    cid: *const u8, cid_len: size_t, key: *const u8, key_len: size_t,
+    out: *mut u8,
+) {
+    let cid = unsafe { slice::from_raw_parts(cid, cid_len) };
+    let cid = ConnectionId::from_ref(cid);
+
+    let key = unsafe { slice::from_raw_parts(key, key_len) };
+    let out = unsafe { slice::from_raw_parts_mut(out, 16) };
+
+    out.copy_from_slice(&stateless_reset_token(&cid, key).to_be_bytes());
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_new(
+    secret: *const u8, secret_len: size_t,
+) -> *mut TokenKey {
+    let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
+
+    Box::into_raw(Box::new(TokenKey::new(secret)))
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_rotate(
+    key: &mut TokenKey, secret: *const u8, secret_len: size_t,
+) {
+    let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
+
+    key.rotate(secret);
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_set_retry_token_lifetime(
+    key: &mut TokenKey, v: u64,
+) {
+    key.set_retry_token_lifetime(std::time::Duration::from_millis(v));
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_set_new_token_lifetime(
+    key: &mut TokenKey, v: u64,
+) {
+    key.set_new_token_lifetime(std::time::Duration::from_millis(v));
+}
+
+fn token_to_c(token: Result<Vec<u8>>, out: *mut u8, out_len: size_t) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    let token = match token {
+        Ok(v) => v,
+
+        Err(e) => return e.to_c(),
+    };
+
+    if token.len() > out.len() {
+        return Error::BufferTooShort.to_c();
+    }
+
+    out[..token.len()].copy_from_slice(&token);
+
+    token.len() as ssize_t
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_seal_retry_token(
+    key: &TokenKey, peer: &sockaddr, peer_len: socklen_t, odcid: *const u8,
+    odcid_len: size_t, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let peer = std_addr_from_c(peer, peer_len);
+
+    let odcid = unsafe { slice::from_raw_parts(odcid, odcid_len) };
+    let odcid = ConnectionId::from_ref(odcid);
+
+    token_to_c(key.seal_retry_token(&peer, &odcid), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_seal_new_token(
+    key: &TokenKey, peer: &sockaddr, peer_len: socklen_t, out: *mut u8,
+    out_len: size_t,
+) -> ssize_t {
+    let peer = std_addr_from_c(peer, peer_len);
+
+    token_to_c(key.seal_new_token(&peer), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_open(
+    key: &TokenKey, peer: &sockaddr, peer_len: socklen_t, token: *const u8,
+    token_len: size_t, odcid: *mut u8, odcid_len: *mut size_t,
+) -> c_int {
+    let peer = std_addr_from_c(peer, peer_len);
+
+    let token = unsafe { slice::from_raw_parts(token, token_len) };
+
+    let odcid_out = unsafe { slice::from_raw_parts_mut(odcid, *odcid_len) };
+
+    match key.open(&peer, token) {
+        Ok(Some(v)) => {
+            if v.len() > odcid_out.len() {
+                return Error::BufferTooShort.to_c() as c_int;
+            }
+
+            odcid_out[..v.len()].copy_from_slice(&v);
+
+            unsafe { *odcid_len = v.len() };
+        },
+
+        Ok(None) => unsafe { *odcid_len = 0 },
+
+        Err(e) => return e.to_c() as c_int,
+    }
+
+    0
+}
+
+#[no_mangle]
+pub extern fn quiche_token_key_free(key: *mut TokenKey) {
+    drop(unsafe { Box::from_raw(key) });
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_new_with_tls(
     scid: *const u8, scid_len: size_t, odcid: *const u8, odcid_len: size_t,
@@ -696,12 +927,63 @@ pub extern fn quiche_conn_set_session(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_token(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.set_token(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_peer_address_verified(
+    conn: &mut Connection,
+) -> c_int {
+    match conn.set_peer_address_verified() {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_new_token_next(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    let token = match conn.new_token_next() {
+        Some(v) => v,
+
+        None => return Error::Done.to_c(),
+    };
+
+    if token.len() > out.len() {
+        return Error::BufferTooShort.to_c();
+    }
+
+    out[..token.len()].copy_from_slice(&token);
+
+    token.len() as ssize_t
+}
+
 #[repr(C)]
 pub struct RecvInfo<'a> {
     from: &'a sockaddr,
     from_len: socklen_t,
     to: &'a sockaddr,
     to_len: socklen_t,
+
+    ecn: u8,
 }
 
 impl<'a> From<&RecvInfo<'a>> for crate::RecvInfo {
@@ -709,6 +991,13 @@ impl<'a> From<&RecvInfo<'a>> for crate::RecvInfo {
         crate::RecvInfo {
             from: std_addr_from_c(info.from, info.from_len),
             to: std_addr_from_c(info.to, info.to_len),
+
+            ecn: match info.ecn & 0x03 {
+                0x01 => crate::Ecn::Ect1,
+                0x02 => crate::Ecn::Ect0,
+                0x03 => crate::Ecn::Ce,
+                _ => crate::Ecn::NotEct,
+            },
         }
     }
 }
@@ -730,6 +1019,24 @@ pub extern fn quiche_conn_recv(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_recv_segments(
+    conn: &mut Connection, buf: *mut u8, buf_len: size_t, segment_size: size_t,
+    info: &RecvInfo,
+) -> ssize_t {
+    if buf_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
+
+    match conn.recv_segments(buf, segment_size, info.into()) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[repr(C)]
 pub struct SendInfo {
     from: sockaddr_storage,
@@ -738,6 +1045,8 @@ pub struct SendInfo {
     to_len: socklen_t,
 
     at: timespec,
+
+    ecn: u8,
 }
 
 #[no_mangle]
@@ -757,6 +1066,37 @@ pub extern fn quiche_conn_send(
 
             std_time_to_c(&info.at, &mut out_info.at);
 
+            out_info.ecn = info.ecn as u8;
+
+            v as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_batch(
+    conn: &mut Connection, out: *mut u8, out_len: size_t, max_segments: size_t,
+    out_info: &mut SendInfo, segment_size: &mut size_t,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.send_batch(out, max_segments) {
+        Ok((v, size, info)) => {
+            out_info.from_len = std_addr_to_c(&info.from, &mut out_info.from);
+            out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+
+            std_time_to_c(&info.at, &mut out_info.at);
+
+            out_info.ecn = info.ecn as u8;
+
+            *segment_size = size;
+
             v as ssize_t
         },
 
@@ -826,6 +1166,17 @@ pub extern fn quiche_conn_stream_shutdown(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_shutdown_at(
+    conn: &mut Connection, stream_id: u64, err: u64, reliable_size: u64,
+) -> c_int {
+    match conn.stream_shutdown_at(stream_id, err, reliable_size) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_capacity(
     conn: &Connection, stream_id: u64,
@@ -894,6 +1245,15 @@ pub extern fn quiche_conn_is_readable(conn: &Connection) -> bool {
     conn.is_readable()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_initiate_key_update(conn: &mut Connection) -> c_int {
+    match conn.initiate_key_update() {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_close(
     conn: &mut Connection, app: bool, err: u64, reason: *const u8,
@@ -1063,6 +1423,199 @@ pub extern fn quiche_conn_local_error(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_poll_event(
+    conn: &mut Connection,
+) -> *mut ConnectionEvent {
+    match conn.poll_event() {
+        Some(v) => Box::into_raw(Box::new(v)),
+
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_type(ev: &ConnectionEvent) -> u32 {
+    match ev {
+        ConnectionEvent::HandshakeCompleted => 0,
+
+        ConnectionEvent::Resumed => 1,
+
+        ConnectionEvent::EarlyDataRejected => 2,
+
+        ConnectionEvent::StreamReadable(_) => 3,
+
+        ConnectionEvent::StreamWritable(_) => 4,
+
+        ConnectionEvent::StreamReset { .. } => 5,
+
+        ConnectionEvent::StreamStopped { .. } => 6,
+
+        ConnectionEvent::DatagramReceived => 7,
+
+        ConnectionEvent::Path(_) => 8,
+
+        ConnectionEvent::ScidRetired(_) => 9,
+
+        ConnectionEvent::PeerClosed(_) => 10,
+
+        ConnectionEvent::DatagramAcked(_) => 11,
+
+        ConnectionEvent::DatagramLost(_) => 12,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_stream(
+    ev: &ConnectionEvent, stream_id: &mut u64, error_code: &mut u64,
+) -> bool {
+    match ev {
+        ConnectionEvent::StreamReadable(id) |
+        ConnectionEvent::StreamWritable(id) => {
+            *stream_id = *id;
+        },
+
+        ConnectionEvent::StreamReset {
+            stream_id: id,
+            error_code: e,
+        } |
+        ConnectionEvent::StreamStopped {
+            stream_id: id,
+            error_code: e,
+        } => {
+            *stream_id = *id;
+            *error_code = *e;
+        },
+
+        _ => return false,
+    }
+
+    true
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_dgram(
+    ev: &ConnectionEvent, id: &mut u64,
+) -> bool {
+    match ev {
+        ConnectionEvent::DatagramAcked(v) | ConnectionEvent::DatagramLost(v) => {
+            *id = *v;
+
+            true
+        },
+
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_retired_scid(
+    ev: &ConnectionEvent, out: &mut *const u8, out_len: &mut size_t,
+) -> bool {
+    match ev {
+        ConnectionEvent::ScidRetired(cid) => {
+            *out = cid.as_ptr();
+            *out_len = cid.len();
+
+            true
+        },
+
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_peer_error(
+    ev: &ConnectionEvent, is_app: *mut bool, error_code: *mut u64,
+    reason: &mut *const u8, reason_len: &mut size_t,
+) -> bool {
+    match ev {
+        ConnectionEvent::PeerClosed(conn_err) => unsafe {
+            *is_app = conn_err.is_app;
+            *error_code = conn_err.error_code;
+            *reason = conn_err.reason.as_ptr();
+            *reason_len = conn_err.reason.len();
+
+            true
+        },
+
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_path(ev: &ConnectionEvent) -> *mut PathEvent {
+    match ev {
+        ConnectionEvent::Path(v) => Box::into_raw(Box::new(v.clone())),
+
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_event_free(ev: *mut ConnectionEvent) {
+    drop(unsafe { Box::from_raw(ev) });
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_type(ev: &PathEvent) -> u32 {
+    match ev {
+        PathEvent::New(..) => 0,
+
+        PathEvent::Validated(..) => 1,
+
+        PathEvent::FailedValidation(..) => 2,
+
+        PathEvent::Closed(..) => 3,
+
+        PathEvent::ReusedSourceConnectionId(..) => 4,
+
+        PathEvent::PeerMigrated(..) => 5,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_addrs(
+    ev: &PathEvent, local: &mut sockaddr_storage, local_len: &mut socklen_t,
+    peer: &mut sockaddr_storage, peer_len: &mut socklen_t,
+) {
+    let (l, p) = match ev {
+        PathEvent::New(l, p) |
+        PathEvent::Validated(l, p) |
+        PathEvent::FailedValidation(l, p) |
+        PathEvent::Closed(l, p) |
+        PathEvent::ReusedSourceConnectionId(_, _, (l, p)) |
+        PathEvent::PeerMigrated(l, p) => (l, p),
+    };
+
+    *local_len = std_addr_to_c(l, local);
+    *peer_len = std_addr_to_c(p, peer);
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_reused_source_connection_id(
+    ev: &PathEvent, cid_seq: &mut u64, old_local: &mut sockaddr_storage,
+    old_local_len: &mut socklen_t, old_peer: &mut sockaddr_storage,
+    old_peer_len: &mut socklen_t,
+) -> bool {
+    match ev {
+        PathEvent::ReusedSourceConnectionId(id, (l, p), _) => {
+            *cid_seq = *id;
+            *old_local_len = std_addr_to_c(l, old_local);
+            *old_peer_len = std_addr_to_c(p, old_peer);
+
+            true
+        },
+
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_free(ev: *mut PathEvent) {
+    drop(unsafe { Box::from_raw(ev) });
+}
+
 #[no_mangle]
 pub extern fn quiche_stream_iter_next(
     iter: &mut StreamIter, stream_id: *mut u64,
@@ -1095,6 +1648,7 @@ pub struct Stats {
     stopped_stream_count_local: u64,
     reset_stream_count_remote: u64,
     stopped_stream_count_remote: u64,
+    congestion_blocked: u64,
 }
 
 pub struct TransportParams {
@@ -1130,6 +1684,7 @@ pub extern fn quiche_conn_stats(conn: &Connection, out: &mut Stats) {
     out.stopped_stream_count_local = stats.stopped_stream_count_local;
     out.reset_stream_count_remote = stats.reset_stream_count_remote;
     out.stopped_stream_count_remote = stats.stopped_stream_count_remote;
+    out.congestion_blocked = stats.congestion_blocked.as_nanos() as u64;
 }
 
 #[no_mangle]
@@ -1215,6 +1770,40 @@ pub extern fn quiche_conn_path_stats(
     0
 }
 
+#[repr(C)]
+pub struct StreamStats {
+    sent_bytes: u64,
+    acked_bytes: u64,
+    retrans_bytes: u64,
+    recv_bytes: u64,
+    first_byte_sent: i64,
+    first_byte_acked: i64,
+    flow_control_blocked: u64,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_stream_stats(
+    conn: &mut Connection, stream_id: u64, out: &mut StreamStats,
+) -> c_int {
+    let stats = match conn.stream_stats(stream_id) {
+        Ok(v) => v,
+
+        Err(e) => return e.to_c() as c_int,
+    };
+
+    out.sent_bytes = stats.sent_bytes;
+    out.acked_bytes = stats.acked_bytes;
+    out.retrans_bytes = stats.retrans_bytes;
+    out.recv_bytes = stats.recv_bytes;
+    out.first_byte_sent =
+        stats.first_byte_sent.map_or(-1, |v| v.as_nanos() as i64);
+    out.first_byte_acked =
+        stats.first_byte_acked.map_or(-1, |v| v.as_nanos() as i64);
+    out.flow_control_blocked = stats.flow_control_blocked.as_nanos() as u64;
+
+    0
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_is_server(conn: &Connection) -> bool {
     conn.is_server()
@@ -1279,6 +1868,27 @@ pub extern fn quiche_conn_dgram_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send_tracked(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t, id: &mut u64,
+) -> ssize_t {
+    if buf_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.dgram_send_tracked(buf) {
+        Ok(v) => {
+            *id = v;
+
+            buf_len as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_dgram_recv(
     conn: &mut Connection, out: *mut u8, out_len: size_t,
@@ -1331,6 +1941,69 @@ pub extern fn quiche_conn_send_ack_eliciting_on_path(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_ack_frequency(
+    conn: &mut Connection, ack_eliciting_threshold: u64, max_ack_delay_us: u64,
+    reordering_threshold: u64,
+) -> c_int {
+    match conn.set_ack_frequency(
+        ack_eliciting_threshold,
+        std::time::Duration::from_micros(max_ack_delay_us),
+        reordering_threshold,
+    ) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_immediate_ack(conn: &mut Connection) -> c_int {
+    match conn.send_immediate_ack() {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_multipath_enabled(conn: &Connection) -> bool {
+    conn.is_multipath_enabled()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_path_status(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, status: PathStatus,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    match conn.set_path_status(local, peer, status) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_abandon_path(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, error_code: u64, reason: *const u8,
+    reason_len: size_t,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    let reason = unsafe { slice::from_raw_parts(reason, reason_len) };
+
+    match conn.abandon_path(local, peer, error_code, reason) {
+        Ok(()) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_free(conn: *mut Connection) {
     drop(unsafe { Box::from_raw(conn) });
@@ -47,11 +47,11 @@ pub const MAX_DGRAM_OVERHEAD: usize = 2;
 pub const MAX_STREAM_OVERHEAD: usize = 12;
 pub const MAX_STREAM_SIZE: u64 = 1 << 62;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct EcnCounts {
-    ect0_count: u64,
-    ect1_count: u64,
-    ecn_ce_count: u64,
+    pub ect0_count: u64,
+    pub ect1_count: u64,
+    pub ecn_ce_count: u64,
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -60,7 +60,9 @@ pub enum Frame {
         len: usize,
     },
 
-    Ping,
+    Ping {
+        mtu_probe: Option<usize>,
+    },
 
     ACK {
         ack_delay: u64,
@@ -79,6 +81,13 @@ pub enum Frame {
         error_code: u64,
     },
 
+    ResetStreamAt {
+        stream_id: u64,
+        error_code: u64,
+        final_size: u64,
+        reliable_size: u64,
+    },
+
     Crypto {
         data: stream::RangeBuf,
     },
@@ -176,6 +185,35 @@ pub enum Frame {
 
     DatagramHeader {
         length: usize,
+        id: Option<u64>,
+    },
+
+    AckFrequency {
+        seq_num: u64,
+        ack_eliciting_threshold: u64,
+        request_max_ack_delay: u64,
+        reordering_threshold: u64,
+    },
+
+    ImmediateAck,
+
+    AckMp {
+        space_id: u64,
+        ack_delay: u64,
+        ranges: ranges::RangeSet,
+        ecn_counts: Option<EcnCounts>,
+    },
+
+    PathAbandon {
+        dcid_seq_num: u64,
+        error_code: u64,
+        reason: Vec<u8>,
+    },
+
+    PathStatus {
+        dcid_seq_num: u64,
+        seq_num: u64,
+        status: u64,
     },
 }
 
@@ -198,7 +236,7 @@ impl Frame {
                 Frame::Padding { len }
             },
 
-            0x01 => Frame::Ping,
+            0x01 => Frame::Ping { mtu_probe: None },
 
             0x02..=0x03 => parse_ack_frame(frame_type, b)?,
 
@@ -221,8 +259,17 @@ impl Frame {
                 Frame::Crypto { data }
             },
 
-            0x07 => Frame::NewToken {
-                token: b.get_bytes_with_varint_length()?.to_vec(),
+            0x07 => {
+                let token = b.get_bytes_with_varint_length()?;
+
+                // Tokens can't be empty.
+                if token.cap() == 0 {
+                    return Err(Error::InvalidFrame);
+                }
+
+                Frame::NewToken {
+                    token: token.to_vec(),
+                }
             },
 
             0x08..=0x0f => parse_stream_frame(frame_type, b)?,
@@ -305,8 +352,62 @@ impl Frame {
 
             0x1e => Frame::HandshakeDone,
 
+            0x1f => Frame::ImmediateAck,
+
+            0x24 => {
+                let stream_id = b.get_varint()?;
+                let error_code = b.get_varint()?;
+                let final_size = b.get_varint()?;
+                let reliable_size = b.get_varint()?;
+
+                // The reliable size can't be larger than the final size.
+                if reliable_size > final_size {
+                    return Err(Error::InvalidFrame);
+                }
+
+                Frame::ResetStreamAt {
+                    stream_id,
+                    error_code,
+                    final_size,
+                    reliable_size,
+                }
+            },
+
             0x30 | 0x31 => parse_datagram_frame(frame_type, b)?,
 
+            0xaf => Frame::AckFrequency {
+                seq_num: b.get_varint()?,
+                ack_eliciting_threshold: b.get_varint()?,
+                request_max_ack_delay: b.get_varint()?,
+                reordering_threshold: b.get_varint()?,
+            },
+
+            0x15228c00..=0x15228c01 => {
+                let space_id = b.get_varint()?;
+
+                let (ack_delay, ranges, ecn_counts) =
+                    parse_ack_body(frame_type, b)?;
+
+                Frame::AckMp {
+                    space_id,
+                    ack_delay,
+                    ranges,
+                    ecn_counts,
+                }
+            },
+
+            0x15228c05 => Frame::PathAbandon {
+                dcid_seq_num: b.get_varint()?,
+                error_code: b.get_varint()?,
+                reason: b.get_bytes_with_varint_length()?.to_vec(),
+            },
+
+            0x15228c06 => Frame::PathStatus {
+                dcid_seq_num: b.get_varint()?,
+                seq_num: b.get_varint()?,
+                status: b.get_varint()?,
+            },
+
             _ => return Err(Error::InvalidFrame),
         };
 
@@ -317,6 +418,7 @@ impl Frame {
             // ACK, CRYPTO, HANDSHAKE_DONE, NEW_TOKEN, PATH_RESPONSE, and
             // RETIRE_CONNECTION_ID can't be sent on 0-RTT packets.
             (packet::Type::ZeroRTT, Frame::ACK { .. }) => false,
+            (packet::Type::ZeroRTT, Frame::AckMp { .. }) => false,
             (packet::Type::ZeroRTT, Frame::Crypto { .. }) => false,
             (packet::Type::ZeroRTT, Frame::HandshakeDone) => false,
             (packet::Type::ZeroRTT, Frame::NewToken { .. }) => false,
@@ -359,7 +461,7 @@ impl Frame {
                 }
             },
 
-            Frame::Ping => {
+            Frame::Ping { .. } => {
                 b.put_varint(0x01)?;
             },
 
@@ -374,33 +476,7 @@ impl Frame {
                     b.put_varint(0x03)?;
                 }
 
-                let mut it = ranges.iter().rev();
-
-                let first = it.next().unwrap();
-                let ack_block = (first.end - 1) - first.start;
-
-                b.put_varint(first.end - 1)?;
-                b.put_varint(*ack_delay)?;
-                b.put_varint(it.len() as u64)?;
-                b.put_varint(ack_block)?;
-
-                let mut smallest_ack = first.start;
-
-                for block in it {
-                    let gap = smallest_ack - block.end - 1;
-                    let ack_block = (block.end - 1) - block.start;
-
-                    b.put_varint(gap)?;
-                    b.put_varint(ack_block)?;
-
-                    smallest_ack = block.start;
-                }
-
-                if let Some(ecn) = ecn_counts {
-                    b.put_varint(ecn.ect0_count)?;
-                    b.put_varint(ecn.ect1_count)?;
-                    b.put_varint(ecn.ecn_ce_count)?;
-                }
+                encode_ack_body(*ack_delay, ranges, ecn_counts.as_ref(), b)?;
             },
 
             Frame::ResetStream {
@@ -425,6 +501,20 @@ impl Frame {
                 b.put_varint(*error_code)?;
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                b.put_varint(0x24)?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*error_code)?;
+                b.put_varint(*final_size)?;
+                b.put_varint(*reliable_size)?;
+            },
+
             Frame::Crypto { data } => {
                 encode_crypto_header(data.off(), data.len() as u64, b)?;
 
@@ -569,52 +659,84 @@ impl Frame {
             },
 
             Frame::DatagramHeader { .. } => (),
-        }
 
-        Ok(before - b.cap())
-    }
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                b.put_varint(0xaf)?;
 
-    pub fn wire_len(&self) -> usize {
-        match self {
-            Frame::Padding { len } => *len,
+                b.put_varint(*seq_num)?;
+                b.put_varint(*ack_eliciting_threshold)?;
+                b.put_varint(*request_max_ack_delay)?;
+                b.put_varint(*reordering_threshold)?;
+            },
 
-            Frame::Ping => 1,
+            Frame::ImmediateAck => {
+                b.put_varint(0x1f)?;
+            },
 
-            Frame::ACK {
+            Frame::AckMp {
+                space_id,
                 ack_delay,
                 ranges,
                 ecn_counts,
             } => {
-                let mut it = ranges.iter().rev();
+                if ecn_counts.is_none() {
+                    b.put_varint(0x15228c00)?;
+                } else {
+                    b.put_varint(0x15228c01)?;
+                }
 
-                let first = it.next().unwrap();
-                let ack_block = (first.end - 1) - first.start;
+                b.put_varint(*space_id)?;
 
-                let mut len = 1 + // frame type
-                    octets::varint_len(first.end - 1) + // largest_ack
-                    octets::varint_len(*ack_delay) + // ack_delay
-                    octets::varint_len(it.len() as u64) + // block_count
-                    octets::varint_len(ack_block); // first_block
+                encode_ack_body(*ack_delay, ranges, ecn_counts.as_ref(), b)?;
+            },
 
-                let mut smallest_ack = first.start;
+            Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                reason,
+            } => {
+                b.put_varint(0x15228c05)?;
 
-                for block in it {
-                    let gap = smallest_ack - block.end - 1;
-                    let ack_block = (block.end - 1) - block.start;
+                b.put_varint(*dcid_seq_num)?;
+                b.put_varint(*error_code)?;
+                b.put_varint(reason.len() as u64)?;
+                b.put_bytes(reason.as_ref())?;
+            },
 
-                    len += octets::varint_len(gap) + // gap
-                           octets::varint_len(ack_block); // ack_block
+            Frame::PathStatus {
+                dcid_seq_num,
+                seq_num,
+                status,
+            } => {
+                b.put_varint(0x15228c06)?;
 
-                    smallest_ack = block.start;
-                }
+                b.put_varint(*dcid_seq_num)?;
+                b.put_varint(*seq_num)?;
+                b.put_varint(*status)?;
+            },
+        }
 
-                if let Some(ecn) = ecn_counts {
-                    len += octets::varint_len(ecn.ect0_count) +
-                        octets::varint_len(ecn.ect1_count) +
-                        octets::varint_len(ecn.ecn_ce_count);
-                }
+        Ok(before - b.cap())
+    }
+
+    pub fn wire_len(&self) -> usize {
+        match self {
+            Frame::Padding { len } => *len,
+
+            Frame::Ping { .. } => 1,
 
-                len
+            Frame::ACK {
+                ack_delay,
+                ranges,
+                ecn_counts,
+            } => {
+                1 + // frame type
+                ack_body_len(*ack_delay, ranges, ecn_counts.as_ref())
             },
 
             Frame::ResetStream {
@@ -637,6 +759,19 @@ impl Frame {
                 octets::varint_len(*error_code) // error_code
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                1 + // frame type
+                octets::varint_len(*stream_id) + // stream_id
+                octets::varint_len(*error_code) + // error_code
+                octets::varint_len(*final_size) + // final_size
+                octets::varint_len(*reliable_size) // reliable_size
+            },
+
             Frame::Crypto { data } => {
                 1 + // frame type
                 octets::varint_len(data.off()) + // offset
@@ -779,11 +914,62 @@ impl Frame {
                 data.len() // data
             },
 
-            Frame::DatagramHeader { length } => {
+            Frame::DatagramHeader { length, .. } => {
                 1 + // frame type
                 2 + // length, always encode as 2-byte varint
                 *length // data
             },
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                octets::varint_len(0xaf) + // frame type
+                octets::varint_len(*seq_num) + // seq_num
+                octets::varint_len(*ack_eliciting_threshold) + // threshold
+                octets::varint_len(*request_max_ack_delay) + // max_ack_delay
+                octets::varint_len(*reordering_threshold) // reordering
+            },
+
+            Frame::ImmediateAck => {
+                1 // frame type
+            },
+
+            Frame::AckMp {
+                space_id,
+                ack_delay,
+                ranges,
+                ecn_counts,
+            } => {
+                octets::varint_len(0x15228c00) + // frame type
+                octets::varint_len(*space_id) + // space_id
+                ack_body_len(*ack_delay, ranges, ecn_counts.as_ref())
+            },
+
+            Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                reason,
+            } => {
+                octets::varint_len(0x15228c05) + // frame type
+                octets::varint_len(*dcid_seq_num) + // dcid_seq_num
+                octets::varint_len(*error_code) + // error_code
+                octets::varint_len(reason.len() as u64) + // reason_len
+                reason.len() // reason
+            },
+
+            Frame::PathStatus {
+                dcid_seq_num,
+                seq_num,
+                status,
+            } => {
+                octets::varint_len(0x15228c06) + // frame type
+                octets::varint_len(*dcid_seq_num) + // dcid_seq_num
+                octets::varint_len(*seq_num) + // seq_num
+                octets::varint_len(*status) // status
+            },
         }
     }
 
@@ -793,6 +979,7 @@ impl Frame {
             self,
             Frame::Padding { .. } |
                 Frame::ACK { .. } |
+                Frame::AckMp { .. } |
                 Frame::ApplicationClose { .. } |
                 Frame::ConnectionClose { .. }
         )
@@ -861,6 +1048,18 @@ impl Frame {
                 error_code: *error_code,
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => QuicFrame::ResetStreamAt {
+                stream_id: *stream_id,
+                error_code: *error_code,
+                final_size: *final_size,
+                reliable_size: *reliable_size,
+            },
+
             Frame::Crypto { data } => QuicFrame::Crypto {
                 offset: data.off(),
                 length: data.len() as u64,
@@ -873,8 +1072,7 @@ impl Frame {
 
             Frame::NewToken { token } => QuicFrame::NewToken {
                 token: qlog::Token {
-                    // TODO: pick the token type some how
-                    ty: Some(qlog::TokenType::Retry),
+                    ty: Some(qlog::TokenType::Resumption),
                     raw: Some(qlog::events::RawInfo {
                         data: qlog::HexSlice::maybe_string(Some(token)),
                         length: Some(token.len() as u64),
@@ -992,10 +1190,74 @@ impl Frame {
                 raw: None,
             },
 
-            Frame::DatagramHeader { length } => QuicFrame::Datagram {
+            Frame::DatagramHeader { length, .. } => QuicFrame::Datagram {
                 length: *length as u64,
                 raw: None,
             },
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => QuicFrame::AckFrequency {
+                sequence_number: *seq_num,
+                ack_eliciting_threshold: *ack_eliciting_threshold,
+                request_max_ack_delay: *request_max_ack_delay,
+                reordering_threshold: *reordering_threshold,
+            },
+
+            Frame::ImmediateAck => QuicFrame::ImmediateAck,
+
+            Frame::AckMp {
+                space_id,
+                ack_delay,
+                ranges,
+                ecn_counts,
+            } => {
+                let ack_ranges = AckedRanges::Double(
+                    ranges.iter().map(|r| (r.start, r.end - 1)).collect(),
+                );
+
+                let (ect0, ect1, ce) = match ecn_counts {
+                    Some(ecn) => (
+                        Some(ecn.ect0_count),
+                        Some(ecn.ect1_count),
+                        Some(ecn.ecn_ce_count),
+                    ),
+
+                    None => (None, None, None),
+                };
+
+                QuicFrame::AckMp {
+                    space_identifier: *space_id,
+                    ack_delay: Some(*ack_delay as f32 / 1000.0),
+                    acked_ranges: Some(ack_ranges),
+                    ect1,
+                    ect0,
+                    ce,
+                }
+            },
+
+            Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                reason,
+            } => QuicFrame::PathAbandon {
+                dcid_sequence_number: *dcid_seq_num,
+                error_code: *error_code,
+                reason: Some(String::from_utf8_lossy(reason).into_owned()),
+            },
+
+            Frame::PathStatus {
+                dcid_seq_num,
+                seq_num,
+                status,
+            } => QuicFrame::PathStatus {
+                dcid_sequence_number: *dcid_seq_num,
+                sequence_number: *seq_num,
+                status: *status,
+            },
         }
     }
 }
@@ -1007,7 +1269,7 @@ impl std::fmt::Debug for Frame {
                 write!(f, "PADDING len={len}")?;
             },
 
-            Frame::Ping => {
+            Frame::Ping { .. } => {
                 write!(f, "PING")?;
             },
 
@@ -1040,6 +1302,18 @@ impl std::fmt::Debug for Frame {
                 write!(f, "STOP_SENDING stream={stream_id} err={error_code:x}")?;
             },
 
+            Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                write!(
+                    f,
+                    "RESET_STREAM_AT stream={stream_id} err={error_code:x} size={final_size} reliable_size={reliable_size}"
+                )?;
+            },
+
             Frame::Crypto { data } => {
                 write!(f, "CRYPTO off={} len={}", data.off(), data.len())?;
             },
@@ -1160,9 +1434,63 @@ impl std::fmt::Debug for Frame {
                 write!(f, "DATAGRAM len={}", data.len())?;
             },
 
-            Frame::DatagramHeader { length } => {
+            Frame::DatagramHeader { length, .. } => {
                 write!(f, "DATAGRAM len={length}")?;
             },
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                write!(
+                    f,
+                    "ACK_FREQUENCY seq_num={} threshold={} max_ack_delay={} reordering={}",
+                    seq_num,
+                    ack_eliciting_threshold,
+                    request_max_ack_delay,
+                    reordering_threshold
+                )?;
+            },
+
+            Frame::ImmediateAck => {
+                write!(f, "IMMEDIATE_ACK")?;
+            },
+
+            Frame::AckMp {
+                space_id,
+                ack_delay,
+                ranges,
+                ecn_counts,
+            } => {
+                write!(
+                    f,
+                    "ACK_MP space_id={space_id} delay={ack_delay} blocks={ranges:?} ecn_counts={ecn_counts:?}"
+                )?;
+            },
+
+            Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                reason,
+            } => {
+                write!(
+                    f,
+                    "PATH_ABANDON dcid_seq_num={dcid_seq_num} err={error_code:x} reason={reason:x?}"
+                )?;
+            },
+
+            Frame::PathStatus {
+                dcid_seq_num,
+                seq_num,
+                status,
+            } => {
+                write!(
+                    f,
+                    "PATH_STATUS dcid_seq_num={dcid_seq_num} seq_num={seq_num} status={status}"
+                )?;
+            },
         }
 
         Ok(())
@@ -1170,6 +1498,19 @@ impl std::fmt::Debug for Frame {
 }
 
 fn parse_ack_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
+    let (ack_delay, ranges, ecn_counts) = parse_ack_body(ty, b)?;
+
+    Ok(Frame::ACK {
+        ack_delay,
+        ranges,
+        ecn_counts,
+    })
+}
+
+/// Parses the fields shared by ACK and ACK_MP frames.
+fn parse_ack_body(
+    ty: u64, b: &mut octets::Octets,
+) -> Result<(u64, ranges::RangeSet, Option<EcnCounts>)> {
     let first = ty as u8;
 
     let largest_ack = b.get_varint()?;
@@ -1218,11 +1559,78 @@ fn parse_ack_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
         None
     };
 
-    Ok(Frame::ACK {
-        ack_delay,
-        ranges,
-        ecn_counts,
-    })
+    Ok((ack_delay, ranges, ecn_counts))
+}
+
+/// Encodes the fields shared by ACK and ACK_MP frames.
+fn encode_ack_body(
+    ack_delay: u64, ranges: &ranges::RangeSet, ecn_counts: Option<&EcnCounts>,
+    b: &mut octets::OctetsMut,
+) -> Result<()> {
+    let mut it = ranges.iter().rev();
+
+    let first = it.next().unwrap();
+    let ack_block = (first.end - 1) - first.start;
+
+    b.put_varint(first.end - 1)?;
+    b.put_varint(ack_delay)?;
+    b.put_varint(it.len() as u64)?;
+    b.put_varint(ack_block)?;
+
+    let mut smallest_ack = first.start;
+
+    for block in it {
+        let gap = smallest_ack - block.end - 1;
+        let ack_block = (block.end - 1) - block.start;
+
+        b.put_varint(gap)?;
+        b.put_varint(ack_block)?;
+
+        smallest_ack = block.start;
+    }
+
+    if let Some(ecn) = ecn_counts {
+        b.put_varint(ecn.ect0_count)?;
+        b.put_varint(ecn.ect1_count)?;
+        b.put_varint(ecn.ecn_ce_count)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the length of the fields shared by ACK and ACK_MP frames.
+fn ack_body_len(
+    ack_delay: u64, ranges: &ranges::RangeSet, ecn_counts: Option<&EcnCounts>,
+) -> usize {
+    let mut it = ranges.iter().rev();
+
+    let first = it.next().unwrap();
+    let ack_block = (first.end - 1) - first.start;
+
+    let mut len = octets::varint_len(first.end - 1) + // largest_ack
+        octets::varint_len(ack_delay) + // ack_delay
+        octets::varint_len(it.len() as u64) + // block_count
+        octets::varint_len(ack_block); // first_block
+
+    let mut smallest_ack = first.start;
+
+    for block in it {
+        let gap = smallest_ack - block.end - 1;
+        let ack_block = (block.end - 1) - block.start;
+
+        len += octets::varint_len(gap) + // gap
+               octets::varint_len(ack_block); // ack_block
+
+        smallest_ack = block.start;
+    }
+
+    if let Some(ecn) = ecn_counts {
+        len += octets::varint_len(ecn.ect0_count) +
+            octets::varint_len(ecn.ect1_count) +
+            octets::varint_len(ecn.ecn_ce_count);
+    }
+
+    len
 }
 
 pub fn encode_crypto_header(
@@ -1358,7 +1766,7 @@ mod tests {
     fn ping() {
         let mut d = [42; 128];
 
-        let frame = Frame::Ping;
+        let frame = Frame::Ping { mtu_probe: None };
 
         let wire_len = {
             let mut b = octets::OctetsMut::with_slice(&mut d);
@@ -1489,6 +1897,57 @@ mod tests {
         assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
     }
 
+    #[test]
+    fn reset_stream_at() {
+        let mut d = [42; 128];
+
+        let frame = Frame::ResetStreamAt {
+            stream_id: 123_213,
+            error_code: 21_123_767,
+            final_size: 21_123_767,
+            reliable_size: 1_000,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 15);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+
+        // The reliable size can't exceed the final size.
+        let frame = Frame::ResetStreamAt {
+            stream_id: 4,
+            error_code: 0,
+            final_size: 10,
+            reliable_size: 11,
+        };
+
+        {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(
+            Frame::from_bytes(&mut b, packet::Type::Short),
+            Err(Error::InvalidFrame)
+        );
+    }
+
     #[test]
     fn stop_sending() {
         let mut d = [42; 128];
@@ -1574,6 +2033,13 @@ mod tests {
 
         let mut b = octets::Octets::with_slice(&d);
         assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+
+        // Empty tokens are invalid.
+        let mut b = octets::Octets::with_slice(&[0x07, 0x00]);
+        assert_eq!(
+            Frame::from_bytes(&mut b, packet::Type::Short),
+            Err(Error::InvalidFrame)
+        );
     }
 
     #[test]
@@ -2082,4 +2548,161 @@ mod tests {
 
         assert_eq!(frame_data, data);
     }
+
+    #[test]
+    fn ack_frequency() {
+        let mut d = [42; 128];
+
+        let frame = Frame::AckFrequency {
+            seq_num: 3,
+            ack_eliciting_threshold: 10,
+            request_max_ack_delay: 50_000,
+            reordering_threshold: 1,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 9);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
+
+    #[test]
+    fn immediate_ack() {
+        let mut d = [42; 128];
+
+        let frame = Frame::ImmediateAck;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 1);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
+
+    #[test]
+    fn ack_mp() {
+        let mut d = [42; 128];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(4..7);
+        ranges.insert(9..12);
+        ranges.insert(15..19);
+        ranges.insert(3000..5000);
+
+        let frame = Frame::AckMp {
+            space_id: 3,
+            ack_delay: 874_656_534,
+            ranges,
+            ecn_counts: Some(EcnCounts {
+                ect0_count: 100,
+                ect1_count: 200,
+                ecn_ce_count: 300,
+            }),
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 27);
+        assert_eq!(frame.wire_len(), wire_len);
+        assert!(!frame.ack_eliciting());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
+
+    #[test]
+    fn path_abandon() {
+        let mut d = [42; 128];
+
+        let frame = Frame::PathAbandon {
+            dcid_seq_num: 1,
+            error_code: 15,
+            reason: b"gone".to_vec(),
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 11);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
+
+    #[test]
+    fn path_status() {
+        let mut d = [42; 128];
+
+        let frame = Frame::PathStatus {
+            dcid_seq_num: 1,
+            seq_num: 3,
+            status: 1,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 7);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
 }
@@ -0,0 +1,741 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! QUIC-LB connection ID encoding and decoding.
+//!
+//! This module implements routable connection IDs as described in
+//! [draft-ietf-quic-load-balancers], allowing a load balancer to extract the
+//! identifier of the server a QUIC connection belongs to from the connection
+//! IDs it uses, without keeping any per-connection state.
+//!
+//! Servers use an [`Encoder`] to generate connection IDs that embed their
+//! server ID, usually by passing it to [`set_connection_id_generator()`],
+//! while load balancers use a [`Decoder`] to extract server IDs from the
+//! destination connection IDs of incoming packets.
+//!
+//! Both sides need to agree on the same [`Config`], which defines the length
+//! of server IDs and nonces, and optionally the key used to encrypt them.
+//! Multiple configurations, identified by their config rotation codepoint,
+//! can be used at the same time by a [`Decoder`] to allow for key rotation.
+//!
+//! ```
+//! let mut config = quiche::lb::Config::new(0, 3, 4)?;
+//! config.set_key(&[0xba; 16]);
+//!
+//! let encoder = quiche::lb::Encoder::new(&config, &[0x01, 0x02, 0x03])?;
+//! let cid = encoder.encode()?;
+//!
+//! let mut decoder = quiche::lb::Decoder::new();
+//! decoder.add_config(&config);
+//!
+//! assert_eq!(decoder.decode(&cid), Some(vec![0x01, 0x02, 0x03]));
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [draft-ietf-quic-load-balancers]:
+//! https://datatracker.ietf.org/doc/html/draft-ietf-quic-load-balancers-20
+//! [`Encoder`]: struct.Encoder.html
+//! [`Decoder`]: struct.Decoder.html
+//! [`Config`]: struct.Config.html
+//! [`set_connection_id_generator()`]:
+//! ../struct.Config.html#method.set_connection_id_generator
+
+use std::mem::MaybeUninit;
+
+use std::sync::atomic;
+use std::sync::atomic::AtomicU64;
+
+use libc::c_int;
+use libc::c_uint;
+
+use crate::Error;
+use crate::Result;
+
+use crate::packet::ConnectionId;
+use crate::rand;
+use crate::ConnectionIdGenerator;
+
+/// The config rotation codepoint reserved for unroutable connection IDs.
+pub const UNROUTABLE_CONFIG_ID: u8 = 0b111;
+
+const CONFIG_ID_SHIFT: u8 = 5;
+
+const FIRST_OCTET_MASK: u8 = 0x1f;
+
+const MAX_SERVER_ID_LEN: usize = 15;
+
+const MIN_NONCE_LEN: usize = 4;
+
+const MAX_NONCE_LEN: usize = 18;
+
+const MAX_PLAINTEXT_LEN: usize = 19;
+
+const KEY_LEN: usize = 16;
+
+const BLOCK_LEN: usize = 16;
+
+/// A QUIC-LB configuration, shared between servers and load balancers.
+#[derive(Clone)]
+pub struct Config {
+    config_id: u8,
+
+    server_id_len: usize,
+
+    nonce_len: usize,
+
+    key: Option<[u8; KEY_LEN]>,
+
+    length_self_encoding: bool,
+}
+
+impl Config {
+    /// Creates a new configuration for plaintext connection IDs.
+    ///
+    /// `config_id` is the config rotation codepoint, between 0 and 6, while
+    /// `server_id_len` and `nonce_len` are the lengths in bytes of server IDs
+    /// (between 1 and 15) and nonces (between 4 and 18). Their sum can't be
+    /// greater than 19.
+    ///
+    /// If any of the values is out of range, [`InvalidLbConfig`] is returned.
+    ///
+    /// [`InvalidLbConfig`]: ../enum.Error.html#variant.InvalidLbConfig
+    pub fn new(
+        config_id: u8, server_id_len: usize, nonce_len: usize,
+    ) -> Result<Config> {
+        if config_id >= UNROUTABLE_CONFIG_ID {
+            return Err(Error::InvalidLbConfig);
+        }
+
+        if !(1..=MAX_SERVER_ID_LEN).contains(&server_id_len) ||
+            !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce_len) ||
+            server_id_len + nonce_len > MAX_PLAINTEXT_LEN
+        {
+            return Err(Error::InvalidLbConfig);
+        }
+
+        Ok(Config {
+            config_id,
+            server_id_len,
+            nonce_len,
+            key: None,
+            length_self_encoding: false,
+        })
+    }
+
+    /// Sets the key used to encrypt connection IDs.
+    ///
+    /// By default connection IDs are not encrypted, and the server ID is
+    /// visible to on-path observers.
+    pub fn set_key(&mut self, key: &[u8; KEY_LEN]) {
+        self.key = Some(*key);
+    }
+
+    /// Configures whether the length of connection IDs is encoded in their
+    /// first octet.
+    ///
+    /// This allows load balancers to extract connection IDs from short header
+    /// packets without knowing their length in advance.
+    ///
+    /// The default value is `false`.
+    pub fn set_length_self_encoding(&mut self, v: bool) {
+        self.length_self_encoding = v;
+    }
+
+    /// Returns the config rotation codepoint.
+    pub fn config_id(&self) -> u8 {
+        self.config_id
+    }
+
+    /// Returns the length of the connection IDs using this configuration.
+    pub fn cid_len(&self) -> usize {
+        1 + self.plaintext_len()
+    }
+
+    fn plaintext_len(&self) -> usize {
+        self.server_id_len + self.nonce_len
+    }
+
+    fn cipher(&self) -> Option<Cipher> {
+        self.key
+            .as_ref()
+            .map(|key| Cipher::new(key, self.plaintext_len()))
+    }
+}
+
+/// Generates connection IDs encoding a server ID.
+///
+/// Encrypted connection IDs use a counter as nonce, so that they are never
+/// reused. When used as a [`ConnectionIdGenerator`], unroutable connection IDs
+/// are generated once the nonces are exhausted, and the configuration should
+/// be replaced. Plaintext connection IDs use random nonces instead, so that
+/// they can't be linked to each other by on-path observers.
+///
+/// [`ConnectionIdGenerator`]: ../trait.ConnectionIdGenerator.html
+pub struct Encoder {
+    config: Config,
+
+    cipher: Option<Cipher>,
+
+    server_id: Vec<u8>,
+
+    /// Random bytes prepended to the nonce counter, for nonces longer than
+    /// the counter.
+    nonce_prefix: Vec<u8>,
+
+    next_nonce: AtomicU64,
+}
+
+impl Encoder {
+    /// Creates a new encoder for the given configuration and server ID.
+    ///
+    /// The length of `server_id` must match the configured server ID length,
+    /// otherwise [`InvalidLbConfig`] is returned.
+    ///
+    /// [`InvalidLbConfig`]: ../enum.Error.html#variant.InvalidLbConfig
+    pub fn new(config: &Config, server_id: &[u8]) -> Result<Encoder> {
+        if server_id.len() != config.server_id_len {
+            return Err(Error::InvalidLbConfig);
+        }
+
+        let mut nonce_prefix =
+            vec![0; config.nonce_len.saturating_sub(std::mem::size_of::<u64>())];
+        rand::rand_bytes(&mut nonce_prefix);
+
+        Ok(Encoder {
+            config: config.clone(),
+            cipher: config.cipher(),
+            server_id: server_id.to_vec(),
+            nonce_prefix,
+            next_nonce: AtomicU64::new(0),
+        })
+    }
+
+    /// Generates a new connection ID.
+    ///
+    /// Nonces of encrypted connection IDs are never reused.
+    /// [`OutOfIdentifiers`] is returned once they are exhausted.
+    ///
+    /// [`OutOfIdentifiers`]: ../enum.Error.html#variant.OutOfIdentifiers
+    pub fn encode(&self) -> Result<ConnectionId<'static>> {
+        // A counter would make plaintext connection IDs of the same server
+        // trivially linkable, as only the encryption hides it.
+        if self.cipher.is_none() {
+            let mut nonce = vec![0; self.config.nonce_len];
+            rand::rand_bytes(&mut nonce);
+
+            return Ok(self.encode_with_nonce(&nonce, rand::rand_u8()));
+        }
+
+        let counter_len = self.config.nonce_len - self.nonce_prefix.len();
+
+        let counter = self.next_nonce.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if counter == u64::MAX ||
+            (counter_len < std::mem::size_of::<u64>() &&
+                counter >> (counter_len * 8) != 0)
+        {
+            // Prevent the counter from wrapping around.
+            self.next_nonce.store(u64::MAX, atomic::Ordering::Relaxed);
+
+            return Err(Error::OutOfIdentifiers);
+        }
+
+        let mut nonce = self.nonce_prefix.clone();
+        nonce.extend_from_slice(&counter.to_be_bytes()[8 - counter_len..]);
+
+        Ok(self.encode_with_nonce(&nonce, rand::rand_u8()))
+    }
+
+    fn encode_with_nonce(
+        &self, nonce: &[u8], first_octet: u8,
+    ) -> ConnectionId<'static> {
+        let mut cid = Vec::with_capacity(self.config.cid_len());
+
+        cid.push(first_octet_for(&self.config, first_octet));
+        cid.extend_from_slice(&self.server_id);
+        cid.extend_from_slice(nonce);
+
+        if let Some(cipher) = &self.cipher {
+            cipher.encrypt(&mut cid[1..]);
+        }
+
+        ConnectionId::from_vec(cid)
+    }
+}
+
+impl ConnectionIdGenerator for Encoder {
+    fn new_connection_id(&self) -> ConnectionId<'static> {
+        self.encode()
+            .unwrap_or_else(|_| unroutable_cid(self.config.cid_len()))
+    }
+
+    fn cid_len(&self) -> usize {
+        self.config.cid_len()
+    }
+}
+
+/// Extracts server IDs from connection IDs.
+#[derive(Default)]
+pub struct Decoder {
+    configs: [Option<(Config, Option<Cipher>)>; UNROUTABLE_CONFIG_ID as usize],
+}
+
+impl Decoder {
+    /// Creates a new decoder without any configuration.
+    pub fn new() -> Decoder {
+        Default::default()
+    }
+
+    /// Adds a configuration, replacing any existing one with the same config
+    /// rotation codepoint.
+    pub fn add_config(&mut self, config: &Config) {
+        self.configs[config.config_id as usize] =
+            Some((config.clone(), config.cipher()));
+    }
+
+    /// Removes the configuration with the given config rotation codepoint.
+    pub fn remove_config(&mut self, config_id: u8) {
+        if let Some(c) = self.configs.get_mut(config_id as usize) {
+            *c = None;
+        }
+    }
+
+    /// Extracts the server ID from the given connection ID.
+    ///
+    /// `None` is returned if the connection ID is unroutable, i.e. it's too
+    /// short or there is no configuration matching its config rotation
+    /// codepoint, in which case the load balancer should use a fallback
+    /// routing algorithm.
+    pub fn decode(&self, cid: &[u8]) -> Option<Vec<u8>> {
+        let first = *cid.first()?;
+
+        let config_id = first >> CONFIG_ID_SHIFT;
+
+        let (config, cipher) = self.configs.get(config_id as usize)?.as_ref()?;
+
+        if cid.len() < config.cid_len() {
+            return None;
+        }
+
+        let mut plaintext = cid[1..config.cid_len()].to_vec();
+
+        if let Some(cipher) = cipher {
+            cipher.decrypt(&mut plaintext);
+        }
+
+        plaintext.truncate(config.server_id_len);
+
+        Some(plaintext)
+    }
+}
+
+/// Returns a random connection ID using the unroutable config rotation
+/// codepoint.
+fn unroutable_cid(len: usize) -> ConnectionId<'static> {
+    let mut cid = vec![0; len];
+    rand::rand_bytes(&mut cid);
+
+    cid[0] =
+        (UNROUTABLE_CONFIG_ID << CONFIG_ID_SHIFT) | (cid[0] & FIRST_OCTET_MASK);
+
+    ConnectionId::from_vec(cid)
+}
+
+fn first_octet_for(config: &Config, random: u8) -> u8 {
+    let low = if config.length_self_encoding {
+        (config.cid_len() - 1) as u8
+    } else {
+        random & FIRST_OCTET_MASK
+    };
+
+    (config.config_id << CONFIG_ID_SHIFT) | low
+}
+
+/// The block cipher used to encrypt connection IDs.
+///
+/// Plaintexts of 16 bytes are encrypted using a single AES-128-ECB pass,
+/// while the other lengths use the four-pass algorithm.
+#[derive(Clone)]
+struct Cipher {
+    enc: AES_KEY,
+
+    dec: AES_KEY,
+
+    plaintext_len: usize,
+}
+
+impl Cipher {
+    fn new(key: &[u8; KEY_LEN], plaintext_len: usize) -> Cipher {
+        let mut enc = MaybeUninit::uninit();
+        let mut dec = MaybeUninit::uninit();
+
+        // Setting up a 128-bit key can't fail.
+        let (enc, dec) = unsafe {
+            AES_set_encrypt_key(key.as_ptr(), 128, enc.as_mut_ptr());
+            AES_set_decrypt_key(key.as_ptr(), 128, dec.as_mut_ptr());
+
+            (enc.assume_init(), dec.assume_init())
+        };
+
+        Cipher {
+            enc,
+            dec,
+            plaintext_len,
+        }
+    }
+
+    fn encrypt_block(&self, block: &[u8; BLOCK_LEN]) -> [u8; BLOCK_LEN] {
+        let mut out = [0; BLOCK_LEN];
+
+        unsafe { AES_encrypt(block.as_ptr(), out.as_mut_ptr(), &self.enc) };
+
+        out
+    }
+
+    fn encrypt(&self, buf: &mut [u8]) {
+        if self.plaintext_len == BLOCK_LEN {
+            let mut block = [0; BLOCK_LEN];
+            block.copy_from_slice(buf);
+
+            buf.copy_from_slice(&self.encrypt_block(&block));
+            return;
+        }
+
+        let (mut left, mut right) = self.split(buf);
+
+        self.pass(&mut right, &left, Side::Right, 1);
+        self.pass(&mut left, &right, Side::Left, 2);
+        self.pass(&mut right, &left, Side::Right, 3);
+        self.pass(&mut left, &right, Side::Left, 4);
+
+        self.join(&left, &right, buf);
+    }
+
+    fn decrypt(&self, buf: &mut [u8]) {
+        if self.plaintext_len == BLOCK_LEN {
+            let mut out = [0; BLOCK_LEN];
+
+            unsafe { AES_decrypt(buf.as_ptr(), out.as_mut_ptr(), &self.dec) };
+
+            buf.copy_from_slice(&out);
+            return;
+        }
+
+        let (mut left, mut right) = self.split(buf);
+
+        self.pass(&mut left, &right, Side::Left, 4);
+        self.pass(&mut right, &left, Side::Right, 3);
+        self.pass(&mut left, &right, Side::Left, 2);
+        self.pass(&mut right, &left, Side::Right, 1);
+
+        self.join(&left, &right, buf);
+    }
+
+    fn half_len(&self) -> usize {
+        (self.plaintext_len + 1) / 2
+    }
+
+    fn is_odd(&self) -> bool {
+        self.plaintext_len % 2 == 1
+    }
+
+    /// Splits the input in two halves. When the length is odd, the middle
+    /// nibble is only kept in the left half.
+    fn split(&self, buf: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let half_len = self.half_len();
+
+        let mut left = buf[..half_len].to_vec();
+        let mut right = buf[buf.len() - half_len..].to_vec();
+
+        self.mask(&mut left, Side::Left);
+        self.mask(&mut right, Side::Right);
+
+        (left, right)
+    }
+
+    fn join(&self, left: &[u8], right: &[u8], out: &mut [u8]) {
+        let half_len = self.half_len();
+
+        out[..half_len].copy_from_slice(left);
+
+        if self.is_odd() {
+            out[half_len - 1] |= right[0];
+            out[half_len..].copy_from_slice(&right[1..]);
+        } else {
+            out[half_len..].copy_from_slice(right);
+        }
+    }
+
+    /// Clears the nibble of the half that belongs to the other one, when the
+    /// length is odd.
+    fn mask(&self, half: &mut [u8], side: Side) {
+        if !self.is_odd() {
+            return;
+        }
+
+        match side {
+            Side::Left => half[half.len() - 1] &= 0xf0,
+
+            Side::Right => half[0] &= 0x0f,
+        }
+    }
+
+    /// XORs `half` with the encryption of the expanded `other` half.
+    fn pass(&self, half: &mut [u8], other: &[u8], side: Side, index: u8) {
+        let mut block = [0; BLOCK_LEN];
+        block[..other.len()].copy_from_slice(other);
+        block[BLOCK_LEN - 2] = self.plaintext_len as u8;
+        block[BLOCK_LEN - 1] = index;
+
+        let mut mask = self.encrypt_block(&block)[..half.len()].to_vec();
+        self.mask(&mut mask, side);
+
+        for (a, b) in half.iter_mut().zip(mask) {
+            *a ^= b;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Left,
+    Right,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone)]
+struct AES_KEY {
+    rd_key: [u32; 60],
+    rounds: c_uint,
+}
+
+extern {
+    fn AES_set_encrypt_key(
+        key: *const u8, bits: c_uint, aeskey: *mut AES_KEY,
+    ) -> c_int;
+
+    fn AES_set_decrypt_key(
+        key: *const u8, bits: c_uint, aeskey: *mut AES_KEY,
+    ) -> c_int;
+
+    fn AES_encrypt(inp: *const u8, out: *mut u8, key: *const AES_KEY);
+
+    fn AES_decrypt(inp: *const u8, out: *mut u8, key: *const AES_KEY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x8f, 0x95, 0xf0, 0x92, 0x45, 0x76, 0x5f, 0x80, 0x25, 0x69, 0x34, 0xe5,
+        0x0c, 0x66, 0x20, 0x7f,
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn check_vector(
+        config_id: u8, key: Option<&[u8; 16]>, server_id: &str, nonce: &str,
+        cid: &str,
+    ) {
+        let server_id = hex(server_id);
+        let nonce = hex(nonce);
+        let cid = hex(cid);
+
+        let mut config =
+            Config::new(config_id, server_id.len(), nonce.len()).unwrap();
+        config.set_length_self_encoding(true);
+
+        if let Some(key) = key {
+            config.set_key(key);
+        }
+
+        let encoder = Encoder::new(&config, &server_id).unwrap();
+        assert_eq!(encoder.encode_with_nonce(&nonce, 0).as_ref(), &cid[..]);
+
+        let mut decoder = Decoder::new();
+        decoder.add_config(&config);
+        assert_eq!(decoder.decode(&cid), Some(server_id));
+    }
+
+    #[test]
+    fn plaintext_vectors() {
+        check_vector(0, None, "c4605e", "4504cbd9", "07c4605e4504cbd9");
+    }
+
+    #[test]
+    fn encrypted_vectors() {
+        // Four-pass encryption, with an odd plaintext length.
+        check_vector(0, Some(&KEY), "ed793a", "ee080dbf", "0720b1d07b359d3c");
+
+        // Four-pass encryption, with a server ID longer than the nonce.
+        check_vector(
+            1,
+            Some(&KEY),
+            "ed793a51d49b8f5fab65",
+            "ee080dbf48",
+            "2fcc381bc74cb4fbad2823a3d1f8fed2",
+        );
+
+        // Single-pass encryption.
+        check_vector(
+            2,
+            Some(&KEY),
+            "ed793a51d49b8f5f",
+            "ee080dbf48c0d1e5",
+            "504dd2d05a7b0de9b2b9907afb5ecf8cc3",
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        for server_id_len in 1..=MAX_SERVER_ID_LEN {
+            for nonce_len in MIN_NONCE_LEN..=MAX_PLAINTEXT_LEN - server_id_len {
+                let mut config =
+                    Config::new(3, server_id_len, nonce_len).unwrap();
+                config.set_key(&KEY);
+
+                let server_id: Vec<u8> = (1..=server_id_len as u8).collect();
+
+                let encoder = Encoder::new(&config, &server_id).unwrap();
+
+                let mut decoder = Decoder::new();
+                decoder.add_config(&config);
+
+                let cid = encoder.encode().unwrap();
+                assert_eq!(cid.len(), config.cid_len());
+                assert_eq!(cid[0] >> CONFIG_ID_SHIFT, 3);
+
+                assert_eq!(decoder.decode(&cid), Some(server_id.clone()));
+
+                // Consecutive connection IDs are different.
+                assert_ne!(encoder.encode().unwrap(), cid);
+            }
+        }
+    }
+
+    #[test]
+    fn config_rotation() {
+        let mut config_a = Config::new(0, 2, 6).unwrap();
+        config_a.set_key(&KEY);
+
+        let mut config_b = Config::new(1, 2, 6).unwrap();
+        config_b.set_key(&[0xba; 16]);
+
+        let encoder_a = Encoder::new(&config_a, &[0xaa, 0xaa]).unwrap();
+        let encoder_b = Encoder::new(&config_b, &[0xbb, 0xbb]).unwrap();
+
+        let cid_a = encoder_a.encode().unwrap();
+        let cid_b = encoder_b.encode().unwrap();
+
+        let mut decoder = Decoder::new();
+        decoder.add_config(&config_a);
+        assert_eq!(decoder.decode(&cid_a), Some(vec![0xaa, 0xaa]));
+        assert_eq!(decoder.decode(&cid_b), None);
+
+        decoder.add_config(&config_b);
+        assert_eq!(decoder.decode(&cid_a), Some(vec![0xaa, 0xaa]));
+        assert_eq!(decoder.decode(&cid_b), Some(vec![0xbb, 0xbb]));
+
+        decoder.remove_config(0);
+        assert_eq!(decoder.decode(&cid_a), None);
+        assert_eq!(decoder.decode(&cid_b), Some(vec![0xbb, 0xbb]));
+
+        // Unroutable and truncated connection IDs can't be decoded.
+        assert_eq!(decoder.decode(&unroutable_cid(9)), None);
+        assert_eq!(decoder.decode(&cid_b[..8]), None);
+        assert_eq!(decoder.decode(&[]), None);
+    }
+
+    #[test]
+    fn plaintext_random_nonce() {
+        let config = Config::new(0, 3, 16).unwrap();
+
+        let encoder = Encoder::new(&config, &[0xba; 3]).unwrap();
+
+        let cid_a = encoder.encode().unwrap();
+        let cid_b = encoder.encode().unwrap();
+
+        // The server ID is visible, but the nonces are unrelated.
+        assert_eq!(&cid_a[1..4], &[0xba; 3]);
+        assert_eq!(&cid_b[1..4], &[0xba; 3]);
+        assert_ne!(&cid_a[4..], &cid_b[4..]);
+
+        assert_eq!(encoder.next_nonce.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn nonce_exhaustion() {
+        let mut config = Config::new(0, 15, 4).unwrap();
+        config.set_key(&KEY);
+
+        let encoder = Encoder::new(&config, &[0xba; 15]).unwrap();
+
+        let mut decoder = Decoder::new();
+        decoder.add_config(&config);
+
+        encoder
+            .next_nonce
+            .store(u32::MAX as u64, atomic::Ordering::Relaxed);
+
+        let cid = encoder.encode().unwrap();
+        assert_eq!(decoder.decode(&cid), Some(vec![0xba; 15]));
+
+        assert_eq!(encoder.encode(), Err(Error::OutOfIdentifiers));
+        assert_eq!(encoder.encode(), Err(Error::OutOfIdentifiers));
+
+        // The generator falls back to unroutable connection IDs.
+        let cid = encoder.new_connection_id();
+        assert_eq!(cid.len(), config.cid_len());
+        assert_eq!(cid[0] >> CONFIG_ID_SHIFT, UNROUTABLE_CONFIG_ID);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert_eq!(
+            Config::new(UNROUTABLE_CONFIG_ID, 3, 4).err(),
+            Some(Error::InvalidLbConfig)
+        );
+        assert_eq!(Config::new(0, 0, 4).err(), Some(Error::InvalidLbConfig));
+        assert_eq!(Config::new(0, 16, 4).err(), Some(Error::InvalidLbConfig));
+        assert_eq!(Config::new(0, 3, 3).err(), Some(Error::InvalidLbConfig));
+        assert_eq!(Config::new(0, 3, 19).err(), Some(Error::InvalidLbConfig));
+        assert_eq!(Config::new(0, 10, 10).err(), Some(Error::InvalidLbConfig));
+        assert!(Config::new(6, 10, 9).is_ok());
+
+        let config = Config::new(0, 3, 4).unwrap();
+        assert_eq!(
+            Encoder::new(&config, &[0xba; 4]).err(),
+            Some(Error::InvalidLbConfig)
+        );
+    }
+}
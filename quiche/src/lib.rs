@@ -122,7 +122,11 @@
 //! loop {
 //!     let (read, from) = socket.recv_from(&mut buf).unwrap();
 //!
-//!     let recv_info = quiche::RecvInfo { from, to };
+//!     let recv_info = quiche::RecvInfo {
+//!         from,
+//!         to,
+//!         ecn: quiche::Ecn::NotEct,
+//!     };
 //!
 //!     let read = match conn.recv(&mut buf[..read], recv_info) {
 //!         Ok(v) => v,
@@ -382,6 +386,8 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::ConnectivityEventType;
 #[cfg(feature = "qlog")]
 use qlog::events::connectivity::TransportOwner;
 #[cfg(feature = "qlog")]
@@ -408,7 +414,11 @@ use std::time;
 
 use std::sync::Arc;
 
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::SocketAddrV6;
 
 use std::str::FromStr;
 
@@ -420,8 +430,19 @@ use smallvec::SmallVec;
 /// The current QUIC wire version.
 pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_V1;
 
-/// Supported QUIC versions.
-const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+/// QUIC version 1, as defined in [RFC 9000].
+///
+/// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000.html
+pub const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+
+/// QUIC version 2, as defined in [RFC 9369].
+///
+/// [RFC 9369]: https://www.rfc-editor.org/rfc/rfc9369.html
+pub const PROTOCOL_VERSION_V2: u32 = 0x6b33_43cf;
+
+// The supported versions, in the order a client prefers them when no
+// available versions are configured.
+const SUPPORTED_VERSIONS: [u32; 2] = [PROTOCOL_VERSION_V1, PROTOCOL_VERSION_V2];
 
 /// The maximum length of a connection ID.
 pub const MAX_CONN_ID_LEN: usize = crate::packet::MAX_CID_LEN as usize;
@@ -467,6 +488,9 @@ const PAYLOAD_LENGTH_LEN: usize = 2;
 // The number of undecryptable that can be buffered.
 const MAX_UNDECRYPTABLE_PACKETS: usize = 10;
 
+// The number of tokens received in NEW_TOKEN frames that can be buffered.
+const MAX_NEW_TOKENS: usize = 8;
+
 const RESERVED_VERSION_MASK: u32 = 0xfafafafa;
 
 // The default size of the receiver connection flow control window.
@@ -486,6 +510,10 @@ const MAX_PROBING_TIMEOUTS: usize = 3;
 // The default initial congestion window size in terms of packet count.
 const DEFAULT_INITIAL_CONGESTION_WINDOW_PACKETS: usize = 10;
 
+// The default fraction of the AEAD confidentiality limit after which a key
+// update is initiated.
+const DEFAULT_KEY_UPDATE_THRESHOLD: f64 = 0.75;
+
 /// A specialized [`Result`] type for quiche operations.
 ///
 /// This type is used throughout quiche's public API for any operation that
@@ -564,6 +592,25 @@ pub enum Error {
 
     /// Error in key update.
     KeyUpdate,
+
+    /// Error in version negotiation, e.g. a downgrade attack was detected.
+    VersionNegotiation,
+
+    /// The address validation token could not be authenticated.
+    InvalidToken,
+
+    /// The address validation token has expired.
+    TokenExpired,
+
+    /// The AEAD limits were reached, as too many packets were protected with
+    /// the same keys, or failed authentication.
+    AeadLimitReached,
+
+    /// The multipath scheduler name is not valid.
+    InvalidMultipathScheduler,
+
+    /// The QUIC-LB configuration is not valid.
+    InvalidLbConfig,
 }
 
 impl Error {
@@ -577,6 +624,10 @@ impl Error {
             Error::StreamLimit => 0x4,
             Error::FinalSize => 0x6,
             Error::KeyUpdate => 0xe,
+            Error::VersionNegotiation => 0x11,
+            Error::InvalidToken => 0xb,
+            Error::TokenExpired => 0xb,
+            Error::AeadLimitReached => 0xf,
             _ => 0xa,
         }
     }
@@ -603,6 +654,12 @@ impl Error {
             Error::IdLimit => -17,
             Error::OutOfIdentifiers => -18,
             Error::KeyUpdate => -19,
+            Error::VersionNegotiation => -20,
+            Error::InvalidToken => -21,
+            Error::TokenExpired => -22,
+            Error::AeadLimitReached => -23,
+            Error::InvalidMultipathScheduler => -24,
+            Error::InvalidLbConfig => -25,
         }
     }
 }
@@ -625,6 +682,28 @@ impl std::convert::From<octets::BufferTooShortError> for Error {
     }
 }
 
+/// The ECN codepoint of an IP packet.
+///
+/// See [RFC 3168] for more details.
+///
+/// [RFC 3168]: https://www.rfc-editor.org/rfc/rfc3168.html#section-5
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Ecn {
+    /// Not ECN-Capable Transport.
+    #[default]
+    NotEct = 0,
+
+    /// ECN-Capable Transport, ECT(1).
+    Ect1   = 1,
+
+    /// ECN-Capable Transport, ECT(0).
+    Ect0   = 2,
+
+    /// Congestion Experienced.
+    Ce     = 3,
+}
+
 /// Ancillary information about incoming packets.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct RecvInfo {
@@ -633,6 +712,14 @@ pub struct RecvInfo {
 
     /// The local address the packet was received on.
     pub to: SocketAddr,
+
+    /// The ECN codepoint of the IP packet that carried the datagram.
+    ///
+    /// Applications that can't read the codepoint from the IP header should
+    /// set this to [`Ecn::NotEct`].
+    ///
+    /// [`Ecn::NotEct`]: enum.Ecn.html#variant.NotEct
+    pub ecn: Ecn,
 }
 
 /// Ancillary information about outgoing packets.
@@ -650,6 +737,16 @@ pub struct SendInfo {
     ///
     /// [Pacing]: index.html#pacing
     pub at: time::Instant,
+
+    /// The ECN codepoint the IP packet carrying the datagram should be marked
+    /// with.
+    ///
+    /// This is always [`Ecn::NotEct`] unless ECN is enabled with
+    /// [`enable_ecn()`].
+    ///
+    /// [`Ecn::NotEct`]: enum.Ecn.html#variant.NotEct
+    /// [`enable_ecn()`]: struct.Config.html#method.enable_ecn
+    pub ecn: Ecn,
 }
 
 /// Represents information carried by `CONNECTION_CLOSE` frames.
@@ -695,12 +792,20 @@ pub enum QlogLevel {
     Extra = 2,
 }
 
+/// The function used by servers to generate address validation tokens sent
+/// in NEW_TOKEN frames.
+type NewTokenGenerator = dyn Fn(SocketAddr) -> Vec<u8> + Send + Sync;
+
+type StreamSchedulerFactory = dyn Fn() -> Box<dyn StreamScheduler> + Send + Sync;
+
 /// Stores configuration shared between multiple connections.
 pub struct Config {
     local_transport_params: TransportParams,
 
     version: u32,
 
+    available_versions: Vec<u32>,
+
     tls_ctx: tls::Context,
 
     application_protos: Vec<Vec<u8>>,
@@ -715,6 +820,23 @@ pub struct Config {
     pacing: bool,
     max_pacing_rate: Option<u64>,
 
+    ecn: bool,
+
+    pmtud: bool,
+
+    spin_bit: bool,
+
+    path_scheduler: PathScheduler,
+
+    new_token_generator: Option<Arc<NewTokenGenerator>>,
+
+    cid_generator: Option<Arc<dyn ConnectionIdGenerator>>,
+
+    stream_scheduler: Option<Arc<StreamSchedulerFactory>>,
+
+    preferred_address_v4: Option<SocketAddrV4>,
+    preferred_address_v6: Option<SocketAddrV6>,
+
     dgram_recv_max_queue_len: usize,
     dgram_send_max_queue_len: usize,
 
@@ -724,6 +846,8 @@ pub struct Config {
     max_stream_window: u64,
 
     disable_dcid_reuse: bool,
+
+    key_update_threshold: f64,
 }
 
 // See https://quicwg.org/base-drafts/rfc9000.html#section-15
@@ -765,8 +889,12 @@ impl Config {
         }
 
         Ok(Config {
-            local_transport_params: TransportParams::default(),
+            local_transport_params: TransportParams {
+                grease_quic_bit: true,
+                ..Default::default()
+            },
             version,
+            available_versions: Vec::new(),
             tls_ctx,
             application_protos: Vec::new(),
             grease: true,
@@ -777,6 +905,23 @@ impl Config {
             pacing: true,
             max_pacing_rate: None,
 
+            ecn: false,
+
+            pmtud: false,
+
+            spin_bit: false,
+
+            path_scheduler: PathScheduler::MinRtt,
+
+            new_token_generator: None,
+
+            cid_generator: None,
+
+            stream_scheduler: None,
+
+            preferred_address_v4: None,
+            preferred_address_v6: None,
+
             dgram_recv_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
             dgram_send_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
 
@@ -786,6 +931,8 @@ impl Config {
             max_stream_window: stream::MAX_STREAM_WINDOW,
 
             disable_dcid_reuse: false,
+
+            key_update_threshold: DEFAULT_KEY_UPDATE_THRESHOLD,
         })
     }
 
@@ -872,9 +1019,16 @@ impl Config {
 
     /// Configures whether to send GREASE values.
     ///
+    /// This also controls whether the `grease_quic_bit` transport parameter
+    /// is advertised, allowing the peer to randomize the fixed bit of the
+    /// packets it sends as described in [RFC 9287].
+    ///
     /// The default value is `true`.
+    ///
+    /// [RFC 9287]: https://www.rfc-editor.org/rfc/rfc9287.html
     pub fn grease(&mut self, grease: bool) {
         self.grease = grease;
+        self.local_transport_params.grease_quic_bit = grease;
     }
 
     /// Enables logging of secrets.
@@ -985,6 +1139,39 @@ impl Config {
         self.max_send_udp_payload_size = cmp::max(v, MAX_SEND_UDP_PAYLOAD_SIZE);
     }
 
+    /// Configures whether to enable Path MTU Discovery.
+    ///
+    /// When enabled, each path starts with the minimum QUIC packet size and
+    /// probes for larger sizes as described in [RFC 8899], up to the value
+    /// configured with [`set_max_send_udp_payload_size()`] and the peer's
+    /// `max_udp_payload_size` transport parameter. If packets of the
+    /// discovered size later stop getting through, the path falls back to the
+    /// minimum size and restarts the discovery.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 8899]: https://www.rfc-editor.org/rfc/rfc8899.html
+    /// [`set_max_send_udp_payload_size()`]:
+    ///     struct.Config.html#method.set_max_send_udp_payload_size
+    pub fn discover_pmtu(&mut self, v: bool) {
+        self.pmtud = v;
+    }
+
+    /// Configures whether to enable the latency spin bit.
+    ///
+    /// When enabled, the spin bit of short header packets toggles once per
+    /// round-trip as described in [RFC 9000], allowing on-path observers to
+    /// measure the connection's RTT. As recommended, spinning is still
+    /// disabled on a random 1 in 16 connections. Connections that don't spin
+    /// set the spin bit to a random value for each Destination CID instead.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000.html#section-17.4
+    pub fn enable_spin_bit(&mut self, v: bool) {
+        self.spin_bit = v;
+    }
+
     /// Sets the `initial_max_data` transport parameter.
     ///
     /// When set to a non-zero value quiche will only allow at most `v` bytes of
@@ -1107,6 +1294,18 @@ impl Config {
         self.local_transport_params.max_ack_delay = v;
     }
 
+    /// Sets the `min_ack_delay` transport parameter, in microseconds.
+    ///
+    /// Advertising this parameter enables the [ACK Frequency] extension,
+    /// allowing the peer to request how often ACKs are sent.
+    ///
+    /// It is not advertised by default.
+    ///
+    /// [ACK Frequency]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-ack-frequency
+    pub fn set_min_ack_delay(&mut self, v: u64) {
+        self.local_transport_params.min_ack_delay = Some(v);
+    }
+
     /// Sets the `active_connection_id_limit` transport parameter.
     ///
     /// The default value is `2`. Lower values will be ignored.
@@ -1176,6 +1375,60 @@ impl Config {
         self.max_pacing_rate = Some(v);
     }
 
+    /// Configures whether to enable ECN.
+    ///
+    /// When enabled, outgoing packets are marked with ECT(0) as indicated by
+    /// [`SendInfo`]'s `ecn` field, the path is validated as described in
+    /// [RFC 9000], and congestion experienced marks reported by the peer are
+    /// handled as congestion signals. Applications should only enable this
+    /// when they are able to set the ECN codepoint on outgoing IP packets.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000.html#section-13.4
+    pub fn enable_ecn(&mut self, v: bool) {
+        self.ecn = v;
+    }
+
+    /// Configures whether to enable the [multipath] extension.
+    ///
+    /// When enabled, the `enable_multipath` transport parameter is advertised
+    /// and, if the peer advertises it too, all validated paths can be used to
+    /// send packets at the same time, each with its own packet number space
+    /// and congestion controller. Both endpoints need to use non-zero length
+    /// connection IDs.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [multipath]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-multipath-04
+    pub fn set_multipath(&mut self, v: bool) {
+        self.local_transport_params.enable_multipath = v;
+    }
+
+    /// Configures whether to enable the [reliable stream reset] extension.
+    ///
+    /// When enabled, the `reset_stream_at` transport parameter is advertised
+    /// and, if the peer advertises it too, streams can be reset using
+    /// [`stream_shutdown_at()`] while still delivering a prefix of the
+    /// stream's data to the peer.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [reliable stream reset]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-reliable-stream-reset-06
+    /// [`stream_shutdown_at()`]: struct.Connection.html#method.stream_shutdown_at
+    pub fn enable_reset_stream_at(&mut self, v: bool) {
+        self.local_transport_params.reset_stream_at = v;
+    }
+
+    /// Sets the scheduler used to pick the path on which packets are sent
+    /// when multipath is in use.
+    ///
+    /// The default value is `PathScheduler::MinRtt`.
+    pub fn set_path_scheduler(&mut self, scheduler: PathScheduler) {
+        self.path_scheduler = scheduler;
+    }
+
     /// Configures whether to enable receiving DATAGRAM frames.
     ///
     /// When enabled, the `max_datagram_frame_size` transport parameter is set
@@ -1218,6 +1471,155 @@ impl Config {
         self.local_transport_params.stateless_reset_token = v;
     }
 
+    /// Sets the server's preferred addresses.
+    ///
+    /// Servers advertise these addresses to clients in the preferred_address
+    /// transport parameter, along with a dedicated connection ID. Once the
+    /// handshake is confirmed, clients validate the path towards the address
+    /// matching the family of their current peer address, and migrate to it
+    /// when the validation succeeds.
+    ///
+    /// The preferred address is not advertised when using zero-length
+    /// connection IDs. Setting preferred addresses as a client has no effect
+    /// on the connection.
+    ///
+    /// The default value is `None` for both address families.
+    pub fn set_preferred_address(
+        &mut self, ipv4: Option<SocketAddrV4>, ipv6: Option<SocketAddrV6>,
+    ) {
+        self.preferred_address_v4 = ipv4;
+        self.preferred_address_v6 = ipv6;
+    }
+
+    /// Sets the function used by servers to generate address validation
+    /// tokens.
+    ///
+    /// Once the handshake completes, the function is called with the
+    /// client's address, and the returned token is sent to the client in a
+    /// NEW_TOKEN frame. The client can then use it in the Initial packet of a
+    /// future connection (see [`set_token()`]) to prove ownership of its
+    /// address, without the need for a Retry.
+    ///
+    /// The application is responsible for validating the tokens sent by
+    /// clients, and for calling [`set_peer_address_verified()`] on the new
+    /// connection when the token is valid. Tokens should therefore be
+    /// protected against forgery, bind the client's address and expire.
+    ///
+    /// Empty tokens are not sent. Setting a token generator as a client has no
+    /// effect on the connection.
+    ///
+    /// By default no token is sent.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # fn mint_token(peer: &std::net::SocketAddr) -> Vec<u8> {
+    /// #     vec![]
+    /// # }
+    /// config.set_new_token_generator(|peer| mint_token(&peer));
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`set_token()`]: struct.Connection.html#method.set_token
+    /// [`set_peer_address_verified()`]: struct.Connection.html#method.set_peer_address_verified
+    pub fn set_new_token_generator<F>(&mut self, f: F)
+    where
+        F: Fn(SocketAddr) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.new_token_generator = Some(Arc::new(f));
+    }
+
+    /// Sets the generator used to issue new source connection IDs.
+    ///
+    /// When set, connections automatically advertise new connection IDs to
+    /// the peer once the handshake completes, up to the active connection ID
+    /// limit, and replace the ones retired by the peer. The application can
+    /// track the connection IDs in use with [`source_ids()`] and
+    /// [`retired_scid_next()`].
+    ///
+    /// By default connection IDs are only issued by the application, using
+    /// [`new_source_cid()`].
+    ///
+    /// [`source_ids()`]: struct.Connection.html#method.source_ids
+    /// [`retired_scid_next()`]: struct.Connection.html#method.retired_scid_next
+    /// [`new_source_cid()`]: struct.Connection.html#method.new_source_cid
+    pub fn set_connection_id_generator<G>(&mut self, generator: G)
+    where
+        G: ConnectionIdGenerator + 'static,
+    {
+        self.cid_generator = Some(Arc::new(generator));
+    }
+
+    /// Sets the scheduler deciding the order in which streams send data.
+    ///
+    /// The given function is called to create a new scheduler for each
+    /// connection. Please refer to [`StreamScheduler`] for more details.
+    ///
+    /// By default streams are scheduled according to their priority, as
+    /// implemented by [`StreamPriorityScheduler`].
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// config.set_stream_scheduler(|| {
+    ///     Box::new(quiche::StreamPriorityScheduler::default())
+    /// });
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`StreamScheduler`]: trait.StreamScheduler.html
+    /// [`StreamPriorityScheduler`]: struct.StreamPriorityScheduler.html
+    pub fn set_stream_scheduler<F>(&mut self, f: F)
+    where
+        F: Fn() -> Box<dyn StreamScheduler> + Send + Sync + 'static,
+    {
+        self.stream_scheduler = Some(Arc::new(f));
+    }
+
+    /// Configures the list of versions available for compatible version
+    /// negotiation, in order of preference.
+    ///
+    /// The list is advertised to the peer through the `version_information`
+    /// transport parameter. A server will switch the connection to the first
+    /// version in the list that the client also supports, while a client will
+    /// accept such a switch only to one of the listed versions.
+    ///
+    /// All the versions in the list must be supported by quiche, otherwise
+    /// [`UnknownVersion`] is returned.
+    ///
+    /// The default value is an empty list, which means only the version the
+    /// connection was started with is advertised, and the negotiated version
+    /// is never changed.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION_V1)?;
+    /// config.set_available_versions(&[
+    ///     quiche::PROTOCOL_VERSION_V2,
+    ///     quiche::PROTOCOL_VERSION_V1,
+    /// ])?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`UnknownVersion`]: enum.Error.html#variant.UnknownVersion
+    pub fn set_available_versions(&mut self, versions: &[u32]) -> Result<()> {
+        if !versions.iter().all(|&v| version_is_supported(v)) {
+            return Err(Error::UnknownVersion);
+        }
+
+        self.available_versions = versions.to_vec();
+
+        // Servers only need to inspect the ClientHello when they have versions
+        // to switch to.
+        self.tls_ctx
+            .enable_compatible_version_negotiation(!versions.is_empty());
+
+        Ok(())
+    }
+
     /// Sets whether the QUIC connection should avoid reusing DCIDs over
     /// different paths.
     ///
@@ -1231,6 +1633,20 @@ impl Config {
     pub fn set_disable_dcid_reuse(&mut self, v: bool) {
         self.disable_dcid_reuse = v;
     }
+
+    /// Sets the fraction of the AEAD confidentiality limit after which a key
+    /// update is automatically initiated.
+    ///
+    /// The confidentiality limit is the number of packets that can be safely
+    /// protected with the same keys, as defined in [RFC 9001 section 6.6]. The
+    /// value is clamped between 0.0 and 1.0.
+    ///
+    /// The default value is `0.75`.
+    ///
+    /// [RFC 9001 section 6.6]: https://www.rfc-editor.org/rfc/rfc9001#section-6.6
+    pub fn set_key_update_threshold(&mut self, v: f64) {
+        self.key_update_threshold = v.clamp(0.0, 1.0);
+    }
 }
 
 /// A QUIC connection.
@@ -1245,7 +1661,10 @@ pub struct Connection {
     trace_id: String,
 
     /// Packet number spaces.
-    pkt_num_spaces: [packet::PktNumSpace; packet::Epoch::count()],
+    pkt_num_spaces: packet::PktNumSpaceMap,
+
+    /// Cryptographic contexts of each encryption level.
+    crypto_ctx: [packet::CryptoContext; packet::Epoch::count()],
 
     /// Peer's transport parameters.
     peer_transport_params: TransportParams,
@@ -1265,6 +1684,9 @@ pub struct Connection {
     /// The configuration for recovery.
     recovery_config: recovery::RecoveryConfig,
 
+    /// Whether the latency spin bit is enabled on the connection's paths.
+    spin_bit: bool,
+
     /// The path manager.
     paths: path::PathMap,
 
@@ -1334,6 +1756,22 @@ pub struct Connection {
     /// Received address verification token.
     token: Option<Vec<u8>>,
 
+    /// Function used to generate the tokens sent in NEW_TOKEN frames.
+    new_token_generator: Option<Arc<NewTokenGenerator>>,
+
+    /// Tokens waiting to be sent in NEW_TOKEN frames.
+    new_tokens_to_send: VecDeque<Vec<u8>>,
+
+    /// Tokens received in NEW_TOKEN frames, not yet read by the application.
+    new_tokens_recv: VecDeque<Vec<u8>>,
+
+    /// Generator used to issue new source connection IDs.
+    cid_generator: Option<Arc<dyn ConnectionIdGenerator>>,
+
+    /// The local and peer addresses of the path towards the server's
+    /// preferred address, while the client is migrating to it.
+    preferred_address_path: Option<(SocketAddr, SocketAddr)>,
+
     /// Error code and reason to be sent to the peer in a CONNECTION_CLOSE
     /// frame.
     local_error: Option<ConnectionError>,
@@ -1367,6 +1805,14 @@ pub struct Connection {
     /// relevant for client connections.
     did_version_negotiation: bool,
 
+    /// Versions available for compatible version negotiation, in order of
+    /// preference.
+    available_versions: Vec<u32>,
+
+    /// The version the server switched away from during compatible version
+    /// negotiation, if any. Only relevant for server connections.
+    original_version: Option<u32>,
+
     /// Whether stateless retry has been performed.
     did_retry: bool,
 
@@ -1382,18 +1828,35 @@ pub struct Connection {
     /// Whether the connection handshake has been completed.
     handshake_completed: bool,
 
+    /// Whether the client attempted to send early data.
+    early_data_attempted: bool,
+
     /// Whether the HANDSHAKE_DONE frame has been sent.
     handshake_done_sent: bool,
 
     /// Whether the HANDSHAKE_DONE frame has been acked.
     handshake_done_acked: bool,
 
+    /// ACK frequency state.
+    ack_freq: ackfreq::AckFreq,
+
+    /// Multipath state.
+    multipath: multipath::Multipath,
+
     /// Whether the connection handshake has been confirmed.
     handshake_confirmed: bool,
 
     /// Key phase bit used for outgoing protected packets.
     key_phase: bool,
 
+    /// The packet number space and number of the first 1-RTT packet sent with
+    /// the current keys, if any.
+    key_phase_first_pn: Option<(u64, u64)>,
+
+    /// Whether a 1-RTT packet protected with the current keys was
+    /// acknowledged by the peer.
+    key_phase_acked: bool,
+
     /// Whether an ack-eliciting packet has been sent since last receiving a
     /// packet.
     ack_eliciting_sent: bool,
@@ -1420,10 +1883,23 @@ pub struct Connection {
     /// Whether to emit DATAGRAM frames in the next packet.
     emit_dgram: bool,
 
+    /// The ID to assign to the next tracked DATAGRAM.
+    next_dgram_id: u64,
+
     /// Whether the connection should prevent from reusing destination
     /// Connection IDs when the peer migrates.
     disable_dcid_reuse: bool,
 
+    /// The fraction of the confidentiality limit after which a key update is
+    /// initiated.
+    key_update_threshold: f64,
+
+    /// The number of 1-RTT packets protected with the current keys.
+    seal_count: u64,
+
+    /// The number of packets that failed authentication.
+    open_fail_count: u64,
+
     /// A resusable buffer used by Recovery
     newly_acked: Vec<recovery::Acked>,
 
@@ -1438,6 +1914,16 @@ pub struct Connection {
 
     /// The number of streams stopped by remote.
     stopped_stream_remote_count: u64,
+
+    /// The time stream data started being blocked by the congestion window,
+    /// if it is.
+    congestion_blocked_since: Option<time::Instant>,
+
+    /// The total time stream data was blocked by the congestion window.
+    congestion_blocked: time::Duration,
+
+    /// Events not yet polled by the application.
+    events: events::EventQueue,
 }
 
 /// Creates a new server-side connection.
@@ -1542,9 +2028,11 @@ pub fn negotiate_version(
 /// token to be sent to the client, and verifying tokens sent back by the
 /// client. The generated token should include the `dcid` parameter, such
 /// that it can be later extracted from the token and passed to the
-/// [`accept()`] function as its `odcid` parameter.
+/// [`accept()`] function as its `odcid` parameter. A [`TokenKey`] can be
+/// used for this purpose.
 ///
 /// [`accept()`]: fn.accept.html
+/// [`TokenKey`]: struct.TokenKey.html
 ///
 /// ## Examples:
 ///
@@ -1555,24 +2043,25 @@ pub fn negotiate_version(
 /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
 /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
 /// # let local = socket.local_addr().unwrap();
-/// # fn mint_token(hdr: &quiche::Header, src: &std::net::SocketAddr) -> Vec<u8> {
-/// #     vec![]
-/// # }
-/// # fn validate_token<'a>(src: &std::net::SocketAddr, token: &'a [u8]) -> Option<quiche::ConnectionId<'a>> {
-/// #     None
-/// # }
+/// # let token_key = quiche::TokenKey::new(b"secret");
 /// let (len, peer) = socket.recv_from(&mut buf).unwrap();
 ///
-/// let hdr = quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
+/// let hdr =
+///     quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
 ///
 /// let token = hdr.token.as_ref().unwrap();
 ///
 /// // No token sent by client, create a new one.
 /// if token.is_empty() {
-///     let new_token = mint_token(&hdr, &peer);
+///     let new_token = token_key.seal_retry_token(&peer, &hdr.dcid)?;
 ///
 ///     let len = quiche::retry(
-///         &hdr.scid, &hdr.dcid, &scid, &new_token, hdr.version, &mut out,
+///         &hdr.scid,
+///         &hdr.dcid,
+///         &scid,
+///         &new_token,
+///         hdr.version,
+///         &mut out,
 ///     )?;
 ///
 ///     socket.send_to(&out[..len], &peer).unwrap();
@@ -1580,12 +2069,12 @@ pub fn negotiate_version(
 /// }
 ///
 /// // Client sent token, validate it.
-/// let odcid = validate_token(&peer, token);
+/// let odcid = match token_key.open(&peer, token) {
+///     Ok(v) => v,
 ///
-/// if odcid.is_none() {
-///     // Invalid address validation token.
-///     return Ok(());
-/// }
+///     // Invalid or expired address validation token.
+///     Err(_) => return Ok(()),
+/// };
 ///
 /// let conn = quiche::accept(&scid, odcid.as_ref(), local, peer, &mut config)?;
 /// # Ok::<(), quiche::Error>(())
@@ -1598,10 +2087,71 @@ pub fn retry(
     packet::retry(scid, dcid, new_scid, token, version, out)
 }
 
+/// Writes a stateless reset packet.
+///
+/// This should be sent by servers in response to packets that can't be
+/// associated with any existing connection, e.g. after losing connection
+/// state due to a restart. The `dcid` parameter is the destination connection
+/// ID extracted from the received packet, `key` is the static key used to
+/// derive stateless reset tokens, and `received_len` is the length of the
+/// received packet.
+///
+/// The stateless reset is always smaller than the received packet, in order
+/// to prevent loops between endpoints. [`Done`] is returned if the received
+/// packet is too small to reply to.
+///
+/// The token included in the packet is the one returned by
+/// [`stateless_reset_token()`] for `dcid`, so it's only recognized by the
+/// peer if it was advertised for the same connection ID, via
+/// [`set_stateless_reset_token()`] or [`new_source_cid()`].
+///
+/// [`Done`]: enum.Error.html#variant.Done
+/// [`stateless_reset_token()`]: fn.stateless_reset_token.html
+/// [`set_stateless_reset_token()`]:
+/// struct.Config.html#method.set_stateless_reset_token
+/// [`new_source_cid()`]: struct.Connection.html#method.new_source_cid
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut buf = [0; 512];
+/// # let mut out = [0; 512];
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// # let reset_key = [0xba; 32];
+/// let (len, peer) = socket.recv_from(&mut buf).unwrap();
+///
+/// let hdr = quiche::Header::from_slice(&mut buf[..len], 16)?;
+///
+/// // No connection matches the packet's destination connection ID.
+/// let len = quiche::stateless_reset(&hdr.dcid, &reset_key, len, &mut out)?;
+///
+/// socket.send_to(&out[..len], &peer).unwrap();
+/// # Ok::<(), quiche::Error>(())
+/// ```
+#[inline]
+pub fn stateless_reset(
+    dcid: &ConnectionId, key: &[u8], received_len: usize, out: &mut [u8],
+) -> Result<usize> {
+    packet::stateless_reset(dcid, key, received_len, out)
+}
+
+/// Derives the stateless reset token for the given connection ID.
+///
+/// The token is derived from the connection ID and a static `key`, so that
+/// it can be recomputed by [`stateless_reset()`] without keeping any
+/// per-connection state. The same key should be used by all servers that
+/// might receive packets for the connection.
+///
+/// [`stateless_reset()`]: fn.stateless_reset.html
+#[inline]
+pub fn stateless_reset_token(cid: &ConnectionId, key: &[u8]) -> u128 {
+    packet::stateless_reset_token(cid, key)
+}
+
 /// Returns true if the given protocol version is supported.
 #[inline]
 pub fn version_is_supported(version: u32) -> bool {
-    matches!(version, PROTOCOL_VERSION_V1)
+    matches!(version, PROTOCOL_VERSION_V1 | PROTOCOL_VERSION_V2)
 }
 
 /// Pushes a frame to the output packet if there is enough space.
@@ -1660,6 +2210,10 @@ macro_rules! qlog_with_type {
 const QLOG_PARAMS_SET: EventType =
     EventType::TransportEventType(TransportEventType::ParametersSet);
 
+#[cfg(feature = "qlog")]
+const QLOG_VERSION_INFO: EventType =
+    EventType::TransportEventType(TransportEventType::VersionInformation);
+
 #[cfg(feature = "qlog")]
 const QLOG_PACKET_RX: EventType =
     EventType::TransportEventType(TransportEventType::PacketReceived);
@@ -1676,6 +2230,14 @@ const QLOG_DATA_MV: EventType =
 const QLOG_METRICS: EventType =
     EventType::RecoveryEventType(RecoveryEventType::MetricsUpdated);
 
+#[cfg(feature = "qlog")]
+const QLOG_MTU_UPDATED: EventType =
+    EventType::ConnectivityEventType(ConnectivityEventType::MtuUpdated);
+
+#[cfg(feature = "qlog")]
+const QLOG_SPIN_BIT_UPDATED: EventType =
+    EventType::ConnectivityEventType(ConnectivityEventType::SpinBitUpdated);
+
 #[cfg(feature = "qlog")]
 struct QlogInfo {
     streamer: Option<qlog::streamer::QlogStreamer>,
@@ -1720,7 +2282,11 @@ impl Connection {
 
         let recovery_config = recovery::RecoveryConfig::from_config(config);
 
+        // Disable the spin bit on 1 in 16 connections.
+        let spin_bit = config.spin_bit && rand::rand_u8() % 16 != 0;
+
         let mut path = path::Path::new(local, peer, &recovery_config, true);
+        path.enable_spin_bit(spin_bit);
         // If we did stateless retry assume the peer's address is verified.
         path.verified_peer_address = odcid.is_some();
         // Assume clients validate the server's address implicitly.
@@ -1749,10 +2315,12 @@ impl Connection {
 
             trace_id: scid_as_hex.join(""),
 
-            pkt_num_spaces: [
-                packet::PktNumSpace::new(),
-                packet::PktNumSpace::new(),
-                packet::PktNumSpace::new(),
+            pkt_num_spaces: packet::PktNumSpaceMap::new(),
+
+            crypto_ctx: [
+                packet::CryptoContext::new(),
+                packet::CryptoContext::new(),
+                packet::CryptoContext::new(),
             ],
 
             peer_transport_params: TransportParams::default(),
@@ -1765,6 +2333,8 @@ impl Connection {
 
             recovery_config,
 
+            spin_bit,
+
             paths,
 
             application_protos: config.application_protos.clone(),
@@ -1807,6 +2377,16 @@ impl Connection {
 
             token: None,
 
+            new_token_generator: config.new_token_generator.clone(),
+
+            new_tokens_to_send: VecDeque::new(),
+
+            new_tokens_recv: VecDeque::new(),
+
+            cid_generator: config.cid_generator.clone(),
+
+            preferred_address_path: None,
+
             local_error: None,
 
             peer_error: None,
@@ -1827,6 +2407,10 @@ impl Connection {
 
             did_version_negotiation: false,
 
+            available_versions: config.available_versions.clone(),
+
+            original_version: None,
+
             did_retry: false,
 
             got_peer_conn_id: false,
@@ -1838,13 +2422,24 @@ impl Connection {
 
             handshake_completed: false,
 
+            early_data_attempted: false,
+
             handshake_done_sent: false,
             handshake_done_acked: false,
 
+            ack_freq: ackfreq::AckFreq::default(),
+
+            multipath: multipath::Multipath::new(config.path_scheduler),
+
             handshake_confirmed: false,
 
             key_phase: false,
 
+            key_phase_first_pn: None,
+
+            // Only subsequent key updates need to wait for an acknowledgment.
+            key_phase_acked: true,
+
             ack_eliciting_sent: false,
 
             closed: false,
@@ -1868,16 +2463,33 @@ impl Connection {
 
             emit_dgram: true,
 
+            next_dgram_id: 0,
+
             disable_dcid_reuse: config.disable_dcid_reuse,
 
+            key_update_threshold: config.key_update_threshold,
+
+            seal_count: 0,
+
+            open_fail_count: 0,
+
             newly_acked: Vec::new(),
 
             reset_stream_local_count: 0,
             stopped_stream_local_count: 0,
             reset_stream_remote_count: 0,
             stopped_stream_remote_count: 0,
+
+            congestion_blocked_since: None,
+            congestion_blocked: time::Duration::ZERO,
+
+            events: events::EventQueue::new(config.dgram_send_max_queue_len),
         };
 
+        if let Some(scheduler) = &config.stream_scheduler {
+            conn.streams.set_scheduler(scheduler());
+        }
+
         if let Some(odcid) = odcid {
             conn.local_transport_params
                 .original_destination_connection_id = Some(odcid.to_vec().into());
@@ -1891,10 +2503,55 @@ impl Connection {
         conn.local_transport_params.initial_source_connection_id =
             Some(conn.ids.get_scid(0)?.cid.to_vec().into());
 
+        // The connection ID associated with the server's preferred address
+        // must have sequence number 1, so issue it before any other.
+        if is_server &&
+            !conn.ids.zero_length_scid() &&
+            (config.preferred_address_v4.is_some() ||
+                config.preferred_address_v6.is_some())
+        {
+            let (cid, reset_token) = match &config.cid_generator {
+                Some(generator) => {
+                    let cid = generator.new_connection_id();
+                    let reset_token = generator.stateless_reset_token(&cid);
+
+                    (cid, reset_token)
+                },
+
+                None => {
+                    let mut cid = vec![0; scid.len()];
+                    rand::rand_bytes(&mut cid);
+
+                    let mut reset_token = [0; 16];
+                    rand::rand_bytes(&mut reset_token);
+
+                    (cid.into(), u128::from_be_bytes(reset_token))
+                },
+            };
+
+            // The connection ID is sent in the transport parameter, so there
+            // is no need to advertise it in a NEW_CONNECTION_ID frame.
+            conn.ids.new_scid(
+                cid.clone(),
+                Some(reset_token),
+                false,
+                None,
+                false,
+            )?;
+
+            conn.local_transport_params.preferred_address =
+                Some(PreferredAddress {
+                    ipv4: config.preferred_address_v4,
+                    ipv6: config.preferred_address_v6,
+                    connection_id: cid,
+                    stateless_reset_token: reset_token,
+                });
+        }
+
         conn.handshake.init(is_server)?;
 
         conn.handshake
-            .use_legacy_codepoint(config.version != PROTOCOL_VERSION_V1);
+            .use_legacy_codepoint(!version_is_supported(config.version));
 
         conn.encode_transport_params()?;
 
@@ -1917,10 +2574,8 @@ impl Connection {
                 active_path_id,
             )?;
 
-            conn.pkt_num_spaces[packet::Epoch::Initial].crypto_open =
-                Some(aead_open);
-            conn.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
-                Some(aead_seal);
+            conn.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+            conn.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
 
             conn.derived_initial_secrets = true;
         }
@@ -2057,16 +2712,71 @@ impl Connection {
         Ok(())
     }
 
-    /// Processes QUIC packets received from the peer.
+    /// Configures the address validation token sent in the client's Initial
+    /// packets.
     ///
-    /// On success the number of bytes processed from the input buffer is
-    /// returned. On error the connection will be closed by calling [`close()`]
-    /// with the appropriate error code.
-    ///
-    /// Coalesced packets will be processed as necessary.
+    /// On the client, this can be used to send a token previously received
+    /// from the same server in a NEW_TOKEN frame, as returned by
+    /// [`new_token_next()`], so that the server can skip address validation.
     ///
-    /// Note that the contents of the input buffer `buf` might be modified by
-    /// this function due to, for example, in-place decryption.
+    /// This must only be called immediately after creating a connection, that
+    /// is, before any packet is sent or received. Calling this method on a
+    /// server returns [`InvalidState`].
+    ///
+    /// [`new_token_next()`]: struct.Connection.html#method.new_token_next
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_token(&mut self, token: &[u8]) -> Result<()> {
+        if self.is_server || self.sent_count > 0 || self.recv_count > 0 {
+            return Err(Error::InvalidState);
+        }
+
+        self.token = Some(token.to_vec());
+
+        Ok(())
+    }
+
+    /// Marks the client's address as verified.
+    ///
+    /// On the server, this can be used once the client provided a valid
+    /// address validation token, previously sent in a NEW_TOKEN frame, in its
+    /// first Initial packet. The server is then not limited by the
+    /// anti-amplification limit anymore when sending to the client.
+    ///
+    /// Calling this method on a client returns [`InvalidState`].
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_peer_address_verified(&mut self) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::InvalidState);
+        }
+
+        self.paths.get_active_mut()?.verified_peer_address = true;
+
+        Ok(())
+    }
+
+    /// Returns the next address validation token received from the server in
+    /// a NEW_TOKEN frame, if any.
+    ///
+    /// The token can be stored, and later used with [`set_token()`] when
+    /// connecting to the same server again.
+    ///
+    /// [`set_token()`]: struct.Connection.html#method.set_token
+    #[inline]
+    pub fn new_token_next(&mut self) -> Option<Vec<u8>> {
+        self.new_tokens_recv.pop_front()
+    }
+
+    /// Processes QUIC packets received from the peer.
+    ///
+    /// On success the number of bytes processed from the input buffer is
+    /// returned. On error the connection will be closed by calling [`close()`]
+    /// with the appropriate error code.
+    ///
+    /// Coalesced packets will be processed as necessary.
+    ///
+    /// Note that the contents of the input buffer `buf` might be modified by
+    /// this function due to, for example, in-place decryption.
     ///
     /// [`close()`]: struct.Connection.html#method.close
     ///
@@ -2086,6 +2796,7 @@ impl Connection {
     ///     let recv_info = quiche::RecvInfo {
     ///         from,
     ///         to: local,
+    ///         ecn: quiche::Ecn::NotEct,
     ///     };
     ///
     ///     let read = match conn.recv(&mut buf[..read], recv_info) {
@@ -2100,6 +2811,104 @@ impl Connection {
     /// # Ok::<(), quiche::Error>(())
     /// ```
     pub fn recv(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
+        let recv_count = self.recv_count;
+
+        let done = match self.recv_datagram(buf, info) {
+            Ok(v) => v,
+
+            Err(e) => {
+                // The connection is closed at this point, but still account
+                // for the packets of the datagram that were already processed.
+                self.on_datagrams_received(recv_count).ok();
+
+                return Err(e);
+            },
+        };
+
+        self.on_datagrams_received(recv_count)?;
+
+        Ok(done)
+    }
+
+    /// Processes a batch of UDP datagrams received from the peer, coalesced
+    /// in a single buffer.
+    ///
+    /// This is meant to be used with Generic Receive Offload (GRO), where
+    /// the kernel coalesces datagrams of the same size, received from the same
+    /// 4-tuple, into a single buffer. The buffer is split into segments of
+    /// `segment_size` bytes, except for the last one which can be shorter,
+    /// and each segment is processed as a separate datagram, as if passed to
+    /// [`recv()`].
+    ///
+    /// The timers and the bookkeeping of acknowledged data and flow control
+    /// are only updated once the whole batch has been processed, instead of
+    /// once per datagram.
+    ///
+    /// On success the number of bytes processed from the input buffer is
+    /// returned. On error the connection is closed, the remaining segments
+    /// are not processed, and the error is returned. Segments processed before
+    /// the error are still taken into account.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 65535];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # fn recv_gro(_: &std::net::UdpSocket, _: &mut [u8]) -> (usize, std::net::SocketAddr, usize) { unimplemented!() }
+    /// // Read a GRO buffer, along with the size of its segments.
+    /// let (len, from, segment_size) = recv_gro(&socket, &mut buf);
+    ///
+    /// let recv_info = quiche::RecvInfo {
+    ///     from,
+    ///     to: local,
+    ///     ecn: quiche::Ecn::NotEct,
+    /// };
+    ///
+    /// conn.recv_segments(&mut buf[..len], segment_size, recv_info)?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn recv_segments(
+        &mut self, buf: &mut [u8], segment_size: usize, info: RecvInfo,
+    ) -> Result<usize> {
+        if buf.is_empty() || segment_size == 0 {
+            return Err(Error::BufferTooShort);
+        }
+
+        let recv_count = self.recv_count;
+
+        let mut done = 0;
+
+        for segment in buf.chunks_mut(segment_size) {
+            match self.recv_datagram(segment, info) {
+                Ok(v) => done += v,
+
+                Err(e) => {
+                    // The connection is closed at this point, but still
+                    // account for the segments that were already processed.
+                    self.on_datagrams_received(recv_count).ok();
+
+                    return Err(e);
+                },
+            }
+        }
+
+        self.on_datagrams_received(recv_count)?;
+
+        Ok(done)
+    }
+
+    /// Processes the QUIC packets contained in a single UDP datagram.
+    ///
+    /// The bookkeeping shared by all the datagrams received at once is left to
+    /// `on_datagrams_received()`.
+    fn recv_datagram(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
         let len = buf.len();
 
         if len == 0 {
@@ -2173,18 +2982,200 @@ impl Connection {
             left -= read;
         }
 
+        Ok(done)
+    }
+
+    /// Updates the connection's state once one or more datagrams have been
+    /// processed.
+    ///
+    /// `recv_count` is the number of packets received before processing the
+    /// datagrams.
+    fn on_datagrams_received(&mut self, recv_count: usize) -> Result<()> {
+        // Only update the state if any packet was successfully processed.
+        if self.recv_count != recv_count {
+            let now = time::Instant::now();
+
+            self.process_acked_frames(now);
+
+            if let Some(idle_timeout) = self.idle_timeout() {
+                self.idle_timer = Some(now + idle_timeout);
+            }
+
+            // Update send capacity.
+            self.update_tx_cap();
+        }
+
         // Even though the packet was previously "accepted", it
         // should be safe to forward the error, as it also comes
         // from the `recv()` method.
-        self.process_undecrypted_0rtt_packets()?;
+        self.process_undecrypted_0rtt_packets()
+    }
 
-        Ok(done)
+    /// Processes the frames acknowledged by the peer in the packets received
+    /// so far. Note that several packets from several paths might have been
+    /// acked.
+    fn process_acked_frames(&mut self, now: time::Instant) {
+        let mut peer_max_ack_delay = None;
+
+        for (_, p) in self.paths.iter_mut() {
+            let mut pmtud_probe_acked = None;
+
+            for &epoch in packet::Epoch::epochs(
+                packet::Epoch::Initial..=packet::Epoch::Application,
+            ) {
+                for acked in p.recovery.acked[epoch].drain(..) {
+                    match acked {
+                        frame::Frame::ACK { ranges, .. } => {
+                            // Stop acknowledging packets less than or equal to
+                            // the largest acknowledged in the sent ACK frame
+                            // that, in turn, got acked.
+                            if let Some(largest_acked) = ranges.last() {
+                                self.pkt_num_spaces[epoch]
+                                    .recv_pkt_need_ack
+                                    .remove_until(largest_acked);
+                            }
+                        },
+
+                        frame::Frame::AckMp {
+                            space_id, ranges, ..
+                        } =>
+                            if let (Some(largest_acked), Some(pkt_space)) = (
+                                ranges.last(),
+                                self.pkt_num_spaces.get_app_mut(space_id),
+                            ) {
+                                pkt_space
+                                    .recv_pkt_need_ack
+                                    .remove_until(largest_acked);
+                            },
+
+                        frame::Frame::CryptoHeader { offset, length } => {
+                            self.crypto_ctx[epoch]
+                                .crypto_stream
+                                .send
+                                .ack_and_drop(offset, length);
+                        },
+
+                        frame::Frame::StreamHeader {
+                            stream_id,
+                            offset,
+                            length,
+                            ..
+                        } => {
+                            let stream = match self.streams.get_mut(stream_id) {
+                                Some(v) => v,
+
+                                None => continue,
+                            };
+
+                            stream.send.ack_and_drop(offset, length);
+
+                            stream.stats.on_data_acked(length, now);
+
+                            self.tx_buffered =
+                                self.tx_buffered.saturating_sub(length);
+
+                            qlog_with_type!(QLOG_DATA_MV, self.qlog, q, {
+                                let ev_data = EventData::DataMoved(
+                                    qlog::events::quic::DataMoved {
+                                        stream_id: Some(stream_id),
+                                        offset: Some(offset),
+                                        length: Some(length as u64),
+                                        from: Some(DataRecipient::Transport),
+                                        to: Some(DataRecipient::Dropped),
+                                        raw: None,
+                                    },
+                                );
+
+                                q.add_event_data_with_instant(ev_data, now).ok();
+                            });
+
+                            // Only collect the stream if it is complete and not
+                            // readable. If it is readable, it will get collected
+                            // when stream_recv() is used.
+                            if stream.is_complete() && !stream.is_readable() {
+                                let local = stream.local;
+                                self.streams.collect(stream_id, local);
+
+                                self.events.on_stream_collected(stream_id);
+                            }
+                        },
+
+                        frame::Frame::Ping {
+                            mtu_probe: Some(size),
+                        } => {
+                            pmtud_probe_acked = Some(size);
+                        },
+
+                        frame::Frame::HandshakeDone => {
+                            // Explicitly set this to true, so that if the frame
+                            // was already scheduled for retransmission, it is
+                            // aborted.
+                            self.handshake_done_sent = true;
+
+                            self.handshake_done_acked = true;
+                        },
+
+                        frame::Frame::AckFrequency { seq_num, .. } =>
+                            if let Some(max_ack_delay) =
+                                self.ack_freq.on_ack_frequency_acked(seq_num)
+                            {
+                                peer_max_ack_delay = Some(max_ack_delay);
+                            },
+
+                        frame::Frame::DatagramHeader { id: Some(id), .. } => {
+                            self.events.on_dgram_acked(id);
+                        },
+
+                        frame::Frame::ResetStream { stream_id, .. } |
+                        frame::Frame::ResetStreamAt { stream_id, .. } => {
+                            let stream = match self.streams.get_mut(stream_id) {
+                                Some(v) => v,
+
+                                None => continue,
+                            };
+
+                            // Only collect the stream if it is complete and not
+                            // readable. If it is readable, it will get collected
+                            // when stream_recv() is used.
+                            if stream.is_complete() && !stream.is_readable() {
+                                let local = stream.local;
+                                self.streams.collect(stream_id, local);
+
+                                self.events.on_stream_collected(stream_id);
+                            }
+                        },
+
+                        _ => (),
+                    }
+                }
+            }
+
+            if let Some(size) = pmtud_probe_acked {
+                p.on_pmtud_probe_acked(size, now);
+            }
+
+            qlog_with_type!(QLOG_MTU_UPDATED, self.qlog, q, {
+                if let Some(ev_data) = p.pmtud_maybe_qlog() {
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                }
+            });
+        }
+
+        // Once the peer acknowledged the ACK_FREQUENCY frame, it delays ACKs
+        // by the requested amount of time, so take it into account for PTO.
+        if let Some(max_ack_delay) = peer_max_ack_delay {
+            self.recovery_config.max_ack_delay = max_ack_delay;
+
+            for (_, p) in self.paths.iter_mut() {
+                p.recovery.max_ack_delay = max_ack_delay;
+            }
+        }
     }
 
     fn process_undecrypted_0rtt_packets(&mut self) -> Result<()> {
         // Process previously undecryptable 0-RTT packets if the decryption key
         // is now available.
-        if self.pkt_num_spaces[packet::Epoch::Application]
+        if self.crypto_ctx[packet::Epoch::Application]
             .crypto_0rtt_open
             .is_some()
         {
@@ -2204,7 +3195,7 @@ impl Connection {
     fn is_stateless_reset(&self, buf: &[u8]) -> bool {
         // If the packet is too small, then we just throw it away.
         let buf_len = buf.len();
-        if buf_len < 21 {
+        if buf_len < packet::MIN_STATELESS_RESET_LEN {
             return false;
         }
 
@@ -2306,24 +3297,18 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            let supported_versions =
-                versions.iter().filter(|&&v| version_is_supported(v));
-
-            let mut found_version = false;
-
-            for &v in supported_versions {
-                found_version = true;
-
-                // The final version takes precedence over draft ones.
-                if v == PROTOCOL_VERSION_V1 {
-                    self.version = v;
-                    break;
-                }
+            // Pick the most preferred of the configured available versions, or
+            // of all the supported ones, so that the choice can later be
+            // validated against the server's version_information.
+            let client_versions = if self.available_versions.is_empty() {
+                &SUPPORTED_VERSIONS[..]
+            } else {
+                &self.available_versions[..]
+            };
 
-                self.version = cmp::max(self.version, v);
-            }
+            match client_versions.iter().find(|v| versions.contains(v)) {
+                Some(&v) => self.version = v,
 
-            if !found_version {
                 // We don't support any of the versions offered.
                 //
                 // While a man-in-the-middle attacker might be able to
@@ -2331,11 +3316,25 @@ impl Connection {
                 // failure, the window of opportunity is very small and
                 // this error is quite useful for debugging, so don't just
                 // ignore the packet.
-                return Err(Error::UnknownVersion);
+                None => return Err(Error::UnknownVersion),
             }
 
             self.did_version_negotiation = true;
 
+            qlog_with_type!(QLOG_VERSION_INFO, self.qlog, q, {
+                let ev_data = EventData::VersionInformation(
+                    qlog::events::quic::VersionInformation {
+                        server_versions: Some(
+                            versions.iter().map(|v| format!("{v:x?}")).collect(),
+                        ),
+                        client_versions: None,
+                        chosen_version: Some(format!("{:x?}", self.version)),
+                    },
+                );
+
+                q.add_event_data_with_instant(ev_data, now).ok();
+            });
+
             // Derive Initial secrets based on the new version.
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
                 &self.destination_id(),
@@ -2348,13 +3347,11 @@ impl Connection {
             self.got_peer_conn_id = false;
             self.handshake.clear()?;
 
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_open =
-                Some(aead_open);
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
-                Some(aead_seal);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(!version_is_supported(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
@@ -2363,6 +3360,14 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        // Packets with the fixed bit cleared are only valid when the peer was
+        // allowed to grease it.
+        if !hdr.fixed_bit && !self.local_transport_params.grease_quic_bit {
+            trace!("{} dropped packet with fixed bit cleared", self.trace_id);
+
+            return Err(Error::Done);
+        }
+
         if hdr.ty == packet::Type::Retry {
             // Retry packets can only be sent by the server.
             if self.is_server {
@@ -2413,10 +3418,8 @@ impl Connection {
             self.got_peer_conn_id = false;
             self.handshake.clear()?;
 
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_open =
-                Some(aead_open);
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
-                Some(aead_seal);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
 
             return Err(Error::Done);
         }
@@ -2430,14 +3433,34 @@ impl Connection {
             self.did_version_negotiation = true;
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(!version_is_supported(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
             self.encode_transport_params()?;
         }
 
-        if hdr.ty != packet::Type::Short && hdr.version != self.version {
+        // The server might have switched to a compatible version, in which
+        // case its Initial packets are protected with the new version's keys.
+        let mut aead_upgrade = None;
+
+        if !self.is_server &&
+            !self.got_peer_conn_id &&
+            hdr.ty == packet::Type::Initial &&
+            hdr.version != self.version &&
+            self.available_versions.contains(&hdr.version)
+        {
+            aead_upgrade = Some(crypto::derive_initial_key_material(
+                &self.destination_id(),
+                hdr.version,
+                self.is_server,
+            )?);
+        }
+
+        if hdr.ty != packet::Type::Short &&
+            hdr.version != self.version &&
+            aead_upgrade.is_none()
+        {
             // At this point version negotiation was already performed, so
             // ignore packets that don't match the connection's version.
             return Err(Error::Done);
@@ -2477,10 +3500,8 @@ impl Connection {
                 self.is_server,
             )?;
 
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_open =
-                Some(aead_open);
-            self.pkt_num_spaces[packet::Epoch::Initial].crypto_seal =
-                Some(aead_seal);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
 
             self.derived_initial_secrets = true;
         }
@@ -2489,12 +3510,15 @@ impl Connection {
         let epoch = hdr.ty.to_epoch()?;
 
         // Select AEAD context used to open incoming packet.
-        let aead = if hdr.ty == packet::Type::ZeroRTT {
+        let aead = if let Some((aead_open, _)) = &aead_upgrade {
+            // Use the new version's Initial key if the server switched.
+            Some(aead_open)
+        } else if hdr.ty == packet::Type::ZeroRTT {
             // Only use 0-RTT key if incoming packet is 0-RTT.
-            self.pkt_num_spaces[epoch].crypto_0rtt_open.as_ref()
+            self.crypto_ctx[epoch].crypto_0rtt_open.as_ref()
         } else {
             // Otherwise use the packet number space's main key.
-            self.pkt_num_spaces[epoch].crypto_open.as_ref()
+            self.crypto_ctx[epoch].crypto_open.as_ref()
         };
 
         // Finally, discard packet if no usable key is available.
@@ -2535,8 +3559,29 @@ impl Connection {
             drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
         })?;
 
+        // When multipath is in use, each path has its own packet number space,
+        // identified by the sequence number of the connection ID the packet
+        // was sent to.
+        let space_id =
+            if self.multipath.enabled() && hdr.ty == packet::Type::Short {
+                let pkt_dcid = ConnectionId::from_ref(&hdr.dcid);
+
+                self.ids.find_scid_seq(&pkt_dcid).map_or(0, |(seq, _)| seq)
+            } else {
+                0
+            };
+
+        let largest_rx_pkt_num = match epoch {
+            packet::Epoch::Application => self
+                .pkt_num_spaces
+                .get_app(space_id)
+                .map_or(0, |s| s.largest_rx_pkt_num),
+
+            _ => self.pkt_num_spaces[epoch].largest_rx_pkt_num,
+        };
+
         let pn = packet::decode_pkt_num(
-            self.pkt_num_spaces[epoch].largest_rx_pkt_num,
+            largest_rx_pkt_num,
             hdr.pkt_num,
             hdr.pkt_num_len,
         );
@@ -2563,7 +3608,7 @@ impl Connection {
             hdr.key_phase != self.key_phase
         {
             // Check if this packet arrived before key update.
-            if let Some(key_update) = self.pkt_num_spaces[epoch]
+            if let Some(key_update) = self.crypto_ctx[epoch]
                 .key_update
                 .as_ref()
                 .and_then(|key_update| {
@@ -2575,12 +3620,12 @@ impl Connection {
                 trace!("{} peer-initiated key update", self.trace_id);
 
                 aead_next = Some((
-                    self.pkt_num_spaces[epoch]
+                    self.crypto_ctx[epoch]
                         .crypto_open
                         .as_ref()
                         .unwrap()
                         .derive_next_packet_key()?,
-                    self.pkt_num_spaces[epoch]
+                    self.crypto_ctx[epoch]
                         .crypto_seal
                         .as_ref()
                         .unwrap()
@@ -2593,18 +3638,44 @@ impl Connection {
             }
         }
 
-        let mut payload = packet::decrypt_pkt(
+        let integrity_limit = aead.alg().integrity_limit();
+
+        let mut payload = match packet::decrypt_pkt(
             &mut b,
+            space_id,
             pn,
             pn_len,
             payload_len,
             aead,
-        )
-        .map_err(|e| {
-            drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
-        })?;
+        ) {
+            Ok(v) => v,
+
+            Err(e) => {
+                // Initial keys are public, so only failures of packets
+                // protected with negotiated keys are counted.
+                if epoch != packet::Epoch::Initial {
+                    self.open_fail_count += 1;
+
+                    if self.open_fail_count > integrity_limit {
+                        return Err(Error::AeadLimitReached);
+                    }
+                }
+
+                return Err(drop_pkt_on_err(
+                    e,
+                    self.recv_count,
+                    self.is_server,
+                    &self.trace_id,
+                ));
+            },
+        };
 
-        if self.pkt_num_spaces[epoch].recv_pkt_num.contains(pn) {
+        if self
+            .pkt_num_spaces
+            .get_mut(epoch, space_id)
+            .recv_pkt_num
+            .contains(pn)
+        {
             trace!("{} ignored duplicate packet {}", self.trace_id, pn);
             return Err(Error::Done);
         }
@@ -2614,6 +3685,21 @@ impl Connection {
             return Err(Error::InvalidPacket);
         }
 
+        // The packet was successfully decrypted using the compatible version's
+        // keys, so switch to that version.
+        if let Some((aead_open, aead_seal)) = aead_upgrade {
+            trace!(
+                "{} switching to compatible version {:x}",
+                self.trace_id,
+                hdr.version
+            );
+
+            self.version = hdr.version;
+
+            self.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+            self.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
+        }
+
         // Now that we decrypted the packet, let's see if we can map it to an
         // existing path.
         let recv_pid = if hdr.ty == packet::Type::Short && self.got_peer_conn_id {
@@ -2627,7 +3713,7 @@ impl Connection {
         // The key update is verified once a packet is successfully decrypted
         // using the new keys.
         if let Some((open_next, seal_next)) = aead_next {
-            if !self.pkt_num_spaces[epoch]
+            if !self.crypto_ctx[epoch]
                 .key_update
                 .as_ref()
                 .map_or(true, |prev| prev.update_acked)
@@ -2638,24 +3724,29 @@ impl Connection {
 
             trace!("{} key update verified", self.trace_id);
 
-            let _ = self.pkt_num_spaces[epoch].crypto_seal.replace(seal_next);
+            let _ = self.crypto_ctx[epoch].crypto_seal.replace(seal_next);
 
-            let open_prev = self.pkt_num_spaces[epoch]
+            let open_prev = self.crypto_ctx[epoch]
                 .crypto_open
                 .replace(open_next)
                 .unwrap();
 
             let recv_path = self.paths.get_mut(recv_pid)?;
 
-            self.pkt_num_spaces[epoch].key_update = Some(packet::KeyUpdate {
+            self.crypto_ctx[epoch].key_update = Some(packet::KeyUpdate {
                 crypto_open: open_prev,
                 pn_on_update: pn,
                 update_acked: false,
-                timer: now + (recv_path.recovery.pto() * 3),
+                timer: Some(now + (recv_path.recovery.pto() * 3)),
             });
 
             self.key_phase = !self.key_phase;
 
+            self.key_phase_first_pn = None;
+            self.key_phase_acked = false;
+
+            self.seal_count = 0;
+
             qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
                 let trigger = Some(
                     qlog::events::security::KeyUpdateOrRetiredTrigger::RemoteUpdate,
@@ -2685,6 +3776,25 @@ impl Connection {
 
                 q.add_event_data_with_instant(ev_data_server, now).ok();
             });
+        } else if hdr.ty == packet::Type::Short && hdr.key_phase == self.key_phase
+        {
+            // Keep track of the first packet protected with the current keys,
+            // in case the key update was initiated locally.
+            if let Some(key_update) = self.crypto_ctx[epoch]
+                .key_update
+                .as_mut()
+                .filter(|key_update| pn < key_update.pn_on_update)
+            {
+                if key_update.pn_on_update == u64::MAX {
+                    trace!("{} key update confirmed by peer", self.trace_id);
+
+                    let recv_path = self.paths.get(recv_pid)?;
+
+                    key_update.timer = Some(now + (recv_path.recovery.pto() * 3));
+                }
+
+                key_update.pn_on_update = pn;
+            }
         }
 
         if !self.is_server && !self.got_peer_conn_id {
@@ -2815,165 +3925,120 @@ impl Connection {
             });
         }
 
-        // Process acked frames. Note that several packets from several paths
-        // might have been acked by the received packet.
-        for (_, p) in self.paths.iter_mut() {
-            for acked in p.recovery.acked[epoch].drain(..) {
-                match acked {
-                    frame::Frame::ACK { ranges, .. } => {
-                        // Stop acknowledging packets less than or equal to the
-                        // largest acknowledged in the sent ACK frame that, in
-                        // turn, got acked.
-                        if let Some(largest_acked) = ranges.last() {
-                            self.pkt_num_spaces[epoch]
-                                .recv_pkt_need_ack
-                                .remove_until(largest_acked);
-                        }
-                    },
+        // Now that we processed all the frames, if there is a path that has no
+        // Destination CID, try to allocate one.
+        let no_dcid = self
+            .paths
+            .iter_mut()
+            .filter(|(_, p)| p.active_dcid_seq.is_none());
 
-                    frame::Frame::CryptoHeader { offset, length } => {
-                        self.pkt_num_spaces[epoch]
-                            .crypto_stream
-                            .send
-                            .ack_and_drop(offset, length);
-                    },
+        for (pid, p) in no_dcid {
+            if self.ids.zero_length_dcid() {
+                p.active_dcid_seq = Some(0);
+                continue;
+            }
 
-                    frame::Frame::StreamHeader {
-                        stream_id,
-                        offset,
-                        length,
-                        ..
-                    } => {
-                        let stream = match self.streams.get_mut(stream_id) {
-                            Some(v) => v,
+            let dcid_seq = match self.ids.lowest_available_dcid_seq() {
+                Some(seq) => seq,
+                None => break,
+            };
 
-                            None => continue,
-                        };
+            self.ids.link_dcid_to_path_id(dcid_seq, pid)?;
 
-                        stream.send.ack_and_drop(offset, length);
-
-                        self.tx_buffered =
-                            self.tx_buffered.saturating_sub(length);
-
-                        qlog_with_type!(QLOG_DATA_MV, self.qlog, q, {
-                            let ev_data = EventData::DataMoved(
-                                qlog::events::quic::DataMoved {
-                                    stream_id: Some(stream_id),
-                                    offset: Some(offset),
-                                    length: Some(length as u64),
-                                    from: Some(DataRecipient::Transport),
-                                    to: Some(DataRecipient::Dropped),
-                                    raw: None,
-                                },
-                            );
+            p.active_dcid_seq = Some(dcid_seq);
+        }
 
-                            q.add_event_data_with_instant(ev_data, now).ok();
-                        });
-
-                        // Only collect the stream if it is complete and not
-                        // readable. If it is readable, it will get collected when
-                        // stream_recv() is used.
-                        if stream.is_complete() && !stream.is_readable() {
-                            let local = stream.local;
-                            self.streams.collect(stream_id, local);
-                        }
-                    },
+        // We only record the time of arrival of the largest packet number
+        // that still needs to be acked, to be used for ACK delay calculation.
+        let pkt_space = self.pkt_num_spaces.get_mut(epoch, space_id);
 
-                    frame::Frame::HandshakeDone => {
-                        // Explicitly set this to true, so that if the frame was
-                        // already scheduled for retransmission, it is aborted.
-                        self.handshake_done_sent = true;
+        if pkt_space.recv_pkt_need_ack.last() < Some(pn) {
+            pkt_space.largest_rx_pkt_time = now;
+        }
 
-                        self.handshake_done_acked = true;
-                    },
-
-                    frame::Frame::ResetStream { stream_id, .. } => {
-                        let stream = match self.streams.get_mut(stream_id) {
-                            Some(v) => v,
+        pkt_space.recv_pkt_num.insert(pn);
 
-                            None => continue,
-                        };
+        pkt_space.recv_pkt_need_ack.push_item(pn);
 
-                        // Only collect the stream if it is complete and not
-                        // readable. If it is readable, it will get collected when
-                        // stream_recv() is used.
-                        if stream.is_complete() && !stream.is_readable() {
-                            let local = stream.local;
-                            self.streams.collect(stream_id, local);
-                        }
-                    },
+        // Keep track of the ECN codepoints to be reported to the peer.
+        let ecn_counts = &mut pkt_space.ecn_counts;
 
-                    _ => (),
-                }
-            }
+        match info.ecn {
+            Ecn::NotEct => (),
+            Ecn::Ect0 => ecn_counts.ect0_count += 1,
+            Ecn::Ect1 => ecn_counts.ect1_count += 1,
+            Ecn::Ce => ecn_counts.ecn_ce_count += 1,
         }
 
-        // Now that we processed all the frames, if there is a path that has no
-        // Destination CID, try to allocate one.
-        let no_dcid = self
-            .paths
-            .iter_mut()
-            .filter(|(_, p)| p.active_dcid_seq.is_none());
-
-        for (pid, p) in no_dcid {
-            if self.ids.zero_length_dcid() {
-                p.active_dcid_seq = Some(0);
-                continue;
-            }
-
-            let dcid_seq = match self.ids.lowest_available_dcid_seq() {
-                Some(seq) => seq,
-                None => break,
-            };
-
-            self.ids.link_dcid_to_path_id(dcid_seq, pid)?;
+        if ack_elicited {
+            pkt_space.ack_eliciting_unacked += 1;
+
+            // ACKs are only delayed in the Application packet number space,
+            // as requested by the peer, and never for CE-marked packets.
+            let delay_ack = epoch == packet::Epoch::Application &&
+                info.ecn != Ecn::Ce &&
+                self.ack_freq.delay_ack(
+                    pn,
+                    pkt_space.ack_eliciting_unacked,
+                    &pkt_space.recv_pkt_need_ack,
+                    pkt_space.largest_rx_pkt_num,
+                    pkt_space.largest_reported_ack,
+                );
 
-            p.active_dcid_seq = Some(dcid_seq);
-        }
+            match self.ack_freq.max_ack_delay() {
+                Some(max_ack_delay) if delay_ack => {
+                    if pkt_space.ack_timer.is_none() {
+                        pkt_space.ack_timer = Some(now + max_ack_delay);
+                    }
+                },
 
-        // We only record the time of arrival of the largest packet number
-        // that still needs to be acked, to be used for ACK delay calculation.
-        if self.pkt_num_spaces[epoch].recv_pkt_need_ack.last() < Some(pn) {
-            self.pkt_num_spaces[epoch].largest_rx_pkt_time = now;
+                _ => pkt_space.ack_elicited = true,
+            }
         }
 
-        self.pkt_num_spaces[epoch].recv_pkt_num.insert(pn);
-
-        self.pkt_num_spaces[epoch].recv_pkt_need_ack.push_item(pn);
-
-        self.pkt_num_spaces[epoch].ack_elicited =
-            cmp::max(self.pkt_num_spaces[epoch].ack_elicited, ack_elicited);
-
-        self.pkt_num_spaces[epoch].largest_rx_pkt_num =
-            cmp::max(self.pkt_num_spaces[epoch].largest_rx_pkt_num, pn);
+        pkt_space.largest_rx_pkt_num = cmp::max(pkt_space.largest_rx_pkt_num, pn);
 
         if !probing {
-            self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num = cmp::max(
-                self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num,
-                pn,
-            );
+            pkt_space.largest_rx_non_probing_pkt_num =
+                cmp::max(pkt_space.largest_rx_non_probing_pkt_num, pn);
+
+            let migrated = pkt_space.largest_rx_non_probing_pkt_num == pn;
 
             // Did the peer migrated to another path?
             let active_path_id = self.paths.get_active_path_id()?;
 
+            // When multipath is in use, all paths can carry non-probing
+            // packets, so receiving one doesn't imply migration.
             if self.is_server &&
+                !self.multipath.enabled() &&
                 recv_pid != active_path_id &&
-                self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num == pn
+                migrated
             {
                 self.on_peer_migrated(recv_pid, self.disable_dcid_reuse, now)?;
             }
         }
 
-        if let Some(idle_timeout) = self.idle_timeout() {
-            self.idle_timer = Some(now + idle_timeout);
-        }
-
-        // Update send capacity.
-        self.update_tx_cap();
-
         self.recv_count += 1;
         self.paths.get_mut(recv_pid)?.recv_count += 1;
 
+        if hdr.ty == packet::Type::Short {
+            let recv_path = self.paths.get_mut(recv_pid)?;
+
+            if let Some(_spin) =
+                recv_path.on_spin_bit_received(pn, hdr.spin_bit, self.is_server)
+            {
+                qlog_with_type!(QLOG_SPIN_BIT_UPDATED, self.qlog, q, {
+                    let ev_data = EventData::SpinBitUpdated(
+                        qlog::events::connectivity::SpinBitUpdated {
+                            state: _spin,
+                        },
+                    );
+
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                });
+            }
+        }
+
         let read = b.off() + aead_tag_len;
 
         self.recv_bytes += read as u64;
@@ -3173,6 +4238,8 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        self.migrate_to_preferred_address();
+
         let mut has_initial = false;
 
         let mut done = 0;
@@ -3198,12 +4265,18 @@ impl Connection {
             left = cmp::min(left, send_path.max_send_bytes);
         }
 
+        // All the packets coalesced in the same datagram share the same ECN
+        // codepoint.
+        let ecn_marked = send_path.recovery.ecn_should_mark();
+
         // Generate coalesced packets.
         while left > 0 {
             let (ty, written) = match self.send_single(
                 &mut out[done..done + left],
                 send_pid,
                 has_initial,
+                ecn_marked,
+                None,
                 now,
             ) {
                 Ok(v) => v,
@@ -3241,12 +4314,46 @@ impl Connection {
             }
         }
 
+        // Send a PMTUD probe when there is nothing else to send, as it is
+        // padded beyond the current PMTU and sent on its own.
+        if done == 0 && self.handshake_confirmed && self.local_error.is_none() {
+            let probe_size = self
+                .paths
+                .get_mut(send_pid)?
+                .pmtud_probe_size(now)
+                .filter(|size| *size <= out.len());
+
+            if let Some(size) = probe_size {
+                match self.send_single(
+                    &mut out[..size],
+                    send_pid,
+                    false,
+                    ecn_marked,
+                    Some(size),
+                    now,
+                ) {
+                    Ok((_, written)) => done += written,
+
+                    Err(Error::BufferTooShort) | Err(Error::Done) => (),
+
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+
         if done == 0 {
             self.last_tx_data = self.tx_data;
 
             return Err(Error::Done);
         }
 
+        self.multipath.on_path_used(send_pid);
+
+        // Pending ACKs of unused packet number spaces might have been sent.
+        if self.multipath.enabled() {
+            self.drop_unused_app_spaces();
+        }
+
         // Pad UDP datagram if it contains a QUIC Initial packet.
         #[cfg(not(feature = "fuzzing"))]
         if has_initial && left > 0 && done < MIN_CLIENT_INITIAL_LEN {
@@ -3266,14 +4373,137 @@ impl Connection {
             to: send_path.peer_addr(),
 
             at: send_path.recovery.get_packet_send_time(),
+
+            ecn: if ecn_marked { Ecn::Ect0 } else { Ecn::NotEct },
         };
 
         Ok((done, info))
     }
 
+    /// Writes a batch of QUIC packets to be sent to the peer, back to back in
+    /// the same buffer.
+    ///
+    /// This is meant to be used with Generic Segmentation Offload (GSO), where
+    /// the kernel splits a single buffer into several UDP datagrams of the same
+    /// size. Up to `max_segments` datagrams are written to the output buffer,
+    /// as if by calling [`send()`] repeatedly. All datagrams have the same
+    /// size, except for the last one which can be shorter.
+    ///
+    /// The batch ends early when the next datagram would need to be sent on a
+    /// different path, marked with a different ECN codepoint, or released at a
+    /// later time by the pacer.
+    ///
+    /// On success the total number of bytes written to the output buffer is
+    /// returned, along with the size of each segment and the [`SendInfo`]
+    /// shared by all of them, or [`Done`] if there was nothing to write. If an
+    /// error occurs after some datagrams were written, the batch ends early
+    /// and the error is returned by the next call instead.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`Done`]: enum.Error.html#variant.Done
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut out = [0; 65535];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # fn send_gso(_: &std::net::UdpSocket, _: &[u8], _: &quiche::SendInfo, _: usize) { unimplemented!() }
+    /// loop {
+    ///     let (write, segment_size, send_info) =
+    ///         match conn.send_batch(&mut out, 64) {
+    ///             Ok(v) => v,
+    ///
+    ///             Err(quiche::Error::Done) => {
+    ///                 // Done writing.
+    ///                 break;
+    ///             },
+    ///
+    ///             Err(e) => {
+    ///                 // An error occurred, handle it.
+    ///                 break;
+    ///             },
+    ///         };
+    ///
+    ///     // Send all the datagrams at once, with UDP_SEGMENT set to
+    ///     // `segment_size`.
+    ///     send_gso(&socket, &out[..write], &send_info, segment_size);
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn send_batch(
+        &mut self, out: &mut [u8], max_segments: usize,
+    ) -> Result<(usize, usize, SendInfo)> {
+        if max_segments == 0 {
+            return Err(Error::BufferTooShort);
+        }
+
+        let (mut done, info) = self.send_on_path(out, None, None)?;
+
+        let segment_size = done;
+
+        let send_pid = self
+            .paths
+            .path_id_from_addrs(&(info.from, info.to))
+            .ok_or(Error::InvalidState)?;
+
+        for _ in 1..max_segments {
+            if out.len() - done < segment_size {
+                break;
+            }
+
+            // Only keep going while the next packet can go in the same batch.
+            if self.get_send_path_id(None, None).ok() != Some(send_pid) {
+                break;
+            }
+
+            let send_path = self.paths.get(send_pid)?;
+
+            if send_path.recovery.get_packet_send_time() != info.at {
+                break;
+            }
+
+            let ecn = if send_path.recovery.ecn_should_mark() {
+                Ecn::Ect0
+            } else {
+                Ecn::NotEct
+            };
+
+            if ecn != info.ecn {
+                break;
+            }
+
+            let written = match self.send_on_path(
+                &mut out[done..done + segment_size],
+                Some(info.from),
+                Some(info.to),
+            ) {
+                Ok((v, _)) => v,
+
+                // The datagrams already written were recorded as sent, so
+                // don't drop them.
+                Err(_) => break,
+            };
+
+            done += written;
+
+            // Only the last segment can be shorter than the others.
+            if written < segment_size {
+                break;
+            }
+        }
+
+        Ok((done, segment_size, info))
+    }
+
     fn send_single(
         &mut self, out: &mut [u8], send_pid: usize, has_initial: bool,
-        now: time::Instant,
+        ecn_marked: bool, pmtud_probe: Option<usize>, now: time::Instant,
     ) -> Result<(packet::Type, usize)> {
         if out.is_empty() {
             return Err(Error::BufferTooShort);
@@ -3287,7 +4517,13 @@ impl Connection {
 
         let mut b = octets::OctetsMut::with_slice(out);
 
-        let pkt_type = self.write_pkt_type(send_pid)?;
+        // PMTUD probes are only sent once the handshake is confirmed, so they
+        // always use 1-RTT packets.
+        let pkt_type = if pmtud_probe.is_some() {
+            packet::Type::Short
+        } else {
+            self.write_pkt_type(send_pid)?
+        };
 
         let max_dgram_len = if !self.dgram_send_queue.is_empty() {
             self.dgram_max_writable_len()
@@ -3296,14 +4532,19 @@ impl Connection {
         };
 
         let epoch = pkt_type.to_epoch()?;
-        let pkt_space = &mut self.pkt_num_spaces[epoch];
+
+        if pkt_type == packet::Type::Short {
+            self.check_confidentiality_limit(now)?;
+        }
+
+        let crypto_ctx = &mut self.crypto_ctx[epoch];
 
         // Process lost frames. There might be several paths having lost frames.
         for (_, p) in self.paths.iter_mut() {
             for lost in p.recovery.lost[epoch].drain(..) {
                 match lost {
                     frame::Frame::CryptoHeader { offset, length } => {
-                        pkt_space.crypto_stream.send.retransmit(offset, length);
+                        crypto_ctx.crypto_stream.send.retransmit(offset, length);
 
                         self.stream_retrans_bytes += length as u64;
                         p.stream_retrans_bytes += length as u64;
@@ -3330,6 +4571,8 @@ impl Connection {
 
                         stream.send.retransmit(offset, length);
 
+                        stream.stats.on_data_lost(length);
+
                         // If the stream is now flushable push it to the
                         // flushable queue, but only if it wasn't already
                         // queued.
@@ -3351,7 +4594,22 @@ impl Connection {
                     },
 
                     frame::Frame::ACK { .. } => {
-                        pkt_space.ack_elicited = true;
+                        self.pkt_num_spaces[epoch].ack_elicited = true;
+                    },
+
+                    frame::Frame::AckMp { space_id, .. } => {
+                        // The packet number space might have been dropped
+                        // already, if its path isn't used anymore.
+                        if let Some(pkt_space) =
+                            self.pkt_num_spaces.get_app_mut(space_id)
+                        {
+                            pkt_space.ack_elicited = true;
+                        }
+                    },
+
+                    frame::Frame::PathAbandon { .. } |
+                    frame::Frame::PathStatus { .. } => {
+                        self.multipath.on_frame_lost(lost);
                     },
 
                     frame::Frame::ResetStream {
@@ -3360,16 +4618,39 @@ impl Connection {
                         final_size,
                     } =>
                         if self.streams.get(stream_id).is_some() {
-                            self.streams
-                                .insert_reset(stream_id, error_code, final_size);
+                            self.streams.insert_reset(
+                                stream_id, error_code, final_size, 0,
+                            );
                         },
 
+                    frame::Frame::ResetStreamAt {
+                        stream_id,
+                        error_code,
+                        final_size,
+                        reliable_size,
+                    } if self.streams.get(stream_id).is_some() => {
+                        self.streams.insert_reset(
+                            stream_id,
+                            error_code,
+                            final_size,
+                            reliable_size,
+                        );
+                    },
+
                     // Retransmit HANDSHAKE_DONE only if it hasn't been acked at
                     // least once already.
                     frame::Frame::HandshakeDone if !self.handshake_done_acked => {
                         self.handshake_done_sent = false;
                     },
 
+                    frame::Frame::AckFrequency { seq_num, .. } => {
+                        self.ack_freq.on_ack_frequency_lost(seq_num);
+                    },
+
+                    frame::Frame::NewToken { token } => {
+                        self.new_tokens_to_send.push_back(token);
+                    },
+
                     frame::Frame::MaxStreamData { stream_id, .. } => {
                         if self.streams.get(stream_id).is_some() {
                             self.streams.insert_almost_full(stream_id);
@@ -3388,6 +4669,16 @@ impl Connection {
                         self.ids.mark_retire_dcid_seq(seq_num, true);
                     },
 
+                    frame::Frame::Ping {
+                        mtu_probe: Some(size),
+                    } => {
+                        p.pmtud.on_probe_lost(size, now);
+                    },
+
+                    frame::Frame::DatagramHeader { id: Some(id), .. } => {
+                        self.events.on_dgram_lost(id);
+                    },
+
                     _ => (),
                 }
             }
@@ -3397,17 +4688,25 @@ impl Connection {
         let n_paths = self.paths.len();
         let path = self.paths.get_mut(send_pid)?;
         let flow_control = &mut self.flow_control;
-        let pkt_space = &mut self.pkt_num_spaces[epoch];
+        let crypto_ctx = &mut self.crypto_ctx[epoch];
 
         let mut left = b.cap();
 
-        let pn = pkt_space.next_pkt_num;
+        let dcid_seq = path.active_dcid_seq.ok_or(Error::OutOfIdentifiers)?;
+
+        // When multipath is in use, 1-RTT packets use the packet number space
+        // of the path, identified by the sequence number of its destination
+        // connection ID.
+        let multipath =
+            self.multipath.enabled() && pkt_type == packet::Type::Short;
+
+        let space_id = if multipath { dcid_seq } else { 0 };
+
+        let pn = self.pkt_num_spaces.get_mut(epoch, space_id).next_pkt_num;
         let pn_len = packet::pkt_num_len(pn)?;
 
         // The AEAD overhead at the current encryption level.
-        let crypto_overhead = pkt_space.crypto_overhead().ok_or(Error::Done)?;
-
-        let dcid_seq = path.active_dcid_seq.ok_or(Error::OutOfIdentifiers)?;
+        let crypto_overhead = crypto_ctx.crypto_overhead().ok_or(Error::Done)?;
 
         let dcid =
             ConnectionId::from_ref(self.ids.get_dcid(dcid_seq)?.cid.as_ref());
@@ -3442,6 +4741,14 @@ impl Connection {
 
             versions: None,
             key_phase: self.key_phase,
+            spin_bit: pkt_type == packet::Type::Short && path.spin_bit(),
+
+            // Grease the fixed bit if the peer supports it, though never on
+            // Initial packets as the peer's transport parameters might not be
+            // known yet.
+            fixed_bit: !(self.peer_transport_params.grease_quic_bit &&
+                pkt_type != packet::Type::Initial &&
+                rand::rand_u8() % 2 == 0),
         };
 
         hdr.to_bytes(&mut b)?;
@@ -3532,47 +4839,75 @@ impl Connection {
         // generate an ACK (if there's anything to ACK) since we're going to
         // send a packet with PING anyways, even if we haven't received anything
         // ACK eliciting.
-        if pkt_space.recv_pkt_need_ack.len() > 0 &&
-            (pkt_space.ack_elicited || ack_elicit_required) &&
-            (!is_closing ||
-                (pkt_type == Type::Handshake &&
-                    self.local_error
-                        .as_ref()
-                        .map_or(false, |le| le.is_app))) &&
-            path.active()
+        //
+        // When multipath is in use, an ACK_MP frame is created for each of the
+        // paths' packet number spaces instead.
+        for (ack_space_id, pkt_space) in
+            self.pkt_num_spaces.epoch_iter_mut(epoch, multipath)
         {
-            let ack_delay = pkt_space.largest_rx_pkt_time.elapsed();
+            if pkt_space.recv_pkt_need_ack.len() > 0 &&
+                (pkt_space.ack_elicited || ack_elicit_required) &&
+                (!is_closing ||
+                    (pkt_type == Type::Handshake &&
+                        self.local_error
+                            .as_ref()
+                            .map_or(false, |le| le.is_app))) &&
+                path.active()
+            {
+                let ack_delay = pkt_space.largest_rx_pkt_time.elapsed();
 
-            let ack_delay = ack_delay.as_micros() as u64 /
-                2_u64
-                    .pow(self.local_transport_params.ack_delay_exponent as u32);
+                let ack_delay = ack_delay.as_micros() as u64 /
+                    2_u64.pow(
+                        self.local_transport_params.ack_delay_exponent as u32,
+                    );
 
-            let frame = frame::Frame::ACK {
-                ack_delay,
-                ranges: pkt_space.recv_pkt_need_ack.clone(),
-                ecn_counts: None, // sending ECN is not supported at this time
-            };
+                // Only send ECN counts once ECN-marked packets were received.
+                let ecn_counts = if pkt_space.ecn_counts == Default::default() {
+                    None
+                } else {
+                    Some(pkt_space.ecn_counts)
+                };
 
-            // When a PING frame needs to be sent, avoid sending the ACK if
-            // there is not enough cwnd available for both (note that PING
-            // frames are always 1 byte, so we just need to check that the
-            // ACK's length is lower than cwnd).
-            if pkt_space.ack_elicited || frame.wire_len() < cwnd_available {
-                // ACK-only packets are not congestion controlled so ACKs must
-                // be bundled considering the buffer capacity only, and not the
-                // available cwnd.
-                if push_frame_to_pkt!(b, frames, frame, left) {
-                    pkt_space.ack_elicited = false;
+                let frame = if multipath {
+                    frame::Frame::AckMp {
+                        space_id: ack_space_id,
+                        ack_delay,
+                        ranges: pkt_space.recv_pkt_need_ack.clone(),
+                        ecn_counts,
+                    }
+                } else {
+                    frame::Frame::ACK {
+                        ack_delay,
+                        ranges: pkt_space.recv_pkt_need_ack.clone(),
+                        ecn_counts,
+                    }
+                };
+
+                // When a PING frame needs to be sent, avoid sending the ACK if
+                // there is not enough cwnd available for both (note that PING
+                // frames are always 1 byte, so we just need to check that the
+                // ACK's length is lower than cwnd).
+                if pkt_space.ack_elicited || frame.wire_len() < cwnd_available {
+                    // ACK-only packets are not congestion controlled so ACKs must
+                    // be bundled considering the buffer capacity only, and not
+                    // the available cwnd.
+                    if push_frame_to_pkt!(b, frames, frame, left) {
+                        pkt_space.ack_elicited = false;
+                        pkt_space.ack_eliciting_unacked = 0;
+                        pkt_space.ack_timer = None;
+                        pkt_space.largest_reported_ack =
+                            pkt_space.recv_pkt_need_ack.last();
+                    }
                 }
             }
         }
 
-        // Limit output packet size by congestion window size.
-        left = cmp::min(
-            left,
-            // Bytes consumed by ACK frames.
-            cwnd_available.saturating_sub(left_before_packing_ack_frame - left),
-        );
+        // Limit output packet size by congestion window size, taking into
+        // account bytes consumed by ACK frames.
+        let cwnd_left =
+            cwnd_available.saturating_sub(left_before_packing_ack_frame - left);
+
+        left = cmp::min(left, cwnd_left);
 
         let mut challenge_data = None;
 
@@ -3608,7 +4943,7 @@ impl Connection {
                 }
             }
 
-            if let Some(key_update) = pkt_space.key_update.as_mut() {
+            if let Some(key_update) = crypto_ctx.key_update.as_mut() {
                 key_update.update_acked = true;
             }
         }
@@ -3627,6 +4962,20 @@ impl Connection {
                     break;
                 }
             }
+
+            // Create PATH_ABANDON and PATH_STATUS frames as needed.
+            while let Some(frame) = self.multipath.peek_frame() {
+                let frame = frame.clone();
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.multipath.on_frame_sent();
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                } else {
+                    break;
+                }
+            }
         }
 
         if pkt_type == packet::Type::Short && !is_closing && path.active() {
@@ -3646,6 +4995,22 @@ impl Connection {
                 }
             }
 
+            // Create NEW_TOKEN frames.
+            while let Some(token) = self.new_tokens_to_send.front() {
+                let frame = frame::Frame::NewToken {
+                    token: token.clone(),
+                };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.new_tokens_to_send.pop_front();
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                } else {
+                    break;
+                }
+            }
+
             // Create MAX_STREAMS_BIDI frame.
             if self.streams.should_update_max_streams_bidi() {
                 let frame = frame::Frame::MaxStreamsBidi {
@@ -3771,17 +5136,26 @@ impl Connection {
                 }
             }
 
-            // Create RESET_STREAM frames as needed.
-            for (stream_id, (error_code, final_size)) in self
+            // Create RESET_STREAM and RESET_STREAM_AT frames as needed.
+            for (stream_id, (error_code, final_size, reliable_size)) in self
                 .streams
                 .reset()
                 .map(|(&k, &v)| (k, v))
-                .collect::<Vec<(u64, (u64, u64))>>()
+                .collect::<Vec<(u64, (u64, u64, u64))>>()
             {
-                let frame = frame::Frame::ResetStream {
-                    stream_id,
-                    error_code,
-                    final_size,
+                let frame = if reliable_size > 0 {
+                    frame::Frame::ResetStreamAt {
+                        stream_id,
+                        error_code,
+                        final_size,
+                        reliable_size,
+                    }
+                } else {
+                    frame::Frame::ResetStream {
+                        stream_id,
+                        error_code,
+                        final_size,
+                    }
                 };
 
                 if push_frame_to_pkt!(b, frames, frame, left) {
@@ -3831,6 +5205,28 @@ impl Connection {
                     break;
                 }
             }
+
+            // Create ACK_FREQUENCY frame.
+            if let Some(frame) = self.ack_freq.ack_frequency_frame() {
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.ack_freq.on_ack_frequency_sent();
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
+
+            // Create IMMEDIATE_ACK frame.
+            if self.ack_freq.immediate_ack_pending() {
+                let frame = frame::Frame::ImmediateAck;
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.ack_freq.on_immediate_ack_sent();
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
         }
 
         // Create CONNECTION_CLOSE frame. Try to send this only on the active
@@ -3873,12 +5269,12 @@ impl Connection {
         }
 
         // Create CRYPTO frame.
-        if pkt_space.crypto_stream.is_flushable() &&
+        if crypto_ctx.crypto_stream.is_flushable() &&
             left > frame::MAX_CRYPTO_OVERHEAD &&
             !is_closing &&
             path.active()
         {
-            let crypto_off = pkt_space.crypto_stream.send.off_front();
+            let crypto_off = crypto_ctx.crypto_stream.send.off_front();
 
             // Encode the frame.
             //
@@ -3903,7 +5299,7 @@ impl Connection {
                     b.split_at(hdr_off + hdr_len)?;
 
                 // Write stream data into the packet buffer.
-                let (len, _) = pkt_space
+                let (len, _) = crypto_ctx
                     .crypto_stream
                     .send
                     .emit(&mut crypto_payload.as_mut()[..max_len])?;
@@ -3969,8 +5365,8 @@ impl Connection {
 
                     if (hdr_len + len) <= left {
                         // Front of the queue fits this packet, send it.
-                        match self.dgram_send_queue.pop() {
-                            Some(data) => {
+                        match self.dgram_send_queue.pop_with_id() {
+                            Some((data, id)) => {
                                 // Encode the frame.
                                 //
                                 // Instead of creating a `frame::Frame` object,
@@ -4010,8 +5406,10 @@ impl Connection {
                                 // Advance the packet buffer's offset.
                                 b.skip(hdr_len + len)?;
 
-                                let frame =
-                                    frame::Frame::DatagramHeader { length: len };
+                                let frame = frame::Frame::DatagramHeader {
+                                    length: len,
+                                    id,
+                                };
 
                                 if push_frame_to_pkt!(b, frames, frame, left) {
                                     ack_eliciting = true;
@@ -4024,7 +5422,11 @@ impl Connection {
                         };
                     } else if len > max_dgram_payload {
                         // This dgram frame will never fit. Let's purge it.
-                        self.dgram_send_queue.pop();
+                        if let Some((_, Some(id))) =
+                            self.dgram_send_queue.pop_with_id()
+                        {
+                            self.events.on_dgram_lost(id);
+                        }
                     } else {
                         break;
                     }
@@ -4032,6 +5434,18 @@ impl Connection {
             }
         }
 
+        // Record the time flushable streams can't send data because the
+        // congestion window is full.
+        if pkt_type == packet::Type::Short || pkt_type == packet::Type::ZeroRTT {
+            if cwnd_left <= frame::MAX_STREAM_OVERHEAD &&
+                self.streams.has_flushable()
+            {
+                self.congestion_blocked_since.get_or_insert(now);
+            } else if let Some(since) = self.congestion_blocked_since.take() {
+                self.congestion_blocked += now.saturating_duration_since(since);
+            }
+        }
+
         // Create a single STREAM frame for the first stream that is flushable.
         if (pkt_type == packet::Type::Short || pkt_type == packet::Type::ZeroRTT) &&
             left > frame::MAX_STREAM_OVERHEAD &&
@@ -4039,8 +5453,7 @@ impl Connection {
             path.active() &&
             !dgram_emitted
         {
-            while let Some(priority_key) = self.streams.peek_flushable() {
-                let stream_id = priority_key.id;
+            while let Some(stream_id) = self.streams.peek_flushable() {
                 let stream = match self.streams.get_mut(stream_id) {
                     // Avoid sending frames for streams that were already stopped.
                     //
@@ -4048,7 +5461,7 @@ impl Connection {
                     // flushed on the wire when a STOP_SENDING frame is received.
                     Some(v) if !v.send.is_stopped() => v,
                     _ => {
-                        self.streams.remove_flushable(&priority_key);
+                        self.streams.remove_flushable(stream_id);
                         continue;
                     },
                 };
@@ -4077,8 +5490,7 @@ impl Connection {
                 let max_len = match left.checked_sub(hdr_len) {
                     Some(v) => v,
                     None => {
-                        let priority_key = Arc::clone(&stream.priority_key);
-                        self.streams.remove_flushable(&priority_key);
+                        self.streams.remove_flushable(stream_id);
 
                         continue;
                     },
@@ -4123,15 +5535,13 @@ impl Connection {
                     has_data = true;
                 }
 
-                let priority_key = Arc::clone(&stream.priority_key);
+                stream.stats.on_data_sent(len, now);
+
                 // If the stream is no longer flushable, remove it from the queue
                 if !stream.is_flushable() {
-                    self.streams.remove_flushable(&priority_key);
-                } else if stream.incremental {
-                    // Shuffle the incremental stream to the back of the the
-                    // queue.
-                    self.streams.remove_flushable(&priority_key);
-                    self.streams.insert_flushable(&priority_key);
+                    self.streams.remove_flushable(stream_id);
+                } else {
+                    self.streams.on_flushable_sent(stream_id, len);
                 }
 
                 break;
@@ -4141,19 +5551,42 @@ impl Connection {
         // Alternate trying to send DATAGRAMs next time.
         self.emit_dgram = !dgram_emitted;
 
-        // If no other ack-eliciting frame is sent, include a PING frame
-        // - if PTO probe needed; OR
-        // - if we've sent too many non ack-eliciting packets without having
-        // sent an ACK eliciting one; OR
-        // - the application requested an ack-eliciting frame be sent.
-        if (ack_elicit_required || path.needs_ack_eliciting) &&
-            !ack_eliciting &&
-            left >= 1 &&
-            !is_closing
-        {
-            let frame = frame::Frame::Ping;
+        let mut is_pmtud_probe = false;
 
-            if push_frame_to_pkt!(b, frames, frame, left) {
+        // Create PMTUD probe, padded to fill the whole packet.
+        if let Some(size) = pmtud_probe {
+            if pkt_type == packet::Type::Short && !is_closing && left >= 1 {
+                let frame = frame::Frame::Ping {
+                    mtu_probe: Some(size),
+                };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    ack_eliciting = true;
+                    in_flight = true;
+                    is_pmtud_probe = true;
+                }
+
+                if left >= 1 {
+                    let frame = frame::Frame::Padding { len: left };
+
+                    push_frame_to_pkt!(b, frames, frame, left);
+                }
+            }
+        }
+
+        // If no other ack-eliciting frame is sent, include a PING frame
+        // - if PTO probe needed; OR
+        // - if we've sent too many non ack-eliciting packets without having
+        // sent an ACK eliciting one; OR
+        // - the application requested an ack-eliciting frame be sent.
+        if (ack_elicit_required || path.needs_ack_eliciting) &&
+            !ack_eliciting &&
+            left >= 1 &&
+            !is_closing
+        {
+            let frame = frame::Frame::Ping { mtu_probe: None };
+
+            if push_frame_to_pkt!(b, frames, frame, left) {
                 ack_eliciting = true;
                 in_flight = true;
             }
@@ -4272,13 +5705,14 @@ impl Connection {
             }
         });
 
-        let aead = match pkt_space.crypto_seal {
+        let aead = match crypto_ctx.crypto_seal {
             Some(ref v) => v,
             None => return Err(Error::InvalidState),
         };
 
         let written = packet::encrypt_pkt(
             &mut b,
+            space_id,
             pn,
             pn_len,
             payload_len,
@@ -4287,6 +5721,10 @@ impl Connection {
             aead,
         )?;
 
+        if pkt_type == packet::Type::Short {
+            self.seal_count += 1;
+        }
+
         let sent_pkt = recovery::Sent {
             pkt_num: pn,
             frames,
@@ -4303,16 +5741,22 @@ impl Connection {
             tx_in_flight: 0,
             lost: 0,
             has_data,
+            ecn_marked,
+            pmtud_probe: is_pmtud_probe,
         };
 
         if in_flight && is_app_limited {
             path.recovery.delivery_rate_update_app_limited(true);
         }
 
-        pkt_space.next_pkt_num += 1;
+        self.pkt_num_spaces.get_mut(epoch, space_id).next_pkt_num += 1;
+
+        if pkt_type == packet::Type::Short && self.key_phase_first_pn.is_none() {
+            self.key_phase_first_pn = Some((space_id, pn));
+        }
 
         let handshake_status = recovery::HandshakeStatus {
-            has_handshake_keys: self.pkt_num_spaces[packet::Epoch::Handshake]
+            has_handshake_keys: self.crypto_ctx[packet::Epoch::Handshake]
                 .has_keys(),
             peer_verified_address: self.peer_verified_initial_address,
             completed: self.handshake_completed,
@@ -4337,6 +5781,10 @@ impl Connection {
             path.add_challenge_sent(data, written, now);
         }
 
+        if is_pmtud_probe {
+            path.pmtud.on_probe_sent(written);
+        }
+
         self.sent_count += 1;
         self.sent_bytes += written as u64;
         path.sent_count += 1;
@@ -4438,6 +5886,100 @@ impl Connection {
     pub fn stream_recv(
         &mut self, stream_id: u64, out: &mut [u8],
     ) -> Result<(usize, bool)> {
+        self.stream_do_recv(stream_id, |stream| stream.recv.emit(out))
+    }
+
+    /// Returns an iterator over the contiguous data that can be read from a
+    /// stream, without copying or consuming it.
+    ///
+    /// The data stays buffered in the stream until it is consumed with
+    /// [`stream_consume()`], which also updates flow control. This lets
+    /// applications process stream data in place, instead of copying it to
+    /// an intermediate buffer with [`stream_recv()`].
+    ///
+    /// [`Done`] is returned if there is no data to read. If the stream was
+    /// reset by the peer, [`StreamReset`] is returned, and the stream's state
+    /// is only released once [`stream_consume()`] is called.
+    ///
+    /// [`stream_consume()`]: struct.Connection.html#method.stream_consume
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`StreamReset`]: enum.Error.html#variant.StreamReset
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let stream_id = 0;
+    /// # fn forward(_: &[u8]) -> usize { 0 }
+    /// let mut read = 0;
+    ///
+    /// for chunk in conn.stream_recv_chunks(stream_id)? {
+    ///     let written = forward(chunk);
+    ///     read += written;
+    ///
+    ///     if written < chunk.len() {
+    ///         break;
+    ///     }
+    /// }
+    ///
+    /// let (_, fin) = conn.stream_consume(stream_id, read)?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn stream_recv_chunks(&self, stream_id: u64) -> Result<StreamChunks<'_>> {
+        // We can't read on our own unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self
+            .streams
+            .get(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        if !stream.is_readable() {
+            return Err(Error::Done);
+        }
+
+        stream.recv.chunks()
+    }
+
+    /// Consumes up to `len` bytes of contiguous data from a stream, as if
+    /// they were read with [`stream_recv()`].
+    ///
+    /// This is meant to be used along with [`stream_recv_chunks()`], once the
+    /// application has processed the data in place.
+    ///
+    /// On success the amount of bytes consumed and a flag indicating the fin
+    /// state is returned as a tuple, or [`Done`] if there is no data to read.
+    ///
+    /// Consuming data from a stream may trigger queueing of control messages
+    /// (e.g. MAX_STREAM_DATA). [`send()`] should be called after consuming.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`stream_recv_chunks()`]: struct.Connection.html#method.stream_recv_chunks
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn stream_consume(
+        &mut self, stream_id: u64, len: usize,
+    ) -> Result<(usize, bool)> {
+        self.stream_do_recv(stream_id, |stream| stream.recv.consume(len))
+    }
+
+    /// Reads contiguous data from a stream using the given function, which
+    /// returns the amount of data read and the fin state.
+    fn stream_do_recv<F>(
+        &mut self, stream_id: u64, read: F,
+    ) -> Result<(usize, bool)>
+    where
+        F: FnOnce(&mut stream::Stream) -> Result<(usize, bool)>,
+    {
         // We can't read on our own unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             stream::is_local(stream_id, self.is_server)
@@ -4460,7 +6002,7 @@ impl Connection {
         #[cfg(feature = "qlog")]
         let offset = stream.recv.off_front();
 
-        let (read, fin) = match stream.recv.emit(out) {
+        let (read, fin) = match read(stream) {
             Ok(v) => v,
 
             Err(e) => {
@@ -4470,6 +6012,8 @@ impl Connection {
                 // anymore.
                 if stream.is_complete() {
                     self.streams.collect(stream_id, local);
+
+                    self.events.on_stream_collected(stream_id);
                 }
 
                 self.streams.remove_readable(&priority_key);
@@ -4493,6 +6037,8 @@ impl Connection {
 
         if complete {
             self.streams.collect(stream_id, local);
+
+            self.events.on_stream_collected(stream_id);
         }
 
         qlog_with_type!(QLOG_DATA_MV, self.qlog, q, {
@@ -4572,6 +6118,58 @@ impl Connection {
     pub fn stream_send(
         &mut self, stream_id: u64, buf: &[u8], fin: bool,
     ) -> Result<usize> {
+        self.stream_do_send(stream_id, buf.len(), fin, |stream, len, fin| {
+            stream.send.write(&buf[..len], fin)
+        })
+    }
+
+    /// Writes data to a stream without copying it.
+    ///
+    /// This behaves like [`stream_send()`], except that instead of copying
+    /// the data into the stream's send buffer, the stream keeps a reference
+    /// to `buf`, which is only released once the data it holds has been
+    /// acknowledged by the peer (or the stream is reset). This also covers
+    /// retransmissions, which are sent from the same buffer.
+    ///
+    /// As with [`stream_send()`], the number of written bytes returned can be
+    /// lower than the length of `buf`. The remaining data can be sent later
+    /// by slicing `buf`, which doesn't copy the data either.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let stream_id = 0;
+    /// let buf = quiche::SharedBuf::new(vec![0xba; 64 * 1024]);
+    ///
+    /// let written = conn.stream_send_zc(stream_id, &buf, true)?;
+    ///
+    /// // Keep the rest of the data around until the stream is writable again.
+    /// let rest = buf.slice(written..);
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn stream_send_zc(
+        &mut self, stream_id: u64, buf: &SharedBuf, fin: bool,
+    ) -> Result<usize> {
+        self.stream_do_send(stream_id, buf.len(), fin, |stream, len, fin| {
+            stream.send.write_shared(buf.slice(..len), fin)
+        })
+    }
+
+    /// Writes `buf_len` bytes to a stream using the given function, which
+    /// receives the number of bytes that can be written and the `fin` flag.
+    fn stream_do_send<F>(
+        &mut self, stream_id: u64, buf_len: usize, fin: bool, write: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&mut stream::Stream, usize, bool) -> Result<usize>,
+    {
         // We can't write on the peer's unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             !stream::is_local(stream_id, self.is_server)
@@ -4584,7 +6182,10 @@ impl Connection {
         //
         // Note that this is separate from "send capacity" as that also takes
         // congestion control into consideration.
-        if self.max_tx_data - self.tx_data < buf.len() as u64 {
+        let blocked_by_max_data =
+            self.max_tx_data - self.tx_data < buf_len as u64;
+
+        if blocked_by_max_data {
             self.blocked_limit = Some(self.max_tx_data);
         }
 
@@ -4593,6 +6194,12 @@ impl Connection {
         // Get existing stream or create a new one.
         let stream = self.get_or_create_stream(stream_id, true)?;
 
+        let now = time::Instant::now();
+
+        if blocked_by_max_data {
+            stream.stats.on_flow_control_blocked(now);
+        }
+
         #[cfg(feature = "qlog")]
         let offset = stream.send.off_back();
 
@@ -4607,7 +6214,7 @@ impl Connection {
         //
         // When the cap is zero, the method returns Ok(0) *only* when the passed
         // buffer is empty. We return Error::Done otherwise.
-        if cap == 0 && !buf.is_empty() {
+        if cap == 0 && buf_len != 0 {
             if was_writable {
                 // When `stream_writable_next()` returns a stream, the writable
                 // mark is removed, but because the stream is blocked by the
@@ -4621,13 +6228,13 @@ impl Connection {
             return Err(Error::Done);
         }
 
-        let (buf, fin, blocked_by_cap) = if cap < buf.len() {
-            (&buf[..cap], false, true)
+        let (buf_len, fin, blocked_by_cap) = if cap < buf_len {
+            (cap, false, true)
         } else {
-            (buf, fin, false)
+            (buf_len, fin, false)
         };
 
-        let sent = match stream.send.write(buf, fin) {
+        let sent = match write(stream, buf_len, fin) {
             Ok(v) => v,
 
             Err(e) => {
@@ -4643,11 +6250,13 @@ impl Connection {
 
         let writable = stream.is_writable();
 
-        let empty_fin = buf.is_empty() && fin;
+        let empty_fin = buf_len == 0 && fin;
 
-        if sent < buf.len() {
+        if sent < buf_len {
             let max_off = stream.send.max_off();
 
+            stream.stats.on_flow_control_blocked(now);
+
             if stream.send.blocked_at() != Some(max_off) {
                 stream.send.update_blocked_at(Some(max_off));
                 self.streams.insert_blocked(stream_id, max_off);
@@ -4694,11 +6303,10 @@ impl Connection {
                 raw: None,
             });
 
-            let now = time::Instant::now();
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
-        if sent == 0 && !buf.is_empty() {
+        if sent == 0 && buf_len != 0 {
             return Err(Error::Done);
         }
 
@@ -4832,7 +6440,7 @@ impl Connection {
                 // Update send capacity.
                 self.update_tx_cap();
 
-                self.streams.insert_reset(stream_id, err, final_size);
+                self.streams.insert_reset(stream_id, err, final_size, 0);
 
                 // Once shutdown, the stream is guaranteed to be non-writable.
                 self.streams.remove_writable(&priority_key);
@@ -4845,6 +6453,71 @@ impl Connection {
         Ok(())
     }
 
+    /// Resets the sending side of a stream, but keeps delivering its data up
+    /// to `reliable_size` to the peer.
+    ///
+    /// This works like [`stream_shutdown()`] in the [`Shutdown::Write`]
+    /// direction, except that only the data past `reliable_size` is dropped,
+    /// and a `RESET_STREAM_AT` frame is sent to the peer instead of a
+    /// `RESET_STREAM` one. The peer will be able to read the data up to
+    /// `reliable_size` before the reset is reported.
+    ///
+    /// This requires the [reliable stream reset] extension to be enabled with
+    /// [`enable_reset_stream_at()`] by both endpoints, otherwise
+    /// [`InvalidState`] is returned. [`FinalSize`] is returned if
+    /// `reliable_size` is larger than the amount of data written to the
+    /// stream.
+    ///
+    /// [`stream_shutdown()`]: struct.Connection.html#method.stream_shutdown
+    /// [`Shutdown::Write`]: enum.Shutdown.html#variant.Write
+    /// [reliable stream reset]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-reliable-stream-reset-06
+    /// [`enable_reset_stream_at()`]: struct.Config.html#method.enable_reset_stream_at
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`FinalSize`]: enum.Error.html#variant.FinalSize
+    pub fn stream_shutdown_at(
+        &mut self, stream_id: u64, err: u64, reliable_size: u64,
+    ) -> Result<()> {
+        if !self.local_transport_params.reset_stream_at ||
+            !self.peer_transport_params.reset_stream_at
+        {
+            return Err(Error::InvalidState);
+        }
+
+        // Dont' try to reset a remote unidirectional stream.
+        if !stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        // Get existing stream.
+        let stream = self.streams.get_mut(stream_id).ok_or(Error::Done)?;
+
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        let (final_size, unsent) = stream.send.shutdown_at(reliable_size)?;
+
+        // Claw back some flow control allowance from data that was buffered
+        // but won't be sent because of the reset.
+        self.tx_data = self.tx_data.saturating_sub(unsent);
+
+        self.tx_buffered = self.tx_buffered.saturating_sub(unsent as usize);
+
+        // Update send capacity.
+        self.update_tx_cap();
+
+        self.streams
+            .insert_reset(stream_id, err, final_size, reliable_size);
+
+        // Once shutdown, the stream is guaranteed to be non-writable.
+        self.streams.remove_writable(&priority_key);
+
+        self.reset_stream_local_count =
+            self.reset_stream_local_count.saturating_add(1);
+
+        Ok(())
+    }
+
     /// Returns the stream's send capacity in bytes.
     ///
     /// If the specified stream doesn't exist (including when it has already
@@ -5206,6 +6879,77 @@ impl Connection {
         Ok(())
     }
 
+    /// Requests the peer to change how often it sends ACKs.
+    ///
+    /// The peer is asked to send an ACK after receiving more than
+    /// `ack_eliciting_threshold` ack-eliciting packets, or once `max_ack_delay`
+    /// has passed since it received an unacknowledged ack-eliciting packet.
+    /// When `reordering_threshold` is not zero, the peer also sends an ACK as
+    /// soon as a packet is missing while `reordering_threshold` packets with
+    /// larger packet numbers were received.
+    ///
+    /// Reducing the number of ACKs can lower the CPU cost of bulk transfers on
+    /// high-bandwidth paths. The `max_ack_delay` value is raised to the
+    /// peer's `min_ack_delay` transport parameter if needed.
+    ///
+    /// [`InvalidState`] is returned if the peer did not advertise support for
+    /// the ACK frequency extension.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_ack_frequency(
+        &mut self, ack_eliciting_threshold: u64, max_ack_delay: time::Duration,
+        reordering_threshold: u64,
+    ) -> Result<()> {
+        let min_ack_delay = self
+            .peer_transport_params
+            .min_ack_delay
+            .ok_or(Error::InvalidState)?;
+
+        let max_ack_delay =
+            cmp::max(max_ack_delay.as_micros() as u64, min_ack_delay);
+
+        self.ack_freq.request(
+            ack_eliciting_threshold,
+            max_ack_delay,
+            reordering_threshold,
+        );
+
+        // Until the frame is acknowledged the peer might already be using the
+        // new delay, so only a larger value is taken into account for now.
+        let max_ack_delay = time::Duration::from_micros(max_ack_delay);
+
+        if max_ack_delay > self.recovery_config.max_ack_delay {
+            self.recovery_config.max_ack_delay = max_ack_delay;
+
+            for (_, p) in self.paths.iter_mut() {
+                p.recovery.max_ack_delay = max_ack_delay;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Requests the peer to send an ACK immediately.
+    ///
+    /// This sends an IMMEDIATE_ACK frame in the next packet, e.g. to get
+    /// timely feedback regardless of the ACK frequency requested with
+    /// [`set_ack_frequency()`].
+    ///
+    /// [`InvalidState`] is returned if the peer did not advertise support for
+    /// the ACK frequency extension.
+    ///
+    /// [`set_ack_frequency()`]: struct.Connection.html#method.set_ack_frequency
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn send_immediate_ack(&mut self) -> Result<()> {
+        if self.peer_transport_params.min_ack_delay.is_none() {
+            return Err(Error::InvalidState);
+        }
+
+        self.ack_freq.request_immediate_ack();
+
+        Ok(())
+    }
+
     /// Reads the first received DATAGRAM.
     ///
     /// On success the DATAGRAM's data is returned along with its size.
@@ -5358,47 +7102,132 @@ impl Connection {
     /// # Ok::<(), quiche::Error>(())
     /// ```
     pub fn dgram_send(&mut self, buf: &[u8]) -> Result<()> {
-        let max_payload_len = match self.dgram_max_writable_len() {
-            Some(v) => v,
+        self.dgram_check_len(buf.len())?;
 
-            None => return Err(Error::InvalidState),
-        };
+        self.dgram_queue(buf.to_vec(), None)
+    }
 
-        if buf.len() > max_payload_len {
-            return Err(Error::BufferTooShort);
-        }
+    /// Sends data in a DATAGRAM frame.
+    ///
+    /// This is the same as [`dgram_send()`] but takes a `Vec<u8>` instead of
+    /// a slice.
+    ///
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    pub fn dgram_send_vec(&mut self, buf: Vec<u8>) -> Result<()> {
+        self.dgram_check_len(buf.len())?;
 
-        self.dgram_send_queue.push(buf.to_vec())?;
+        self.dgram_queue(buf, None)
+    }
 
-        let active_path = self.paths.get_active_mut()?;
+    /// Sends data in a DATAGRAM frame, and tracks its delivery.
+    ///
+    /// This is the same as [`dgram_send()`], but on success an ID identifying
+    /// the DATAGRAM is returned. Once the packet carrying the DATAGRAM is
+    /// acknowledged by the peer, or declared lost, a [`DatagramAcked`] or
+    /// [`DatagramLost`] event with that ID is returned by [`poll_event()`], so
+    /// the application can decide whether to send the data again.
+    ///
+    /// IDs are assigned sequentially, starting from 0, to all the tracked
+    /// DATAGRAMs of the connection. DATAGRAMs removed from the queue with
+    /// [`dgram_purge_outgoing()`] don't raise any event.
+    ///
+    /// Note that a DATAGRAM declared lost might still have been received by
+    /// the peer, in which case it won't be reported as acknowledged later.
+    ///
+    /// At most four times the DATAGRAM send queue length configured with
+    /// [`enable_dgram()`] can be tracked until their delivery event is polled.
+    /// Past that [`Done`] is returned, so applications should poll events
+    /// regularly.
+    ///
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`enable_dgram()`]: struct.Config.html#method.enable_dgram
+    /// [`DatagramAcked`]: enum.ConnectionEvent.html#variant.DatagramAcked
+    /// [`DatagramLost`]: enum.ConnectionEvent.html#variant.DatagramLost
+    /// [`poll_event()`]: struct.Connection.html#method.poll_event
+    /// [`dgram_purge_outgoing()`]:
+    /// struct.Connection.html#method.dgram_purge_outgoing
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// let id = conn.dgram_send_tracked(b"hello")?;
+    ///
+    /// while let Some(ev) = conn.poll_event() {
+    ///     match ev {
+    ///         quiche::ConnectionEvent::DatagramLost(lost) if lost == id => {
+    ///             conn.dgram_send_tracked(b"hello")?;
+    ///         },
+    ///
+    ///         _ => (),
+    ///     }
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn dgram_send_tracked(&mut self, buf: &[u8]) -> Result<u64> {
+        self.dgram_check_len(buf.len())?;
 
-        if self.dgram_send_queue.byte_size() >
-            active_path.recovery.cwnd_available()
-        {
-            active_path.recovery.update_app_limited(false);
+        if !self.events.dgram_trackable() {
+            return Err(Error::Done);
         }
 
-        Ok(())
+        let id = self.next_dgram_id;
+
+        self.dgram_queue(buf.to_vec(), Some(id))?;
+
+        self.next_dgram_id += 1;
+        self.events.on_dgram_tracked();
+
+        Ok(id)
     }
 
-    /// Sends data in a DATAGRAM frame.
+    /// Sends data in a DATAGRAM frame, and tracks its delivery.
     ///
-    /// This is the same as [`dgram_send()`] but takes a `Vec<u8>` instead of
-    /// a slice.
+    /// This is the same as [`dgram_send_tracked()`] but takes a `Vec<u8>`
+    /// instead of a slice.
     ///
-    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
-    pub fn dgram_send_vec(&mut self, buf: Vec<u8>) -> Result<()> {
+    /// [`dgram_send_tracked()`]: struct.Connection.html#method.dgram_send_tracked
+    pub fn dgram_send_vec_tracked(&mut self, buf: Vec<u8>) -> Result<u64> {
+        self.dgram_check_len(buf.len())?;
+
+        if !self.events.dgram_trackable() {
+            return Err(Error::Done);
+        }
+
+        let id = self.next_dgram_id;
+
+        self.dgram_queue(buf, Some(id))?;
+
+        self.next_dgram_id += 1;
+        self.events.on_dgram_tracked();
+
+        Ok(id)
+    }
+
+    /// Checks that a DATAGRAM with a payload of the given length can be sent.
+    fn dgram_check_len(&self, len: usize) -> Result<()> {
         let max_payload_len = match self.dgram_max_writable_len() {
             Some(v) => v,
 
             None => return Err(Error::InvalidState),
         };
 
-        if buf.len() > max_payload_len {
+        if len > max_payload_len {
             return Err(Error::BufferTooShort);
         }
 
-        self.dgram_send_queue.push(buf)?;
+        Ok(())
+    }
+
+    /// Queues a DATAGRAM for sending, with the given tracking ID.
+    fn dgram_queue(&mut self, buf: Vec<u8>, id: Option<u64>) -> Result<()> {
+        self.dgram_send_queue.push_with_id(buf, id)?;
 
         let active_path = self.paths.get_active_mut()?;
 
@@ -5429,7 +7258,9 @@ impl Connection {
     /// ```
     #[inline]
     pub fn dgram_purge_outgoing<F: Fn(&[u8]) -> bool>(&mut self, f: F) {
-        self.dgram_send_queue.purge(f);
+        let purged = self.dgram_send_queue.purge(f);
+
+        self.events.on_dgram_purged(purged);
     }
 
     /// Returns the maximum DATAGRAM payload that can be sent.
@@ -5469,7 +7300,7 @@ impl Connection {
                 max_len = max_len.saturating_sub(packet::MAX_PKT_NUM_LEN);
                 // ...subtract the crypto overhead...
                 max_len = max_len.saturating_sub(
-                    self.pkt_num_spaces[packet::Epoch::Application]
+                    self.crypto_ctx[packet::Epoch::Application]
                         .crypto_overhead()?,
                 );
                 // ...clamp to what peer can support...
@@ -5515,13 +7346,19 @@ impl Connection {
                 .filter_map(|(_, p)| p.recovery.loss_detection_timer())
                 .min();
 
-            let key_update_timer = self.pkt_num_spaces
-                [packet::Epoch::Application]
+            let key_update_timer = self.crypto_ctx[packet::Epoch::Application]
                 .key_update
                 .as_ref()
-                .map(|key_update| key_update.timer);
+                .and_then(|key_update| key_update.timer);
+
+            let ack_timer = self
+                .pkt_num_spaces
+                .app_iter()
+                .filter_map(|(_, s)| s.ack_timer)
+                .min();
 
-            let timers = [self.idle_timer, path_timer, key_update_timer];
+            let timers =
+                [self.idle_timer, path_timer, key_update_timer, ack_timer];
 
             timers.iter().filter_map(|&x| x).min()
         }
@@ -5582,23 +7419,34 @@ impl Connection {
             }
         }
 
-        if let Some(timer) = self.pkt_num_spaces[packet::Epoch::Application]
+        if let Some(timer) = self.crypto_ctx[packet::Epoch::Application]
             .key_update
             .as_ref()
-            .map(|key_update| key_update.timer)
+            .and_then(|key_update| key_update.timer)
         {
             if timer <= now {
                 // Discard previous key once key update timer expired.
-                let _ = self.pkt_num_spaces[packet::Epoch::Application]
+                let _ = self.crypto_ctx[packet::Epoch::Application]
                     .key_update
                     .take();
             }
         }
 
-        let handshake_status = self.handshake_status();
-
-        for (_, p) in self.paths.iter_mut() {
-            if let Some(timer) = p.recovery.loss_detection_timer() {
+        for (_, pkt_space) in self.pkt_num_spaces.app_iter_mut() {
+            if let Some(timer) = pkt_space.ack_timer {
+                if timer <= now {
+                    trace!("{} delayed ACK timeout expired", self.trace_id);
+
+                    pkt_space.ack_timer = None;
+                    pkt_space.ack_elicited = true;
+                }
+            }
+        }
+
+        let handshake_status = self.handshake_status();
+
+        for (_, p) in self.paths.iter_mut() {
+            if let Some(timer) = p.recovery.loss_detection_timer() {
                 if timer <= now {
                     trace!("{} loss detection timeout expired", self.trace_id);
 
@@ -5617,6 +7465,12 @@ impl Connection {
                             q.add_event_data_with_instant(ev_data, now).ok();
                         }
                     });
+
+                    qlog_with_type!(QLOG_MTU_UPDATED, self.qlog, q, {
+                        if let Some(ev_data) = p.pmtud_maybe_qlog() {
+                            q.add_event_data_with_instant(ev_data, now).ok();
+                        }
+                    });
                 }
             }
         }
@@ -5773,6 +7627,94 @@ impl Connection {
         Ok(dcid_seq)
     }
 
+    /// Returns true if the multipath extension was negotiated with the peer.
+    ///
+    /// When multipath is in use, all the validated paths are active and can
+    /// be used to send packets, as selected by the configured
+    /// [`PathScheduler`].
+    ///
+    /// [`PathScheduler`]: enum.PathScheduler.html
+    #[inline]
+    pub fn is_multipath_enabled(&self) -> bool {
+        self.multipath.enabled()
+    }
+
+    /// Advertises the preferred status of the path between `local_addr` and
+    /// `peer_addr` to the peer, using a PATH_STATUS frame.
+    ///
+    /// Paths marked as [`Standby`] are only used to send packets when no other
+    /// path is available.
+    ///
+    /// If multipath is not in use, or if the path doesn't exist, an
+    /// [`InvalidState`] is returned.
+    ///
+    /// [`Standby`]: enum.PathStatus.html#variant.Standby
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_path_status(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        status: PathStatus,
+    ) -> Result<()> {
+        if !self.multipath.enabled() {
+            return Err(Error::InvalidState);
+        }
+
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        let path = self.paths.get_mut(pid)?;
+
+        if path.abandoned() {
+            return Err(Error::InvalidState);
+        }
+
+        let dcid_seq_num = path.active_scid_seq.ok_or(Error::InvalidState)?;
+
+        let seq_num = path.set_status(status);
+
+        self.multipath.queue_frame(frame::Frame::PathStatus {
+            dcid_seq_num,
+            seq_num,
+            status: status.to_wire(),
+        });
+
+        Ok(())
+    }
+
+    /// Abandons the path between `local_addr` and `peer_addr`, using a
+    /// PATH_ABANDON frame with the given error code and reason.
+    ///
+    /// The path is not used to send packets anymore, and a
+    /// [`PathEvent::Closed`] event is generated.
+    ///
+    /// If multipath is not in use, if the path doesn't exist or if it is the
+    /// last active path of the connection, an [`InvalidState`] is returned.
+    ///
+    /// [`PathEvent::Closed`]: enum.PathEvent.html#variant.Closed
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn abandon_path(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        error_code: u64, reason: &[u8],
+    ) -> Result<()> {
+        if !self.multipath.enabled() {
+            return Err(Error::InvalidState);
+        }
+
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        if self.paths.get(pid)?.abandoned() ||
+            !self.paths.iter().any(|(id, p)| id != pid && p.active())
+        {
+            return Err(Error::InvalidState);
+        }
+
+        self.close_path(pid, error_code, reason.to_vec(), time::Instant::now())
+    }
+
     /// Provides additional source Connection IDs that the peer can use to reach
     /// this host.
     ///
@@ -5844,6 +7786,126 @@ impl Connection {
         max_active_source_cids - self.active_source_cids()
     }
 
+    /// Validates the path towards the server's preferred address, and
+    /// migrates to it once validated.
+    fn migrate_to_preferred_address(&mut self) {
+        let (local_addr, peer_addr) = match self.preferred_address_path {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let pid = match self.paths.path_id_from_addrs(&(local_addr, peer_addr)) {
+            Some(pid) => pid,
+
+            None => {
+                match self.probe_path(local_addr, peer_addr) {
+                    Ok(_) => (),
+
+                    // Wait for a spare connection ID to probe the path with.
+                    Err(Error::OutOfIdentifiers) => (),
+
+                    // Keep using the current path.
+                    Err(_) => self.preferred_address_path = None,
+                }
+
+                return;
+            },
+        };
+
+        let path = match self.paths.get(pid) {
+            Ok(v) => v,
+
+            Err(_) => return,
+        };
+
+        if path.validated() {
+            self.preferred_address_path = None;
+
+            if let Err(e) = self.migrate(local_addr, peer_addr) {
+                trace!(
+                    "{} failed to migrate to preferred address {}: {:?}",
+                    self.trace_id,
+                    peer_addr,
+                    e
+                );
+            }
+        } else if path.validation_failed() {
+            self.preferred_address_path = None;
+        }
+    }
+
+    /// Returns the server's preferred address matching the address family of
+    /// the active path, if it differs from the active path's peer address.
+    fn peer_preferred_address(&self) -> Result<Option<SocketAddr>> {
+        let peer_addr = self.paths.get_active()?.peer_addr();
+
+        Ok(self
+            .peer_transport_params
+            .preferred_address
+            .as_ref()
+            .and_then(|pa| match peer_addr {
+                SocketAddr::V4(_) => pa.ipv4.map(SocketAddr::V4),
+
+                SocketAddr::V6(_) => pa.ipv6.map(SocketAddr::V6),
+            })
+            .filter(|addr| *addr != peer_addr))
+    }
+
+    /// Issues a spare source Connection ID for the client to probe the path
+    /// towards the server's preferred address with, so that migration doesn't
+    /// wait on the application providing one.
+    fn reserve_preferred_address_scid(&mut self) -> Result<()> {
+        if self.is_server ||
+            self.ids.zero_length_scid() ||
+            self.ids.available_scids() > 0 ||
+            self.source_cids_left() == 0 ||
+            self.peer_preferred_address()?.is_none()
+        {
+            return Ok(());
+        }
+
+        let (cid, reset_token) = match &self.cid_generator {
+            Some(generator) => {
+                let cid = generator.new_connection_id();
+                let reset_token = generator.stateless_reset_token(&cid);
+
+                (cid, reset_token)
+            },
+
+            None => {
+                let mut cid = vec![0; self.source_id().len()];
+                rand::rand_bytes(&mut cid);
+
+                let mut reset_token = [0; 16];
+                rand::rand_bytes(&mut reset_token);
+
+                (cid.into(), u128::from_be_bytes(reset_token))
+            },
+        };
+
+        // Failing to reserve a connection ID only delays the migration until
+        // the application provides one.
+        self.ids
+            .new_scid(cid, Some(reset_token), true, None, false)
+            .ok();
+
+        Ok(())
+    }
+
+    /// Issues new source Connection IDs using the configured generator, up
+    /// to the active connection ID limit.
+    fn replenish_source_cids(&mut self) {
+        if let Some(generator) = &self.cid_generator {
+            let limit = cmp::min(
+                self.peer_transport_params.active_conn_id_limit,
+                self.local_transport_params.active_conn_id_limit,
+            ) as usize;
+
+            self.ids.replenish_scids(generator.as_ref(), limit);
+        }
+    }
+
     /// Requests the retirement of the destination Connection ID used by the
     /// host to reach its peer.
     ///
@@ -5899,6 +7961,8 @@ impl Connection {
             path.active_dcid_seq = dcid_seq;
         }
 
+        self.drop_unused_app_spaces();
+
         Ok(())
     }
 
@@ -5932,6 +7996,68 @@ impl Connection {
         self.ids.pop_retired_scid()
     }
 
+    /// Returns the next event raised on the connection.
+    ///
+    /// On success it returns a [`ConnectionEvent`], or `None` when there are
+    /// no events to report. Please refer to [`ConnectionEvent`] for the
+    /// exhaustive event list. This is meant to be called repeatedly after
+    /// [`recv()`] until `None` is returned, instead of polling the state of
+    /// the connection and of its streams separately.
+    ///
+    /// Note that all events are edge-triggered, meaning that once reported they
+    /// will not be reported again, until the event is re-armed. For example a
+    /// stream is only reported as readable again once all its buffered data
+    /// was read, and new data is received.
+    ///
+    /// Path events and retired source connection IDs are shared with
+    /// [`path_event_next()`] and [`retired_scid_next()`], so they are only
+    /// reported by whichever method is called first.
+    ///
+    /// [`ConnectionEvent`]: enum.ConnectionEvent.html
+    /// [`recv()`]: struct.Connection.html#method.recv
+    /// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+    /// [`retired_scid_next()`]: struct.Connection.html#method.retired_scid_next
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// while let Some(ev) = conn.poll_event() {
+    ///     match ev {
+    ///         quiche::ConnectionEvent::StreamReadable(stream_id) => {
+    ///             while let Ok((read, fin)) = conn.stream_recv(stream_id, &mut buf)
+    ///             {
+    ///                 println!("Got {} bytes on stream {}", read, stream_id);
+    ///             }
+    ///         },
+    ///
+    ///         quiche::ConnectionEvent::PeerClosed(err) => {
+    ///             println!("Connection closed by peer: {:?}", err);
+    ///         },
+    ///
+    ///         _ => (),
+    ///     }
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn poll_event(&mut self) -> Option<ConnectionEvent> {
+        self.events
+            .pop_handshake()
+            .or_else(|| self.paths.pop_event().map(ConnectionEvent::Path))
+            .or_else(|| {
+                self.ids
+                    .pop_retired_scid()
+                    .map(ConnectionEvent::ScidRetired)
+            })
+            .or_else(|| self.events.pop())
+    }
+
     /// Returns the number of spare Destination Connection IDs, i.e.,
     /// Destination Connection IDs that are still unused.
     ///
@@ -6005,6 +8131,147 @@ impl Connection {
         }
     }
 
+    /// Initiates a key update.
+    ///
+    /// The keys used to protect 1-RTT packets are replaced with new ones,
+    /// which will be used for all packets sent from now on. Key updates are
+    /// also initiated automatically before too many packets are protected with
+    /// the same keys, as configured with [`set_key_update_threshold()`].
+    ///
+    /// Returns [`InvalidState`] if the handshake is not confirmed yet, or
+    /// [`Done`] if no packet protected with the current keys was acknowledged
+    /// by the peer, or the peer didn't start using them yet.
+    ///
+    /// [`set_key_update_threshold()`]: struct.Config.html#method.set_key_update_threshold
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn initiate_key_update(&mut self) -> Result<()> {
+        self.update_key(time::Instant::now())
+    }
+
+    /// Initiates a key update once the configured fraction of the
+    /// confidentiality limit is reached, or closes the connection if the limit
+    /// itself is reached.
+    fn check_confidentiality_limit(&mut self, now: time::Instant) -> Result<()> {
+        let limit = match &self.crypto_ctx[packet::Epoch::Application].crypto_seal
+        {
+            Some(seal) => seal.alg().confidentiality_limit(),
+
+            None => return Ok(()),
+        };
+
+        let threshold = (limit as f64 * self.key_update_threshold) as u64;
+
+        if self.seal_count >= threshold && self.handshake_confirmed {
+            match self.update_key(now) {
+                Ok(()) | Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        // The keys can't be used anymore, so close the connection. The
+        // CONNECTION_CLOSE frame is sent by the next call to `send()`.
+        if self.seal_count >= limit && self.local_error.is_none() {
+            trace!("{} confidentiality limit reached", self.trace_id);
+
+            self.local_error = Some(ConnectionError {
+                is_app: false,
+                error_code: Error::AeadLimitReached.to_wire(),
+                reason: Vec::new(),
+            });
+
+            return Err(Error::AeadLimitReached);
+        }
+
+        Ok(())
+    }
+
+    fn update_key(&mut self, now: time::Instant) -> Result<()> {
+        if !self.handshake_confirmed {
+            return Err(Error::InvalidState);
+        }
+
+        let crypto_ctx = &mut self.crypto_ctx[packet::Epoch::Application];
+
+        // Keys can't be updated again until a packet protected with the
+        // current keys is acknowledged, and one is received.
+        if !self.key_phase_acked ||
+            crypto_ctx.key_update.as_ref().map_or(false, |key_update| {
+                key_update.pn_on_update == u64::MAX
+            })
+        {
+            return Err(Error::Done);
+        }
+
+        let (open_next, seal_next) =
+            match (&crypto_ctx.crypto_open, &crypto_ctx.crypto_seal) {
+                (Some(open), Some(seal)) => (
+                    open.derive_next_packet_key()?,
+                    seal.derive_next_packet_key()?,
+                ),
+
+                _ => return Err(Error::InvalidState),
+            };
+
+        trace!("{} local key update", self.trace_id);
+
+        let _ = crypto_ctx.crypto_seal.replace(seal_next);
+
+        let open_prev = crypto_ctx.crypto_open.replace(open_next).unwrap();
+
+        crypto_ctx.key_update = Some(packet::KeyUpdate {
+            crypto_open: open_prev,
+
+            // The previous keys are used for all packets received until the
+            // peer starts using the new keys.
+            pn_on_update: u64::MAX,
+
+            update_acked: false,
+
+            // The previous keys are only discarded once the peer confirms the
+            // key update.
+            timer: None,
+        });
+
+        self.key_phase = !self.key_phase;
+
+        self.key_phase_first_pn = None;
+        self.key_phase_acked = false;
+
+        self.seal_count = 0;
+
+        qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+            let trigger = Some(
+                qlog::events::security::KeyUpdateOrRetiredTrigger::LocalUpdate,
+            );
+
+            let ev_data_client =
+                EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                    key_type: qlog::events::security::KeyType::Client1RttSecret,
+                    old: None,
+                    new: String::new(),
+                    generation: None,
+                    trigger: trigger.clone(),
+                });
+
+            q.add_event_data_with_instant(ev_data_client, now).ok();
+
+            let ev_data_server =
+                EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                    key_type: qlog::events::security::KeyType::Server1RttSecret,
+                    old: None,
+                    new: String::new(),
+                    generation: None,
+                    trigger,
+                });
+
+            q.add_event_data_with_instant(ev_data_server, now).ok();
+        });
+
+        Ok(())
+    }
+
     /// Closes the connection with the given error and reason.
     ///
     /// The `app` parameter specifies whether an application close should be
@@ -6284,6 +8551,10 @@ impl Connection {
             stopped_stream_count_local: self.stopped_stream_local_count,
             reset_stream_count_remote: self.reset_stream_remote_count,
             stopped_stream_count_remote: self.stopped_stream_remote_count,
+            congestion_blocked: self.congestion_blocked +
+                self.congestion_blocked_since
+                    .map(|since| since.elapsed())
+                    .unwrap_or_default(),
         }
     }
 
@@ -6303,13 +8574,45 @@ impl Connection {
         self.paths.iter().map(|(_, p)| p.stats())
     }
 
+    /// Collects and returns statistics about the given stream.
+    ///
+    /// Statistics are available for as long as the stream is known to the
+    /// connection, including after it is finished. The final statistics of a
+    /// stream that was collected are retained until they are returned once.
+    /// Otherwise [`InvalidStreamState`] is returned.
+    ///
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_stats(&mut self, stream_id: u64) -> Result<StreamStats> {
+        if let Some(stream) = self.streams.get(stream_id) {
+            return Ok(stream
+                .stats
+                .stats(stream.send.acked_bytes(), time::Instant::now()));
+        }
+
+        self.streams
+            .take_collected_stats(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))
+    }
+
     /// Returns whether or not this is a server-side connection.
     pub fn is_server(&self) -> bool {
         self.is_server
     }
 
     fn encode_transport_params(&mut self) -> Result<()> {
-        let mut raw_params = [0; 128];
+        let available_versions = if self.available_versions.is_empty() {
+            vec![self.version]
+        } else {
+            self.available_versions.clone()
+        };
+
+        self.local_transport_params.version_information =
+            Some(VersionInformation {
+                chosen_version: self.version,
+                available_versions,
+            });
+
+        let mut raw_params = [0; 512];
 
         let raw_params = TransportParams::encode(
             &self.local_transport_params,
@@ -6368,30 +8671,130 @@ impl Connection {
             }
         }
 
-        self.process_peer_transport_params(peer_params)?;
+        // Validate version_information. The client's chosen version must match
+        // the version of the packet that carried the ClientHello, even if the
+        // server then switched to a compatible version.
+        if self.is_server {
+            if let Some(version_info) = &peer_params.version_information {
+                let version = self.original_version.unwrap_or(self.version);
 
-        self.parsed_peer_transport_params = true;
+                if version_info.chosen_version != version {
+                    return Err(Error::VersionNegotiation);
+                }
+            }
+        }
 
-        Ok(())
-    }
+        if !self.is_server {
+            if let Some(version_info) = &peer_params.version_information {
+                if version_info.chosen_version != self.version {
+                    return Err(Error::VersionNegotiation);
+                }
+            }
 
-    fn process_peer_transport_params(
-        &mut self, peer_params: TransportParams,
-    ) -> Result<()> {
-        self.max_tx_data = peer_params.initial_max_data;
+            // After incompatible version negotiation, check that the client
+            // would have selected the same version given the server's
+            // available versions, as the Version Negotiation packet is not
+            // authenticated.
+            if self.did_version_negotiation {
+                let server_versions = match &peer_params.version_information {
+                    Some(v) => &v.available_versions,
 
-        // Update send capacity.
-        self.update_tx_cap();
+                    // version_information must be sent by the server.
+                    None => return Err(Error::VersionNegotiation),
+                };
 
-        self.streams
-            .update_peer_max_streams_bidi(peer_params.initial_max_streams_bidi);
-        self.streams
-            .update_peer_max_streams_uni(peer_params.initial_max_streams_uni);
+                // Without configured versions, the client picks among all the
+                // supported ones, preferring version 1.
+                let client_versions = if self.available_versions.is_empty() {
+                    &SUPPORTED_VERSIONS[..]
+                } else {
+                    &self.available_versions[..]
+                };
 
-        let max_ack_delay =
-            time::Duration::from_millis(peer_params.max_ack_delay);
+                let downgraded = client_versions
+                    .iter()
+                    .take_while(|&&v| v != self.version)
+                    .any(|v| server_versions.contains(v));
 
-        self.recovery_config.max_ack_delay = max_ack_delay;
+                if downgraded {
+                    return Err(Error::VersionNegotiation);
+                }
+            }
+        }
+
+        // Record the connection ID associated with the server's preferred
+        // address, which the server can't send when using zero-length
+        // connection IDs.
+        if let Some(pa) = &peer_params.preferred_address {
+            if self.is_server || self.ids.zero_length_dcid() {
+                return Err(Error::InvalidTransportParam);
+            }
+
+            self.ids.new_dcid(
+                pa.connection_id.clone(),
+                1,
+                pa.stateless_reset_token,
+                0,
+            )?;
+        }
+
+        qlog_with_type!(QLOG_VERSION_INFO, self.qlog, q, {
+            let to_qlog = |info: Option<&VersionInformation>| {
+                info.map(|info| {
+                    info.available_versions
+                        .iter()
+                        .map(|v| format!("{v:x?}"))
+                        .collect::<Vec<_>>()
+                })
+            };
+
+            let local_versions =
+                to_qlog(self.local_transport_params.version_information.as_ref());
+            let peer_versions = to_qlog(peer_params.version_information.as_ref());
+
+            let (server_versions, client_versions) = if self.is_server {
+                (local_versions, peer_versions)
+            } else {
+                (peer_versions, local_versions)
+            };
+
+            let ev_data = EventData::VersionInformation(
+                qlog::events::quic::VersionInformation {
+                    server_versions,
+                    client_versions,
+                    chosen_version: Some(format!("{:x?}", self.version)),
+                },
+            );
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        self.process_peer_transport_params(peer_params)?;
+
+        self.reserve_preferred_address_scid()?;
+
+        self.parsed_peer_transport_params = true;
+
+        Ok(())
+    }
+
+    fn process_peer_transport_params(
+        &mut self, peer_params: TransportParams,
+    ) -> Result<()> {
+        self.max_tx_data = peer_params.initial_max_data;
+
+        // Update send capacity.
+        self.update_tx_cap();
+
+        self.streams
+            .update_peer_max_streams_bidi(peer_params.initial_max_streams_bidi);
+        self.streams
+            .update_peer_max_streams_uni(peer_params.initial_max_streams_uni);
+
+        let max_ack_delay =
+            time::Duration::from_millis(peer_params.max_ack_delay);
+
+        self.recovery_config.max_ack_delay = max_ack_delay;
 
         let active_path = self.paths.get_active_mut()?;
 
@@ -6401,23 +8804,63 @@ impl Connection {
             .recovery
             .update_max_datagram_size(peer_params.max_udp_payload_size as usize);
 
+        active_path
+            .pmtud
+            .update_max(peer_params.max_udp_payload_size as usize);
+
         // Record the max_active_conn_id parameter advertised by the peer.
         self.ids
             .set_source_conn_id_limit(peer_params.active_conn_id_limit);
 
+        // Multipath requires both endpoints to use non-zero length connection
+        // IDs, as these identify the paths' packet number spaces.
+        let multipath = self.local_transport_params.enable_multipath &&
+            peer_params.enable_multipath &&
+            !self.ids.zero_length_scid() &&
+            !self.ids.zero_length_dcid();
+
+        self.multipath.set_enabled(multipath);
+        self.paths.set_multipath(multipath);
+
         self.peer_transport_params = peer_params;
 
         Ok(())
     }
 
+    /// Derives the Initial secrets for the version selected by the server
+    /// through compatible version negotiation.
+    fn derive_compatible_initial_secrets(&mut self) -> Result<()> {
+        // The new keys are derived from the same connection ID used for the
+        // original version.
+        let dcid = self
+            .local_transport_params
+            .retry_source_connection_id
+            .as_ref()
+            .or(self
+                .local_transport_params
+                .original_destination_connection_id
+                .as_ref())
+            .ok_or(Error::InvalidState)?;
+
+        let (aead_open, aead_seal) =
+            crypto::derive_initial_key_material(dcid, self.version, true)?;
+
+        self.crypto_ctx[packet::Epoch::Initial].crypto_open = Some(aead_open);
+        self.crypto_ctx[packet::Epoch::Initial].crypto_seal = Some(aead_seal);
+
+        Ok(())
+    }
+
     /// Continues the handshake.
     ///
     /// If the connection is already established, it does nothing.
     fn do_handshake(&mut self, now: time::Instant) -> Result<()> {
+        let version = self.version;
+
         let mut ex_data = tls::ExData {
             application_protos: &self.application_protos,
 
-            pkt_num_spaces: &mut self.pkt_num_spaces,
+            crypto_ctx: &mut self.crypto_ctx,
 
             session: &mut self.session,
 
@@ -6428,13 +8871,35 @@ impl Connection {
             trace_id: &self.trace_id,
 
             is_server: self.is_server,
+
+            version: &mut self.version,
+
+            available_versions: &self.available_versions,
+
+            local_transport_params: &mut self.local_transport_params,
         };
 
         if self.handshake_completed {
             return self.handshake.process_post_handshake(&mut ex_data);
         }
 
-        match self.handshake.do_handshake(&mut ex_data) {
+        let res = self.handshake.do_handshake(&mut ex_data);
+
+        // Keep track of whether the client attempted to send early data, to
+        // report it if the server ends up rejecting it.
+        if !self.is_server && self.handshake.is_in_early_data() {
+            self.early_data_attempted = true;
+        }
+
+        // The server might have switched to a compatible version while
+        // processing the client's first flight.
+        if self.version != version {
+            self.original_version = Some(version);
+
+            self.derive_compatible_initial_secrets()?;
+        }
+
+        match res {
             Ok(_) => (),
 
             Err(Error::Done) => {
@@ -6480,12 +8945,32 @@ impl Connection {
                 self.handshake_confirmed = true;
 
                 self.drop_epoch_state(packet::Epoch::Handshake, now);
+
+                // Provide the client with a token it can use to skip address
+                // validation on future connections.
+                if let Some(generator) = &self.new_token_generator {
+                    let token = generator(self.paths.get_active()?.peer_addr());
+
+                    if !token.is_empty() {
+                        self.new_tokens_to_send.push_back(token);
+                    }
+                }
             }
 
+            // Now that the peer's active connection ID limit is known, issue
+            // new source connection IDs if quiche is responsible for it.
+            self.replenish_source_cids();
+
             // Once the handshake is completed there's no point in processing
             // 0-RTT packets anymore, so clear the buffer now.
             self.undecryptable_pkts.clear();
 
+            self.events.on_handshake_completed(
+                self.handshake.is_resumed(),
+                self.early_data_attempted &&
+                    !self.handshake.is_early_data_accepted(),
+            );
+
             trace!("{} connection established: proto={:?} cipher={:?} curve={:?} sigalg={:?} resumed={} {:?}",
                    &self.trace_id,
                    std::str::from_utf8(self.application_proto()),
@@ -6525,8 +9010,7 @@ impl Connection {
                     // Downgrade the epoch to Initial as the remote peer might
                     // not be able to decrypt handshake packets yet.
                     packet::Epoch::Handshake
-                        if self.pkt_num_spaces[packet::Epoch::Initial]
-                            .has_keys() =>
+                        if self.crypto_ctx[packet::Epoch::Initial].has_keys() =>
                         return Ok(packet::Type::Initial),
 
                     _ => (),
@@ -6540,12 +9024,14 @@ impl Connection {
             packet::Epoch::Initial..=packet::Epoch::Application,
         ) {
             // Only send packets in a space when we have the send keys for it.
-            if self.pkt_num_spaces[epoch].crypto_seal.is_none() {
+            if self.crypto_ctx[epoch].crypto_seal.is_none() {
                 continue;
             }
 
             // We are ready to send data for this packet number space.
-            if self.pkt_num_spaces[epoch].ready() {
+            if self.crypto_ctx[epoch].data_available() ||
+                self.pkt_num_spaces.ready(epoch)
+            {
                 return Ok(packet::Type::from_epoch(epoch));
             }
 
@@ -6582,6 +9068,9 @@ impl Connection {
                 self.streams.has_stopped() ||
                 self.ids.has_new_scids() ||
                 self.ids.has_retire_dcids() ||
+                self.ack_freq.has_pending() ||
+                self.multipath.has_pending() ||
+                !self.new_tokens_to_send.is_empty() ||
                 send_path.needs_ack_eliciting ||
                 send_path.probing_required())
         {
@@ -6610,6 +9099,67 @@ impl Connection {
         )
     }
 
+    /// Processes an incoming RESET_STREAM or RESET_STREAM_AT frame.
+    fn process_reset_stream(
+        &mut self, stream_id: u64, error_code: u64, final_size: u64,
+        reliable_size: u64,
+    ) -> Result<()> {
+        // Peer can't send on our unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let max_rx_data_left = self.max_rx_data() - self.rx_data;
+
+        // Get existing stream or create a new one, but if the stream has
+        // already been closed and collected, ignore the frame.
+        //
+        // This can happen if e.g. an ACK frame is lost, and the peer
+        // retransmits another frame before it realizes that the stream is gone.
+        //
+        // Note that it makes it impossible to check if the frame is illegal,
+        // since we have no state, but since we ignore the frame, it should be
+        // fine.
+        let stream = match self.get_or_create_stream(stream_id, false) {
+            Ok(v) => v,
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        let was_readable = stream.is_readable();
+        let was_reset = stream.recv.is_reset();
+        let priority_key = Arc::clone(&stream.priority_key);
+
+        let max_off_delta =
+            stream
+                .recv
+                .reset_at(error_code, final_size, reliable_size)?
+                as u64;
+
+        if max_off_delta > max_rx_data_left {
+            return Err(Error::FlowControl);
+        }
+
+        if !was_readable && stream.is_readable() {
+            self.streams.insert_readable(&priority_key);
+        }
+
+        if !was_reset {
+            self.events.on_stream_reset(stream_id, error_code);
+        }
+
+        self.rx_data += max_off_delta;
+
+        self.reset_stream_remote_count =
+            self.reset_stream_remote_count.saturating_add(1);
+
+        Ok(())
+    }
+
     /// Processes an incoming frame.
     fn process_frame(
         &mut self, frame: frame::Frame, hdr: &packet::Header,
@@ -6620,98 +9170,72 @@ impl Connection {
         match frame {
             frame::Frame::Padding { .. } => (),
 
-            frame::Frame::Ping => (),
+            frame::Frame::Ping { .. } => (),
 
             frame::Frame::ACK {
-                ranges, ack_delay, ..
+                ranges,
+                ack_delay,
+                ecn_counts,
             } => {
-                let ack_delay = ack_delay
-                    .checked_mul(2_u64.pow(
-                        self.peer_transport_params.ack_delay_exponent as u32,
-                    ))
-                    .ok_or(Error::InvalidFrame)?;
+                // When multipath is in use, ACK frames only acknowledge
+                // packets of the initial packet number space.
+                let space_id = (self.multipath.enabled() &&
+                    epoch == packet::Epoch::Application)
+                    .then_some(0);
+
+                self.process_ack(
+                    &ranges,
+                    ack_delay,
+                    ecn_counts.as_ref(),
+                    epoch,
+                    space_id,
+                    now,
+                )?;
+            },
 
-                if epoch == packet::Epoch::Handshake ||
-                    (epoch == packet::Epoch::Application &&
-                        self.is_established())
-                {
-                    self.peer_verified_initial_address = true;
+            frame::Frame::AckMp {
+                space_id,
+                ranges,
+                ack_delay,
+                ecn_counts,
+            } => {
+                if !self.multipath.enabled() {
+                    return Err(Error::InvalidState);
                 }
 
-                let handshake_status = self.handshake_status();
-
-                let is_app_limited = self.delivery_rate_check_if_app_limited();
-
-                for (_, p) in self.paths.iter_mut() {
-                    if is_app_limited {
-                        p.recovery.delivery_rate_update_app_limited(true);
-                    }
-
-                    let (lost_packets, lost_bytes) = p.recovery.on_ack_received(
-                        &ranges,
-                        ack_delay,
-                        epoch,
-                        handshake_status,
-                        now,
-                        &self.trace_id,
-                        &mut self.newly_acked,
-                    )?;
-
-                    self.lost_count += lost_packets;
-                    self.lost_bytes += lost_bytes as u64;
-                }
+                self.process_ack(
+                    &ranges,
+                    ack_delay,
+                    ecn_counts.as_ref(),
+                    epoch,
+                    Some(space_id),
+                    now,
+                )?;
             },
 
             frame::Frame::ResetStream {
                 stream_id,
                 error_code,
                 final_size,
-            } => {
-                // Peer can't send on our unidirectional streams.
-                if !stream::is_bidi(stream_id) &&
-                    stream::is_local(stream_id, self.is_server)
-                {
-                    return Err(Error::InvalidStreamState(stream_id));
-                }
-
-                let max_rx_data_left = self.max_rx_data() - self.rx_data;
-
-                // Get existing stream or create a new one, but if the stream
-                // has already been closed and collected, ignore the frame.
-                //
-                // This can happen if e.g. an ACK frame is lost, and the peer
-                // retransmits another frame before it realizes that the stream
-                // is gone.
-                //
-                // Note that it makes it impossible to check if the frame is
-                // illegal, since we have no state, but since we ignore the
-                // frame, it should be fine.
-                let stream = match self.get_or_create_stream(stream_id, false) {
-                    Ok(v) => v,
-
-                    Err(Error::Done) => return Ok(()),
-
-                    Err(e) => return Err(e),
-                };
+            } =>
+                self.process_reset_stream(stream_id, error_code, final_size, 0)?,
 
-                let was_readable = stream.is_readable();
-                let priority_key = Arc::clone(&stream.priority_key);
-
-                let max_off_delta =
-                    stream.recv.reset(error_code, final_size)? as u64;
-
-                if max_off_delta > max_rx_data_left {
-                    return Err(Error::FlowControl);
-                }
-
-                if !was_readable && stream.is_readable() {
-                    self.streams.insert_readable(&priority_key);
+            frame::Frame::ResetStreamAt {
+                stream_id,
+                error_code,
+                final_size,
+                reliable_size,
+            } => {
+                if !self.local_transport_params.reset_stream_at {
+                    return Err(Error::InvalidFrame);
                 }
 
-                self.rx_data += max_off_delta;
-
-                self.reset_stream_remote_count =
-                    self.reset_stream_remote_count.saturating_add(1);
+                self.process_reset_stream(
+                    stream_id,
+                    error_code,
+                    final_size,
+                    reliable_size,
+                )?;
             },
 
             frame::Frame::StopSending {
@@ -6760,12 +9284,15 @@ impl Connection {
                     self.tx_buffered =
                         self.tx_buffered.saturating_sub(unsent as usize);
 
-                    self.streams.insert_reset(stream_id, error_code, final_size);
+                    self.streams
+                        .insert_reset(stream_id, error_code, final_size, 0);
 
                     if !was_writable {
                         self.streams.insert_writable(&priority_key);
                     }
 
+                    self.events.on_stream_stopped(stream_id, error_code);
+
                     self.stopped_stream_remote_count =
                         self.stopped_stream_remote_count.saturating_add(1);
                     self.reset_stream_local_count =
@@ -6775,7 +9302,7 @@ impl Connection {
 
             frame::Frame::Crypto { data } => {
                 // Push the data to the stream so it can be re-ordered.
-                self.pkt_num_spaces[epoch].crypto_stream.recv.write(data)?;
+                self.crypto_ctx[epoch].crypto_stream.recv.write(data)?;
 
                 // Feed crypto data to the TLS state, if there's data
                 // available at the expected offset.
@@ -6783,7 +9310,7 @@ impl Connection {
 
                 let level = crypto::Level::from_epoch(epoch);
 
-                let stream = &mut self.pkt_num_spaces[epoch].crypto_stream;
+                let stream = &mut self.crypto_ctx[epoch].crypto_stream;
 
                 while let Ok((read, _)) = stream.recv.emit(&mut crypto_buf) {
                     let recv_buf = &crypto_buf[..read];
@@ -6795,8 +9322,18 @@ impl Connection {
 
             frame::Frame::CryptoHeader { .. } => unreachable!(),
 
-            // TODO: implement stateless retry
-            frame::Frame::NewToken { .. } => (),
+            frame::Frame::NewToken { token } => {
+                // Only servers can send NEW_TOKEN frames.
+                if self.is_server {
+                    return Err(Error::InvalidPacket);
+                }
+
+                if self.new_tokens_recv.len() == MAX_NEW_TOKENS {
+                    self.new_tokens_recv.pop_front();
+                }
+
+                self.new_tokens_recv.push_back(token);
+            },
 
             frame::Frame::Stream { stream_id, data } => {
                 // Peer can't send on our unidirectional streams.
@@ -6839,10 +9376,14 @@ impl Connection {
 
                 let was_draining = stream.recv.is_draining();
 
+                stream.stats.on_data_received(data.len());
+
                 stream.recv.write(data)?;
 
                 if !was_readable && stream.is_readable() {
                     self.streams.insert_readable(&priority_key);
+
+                    self.events.on_stream_readable(stream_id);
                 }
 
                 self.rx_data += max_off_delta;
@@ -6863,7 +9404,21 @@ impl Connection {
             frame::Frame::StreamHeader { .. } => unreachable!(),
 
             frame::Frame::MaxData { max } => {
+                let was_blocked = self.max_tx_data <= self.tx_data;
+
                 self.max_tx_data = cmp::max(self.max_tx_data, max);
+
+                // Streams that were only blocked by the connection's flow
+                // control can be written to again.
+                if was_blocked && self.max_tx_data > self.tx_data {
+                    for stream_id in self.streams.writable() {
+                        if let Some(stream) = self.streams.get_mut(stream_id) {
+                            stream.stats.on_flow_control_unblocked(now);
+                        }
+
+                        self.events.on_stream_writable(stream_id);
+                    }
+                }
             },
 
             frame::Frame::MaxStreamData { stream_id, max } => {
@@ -6893,11 +9448,16 @@ impl Connection {
                 };
 
                 let was_flushable = stream.is_flushable();
+                let was_writable = stream.is_writable();
 
                 stream.send.update_max_data(max);
 
                 let writable = stream.is_writable();
 
+                if writable && !was_writable {
+                    stream.stats.on_flow_control_unblocked(now);
+                }
+
                 let priority_key = Arc::clone(&stream.priority_key);
 
                 // If the stream is now flushable push it to the flushable queue,
@@ -6910,6 +9470,10 @@ impl Connection {
                 if writable {
                     self.streams.insert_writable(&priority_key);
                 }
+
+                if writable && !was_writable {
+                    self.events.on_stream_writable(stream_id);
+                }
             },
 
             frame::Frame::MaxStreamsBidi { max } => {
@@ -6988,6 +9552,8 @@ impl Connection {
                         );
                     }
                 }
+
+                self.drop_unused_app_spaces();
             },
 
             frame::Frame::RetireConnectionId { seq_num } => {
@@ -7006,6 +9572,10 @@ impl Connection {
                         path.active_scid_seq = None;
                     }
                 }
+
+                self.replenish_source_cids();
+
+                self.drop_unused_app_spaces();
             },
 
             frame::Frame::PathChallenge { data } => {
@@ -7027,6 +9597,10 @@ impl Connection {
                     reason,
                 });
 
+                if let Some(err) = &self.peer_error {
+                    self.events.on_peer_closed(err);
+                }
+
                 let path = self.paths.get_active()?;
                 self.draining_timer = Some(now + (path.recovery.pto() * 3));
             },
@@ -7038,6 +9612,10 @@ impl Connection {
                     reason,
                 });
 
+                if let Some(err) = &self.peer_error {
+                    self.events.on_peer_closed(err);
+                }
+
                 let path = self.paths.get_active()?;
                 self.draining_timer = Some(now + (path.recovery.pto() * 3));
             },
@@ -7053,6 +9631,14 @@ impl Connection {
 
                 // Once the handshake is confirmed, we can drop Handshake keys.
                 self.drop_epoch_state(packet::Epoch::Handshake, now);
+
+                // Start migrating to the server's preferred address, if any,
+                // as the handshake is now confirmed.
+                let local_addr = self.paths.get_active()?.local_addr();
+
+                self.preferred_address_path = self
+                    .peer_preferred_address()?
+                    .map(|addr| (local_addr, addr));
             },
 
             frame::Frame::Datagram { data } => {
@@ -7070,47 +9656,271 @@ impl Connection {
                 }
 
                 self.dgram_recv_queue.push(data)?;
+
+                self.events.on_dgram_received();
             },
 
             frame::Frame::DatagramHeader { .. } => unreachable!(),
-        }
-
-        Ok(())
-    }
 
-    /// Drops the keys and recovery state for the given epoch.
-    fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
-        if self.pkt_num_spaces[epoch].crypto_open.is_none() {
-            return;
-        }
+            frame::Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                // Close the connection if the ACK frequency extension was not
+                // advertised, or if the requested delay is too low.
+                let min_ack_delay = self
+                    .local_transport_params
+                    .min_ack_delay
+                    .ok_or(Error::InvalidFrame)?;
 
-        self.pkt_num_spaces[epoch].crypto_open = None;
-        self.pkt_num_spaces[epoch].crypto_seal = None;
-        self.pkt_num_spaces[epoch].clear();
+                if request_max_ack_delay < min_ack_delay {
+                    return Err(Error::InvalidFrame);
+                }
 
-        let handshake_status = self.handshake_status();
-        for (_, p) in self.paths.iter_mut() {
-            p.recovery
-                .on_pkt_num_space_discarded(epoch, handshake_status, now);
-        }
+                self.ack_freq.on_ack_frequency_received(
+                    seq_num,
+                    ack_eliciting_threshold,
+                    request_max_ack_delay,
+                    reordering_threshold,
+                );
+            },
 
-        trace!("{} dropped epoch {} state", self.trace_id, epoch);
-    }
+            frame::Frame::ImmediateAck => {
+                if self.local_transport_params.min_ack_delay.is_none() {
+                    return Err(Error::InvalidFrame);
+                }
 
-    /// Returns true if the connection-level flow control needs to be updated.
-    ///
-    /// This happens when the new max data limit is at least double the amount
-    /// of data that can be received before blocking.
-    fn should_update_max_data(&self) -> bool {
-        self.flow_control.should_update_max_data()
-    }
+                if self.multipath.enabled() && epoch == packet::Epoch::Application
+                {
+                    // Acknowledge all the paths' packet number spaces.
+                    for (_, pkt_space) in self.pkt_num_spaces.app_iter_mut() {
+                        if pkt_space.recv_pkt_need_ack.last().is_some() {
+                            pkt_space.ack_elicited = true;
+                        }
+                    }
+                }
 
-    /// Returns the connection level flow control limit.
-    fn max_rx_data(&self) -> u64 {
-        self.flow_control.max_data()
-    }
+                self.pkt_num_spaces[epoch].ack_elicited = true;
+            },
 
-    /// Returns true if the HANDSHAKE_DONE frame needs to be sent.
+            frame::Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                ..
+            } => {
+                if !self.multipath.enabled() {
+                    return Err(Error::InvalidState);
+                }
+
+                // The sequence number is the one of the connection ID the
+                // peer uses to send packets to us, which is our destination
+                // connection ID on that path.
+                let pid = match self
+                    .paths
+                    .iter()
+                    .find(|(_, p)| p.active_dcid_seq == Some(dcid_seq_num))
+                {
+                    Some((pid, _)) => pid,
+
+                    None => return Ok(()),
+                };
+
+                trace!(
+                    "{} peer abandoned path {} error_code={}",
+                    self.trace_id,
+                    pid,
+                    error_code
+                );
+
+                self.close_path(pid, 0, Vec::new(), now)?;
+            },
+
+            frame::Frame::PathStatus {
+                dcid_seq_num,
+                seq_num,
+                status,
+            } => {
+                if !self.multipath.enabled() {
+                    return Err(Error::InvalidState);
+                }
+
+                let status = multipath::PathStatus::from_wire(status)?;
+
+                if let Some((_, p)) = self
+                    .paths
+                    .iter_mut()
+                    .find(|(_, p)| p.active_dcid_seq == Some(dcid_seq_num))
+                {
+                    p.on_status_received(seq_num, status);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Abandons the path with identifier `pid` and notifies the peer with a
+    /// PATH_ABANDON frame, unless the path was already abandoned.
+    fn close_path(
+        &mut self, pid: usize, error_code: u64, reason: Vec<u8>,
+        now: time::Instant,
+    ) -> Result<()> {
+        let path = self.paths.get_mut(pid)?;
+
+        if path.abandoned() {
+            return Ok(());
+        }
+
+        path.abandon();
+
+        // Packets in flight on the path won't be acknowledged anymore.
+        let (lost_packets, lost_bytes) = path.recovery.on_path_change(
+            packet::Epoch::Application,
+            now,
+            &self.trace_id,
+        );
+
+        self.lost_count += lost_packets;
+        self.lost_bytes += lost_bytes as u64;
+
+        let local_addr = path.local_addr();
+        let peer_addr = path.peer_addr();
+
+        if let Some(dcid_seq_num) = path.active_scid_seq {
+            self.multipath.queue_frame(frame::Frame::PathAbandon {
+                dcid_seq_num,
+                error_code,
+                reason,
+            });
+        }
+
+        self.paths
+            .notify_event(path::PathEvent::Closed(local_addr, peer_addr));
+
+        self.drop_unused_app_spaces();
+
+        Ok(())
+    }
+
+    /// Drops the Application Data packet number spaces that are not used by
+    /// any path anymore, once there are no ACKs left to send for them.
+    fn drop_unused_app_spaces(&mut self) {
+        let paths = &self.paths;
+
+        self.pkt_num_spaces.retain_app(|space_id, pkt_space| {
+            pkt_space.ready() ||
+                paths.iter().any(|(_, p)| {
+                    !p.abandoned() &&
+                        (p.active_scid_seq == Some(space_id) ||
+                            p.active_dcid_seq == Some(space_id))
+                })
+        });
+    }
+
+    /// Processes an ACK or ACK_MP frame received from the peer.
+    ///
+    /// If `space_id` is set, only the path that uses that packet number space
+    /// is considered, otherwise the acknowledged packets can belong to any
+    /// path.
+    fn process_ack(
+        &mut self, ranges: &ranges::RangeSet, ack_delay: u64,
+        ecn_counts: Option<&frame::EcnCounts>, epoch: packet::Epoch,
+        space_id: Option<u64>, now: time::Instant,
+    ) -> Result<()> {
+        let ack_delay = ack_delay
+            .checked_mul(
+                2_u64.pow(self.peer_transport_params.ack_delay_exponent as u32),
+            )
+            .ok_or(Error::InvalidFrame)?;
+
+        if epoch == packet::Epoch::Handshake ||
+            (epoch == packet::Epoch::Application && self.is_established())
+        {
+            self.peer_verified_initial_address = true;
+        }
+
+        let handshake_status = self.handshake_status();
+
+        let is_app_limited = self.delivery_rate_check_if_app_limited();
+
+        // When multipath is in use, only the path using the acknowledged
+        // packet number space is affected.
+        let paths = self.paths.iter_mut().filter(|(_, p)| {
+            space_id.map_or(true, |id| p.active_dcid_seq == Some(id))
+        });
+
+        for (_, p) in paths {
+            if is_app_limited {
+                p.recovery.delivery_rate_update_app_limited(true);
+            }
+
+            let (lost_packets, lost_bytes) = p.recovery.on_ack_received(
+                ranges,
+                ack_delay,
+                ecn_counts,
+                epoch,
+                handshake_status,
+                now,
+                &self.trace_id,
+                &mut self.newly_acked,
+            )?;
+
+            p.pmtud_check_black_hole(now);
+
+            self.lost_count += lost_packets;
+            self.lost_bytes += lost_bytes as u64;
+        }
+
+        // Acknowledging any packet sent after the first one protected with the
+        // current keys lets them be updated again.
+        if let Some((first_space_id, first_pn)) = self.key_phase_first_pn {
+            if epoch == packet::Epoch::Application &&
+                space_id.unwrap_or(0) == first_space_id &&
+                ranges.last().map_or(false, |largest| largest >= first_pn)
+            {
+                self.key_phase_acked = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the keys and recovery state for the given epoch.
+    fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
+        if self.crypto_ctx[epoch].crypto_open.is_none() {
+            return;
+        }
+
+        self.crypto_ctx[epoch].crypto_open = None;
+        self.crypto_ctx[epoch].crypto_seal = None;
+        self.crypto_ctx[epoch].clear();
+        self.pkt_num_spaces[epoch].clear();
+
+        let handshake_status = self.handshake_status();
+        for (_, p) in self.paths.iter_mut() {
+            p.recovery
+                .on_pkt_num_space_discarded(epoch, handshake_status, now);
+        }
+
+        trace!("{} dropped epoch {} state", self.trace_id, epoch);
+    }
+
+    /// Returns true if the connection-level flow control needs to be updated.
+    ///
+    /// This happens when the new max data limit is at least double the amount
+    /// of data that can be received before blocking.
+    fn should_update_max_data(&self) -> bool {
+        self.flow_control.should_update_max_data()
+    }
+
+    /// Returns the connection level flow control limit.
+    fn max_rx_data(&self) -> u64 {
+        self.flow_control.max_data()
+    }
+
+    /// Returns true if the HANDSHAKE_DONE frame needs to be sent.
     fn should_send_handshake_done(&self) -> bool {
         self.is_established() && !self.handshake_done_sent && self.is_server
     }
@@ -7155,7 +9965,7 @@ impl Connection {
     /// Returns the connection's handshake status for use in loss recovery.
     fn handshake_status(&self) -> recovery::HandshakeStatus {
         recovery::HandshakeStatus {
-            has_handshake_keys: self.pkt_num_spaces[packet::Epoch::Handshake]
+            has_handshake_keys: self.crypto_ctx[packet::Epoch::Handshake]
                 .has_keys(),
 
             peer_verified_address: self.peer_verified_initial_address,
@@ -7304,6 +10114,7 @@ impl Connection {
         let mut path =
             path::Path::new(info.to, info.from, &self.recovery_config, false);
 
+        path.enable_spin_bit(self.spin_bit);
         path.max_send_bytes = buf_len * MAX_AMPLIFICATION_FACTOR;
         path.active_scid_seq = Some(in_scid_seq);
 
@@ -7341,6 +10152,40 @@ impl Connection {
             }
         }
 
+        // When multipath is in use, any active path can be used.
+        if self.multipath.enabled() {
+            let mut active = self
+                .paths
+                .iter()
+                .filter(|(_, p)| from.is_none() || Some(p.local_addr()) == from)
+                .filter(|(_, p)| to.is_none() || Some(p.peer_addr()) == to)
+                .filter(|(_, p)| p.active())
+                .peekable();
+
+            if active.peek().is_none() {
+                if from.is_some() || to.is_some() {
+                    return Err(Error::Done);
+                }
+
+                return Err(Error::InvalidState);
+            }
+
+            // Paths that need to send PTO probes are served first.
+            let (probes, others): (Vec<_>, Vec<_>) =
+                active.partition(|(_, p)| {
+                    p.recovery.loss_probes[packet::Epoch::Application] > 0
+                });
+
+            if let Some((pid, _)) = probes.first() {
+                return Ok(*pid);
+            }
+
+            return self
+                .multipath
+                .select_path(others.into_iter())
+                .ok_or(Error::Done);
+        }
+
         if let Some((pid, p)) = self.paths.get_active_with_pid() {
             if from.is_some() && Some(p.local_addr()) != from {
                 return Err(Error::Done);
@@ -7426,6 +10271,7 @@ impl Connection {
 
         let mut path =
             path::Path::new(local_addr, peer_addr, &self.recovery_config, false);
+        path.enable_spin_bit(self.spin_bit);
         path.active_dcid_seq = Some(dcid_seq);
 
         let pid = self
@@ -7532,6 +10378,10 @@ pub struct Stats {
 
     /// The number of streams stopped by remote.
     pub stopped_stream_count_remote: u64,
+
+    /// The time stream data was ready to be sent, but couldn't be due to the
+    /// congestion window.
+    pub congestion_blocked: time::Duration,
 }
 
 impl std::fmt::Debug for Stats {
@@ -7592,7 +10442,48 @@ pub struct TransportParams {
     pub retry_source_connection_id: Option<ConnectionId<'static>>,
     /// DATAGRAM frame extension parameter, if any.
     pub max_datagram_frame_size: Option<u64>,
-    // pub preferred_address: ...,
+    /// Version Information parameter, if any.
+    pub version_information: Option<VersionInformation>,
+    /// ACK Frequency extension parameter, in microseconds, if any.
+    pub min_ack_delay: Option<u64>,
+    /// Whether the fixed bit of packets can be greased.
+    pub grease_quic_bit: bool,
+    /// Whether the multipath extension is supported.
+    pub enable_multipath: bool,
+    /// Whether the reliable stream reset extension is supported.
+    pub reset_stream_at: bool,
+    /// Preferred Address parameter, if any.
+    pub preferred_address: Option<PreferredAddress>,
+}
+
+/// Version Information transport parameter.
+///
+/// See [RFC 9368].
+///
+/// [RFC 9368]: https://www.rfc-editor.org/rfc/rfc9368.html#section-3
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInformation {
+    /// The version used in the packet that carries the parameter.
+    pub chosen_version: u32,
+    /// The versions supported by the endpoint, in order of preference.
+    pub available_versions: Vec<u32>,
+}
+
+/// Preferred Address transport parameter.
+///
+/// See [RFC 9000].
+///
+/// [RFC 9000]: https://www.rfc-editor.org/rfc/rfc9000.html#section-18.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreferredAddress {
+    /// The server's IPv4 address and port, if any.
+    pub ipv4: Option<SocketAddrV4>,
+    /// The server's IPv6 address and port, if any.
+    pub ipv6: Option<SocketAddrV6>,
+    /// The connection ID to use when sending to the preferred address.
+    pub connection_id: ConnectionId<'static>,
+    /// The stateless reset token associated with the connection ID.
+    pub stateless_reset_token: u128,
 }
 
 impl Default for TransportParams {
@@ -7615,6 +10506,12 @@ impl Default for TransportParams {
             initial_source_connection_id: None,
             retry_source_connection_id: None,
             max_datagram_frame_size: None,
+            version_information: None,
+            min_ack_delay: None,
+            grease_quic_bit: false,
+            enable_multipath: false,
+            reset_stream_at: false,
+            preferred_address: None,
         }
     }
 }
@@ -7736,7 +10633,54 @@ impl TransportParams {
                         return Err(Error::InvalidTransportParam);
                     }
 
-                    // TODO: decode preferred_address
+                    let ip_v4: [u8; 4] = val
+                        .get_bytes(4)?
+                        .buf()
+                        .try_into()
+                        .map_err(|_| Error::BufferTooShort)?;
+                    let port_v4 = val.get_u16()?;
+
+                    let ip_v6: [u8; 16] = val
+                        .get_bytes(16)?
+                        .buf()
+                        .try_into()
+                        .map_err(|_| Error::BufferTooShort)?;
+                    let port_v6 = val.get_u16()?;
+
+                    // The server must not use a zero-length connection ID
+                    // for its preferred address.
+                    let cid_len = val.get_u8()? as usize;
+
+                    if cid_len == 0 || cid_len > MAX_CONN_ID_LEN {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let connection_id = val.get_bytes(cid_len)?.to_vec().into();
+
+                    let stateless_reset_token = u128::from_be_bytes(
+                        val.get_bytes(16)?
+                            .to_vec()
+                            .try_into()
+                            .map_err(|_| Error::BufferTooShort)?,
+                    );
+
+                    // An all-zero address and port means the address family
+                    // is not provided.
+                    let ipv4 = Some(SocketAddrV4::new(ip_v4.into(), port_v4))
+                        .filter(|a| !a.ip().is_unspecified() || a.port() != 0);
+
+                    let ipv6 =
+                        Some(SocketAddrV6::new(ip_v6.into(), port_v6, 0, 0))
+                            .filter(|a| {
+                                !a.ip().is_unspecified() || a.port() != 0
+                            });
+
+                    tp.preferred_address = Some(PreferredAddress {
+                        ipv4,
+                        ipv6,
+                        connection_id,
+                        stateless_reset_token,
+                    });
                 },
 
                 0x000e => {
@@ -7761,15 +10705,80 @@ impl TransportParams {
                     tp.retry_source_connection_id = Some(val.to_vec().into());
                 },
 
+                0x0011 => {
+                    let chosen_version = val.get_u32()?;
+
+                    // The chosen version can't be zero and the parameter must
+                    // only contain whole versions.
+                    if chosen_version == 0 || val.cap() % 4 != 0 {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let mut available_versions =
+                        Vec::with_capacity(val.cap() / 4);
+
+                    while val.cap() > 0 {
+                        available_versions.push(val.get_u32()?);
+                    }
+
+                    tp.version_information = Some(VersionInformation {
+                        chosen_version,
+                        available_versions,
+                    });
+                },
+
                 0x0020 => {
                     tp.max_datagram_frame_size = Some(val.get_varint()?);
                 },
 
+                0x2ab2 => {
+                    if val.cap() != 0 {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    tp.grease_quic_bit = true;
+                },
+
+                0xff04de1b => {
+                    let min_ack_delay = val.get_varint()?;
+
+                    if min_ack_delay >= 2_u64.pow(24) {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    tp.min_ack_delay = Some(min_ack_delay);
+                },
+
+                0x0f739bbc1b666d04 => {
+                    tp.enable_multipath = match val.get_varint()? {
+                        0 => false,
+
+                        1 => true,
+
+                        _ => return Err(Error::InvalidTransportParam),
+                    };
+                },
+
+                0x17f7586d2cb571 => {
+                    if val.cap() != 0 {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    tp.reset_stream_at = true;
+                },
+
                 // Ignore unknown parameters.
                 _ => (),
             }
         }
 
+        // The minimum ACK delay can't be larger than the maximum ACK delay.
+        if let Some(min_ack_delay) = tp.min_ack_delay {
+            if min_ack_delay > tp.max_ack_delay * 1000 {
+                return Err(Error::InvalidTransportParam);
+            }
+        }
+
         Ok(tp)
     }
 
@@ -7895,7 +10904,30 @@ impl TransportParams {
             TransportParams::encode_param(&mut b, 0x000c, 0)?;
         }
 
-        // TODO: encode preferred_address
+        if is_server {
+            if let Some(ref pa) = tp.preferred_address {
+                let ipv4 = pa.ipv4.unwrap_or_else(|| {
+                    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+                });
+
+                let ipv6 = pa.ipv6.unwrap_or_else(|| {
+                    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)
+                });
+
+                TransportParams::encode_param(
+                    &mut b,
+                    0x000d,
+                    4 + 2 + 16 + 2 + 1 + pa.connection_id.len() + 16,
+                )?;
+                b.put_bytes(&ipv4.ip().octets())?;
+                b.put_u16(ipv4.port())?;
+                b.put_bytes(&ipv6.ip().octets())?;
+                b.put_u16(ipv6.port())?;
+                b.put_u8(pa.connection_id.len() as u8)?;
+                b.put_bytes(&pa.connection_id)?;
+                b.put_bytes(&pa.stateless_reset_token.to_be_bytes())?;
+            }
+        }
 
         if tp.active_conn_id_limit != 2 {
             TransportParams::encode_param(
@@ -7918,6 +10950,19 @@ impl TransportParams {
             }
         }
 
+        if let Some(version_info) = &tp.version_information {
+            TransportParams::encode_param(
+                &mut b,
+                0x0011,
+                4 + version_info.available_versions.len() * 4,
+            )?;
+            b.put_u32(version_info.chosen_version)?;
+
+            for &v in &version_info.available_versions {
+                b.put_u32(v)?;
+            }
+        }
+
         if let Some(max_datagram_frame_size) = tp.max_datagram_frame_size {
             TransportParams::encode_param(
                 &mut b,
@@ -7927,6 +10972,32 @@ impl TransportParams {
             b.put_varint(max_datagram_frame_size)?;
         }
 
+        if tp.grease_quic_bit {
+            TransportParams::encode_param(&mut b, 0x2ab2, 0)?;
+        }
+
+        if let Some(min_ack_delay) = tp.min_ack_delay {
+            TransportParams::encode_param(
+                &mut b,
+                0xff04de1b,
+                octets::varint_len(min_ack_delay),
+            )?;
+            b.put_varint(min_ack_delay)?;
+        }
+
+        if tp.enable_multipath {
+            TransportParams::encode_param(
+                &mut b,
+                0x0f739bbc1b666d04,
+                octets::varint_len(1),
+            )?;
+            b.put_varint(1)?;
+        }
+
+        if tp.reset_stream_at {
+            TransportParams::encode_param(&mut b, 0x17f7586d2cb571, 0)?;
+        }
+
         let out_len = b.off();
 
         Ok(&mut out[..out_len])
@@ -7945,6 +11016,28 @@ impl TransportParams {
             self.stateless_reset_token.map(|s| s.to_be_bytes()).as_ref(),
         );
 
+        let preferred_address = self.preferred_address.as_ref().map(|pa| {
+            let ipv4 = pa
+                .ipv4
+                .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+            let ipv6 = pa.ipv6.unwrap_or_else(|| {
+                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)
+            });
+
+            qlog::events::quic::PreferredAddress {
+                ip_v4: ipv4.ip().to_string(),
+                ip_v6: ipv6.ip().to_string(),
+                port_v4: ipv4.port(),
+                port_v6: ipv6.port(),
+                connection_id: qlog::HexSlice::new(&pa.connection_id).to_string(),
+                stateless_reset_token: qlog::HexSlice::new(
+                    &pa.stateless_reset_token.to_be_bytes(),
+                )
+                .to_string(),
+            }
+        });
+
         EventData::TransportParametersSet(
             qlog::events::quic::TransportParametersSet {
                 owner: Some(owner),
@@ -7978,7 +11071,11 @@ impl TransportParams {
                 initial_max_streams_bidi: Some(self.initial_max_streams_bidi),
                 initial_max_streams_uni: Some(self.initial_max_streams_uni),
 
-                preferred_address: None,
+                preferred_address,
+
+                min_ack_delay: self.min_ack_delay,
+
+                enable_multipath: Some(self.enable_multipath),
             },
         )
     }
@@ -7995,6 +11092,14 @@ pub mod testing {
 
     impl Pipe {
         pub fn new() -> Result<Pipe> {
+            let mut config = Pipe::default_config()?;
+
+            Pipe::with_config(&mut config)
+        }
+
+        /// Returns the configuration used by [`Pipe::new()`], so that tests
+        /// only need to change the settings they care about.
+        pub fn default_config() -> Result<Config> {
             let mut config = Config::new(crate::PROTOCOL_VERSION)?;
             config.load_cert_chain_from_pem_file("examples/cert.crt")?;
             config.load_priv_key_from_pem_file("examples/cert.key")?;
@@ -8009,7 +11114,7 @@ pub mod testing {
             config.verify_peer(false);
             config.set_ack_delay_exponent(8);
 
-            Pipe::with_config(&mut config)
+            Ok(config)
         }
 
         pub fn client_addr() -> SocketAddr {
@@ -8231,6 +11336,7 @@ pub mod testing {
             let info = RecvInfo {
                 to: server_path.peer_addr(),
                 from: server_path.local_addr(),
+                ecn: Ecn::NotEct,
             };
 
             self.client.recv(buf, info)
@@ -8241,6 +11347,7 @@ pub mod testing {
             let info = RecvInfo {
                 to: client_path.peer_addr(),
                 from: client_path.local_addr(),
+                ecn: Ecn::NotEct,
             };
 
             self.server.recv(buf, info)
@@ -8255,8 +11362,7 @@ pub mod testing {
         }
 
         pub fn client_update_key(&mut self) -> Result<()> {
-            let space =
-                &mut self.client.pkt_num_spaces[packet::Epoch::Application];
+            let space = &mut self.client.crypto_ctx[packet::Epoch::Application];
 
             let open_next = space
                 .crypto_open
@@ -8276,9 +11382,11 @@ pub mod testing {
 
             space.key_update = Some(packet::KeyUpdate {
                 crypto_open: open_prev.unwrap(),
-                pn_on_update: space.next_pkt_num,
+                pn_on_update: self.client.pkt_num_spaces
+                    [packet::Epoch::Application]
+                    .next_pkt_num,
                 update_acked: true,
-                timer: time::Instant::now(),
+                timer: Some(time::Instant::now()),
             });
 
             self.client.key_phase = !self.client.key_phase;
@@ -8294,6 +11402,7 @@ pub mod testing {
         let info = RecvInfo {
             to: active_path.local_addr(),
             from: active_path.peer_addr(),
+            ecn: Ecn::NotEct,
         };
 
         conn.recv(&mut buf[..len], info)?;
@@ -8318,6 +11427,7 @@ pub mod testing {
             let info = RecvInfo {
                 to: si.to,
                 from: si.from,
+                ecn: si.ecn,
             };
 
             conn.recv(&mut pkt, info)?;
@@ -8377,6 +11487,7 @@ pub mod testing {
         let epoch = pkt_type.to_epoch()?;
 
         let space = &mut conn.pkt_num_spaces[epoch];
+        let crypto_ctx = &mut conn.crypto_ctx[epoch];
 
         let pn = space.next_pkt_num;
         let pn_len = 4;
@@ -8405,6 +11516,8 @@ pub mod testing {
             token: conn.token.clone(),
             versions: None,
             key_phase: conn.key_phase,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         hdr.to_bytes(&mut b)?;
@@ -8412,7 +11525,8 @@ pub mod testing {
         let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
 
         if pkt_type != packet::Type::Short {
-            let len = pn_len + payload_len + space.crypto_overhead().unwrap();
+            let len =
+                pn_len + payload_len + crypto_ctx.crypto_overhead().unwrap();
             b.put_varint(len as u64)?;
         }
 
@@ -8426,13 +11540,14 @@ pub mod testing {
             frame.to_bytes(&mut b)?;
         }
 
-        let aead = match space.crypto_seal {
+        let aead = match crypto_ctx.crypto_seal {
             Some(ref v) => v,
             None => return Err(Error::InvalidState),
         };
 
         let written = packet::encrypt_pkt(
             &mut b,
+            0,
             pn,
             pn_len,
             payload_len,
@@ -8455,7 +11570,7 @@ pub mod testing {
 
         let epoch = hdr.ty.to_epoch()?;
 
-        let aead = conn.pkt_num_spaces[epoch].crypto_open.as_ref().unwrap();
+        let aead = conn.crypto_ctx[epoch].crypto_open.as_ref().unwrap();
 
         let payload_len = b.cap();
 
@@ -8467,9 +11582,15 @@ pub mod testing {
             hdr.pkt_num_len,
         );
 
-        let mut payload =
-            packet::decrypt_pkt(&mut b, pn, hdr.pkt_num_len, payload_len, aead)
-                .unwrap();
+        let mut payload = packet::decrypt_pkt(
+            &mut b,
+            0,
+            pn,
+            hdr.pkt_num_len,
+            payload_len,
+            aead,
+        )
+        .unwrap();
 
         let mut frames = Vec::new();
 
@@ -8521,12 +11642,18 @@ mod tests {
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: Some(b"retry".to_vec().into()),
             max_datagram_frame_size: Some(32),
+            version_information: None,
+            min_ack_delay: None,
+            grease_quic_bit: true,
+            enable_multipath: false,
+            reset_stream_at: false,
+            preferred_address: None,
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, true, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 94);
+        assert_eq!(raw_params.len(), 97);
 
         let new_tp = TransportParams::decode(raw_params, false).unwrap();
 
@@ -8551,12 +11678,18 @@ mod tests {
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: None,
             max_datagram_frame_size: Some(32),
+            version_information: None,
+            min_ack_delay: Some(1_000),
+            grease_quic_bit: false,
+            enable_multipath: true,
+            reset_stream_at: true,
+            preferred_address: None,
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, false, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 69);
+        assert_eq!(raw_params.len(), 99);
 
         let new_tp = TransportParams::decode(raw_params, true).unwrap();
 
@@ -8599,12 +11732,93 @@ mod tests {
     }
 
     #[test]
-    fn unknown_version() {
-        let mut config = Config::new(0xbabababa).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.verify_peer(false);
+    fn transport_params_version_information() {
+        let tp = TransportParams {
+            version_information: Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V1,
+                available_versions: vec![
+                    PROTOCOL_VERSION_V2,
+                    PROTOCOL_VERSION_V1,
+                ],
+            }),
+            ..Default::default()
+        };
+
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, false, &mut raw_params).unwrap();
+
+        let new_tp = TransportParams::decode(raw_params, true).unwrap();
+
+        assert_eq!(new_tp.version_information, tp.version_information);
+
+        // Chosen version can't be zero.
+        let raw_params = [0x11, 4, 0, 0, 0, 0];
+        assert_eq!(
+            TransportParams::decode(&raw_params, true),
+            Err(Error::InvalidTransportParam)
+        );
+
+        // Available versions must be 4 bytes long.
+        let raw_params = [0x11, 6, 0, 0, 0, 1, 0, 0];
+        assert_eq!(
+            TransportParams::decode(&raw_params, true),
+            Err(Error::InvalidTransportParam)
+        );
+    }
+
+    #[test]
+    fn transport_params_preferred_address() {
+        let tp = TransportParams {
+            preferred_address: Some(PreferredAddress {
+                ipv4: Some("192.0.2.1:4433".parse().unwrap()),
+                ipv6: None,
+                connection_id: b"preferred".to_vec().into(),
+                stateless_reset_token: u128::from_be_bytes([0xba; 16]),
+            }),
+            ..Default::default()
+        };
+
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, true, &mut raw_params).unwrap();
+
+        let new_tp = TransportParams::decode(raw_params, false).unwrap();
+
+        assert_eq!(new_tp.preferred_address, tp.preferred_address);
+
+        // Clients can't send a preferred address.
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, false, &mut raw_params).unwrap();
+
+        let new_tp = TransportParams::decode(raw_params, true).unwrap();
+
+        assert_eq!(new_tp.preferred_address, None);
+
+        // Servers can't receive a preferred address.
+        let mut raw_params = vec![0x0d, 41];
+        raw_params.extend_from_slice(&[0; 41]);
+
+        assert_eq!(
+            TransportParams::decode(&raw_params, true),
+            Err(Error::InvalidTransportParam)
+        );
+
+        // The connection ID can't be zero-length.
+        assert_eq!(
+            TransportParams::decode(&raw_params, false),
+            Err(Error::InvalidTransportParam)
+        );
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut config = Config::new(0xbabababa).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Err(Error::UnknownVersion));
@@ -8649,6 +11863,283 @@ mod tests {
         assert_eq!(pipe.server.version, PROTOCOL_VERSION);
     }
 
+    #[test]
+    fn version_negotiation_downgrade() {
+        let mut buf = [0; 65535];
+
+        let mut client_config = Config::new(0xbabababa).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config
+            .set_available_versions(&[PROTOCOL_VERSION_V2, PROTOCOL_VERSION_V1])
+            .unwrap();
+        client_config.verify_peer(false);
+
+        let mut server_config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config
+            .set_available_versions(&[PROTOCOL_VERSION_V1, PROTOCOL_VERSION_V2])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Forge a Version Negotiation packet that only lists version 1, even
+        // though the server also supports version 2.
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+
+        let mut vn_buf = [0; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut vn_buf);
+        b.put_u8(0x80).unwrap();
+        b.put_u32(0).unwrap();
+        b.put_u8(hdr.scid.len() as u8).unwrap();
+        b.put_bytes(&hdr.scid).unwrap();
+        b.put_u8(hdr.dcid.len() as u8).unwrap();
+        b.put_bytes(&hdr.dcid).unwrap();
+        b.put_u32(PROTOCOL_VERSION_V1).unwrap();
+        len = b.off();
+
+        assert_eq!(pipe.client_recv(&mut vn_buf[..len]), Ok(len));
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V1);
+
+        // The client detects that it would have picked version 2 given the
+        // server's available versions.
+        assert_eq!(pipe.handshake(), Err(Error::VersionNegotiation));
+    }
+
+    #[test]
+    fn version_negotiation_downgrade_default_versions() {
+        let mut buf = [0; 65535];
+
+        // The client doesn't configure any available versions, so it prefers
+        // version 1 over version 2.
+        let mut client_config = testing::Pipe::default_config().unwrap();
+        client_config.version = 0xbabababa;
+
+        let mut server_config = testing::Pipe::default_config().unwrap();
+        server_config
+            .set_available_versions(&[PROTOCOL_VERSION_V1, PROTOCOL_VERSION_V2])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Forge a Version Negotiation packet that only lists version 2, even
+        // though the server also supports version 1.
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+
+        let mut vn_buf = [0; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut vn_buf);
+        b.put_u8(0x80).unwrap();
+        b.put_u32(0).unwrap();
+        b.put_u8(hdr.scid.len() as u8).unwrap();
+        b.put_bytes(&hdr.scid).unwrap();
+        b.put_u8(hdr.dcid.len() as u8).unwrap();
+        b.put_bytes(&hdr.dcid).unwrap();
+        b.put_u32(PROTOCOL_VERSION_V2).unwrap();
+        len = b.off();
+
+        assert_eq!(pipe.client_recv(&mut vn_buf[..len]), Ok(len));
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.handshake(), Err(Error::VersionNegotiation));
+
+        assert_eq!(
+            pipe.client.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x11,
+                reason: vec![],
+            })
+        );
+
+        // The server is notified with a CONNECTION_CLOSE frame.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x11,
+                reason: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn compatible_version_negotiation() {
+        let mut buf = [0; 65535];
+
+        let mut client_config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config
+            .set_available_versions(&[PROTOCOL_VERSION_V1, PROTOCOL_VERSION_V2])
+            .unwrap();
+        client_config.verify_peer(false);
+
+        let mut server_config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config
+            .set_available_versions(&[PROTOCOL_VERSION_V2, PROTOCOL_VERSION_V1])
+            .unwrap();
+        server_config.set_initial_max_data(30);
+        server_config.set_initial_max_stream_data_bidi_remote(15);
+        server_config.set_initial_max_streams_bidi(3);
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+
+        // Client starts with version 1.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V1);
+
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
+
+        // Server replies using version 2.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+
+        assert_eq!(
+            pipe.client.peer_transport_params.version_information,
+            Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V2,
+                available_versions: vec![
+                    PROTOCOL_VERSION_V2,
+                    PROTOCOL_VERSION_V1
+                ],
+            })
+        );
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
+    }
+
+    #[test]
+    fn compatible_version_negotiation_not_available() {
+        let mut client_config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config.verify_peer(false);
+
+        let mut server_config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config
+            .set_available_versions(&[PROTOCOL_VERSION_V2, PROTOCOL_VERSION_V1])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+
+        // The client only advertises version 1, so the server can't switch.
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V1);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V1);
+    }
+
+    #[test]
+    fn compatible_version_negotiation_chosen_version_mismatch() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client claims a chosen version different from the one used for its
+        // Initial packet.
+        pipe.client.local_transport_params.version_information =
+            Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V2,
+                available_versions: vec![PROTOCOL_VERSION_V2],
+            });
+
+        let mut raw_params = [0; 256];
+        let raw_params = TransportParams::encode(
+            &pipe.client.local_transport_params,
+            false,
+            &mut raw_params,
+        )
+        .unwrap();
+        pipe.client
+            .handshake
+            .set_quic_transport_params(raw_params)
+            .unwrap();
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::VersionNegotiation)
+        );
+
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x11,
+                reason: vec![],
+            })
+        );
+    }
+
     #[test]
     fn verify_custom_root() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
@@ -8803,23 +12294,65 @@ mod tests {
     }
 
     #[test]
-    fn handshake_done() {
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn handshake_v2() {
+        let mut buf = [0; 65535];
 
-        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
-        // triggering 1-RTT packet send with a CRYPTO frame.
-        pipe.server.handshake.set_options(0x0000_4000);
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.version = PROTOCOL_VERSION_V2;
 
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        assert!(pipe.server.handshake_done_sent);
-    }
+        // Client sends an Initial packet using the v2 type bits.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(buf[0] & 0x30, 0x10);
 
-    #[test]
-    fn handshake_confirmation() {
-        let mut pipe = testing::Pipe::new().unwrap();
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, packet::Type::Initial);
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V2);
 
-        // Client sends initial flight.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"hello");
+
+        // Key updates use the v2 "quicv2 ku" label.
+        assert_eq!(pipe.client_update_key(), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(8, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn handshake_done() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
+        // triggering 1-RTT packet send with a CRYPTO frame.
+        pipe.server.handshake.set_options(0x0000_4000);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(pipe.server.handshake_done_sent);
+    }
+
+    #[test]
+    fn handshake_confirmation() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends initial flight.
         let flight = testing::emit_flight(&mut pipe.client).unwrap();
         testing::process_flight(&mut pipe.server, flight).unwrap();
 
@@ -9150,6 +12683,173 @@ mod tests {
         assert!(pipe.server.is_closed());
     }
 
+    #[test]
+    fn poll_event() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_dgram(true, 10, 10);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        // Streams are reported as readable only once, until all their data is
+        // read.
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"world", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReadable(4))
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((10, false)));
+
+        assert_eq!(pipe.client.stream_send(4, b"again", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReadable(4))
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        // The client can write to the stream again once the server reads the
+        // data, releasing flow control credits.
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::StreamWritable(4))
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
+        // Stream reset and stopped by the peer.
+        assert_eq!(pipe.client.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(pipe.client.stream_shutdown(8, Shutdown::Write, 42), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReset {
+                stream_id: 8,
+                error_code: 42
+            })
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 43), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::StreamStopped {
+                stream_id: 4,
+                error_code: 43
+            })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
+        // The client resets the stream in response.
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReset {
+                stream_id: 4,
+                error_code: 43
+            })
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        // Datagrams are reported once, regardless of how many are received.
+        assert_eq!(pipe.client.dgram_send(b"hello"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"world"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::DatagramReceived)
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        // Closing the connection.
+        assert_eq!(pipe.client.close(true, 0x1234, b"bye"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::PeerClosed(ConnectionError {
+                is_app: true,
+                error_code: 0x1234,
+                reason: b"bye".to_vec(),
+            }))
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+    }
+
+    #[test]
+    fn poll_event_early_data_rejected() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_early_data();
+        config.set_ticket_key(&[0xa5; 48]).unwrap();
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let session = pipe.client.session().unwrap();
+
+        // The server still accepts the session, but not early data anymore.
+        let mut server_config = testing::Pipe::default_config().unwrap();
+        server_config.set_ticket_key(&[0xa5; 48]).unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.client.set_session(session), Ok(()));
+
+        // Client sends initial flight, along with 0-RTT data.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert!(pipe.client.is_in_early_data());
+
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.is_resumed());
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(pipe.client.poll_event(), Some(ConnectionEvent::Resumed));
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::EarlyDataRejected)
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+    }
+
     #[test]
     fn limit_handshake_data() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
@@ -9196,6 +12896,85 @@ mod tests {
         assert!(pipe.server.stream_finished(4));
     }
 
+    #[test]
+    fn stream_zero_copy() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let buf = SharedBuf::new(b"hello, world, again".to_vec());
+
+        // The stream's flow control only allows part of the data.
+        assert_eq!(pipe.client.stream_send_zc(4, &buf, true), Ok(15));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((15, false)));
+        assert_eq!(&b[..15], b"hello, world, a");
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let rest = buf.slice(15..);
+        assert_eq!(pipe.client.stream_send_zc(4, &rest, true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((4, true)));
+        assert_eq!(&b[..4], b"gain");
+
+        assert!(pipe.server.stream_finished(4));
+
+        // Zero-copy and regular writes can be mixed on the same stream.
+        assert_eq!(pipe.client.stream_send_zc(0, &buf.slice(..5), false), Ok(5));
+        assert_eq!(pipe.client.stream_send(0, b", world", true), Ok(7));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
+    }
+
+    #[test]
+    fn stream_recv_chunks() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello, ", false), Ok(7));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let chunks: Vec<&[u8]> =
+            pipe.server.stream_recv_chunks(4).unwrap().collect();
+        assert_eq!(chunks, vec![&b"hello, "[..], &b"world"[..]]);
+
+        // The data is still readable until it is consumed.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_consume(4, 7), Ok((7, false)));
+
+        let chunks: Vec<&[u8]> =
+            pipe.server.stream_recv_chunks(4).unwrap().collect();
+        assert_eq!(chunks, vec![&b"world"[..]]);
+
+        assert_eq!(pipe.server.stream_consume(4, 5), Ok((5, true)));
+
+        assert_eq!(pipe.server.readable().len(), 0);
+        assert!(pipe.server.stream_finished(4));
+        assert_eq!(pipe.server.stream_recv_chunks(4).err(), Some(Error::Done));
+
+        // Consumed data is accounted for in flow control.
+        assert_eq!(
+            pipe.client.stream_send(8, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_consume(8, 15), Ok((15, false)));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"a", true), Ok(1));
+    }
+
     #[test]
     fn zero_rtt() {
         let mut buf = [0; 65535];
@@ -9355,7 +13134,7 @@ mod tests {
 
         // Ensure ACK for key update.
         assert!(
-            pipe.server.pkt_num_spaces[packet::Epoch::Application]
+            pipe.server.crypto_ctx[packet::Epoch::Application]
                 .key_update
                 .as_ref()
                 .unwrap()
@@ -9417,65 +13196,249 @@ mod tests {
     }
 
     #[test]
-    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
-    /// unidirectional stream is forbidden.
-    fn max_stream_data_receive_uni() {
-        let mut buf = [0; 65535];
+    fn initiate_key_update() {
+        let mut b = [0; 15];
 
         let mut pipe = testing::Pipe::new().unwrap();
+
+        // Keys can't be updated before the handshake is confirmed.
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::InvalidState));
+
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client opens unidirectional stream.
-        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+
+        // The peer didn't use the new keys yet, so the previous ones are kept
+        // until it does.
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::Done));
+
+        let key_update = pipe.client.crypto_ctx[packet::Epoch::Application]
+            .key_update
+            .as_ref()
+            .unwrap();
+        assert_eq!(key_update.timer, None);
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends MAX_STREAM_DATA on local unidirectional stream.
-        let frames = [frame::Frame::MaxStreamData {
-            stream_id: 2,
-            max: 1024,
-        }];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"hello");
+        assert!(pipe.server.key_phase);
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidStreamState(2)),
-        );
-    }
+        let key_update = pipe.client.crypto_ctx[packet::Epoch::Application]
+            .key_update
+            .as_ref()
+            .unwrap();
+        assert!(key_update.timer.is_some());
 
-    #[test]
-    fn empty_payload() {
-        let mut buf = [0; 65535];
+        // The server acknowledged a packet protected with the new keys, and
+        // used them itself, so the client can update them again.
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+        assert_eq!(pipe.client.stream_send(4, b"world", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"world");
+        assert!(!pipe.server.key_phase);
 
-        // Send a packet with no frames.
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
-            Err(Error::InvalidPacket)
-        );
+        // Keys can be updated by the server as well, once a packet it
+        // protected with the current keys is acknowledged.
+        assert_eq!(pipe.server.initiate_key_update(), Err(Error::Done));
+
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.initiate_key_update(), Ok(()));
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((10, true)));
+        assert_eq!(&b[..10], b"helloworld");
+        assert!(pipe.client.key_phase);
     }
 
     #[test]
-    fn min_payload() {
+    fn key_update_confidentiality_limit() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_key_update_threshold(0.5);
 
-        // Send a non-ack-eliciting packet.
-        let frames = [frame::Frame::Padding { len: 4 }];
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Initial;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let limit = pipe.client.crypto_ctx[packet::Epoch::Application]
+            .crypto_seal
+            .as_ref()
+            .unwrap()
+            .alg()
+            .confidentiality_limit();
 
-        let initial_path = pipe
-            .server
-            .paths
-            .get_active()
+        // Keys are updated once half of the limit is reached.
+        pipe.client.seal_count = limit / 2 - 1;
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(!pipe.client.key_phase);
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.key_phase);
+        assert!(pipe.server.key_phase);
+        assert!(pipe.client.seal_count < limit / 2);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((10, false)));
+
+        // The connection is closed when the limit is reached and keys can't be
+        // updated.
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+        pipe.client.seal_count = limit;
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::AeadLimitReached));
+
+        assert_eq!(
+            pipe.client.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0xf,
+                reason: Vec::new(),
+            })
+        );
+
+        // The peer is notified with a CONNECTION_CLOSE frame.
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.is_draining());
+
+        assert_eq!(
+            pipe.server.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0xf,
+                reason: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn key_update_integrity_limit() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let frames = [frame::Frame::Ping { mtu_probe: None }];
+
+        let open_fail_count = pipe.server.open_fail_count;
+
+        // Packets failing authentication are dropped.
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+        buf[written - 1] = !buf[written - 1];
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        assert_eq!(pipe.server.open_fail_count, open_fail_count + 1);
+
+        // Once the limit is exceeded, the connection is closed.
+        pipe.server.open_fail_count = pipe.server.crypto_ctx
+            [packet::Epoch::Application]
+            .crypto_open
+            .as_ref()
+            .unwrap()
+            .alg()
+            .integrity_limit();
+
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+        buf[written - 1] = !buf[written - 1];
+
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::AeadLimitReached)
+        );
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0xf,
+                reason: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
+    /// unidirectional stream is forbidden.
+    fn max_stream_data_receive_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client opens unidirectional stream.
+        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client sends MAX_STREAM_DATA on local unidirectional stream.
+        let frames = [frame::Frame::MaxStreamData {
+            stream_id: 2,
+            max: 1024,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidStreamState(2)),
+        );
+    }
+
+    #[test]
+    fn empty_payload() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Send a packet with no frames.
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn min_payload() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Send a non-ack-eliciting packet.
+        let frames = [frame::Frame::Padding { len: 4 }];
+
+        let pkt_type = packet::Type::Initial;
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        let initial_path = pipe
+            .server
+            .paths
+            .get_active()
             .expect("initial path not found");
 
         assert_eq!(initial_path.max_send_bytes, 195);
@@ -11154,970 +15117,1152 @@ mod tests {
     }
 
     #[test]
-    /// Tests that the order of flushable streams scheduled on the wire is the
-    /// same as the order of `stream_send()` calls done by the application.
-    fn stream_round_robin() {
+    fn stream_shutdown_at() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_reset_stream_at(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        // Client writes a header and some payload, but resets the stream
+        // before sending it, keeping only the header.
+        assert_eq!(pipe.client.stream_send(0, b"headerpayload", false), Ok(13));
+
+        assert_eq!(
+            pipe.client.stream_shutdown_at(0, 42, 14),
+            Err(Error::FinalSize)
+        );
+        assert_eq!(pipe.client.stream_shutdown_at(0, 42, 6), Ok(()));
+        assert_eq!(pipe.client.stream_shutdown_at(0, 42, 6), Err(Error::Done));
+
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), None);
 
         let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        let mut dummy = buf[..len].to_vec();
 
-        let mut iter = frames.iter();
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut dummy[..len]).unwrap();
 
-        // Skip ACK frame.
-        iter.next();
+        assert!(frames.contains(&frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 6,
+            reliable_size: 6,
+        }));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
-        );
+        assert!(frames.contains(&frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"header", 0, true),
+        }));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        // Server reads the header first, and then the reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReset {
                 stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+                error_code: 42
             })
         );
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((6, false)));
+        assert_eq!(&buf[..6], b"header");
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
+            pipe.server.stream_recv(0, &mut buf),
+            Err(Error::StreamReset(42))
         );
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Unsent data past the reliable size doesn't count towards the
+        // connection's flow control.
+        assert_eq!(pipe.client.tx_data, 6);
     }
 
     #[test]
-    /// Tests the readable iterator.
-    fn stream_readable() {
+    fn stream_shutdown_at_not_negotiated() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No readable streams.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
 
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.stream_shutdown_at(0, 42, 2),
+            Err(Error::InvalidState)
+        );
 
-        // Server received stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        // Peer sends RESET_STREAM_AT even though it wasn't negotiated.
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 5,
+            reliable_size: 2,
+        }];
 
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
         );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+    }
 
-        // Client drains stream.
+    #[test]
+    /// Tests that data up to the reliable size received after a
+    /// RESET_STREAM_AT frame is delivered before the reset.
+    fn reset_stream_at_before_data() {
         let mut b = [0; 15];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut buf = [0; 65535];
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_reset_stream_at(true);
 
-        // Server shuts down stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 0), Ok(()));
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 10,
+            reliable_size: 5,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
+        // Nothing can be read until the reliable data is received.
         let mut r = pipe.server.readable();
         assert_eq!(r.next(), None);
 
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.len(), 2);
+        // A later frame can reduce the reliable size, but not increase it.
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 10,
+            reliable_size: 4,
+        }];
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 10,
+            reliable_size: 8,
+        }];
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        assert_eq!(r.len(), 0);
-    }
+        // The final size can't change.
+        let frames = [frame::Frame::ResetStreamAt {
+            stream_id: 0,
+            error_code: 42,
+            final_size: 11,
+            reliable_size: 4,
+        }];
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FinalSize)
+        );
 
-    #[test]
-    /// Tests the writable iterator.
-    fn stream_writable() {
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No writable streams.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), None);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        let frames = [
+            frame::Frame::ResetStreamAt {
+                stream_id: 0,
+                error_code: 42,
+                final_size: 10,
+                reliable_size: 5,
+            },
+            frame::Frame::ResetStreamAt {
+                stream_id: 0,
+                error_code: 42,
+                final_size: 10,
+                reliable_size: 8,
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"helloworld", 0, true),
+            },
+        ];
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Client created stream.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Data past the reliable size is discarded.
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"hello");
 
-        // Server created stream.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+        assert_eq!(
+            pipe.server.stream_recv(0, &mut b),
+            Err(Error::StreamReset(42))
+        );
 
         assert_eq!(
-            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReset {
+                stream_id: 0,
+                error_code: 42
+            })
+        );
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(ConnectionEvent::StreamReadable(0))
         );
+        assert_eq!(pipe.server.poll_event(), None);
 
-        // Server stream is full.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
+        // The connection's flow control accounts for the final size.
+        assert_eq!(pipe.server.rx_data, 10);
+    }
 
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    /// Tests that the order of flushable streams scheduled on the wire is the
+    /// same as the order of `stream_send()` calls done by the application.
+    fn stream_round_robin() {
+        let mut buf = [0; 65535];
 
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server stream is writable again.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(0));
-        assert_eq!(w.next(), None);
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Write, 0), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut iter = frames.iter();
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Skip ACK frame.
+        iter.next();
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.len(), 2);
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
 
-        assert!(w.next().is_some());
-        assert!(w.next().is_some());
-        assert!(w.next().is_none());
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(w.len(), 0);
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
-        // Server finishes stream.
-        assert_eq!(pipe.server.stream_send(8, b"aaaaa", true), Ok(5));
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(4));
-        assert_eq!(w.next(), None);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
     }
 
-    #[test]
-    fn stream_writable_blocked() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(&[b"h3"]).unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150000);
-        config.set_initial_max_stream_data_bidi_remote(150000);
-        config.set_initial_max_stream_data_uni(150000);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    /// Sends data on streams 0, 4 and 8 in reverse order, with stream 4 being
+    /// the most urgent one, and returns the IDs of the streams in the order
+    /// their STREAM frames are sent on the wire.
+    fn scheduled_stream_order(config: &mut Config) -> Vec<u64> {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        config.set_initial_max_data(100_000);
+        config.set_initial_max_stream_data_bidi_local(100_000);
+        config.set_initial_max_stream_data_bidi_remote(100_000);
+
+        let mut pipe = testing::Pipe::with_config(config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client creates stream and sends some data.
-        let send_buf = [0; 35];
-        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
+        assert_eq!(pipe.client.stream_priority(4, 0, false), Ok(()));
 
-        // Stream is still writable as it still has capacity.
-        assert_eq!(pipe.client.stream_writable_next(), Some(0));
-        assert_eq!(pipe.client.stream_writable_next(), None);
+        let data = [0xba; 2000];
 
-        // Client fills stream, which becomes unwritable due to connection
-        // capacity.
-        let send_buf = [0; 36];
-        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
+        for &stream_id in &[8, 4, 0] {
+            assert_eq!(pipe.client.stream_send(stream_id, &data, true), Ok(2000));
+        }
 
-        assert_eq!(pipe.client.stream_writable_next(), None);
+        let mut order = Vec::new();
 
-        assert_eq!(pipe.client.tx_cap, 0);
+        loop {
+            let len = match pipe.client.send(&mut buf) {
+                Ok((len, _)) => len,
 
-        assert_eq!(pipe.advance(), Ok(()));
+                Err(Error::Done) => break,
 
-        let mut b = [0; 70];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+                Err(e) => panic!("send failed: {:?}", e),
+            };
 
-        assert_eq!(pipe.advance(), Ok(()));
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
 
-        // The connection capacity has increased and the stream is now writable
-        // again.
-        assert_ne!(pipe.client.tx_cap, 0);
+            for frame in frames {
+                if let frame::Frame::Stream { stream_id, .. } = frame {
+                    order.push(stream_id);
+                }
+            }
+        }
 
-        assert_eq!(pipe.client.stream_writable_next(), Some(0));
-        assert_eq!(pipe.client.stream_writable_next(), None);
+        order
     }
 
     #[test]
-    /// Tests that we don't exceed the per-connection flow control limit set by
-    /// the peer.
-    fn flow_control_limit_send() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(
-            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
-        assert_eq!(pipe.advance(), Ok(()));
+    /// Tests the order of streams on the wire with the default scheduler.
+    fn stream_scheduler_priority() {
+        let mut config = testing::Pipe::default_config().unwrap();
 
-        let mut r = pipe.server.readable();
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
+        assert_eq!(scheduled_stream_order(&mut config), [4, 4, 8, 0, 8, 0]);
     }
 
     #[test]
-    /// Tests that invalid packets received before any other valid ones cause
-    /// the server to close the connection immediately.
-    fn invalid_initial_server() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::new().unwrap();
+    /// Tests a scheduler that sends streams in the order they became
+    /// flushable, ignoring priorities.
+    fn stream_scheduler_fifo() {
+        #[derive(Default)]
+        struct FifoScheduler {
+            queue: VecDeque<u64>,
+        }
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        impl StreamScheduler for FifoScheduler {
+            fn insert(&mut self, stream_id: u64, _: u8, _: bool) {
+                self.queue.push_back(stream_id);
+            }
 
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+            fn remove(&mut self, stream_id: u64) {
+                self.queue.retain(|id| *id != stream_id);
+            }
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+            fn peek(&mut self) -> Option<u64> {
+                self.queue.front().copied()
+            }
 
-        assert_eq!(pipe.server.timeout(), None);
+            fn on_sent(&mut self, _: u64, _: usize) {}
 
-        assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::CryptoFail)
-        );
+            fn update_priority(&mut self, _: u64, _: u8, _: bool) {}
+        }
 
-        assert!(pipe.server.is_closed());
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_stream_scheduler(|| Box::<FifoScheduler>::default());
+
+        assert_eq!(scheduled_stream_order(&mut config), [8, 8, 4, 4, 0, 0]);
+
+        // Streams returned by the scheduler that don't have data to send are
+        // removed from it, instead of stalling the connection.
+        config.set_stream_scheduler(|| {
+            Box::new(FifoScheduler {
+                queue: VecDeque::from(vec![100]),
+            })
+        });
+
+        assert_eq!(scheduled_stream_order(&mut config), [8, 8, 4, 4, 0, 0]);
     }
 
     #[test]
-    /// Tests that invalid Initial packets received to cause
-    /// the client to close the connection immediately.
-    fn invalid_initial_client() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::new().unwrap();
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+    /// Tests a weighted fair queuing scheduler, where streams are sent in
+    /// proportion to their weight, regardless of their priority.
+    fn stream_scheduler_weighted_fair() {
+        struct WeightedFairScheduler {
+            weights: std::collections::HashMap<u64, usize>,
+
+            // Bytes sent by each stream, scaled by the inverse of its weight.
+            queue: Vec<(u64, usize)>,
+        }
 
-        // Server sends initial flight.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
+        impl StreamScheduler for WeightedFairScheduler {
+            fn insert(&mut self, stream_id: u64, _: u8, _: bool) {
+                self.queue.push((stream_id, 0));
+            }
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+            fn remove(&mut self, stream_id: u64) {
+                self.queue.retain(|(id, _)| *id != stream_id);
+            }
 
-        let written = testing::encode_pkt(
-            &mut pipe.server,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+            fn peek(&mut self) -> Option<u64> {
+                self.queue
+                    .iter()
+                    .min_by_key(|(_, credit)| *credit)
+                    .map(|(id, _)| *id)
+            }
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+            fn on_sent(&mut self, stream_id: u64, len: usize) {
+                let weight = self.weights[&stream_id];
 
-        // Client will ignore invalid packet.
-        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+                for (id, credit) in self.queue.iter_mut() {
+                    if *id == stream_id {
+                        *credit += len / weight;
+                    }
+                }
+            }
+        }
 
-        // The connection should be alive...
-        assert!(!pipe.client.is_closed());
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_stream_scheduler(|| {
+            Box::new(WeightedFairScheduler {
+                weights: [(0, 4), (4, 1), (8, 1)].iter().copied().collect(),
+                queue: Vec::new(),
+            })
+        });
 
-        // ...and the idle timeout should be armed.
-        assert!(pipe.client.idle_timer.is_some());
+        assert_eq!(scheduled_stream_order(&mut config), [8, 4, 0, 0, 8, 4]);
     }
 
     #[test]
-    /// Tests that packets with invalid payload length received before any other
-    /// valid packet cause the server to close the connection immediately.
-    fn invalid_initial_payload() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::new().unwrap();
+    /// Tests a scheduler sending streams with the earliest deadline first, as
+    /// set by the application.
+    fn stream_scheduler_deadline() {
+        struct DeadlineScheduler {
+            deadlines: Arc<std::sync::Mutex<std::collections::HashMap<u64, u64>>>,
 
-        let mut b = octets::OctetsMut::with_slice(&mut buf);
+            streams: Vec<u64>,
+        }
 
-        let epoch = packet::Type::Initial.to_epoch().unwrap();
+        impl StreamScheduler for DeadlineScheduler {
+            fn insert(&mut self, stream_id: u64, _: u8, _: bool) {
+                self.streams.push(stream_id);
+            }
 
-        let pn = 0;
-        let pn_len = packet::pkt_num_len(pn).unwrap();
+            fn remove(&mut self, stream_id: u64) {
+                self.streams.retain(|id| *id != stream_id);
+            }
 
-        let dcid = pipe.client.destination_id();
-        let scid = pipe.client.source_id();
+            fn peek(&mut self) -> Option<u64> {
+                let deadlines = self.deadlines.lock().unwrap();
 
-        let hdr = Header {
-            ty: packet::Type::Initial,
-            version: pipe.client.version,
-            dcid: ConnectionId::from_ref(&dcid),
-            scid: ConnectionId::from_ref(&scid),
-            pkt_num: 0,
-            pkt_num_len: pn_len,
-            token: pipe.client.token.clone(),
-            versions: None,
-            key_phase: false,
-        };
+                self.streams
+                    .iter()
+                    .min_by_key(|id| deadlines.get(id).unwrap_or(&u64::MAX))
+                    .copied()
+            }
 
-        hdr.to_bytes(&mut b).unwrap();
+            fn on_sent(&mut self, _: u64, _: usize) {}
+        }
 
-        // Payload length is invalid!!!
-        let payload_len = 4096;
+        let deadlines =
+            Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+        deadlines
+            .lock()
+            .unwrap()
+            .extend([(0, 10), (4, 30), (8, 20)].iter().copied());
 
-        let len = pn_len + payload_len;
-        b.put_varint(len as u64).unwrap();
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_stream_scheduler(move || {
+            Box::new(DeadlineScheduler {
+                deadlines: Arc::clone(&deadlines),
+                streams: Vec::new(),
+            })
+        });
 
-        packet::encode_pkt_num(pn, &mut b).unwrap();
+        assert_eq!(scheduled_stream_order(&mut config), [0, 0, 8, 8, 4, 4]);
+    }
 
-        let payload_offset = b.off();
+    #[test]
+    fn stream_stats() {
+        let mut buf = [0; 65535];
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        for frame in &frames {
-            frame.to_bytes(&mut b).unwrap();
-        }
+        // The stream's flow control limit only lets 15 bytes through.
+        assert_eq!(pipe.client.stream_send(4, &[0xba; 20], false), Ok(15));
 
-        let space = &mut pipe.client.pkt_num_spaces[epoch];
+        let stats = pipe.client.stream_stats(4).unwrap();
+        assert_eq!(stats.sent_bytes, 0);
+        assert_eq!(stats.first_byte_sent, None);
 
-        // Use correct payload length when encrypting the packet.
-        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let aead = space.crypto_seal.as_ref().unwrap();
+        let stats = pipe.client.stream_stats(4).unwrap();
+        assert_eq!(stats.sent_bytes, 15);
+        assert_eq!(stats.acked_bytes, 15);
+        assert_eq!(stats.retrans_bytes, 0);
+        assert!(stats.first_byte_sent.is_some());
+        assert!(stats.first_byte_acked >= stats.first_byte_sent);
 
-        let written = packet::encrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            payload_offset,
-            None,
-            aead,
-        )
-        .unwrap();
+        let stats = pipe.server.stream_stats(4).unwrap();
+        assert_eq!(stats.recv_bytes, 15);
 
-        assert_eq!(pipe.server.timeout(), None);
+        // The client is still blocked by flow control.
+        let blocked = pipe.client.stream_stats(4).unwrap().flow_control_blocked;
+        std::thread::sleep(time::Duration::from_millis(1));
+        assert!(
+            pipe.client.stream_stats(4).unwrap().flow_control_blocked > blocked
+        );
 
+        // Reading the data unblocks the client.
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, false)));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let blocked = pipe.client.stream_stats(4).unwrap().flow_control_blocked;
+        std::thread::sleep(time::Duration::from_millis(1));
         assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::InvalidPacket)
+            pipe.client.stream_stats(4).unwrap().flow_control_blocked,
+            blocked
         );
 
-        assert!(pipe.server.is_closed());
+        // The final stats of a collected stream are returned once.
+        assert_eq!(pipe.client.stream_send(4, &[0xba; 5], true), Ok(5));
+        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((0, true)));
+        assert!(pipe.client.streams.get(4).is_none());
+
+        let stats = pipe.client.stream_stats(4).unwrap();
+        assert_eq!(stats.sent_bytes, 20);
+        assert_eq!(stats.acked_bytes, 20);
+
+        assert_eq!(
+            pipe.client.stream_stats(4).err(),
+            Some(Error::InvalidStreamState(4))
+        );
     }
 
     #[test]
-    /// Tests that invalid packets don't cause the connection to be closed.
-    fn invalid_packet() {
-        let mut buf = [0; 65535];
+    fn stats_congestion_blocked() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        assert_eq!(pipe.client.stats().congestion_blocked, time::Duration::ZERO);
 
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Short,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        // Fill the congestion window, with stream data left to send due to
+        // packet overhead.
+        assert_eq!(
+            pipe.client.stream_send(4, &[0xba; 100_000], false),
+            Ok(12_000)
+        );
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(pipe.client.streams.has_flushable());
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let blocked = pipe.client.stats().congestion_blocked;
+        std::thread::sleep(time::Duration::from_millis(1));
+        assert!(pipe.client.stats().congestion_blocked > blocked);
 
-        // Corrupt the packets's first byte to make the header fail decoding.
-        buf[0] = 255;
+        // Once all data is sent the connection isn't blocked anymore.
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(!pipe.client.streams.has_flushable());
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        let blocked = pipe.client.stats().congestion_blocked;
+        std::thread::sleep(time::Duration::from_millis(1));
+        assert_eq!(pipe.client.stats().congestion_blocked, blocked);
     }
 
     #[test]
-    fn recv_empty_buffer() {
-        let mut buf = [0; 65535];
-
+    /// Tests the readable iterator.
+    fn stream_readable() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
-    }
+        // No readable streams.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
 
-    #[test]
-    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
-    fn stream_limit_update_bidi() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server received stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
+        assert_eq!(
+            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        // Server sends stream data, with fin.
-        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server shuts down stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
+        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Read, 0), Ok(()));
 
-        // Server sends MAX_STREAMS.
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.len(), 2);
 
-        assert_eq!(
-            pipe.client.stream_send(20, b"a", false),
-            Err(Error::StreamLimit)
-        );
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
+        assert!(r.next().is_none());
 
-        assert_eq!(pipe.server.readable().len(), 3);
+        assert_eq!(r.len(), 0);
     }
 
     #[test]
-    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
-    fn stream_limit_update_uni() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(0);
-        config.set_initial_max_streams_uni(3);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+    /// Tests the writable iterator.
+    fn stream_writable() {
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // No writable streams.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), None);
 
-        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
 
-        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client created stream.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(2, &mut b).unwrap();
-        pipe.server.stream_recv(6, &mut b).unwrap();
+        // Server created stream.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-        // Server sends MAX_STREAMS.
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server stream is full.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
 
-        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(22, b"a", false),
-            Err(Error::StreamLimit)
-        );
+        // Server stream is writable again.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(0));
+        assert_eq!(w.next(), None);
 
-        assert_eq!(pipe.server.readable().len(), 3);
-    }
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(0, Shutdown::Write, 0), Ok(()));
 
-    #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, and that the buffer becomes readable on the other
-    /// side.
-    fn stream_zero_length_fin() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
 
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
-
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut w = pipe.server.writable();
+        assert_eq!(w.len(), 2);
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        assert!(w.next().is_some());
+        assert!(w.next().is_some());
+        assert!(w.next().is_none());
 
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(w.len(), 0);
 
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server finishes stream.
+        assert_eq!(pipe.server.stream_send(8, b"aaaaa", true), Ok(5));
 
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(4));
+        assert_eq!(w.next(), None);
     }
 
     #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, that the buffer becomes readable on the other
-    /// side and stays readable even if the stream is fin'd locally.
-    fn stream_zero_length_fin_deferred_collection() {
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn stream_writable_blocked() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(&[b"h3"]).unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150000);
+        config.set_initial_max_stream_data_bidi_remote(150000);
+        config.set_initial_max_stream_data_uni(150000);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client creates stream and sends some data.
+        let send_buf = [0; 35];
+        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        // Stream is still writable as it still has capacity.
+        assert_eq!(pipe.client.stream_writable_next(), Some(0));
+        assert_eq!(pipe.client.stream_writable_next(), None);
 
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client fills stream, which becomes unwritable due to connection
+        // capacity.
+        let send_buf = [0; 36];
+        assert_eq!(pipe.client.stream_send(0, &send_buf, false), Ok(35));
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_writable_next(), None);
 
-        // Server sends zero-length frame.
-        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.tx_cap, 0);
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 15];
+        let mut b = [0; 70];
         pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-
-        // Stream _is_readable on the client side.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
-
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // The connection capacity has increased and the stream is now writable
+        // again.
+        assert_ne!(pipe.client.tx_cap, 0);
 
-        // Stream is completed and _is not_ readable.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_writable_next(), Some(0));
+        assert_eq!(pipe.client.stream_writable_next(), None);
     }
 
     #[test]
-    /// Tests that the stream gets created with stream_send() even if there's
-    /// no data in the buffer and the fin flag is not set.
-    fn stream_zero_length_non_fin() {
+    /// Tests that we don't exceed the per-connection flow control limit set by
+    /// the peer.
+    fn flow_control_limit_send() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
-
-        // The stream now should have been created.
-        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Sending an empty non-fin should not change any stream state on the
-        // other side.
         let mut r = pipe.server.readable();
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
         assert!(r.next().is_none());
     }
 
     #[test]
-    /// Tests that completed streams are garbage collected.
-    fn collect_streams() {
+    /// Tests that invalid packets received before any other valid ones cause
+    /// the server to close the connection immediately.
+    fn invalid_initial_server() {
         let mut buf = [0; 65535];
-
         let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(!pipe.server.stream_finished(0));
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+        assert_eq!(pipe.server.timeout(), None);
 
-        let mut b = [0; 5];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::CryptoFail)
+        );
 
-        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.server.is_closed());
+    }
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+    #[test]
+    /// Tests that invalid Initial packets received to cause
+    /// the client to close the connection immediately.
+    fn invalid_initial_client() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 0);
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let mut b = [0; 5];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server sends initial flight.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-        assert!(pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+        let written = testing::encode_pkt(
+            &mut pipe.server,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 0,
-            data: stream::RangeBuf::from(b"aa", 0, false),
-        }];
+        // Client will ignore invalid packet.
+        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // The connection should be alive...
+        assert!(!pipe.client.is_closed());
+
+        // ...and the idle timeout should be armed.
+        assert!(pipe.client.idle_timer.is_some());
     }
 
     #[test]
-    fn config_set_cc_algorithm_name() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+    /// Tests that packets with invalid payload length received before any other
+    /// valid packet cause the server to close the connection immediately.
+    fn invalid_initial_payload() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        let epoch = packet::Type::Initial.to_epoch().unwrap();
+
+        let pn = 0;
+        let pn_len = packet::pkt_num_len(pn).unwrap();
+
+        let dcid = pipe.client.destination_id();
+        let scid = pipe.client.source_id();
+
+        let hdr = Header {
+            ty: packet::Type::Initial,
+            version: pipe.client.version,
+            dcid: ConnectionId::from_ref(&dcid),
+            scid: ConnectionId::from_ref(&scid),
+            pkt_num: 0,
+            pkt_num_len: pn_len,
+            token: pipe.client.token.clone(),
+            versions: None,
+            key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
+        };
+
+        hdr.to_bytes(&mut b).unwrap();
+
+        // Payload length is invalid!!!
+        let payload_len = 4096;
+
+        let len = pn_len + payload_len;
+        b.put_varint(len as u64).unwrap();
+
+        packet::encode_pkt_num(pn, &mut b).unwrap();
+
+        let payload_offset = b.off();
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        for frame in &frames {
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let crypto_ctx = &mut pipe.client.crypto_ctx[epoch];
+
+        // Use correct payload length when encrypting the packet.
+        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+
+        let aead = crypto_ctx.crypto_seal.as_ref().unwrap();
+
+        let written = packet::encrypt_pkt(
+            &mut b,
+            0,
+            pn,
+            pn_len,
+            payload_len,
+            payload_offset,
+            None,
+            aead,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.server.timeout(), None);
 
-        // Unknown name.
         assert_eq!(
-            config.set_cc_algorithm_name("???"),
-            Err(Error::CongestionControl)
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::InvalidPacket)
         );
+
+        assert!(pipe.server.is_closed());
     }
 
     #[test]
-    fn peer_cert() {
+    /// Tests that invalid packets don't cause the connection to be closed.
+    fn invalid_packet() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        match pipe.client.peer_cert() {
-            Some(c) => assert_eq!(c.len(), 753),
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-            None => panic!("missing server certificate"),
-        }
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        // Corrupt the packets's first byte to make the header fail decoding.
+        buf[0] = 255;
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
     }
 
     #[test]
-    fn peer_cert_chain() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+    fn recv_segments() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(100_000);
+        config.set_initial_max_stream_data_bidi_local(100_000);
+        config.set_initial_max_stream_data_bidi_remote(100_000);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        match pipe.client.peer_cert_chain() {
-            Some(c) => assert_eq!(c.len(), 5),
+        let data = vec![0xba; 10_000];
+        assert_eq!(pipe.client.stream_send(4, &data, false), Ok(10_000));
 
-            None => panic!("missing server certificate chain"),
-        }
-    }
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight.len() > 2);
 
-    #[test]
-    fn retry() {
-        let mut buf = [0; 65535];
+        // Coalesce the datagrams into a single GRO buffer.
+        let segment_size = flight[0].0.len();
+        let info = flight[0].1;
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        let mut gro = Vec::new();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        for (i, (pkt, _)) in flight.iter().enumerate() {
+            if i < flight.len() - 1 {
+                assert_eq!(pkt.len(), segment_size);
+            }
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+            gro.extend_from_slice(pkt);
+        }
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        let recv_info = RecvInfo {
+            to: info.to,
+            from: info.from,
+            ecn: info.ecn,
+        };
 
-        let odcid = hdr.dcid.clone();
+        let recv_count = pipe.server.recv_count;
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        assert_eq!(
+            pipe.server.recv_segments(&mut gro, segment_size, recv_info),
+            Ok(gro.len())
+        );
+        assert_eq!(pipe.server.recv_count, recv_count + flight.len());
 
-        let token = b"quiche test retry token";
+        let mut b = vec![0; 20_000];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((10_000, false)));
+        assert_eq!(&b[..10_000], &data[..]);
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        // A segment that can't be processed is dropped, without affecting the
+        // others.
+        assert_eq!(pipe.client.stream_send(4, &data, true), Ok(10_000));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let mut gro: Vec<u8> =
+            flight.iter().flat_map(|(p, _)| p.clone()).collect();
 
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
-        assert_eq!(&hdr.token.unwrap(), token);
+        // Corrupt the AEAD tag of the first segment.
+        gro[segment_size - 1] = !gro[segment_size - 1];
 
-        // Server accepts connection.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(
-            &scid,
-            Some(&odcid),
-            testing::Pipe::server_addr(),
-            from,
-            &mut config,
-        )
-        .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let recv_count = pipe.server.recv_count;
 
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.recv_segments(&mut gro, segment_size, recv_info),
+            Ok(gro.len())
+        );
+        assert_eq!(pipe.server.recv_count, recv_count + flight.len() - 1);
+        assert!(!pipe.server.is_closed());
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.server.is_established());
+        // The data following the dropped segment can't be read until it is
+        // retransmitted.
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Err(Error::Done));
+
+        assert_eq!(
+            pipe.server.recv_segments(&mut gro, 0, recv_info),
+            Err(Error::BufferTooShort)
+        );
     }
 
     #[test]
-    fn missing_retry_source_connection_id() {
-        let mut buf = [0; 65535];
+    fn send_batch() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(100_000);
+        config.set_initial_max_stream_data_bidi_local(100_000);
+        config.set_initial_max_stream_data_bidi_remote(100_000);
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let data = vec![0xba; 10_000];
+        assert_eq!(pipe.client.stream_send(4, &data, true), Ok(10_000));
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut out = vec![0; 65535];
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(
+            pipe.client.send_batch(&mut out, 0),
+            Err(Error::BufferTooShort)
+        );
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        // The number of segments is limited by the caller.
+        let (written, segment_size, info) =
+            pipe.client.send_batch(&mut out, 2).unwrap();
 
-        let token = b"quiche test retry token";
+        assert_eq!(segment_size, pipe.client.max_send_udp_payload_size());
+        assert_eq!(written, segment_size * 2);
+        assert_eq!(info.from, testing::Pipe::client_addr());
+        assert_eq!(info.to, testing::Pipe::server_addr());
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        let recv_info = RecvInfo {
+            to: info.to,
+            from: info.from,
+            ecn: info.ecn,
+        };
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(
+            pipe.server.recv_segments(
+                &mut out[..written],
+                segment_size,
+                recv_info
+            ),
+            Ok(written)
+        );
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Send the rest of the data, possibly over several batches depending
+        // on pacing.
+        loop {
+            let (written, segment_size, _) =
+                match pipe.client.send_batch(&mut out, 64) {
+                    Ok(v) => v,
 
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is ignored.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server =
-            accept(&scid, None, testing::Pipe::server_addr(), from, &mut config)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+                    Err(Error::Done) => break,
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+                    Err(e) => panic!("send_batch failed: {:?}", e),
+                };
 
-        assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
-        );
+            assert!(written <= segment_size * 64);
+
+            assert_eq!(
+                pipe.server.recv_segments(
+                    &mut out[..written],
+                    segment_size,
+                    recv_info
+                ),
+                Ok(written)
+            );
+        }
+
+        let mut b = vec![0; 20_000];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((10_000, true)));
+        assert_eq!(&b[..10_000], &data[..]);
+
+        assert_eq!(pipe.client.send_batch(&mut out, 64), Err(Error::Done));
     }
 
     #[test]
-    fn invalid_retry_source_connection_id() {
+    fn recv_empty_buffer() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
+    fn stream_limit_update_bidi() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -12127,416 +16272,488 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let token = b"quiche test retry token";
+        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Server sends stream data, with fin.
+        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is invalid.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        let odcid = ConnectionId::from_ref(b"bogus value");
-        pipe.server = accept(
-            &scid,
-            Some(&odcid),
-            testing::Pipe::server_addr(),
-            from,
-            &mut config,
-        )
-        .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
-        );
-    }
+        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
 
-    fn check_send(_: &mut impl Send) {}
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    fn config_must_be_send() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_send(&mut config);
-    }
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    fn connection_must_be_send() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        check_send(&mut pipe.client);
-    }
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    fn check_sync(_: &mut impl Sync) {}
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    fn config_must_be_sync() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_sync(&mut config);
-    }
+        assert_eq!(
+            pipe.client.stream_send(20, b"a", false),
+            Err(Error::StreamLimit)
+        );
 
-    #[test]
-    fn connection_must_be_sync() {
-        let mut pipe = testing::Pipe::new().unwrap();
-        check_sync(&mut pipe.client);
+        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    fn data_blocked() {
-        let mut buf = [0; 65535];
+    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
+    fn stream_limit_update_uni() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(0);
+        config.set_initial_max_streams_uni(3);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, Some(30));
+        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(2, &mut b).unwrap();
+        pipe.server.stream_recv(6, &mut b).unwrap();
 
-        let mut iter = frames.iter();
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
-            })
+            pipe.client.stream_send(22, b"a", false),
+            Err(Error::StreamLimit)
         );
 
-        assert_eq!(iter.next(), None);
+        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    fn stream_data_blocked() {
-        let mut buf = [0; 65535];
-
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, and that the buffer becomes readable on the other
+    /// side.
+    fn stream_zero_length_fin() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut iter = frames.iter();
-
-        // Skip ACK frame.
-        iter.next();
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
-                stream_id: 0,
-                limit: 15,
-            })
-        );
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            })
-        );
-
-        assert_eq!(iter.next(), None);
-
-        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
-        // again.
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
-
-        let mut iter = frames.iter();
+        // Stream should be readable on the server after receiving empty fin.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            })
-        );
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(iter.next(), None);
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Send again from blocked stream and make sure it is not marked as
-        // blocked again.
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaa", false),
-            Err(Error::Done)
-        );
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    fn stream_data_blocked_unblocked_flow_control() {
-        let mut buf = [0; 65535];
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, that the buffer becomes readable on the other
+    /// side and stays readable even if the stream is fin'd locally.
+    fn stream_zero_length_fin_deferred_collection() {
         let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaah", false),
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
             Ok(15)
         );
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
         assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        // Send again on blocked stream. It's blocked at the same offset as
-        // previously, so it should not be marked as blocked again.
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        // No matter how many times we try to write stream data tried, no
-        // packets containing STREAM_BLOCKED should be emitted.
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        // Server sends zero-length frame.
+        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Now read some data at the server to release flow control.
+        // Stream should be readable on the server after receiving empty fin.
         let mut r = pipe.server.readable();
         assert_eq!(r.next(), Some(0));
-        assert_eq!(r.next(), None);
+        assert!(r.next().is_none());
 
-        let mut b = [0; 10];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, false)));
-        assert_eq!(&b[..10], b"aaaaaaaaaa");
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"hhhhhhhhhh!", false), Ok(10));
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+        // Stream _is_readable on the client side.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
 
-        let mut iter = frames.iter();
+        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
-                stream_id: 0,
-                limit: 25,
-            })
-        );
+        // Stream is completed and _is not_ readable.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+    }
 
-        // don't care about remaining received frames
+    #[test]
+    /// Tests that the stream gets created with stream_send() even if there's
+    /// no data in the buffer and the fin flag is not set.
+    fn stream_zero_length_non_fin() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"!", false), Err(Error::Done));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
+
+        // The stream now should have been created.
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Sending an empty non-fin should not change any stream state on the
+        // other side.
+        let mut r = pipe.server.readable();
+        assert!(r.next().is_none());
     }
 
     #[test]
-    fn app_limited_true() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+    /// Tests that completed streams are garbage collected.
+    fn collect_streams() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
+        assert!(!pipe.client.stream_finished(0));
+        assert!(!pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        let mut b = [0; 5];
         pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends stream data smaller than cwnd.
-        let send_buf = [0; 10000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
+        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // app_limited should be true because we send less than cwnd.
-        assert!(pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
-
-    #[test]
-    fn app_limited_false() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+        assert!(!pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        let mut b = [0; 5];
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert!(pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
 
-        testing::emit_flight(&mut pipe.server).ok();
+        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
     }
 
     #[test]
-    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited() {
+    fn config_set_cc_algorithm_name() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
+
+        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+
+        // Unknown name.
+        assert_eq!(
+            config.set_cc_algorithm_name("???"),
+            Err(Error::CongestionControl)
+        );
+    }
+
+    #[test]
+    fn peer_cert() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        match pipe.client.peer_cert() {
+            Some(c) => assert_eq!(c.len(), 753),
+
+            None => panic!("missing server certificate"),
+        }
+    }
+
+    #[test]
+    fn peer_cert_chain() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data bigger than cwnd (it will never arrive to the
-        // server).
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
+        match pipe.client.peer_cert_chain() {
+            Some(c) => assert_eq!(c.len(), 5),
 
-        testing::emit_flight(&mut pipe.client).ok();
+            None => panic!("missing server certificate chain"),
+        }
+    }
+
+    #[test]
+    fn new_token() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_new_token_generator(|peer| format!("{peer}").into_bytes());
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client receives the token once the handshake completes.
+        let token = format!("{}", testing::Pipe::client_addr()).into_bytes();
+
+        assert_eq!(pipe.client.new_token_next(), Some(token.clone()));
+        assert_eq!(pipe.client.new_token_next(), None);
+
+        // Servers can't receive NEW_TOKEN frames.
+        let frames = [frame::Frame::NewToken {
+            token: token.clone(),
+        }];
 
-        // Server sends some stream data that will need ACKs.
         assert_eq!(
-            pipe.server.stream_send(1, &send_buf1[..500], false),
-            Ok(500)
+            pipe.send_pkt_to_server(packet::Type::Short, &frames, &mut buf),
+            Err(Error::InvalidPacket)
         );
 
-        testing::process_flight(
-            &mut pipe.client,
-            testing::emit_flight(&mut pipe.server).unwrap(),
+        // A new connection sends the token in its Initial packet.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.server.set_token(&token), Err(Error::InvalidState));
+        assert_eq!(pipe.client.set_token(&token), Ok(()));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(hdr.token, Some(token.clone()));
+
+        // The token can't be changed once packets were sent.
+        assert_eq!(pipe.client.set_token(&token), Err(Error::InvalidState));
+    }
+
+    #[test]
+    fn peer_address_verified() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        assert_eq!(
+            pipe.client.set_peer_address_verified(),
+            Err(Error::InvalidState)
+        );
+
+        assert!(
+            !pipe
+                .server
+                .paths
+                .get_active()
+                .unwrap()
+                .verified_peer_address
+        );
+        assert_eq!(pipe.server.set_peer_address_verified(), Ok(()));
+        assert!(
+            pipe.server
+                .paths
+                .get_active()
+                .unwrap()
+                .verified_peer_address
+        );
+
+        assert_eq!(pipe.handshake(), Ok(()));
+    }
+
+    #[test]
+    fn stateless_reset() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+
+        let reset_key = [0xab; 32];
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        config.set_stateless_reset_token(Some(stateless_reset_token(
+            &scid, &reset_key,
+        )));
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        pipe.server = accept(
+            &scid,
+            None,
+            testing::Pipe::server_addr(),
+            testing::Pipe::client_addr(),
+            &mut config,
         )
         .unwrap();
 
-        let mut buf = [0; 2000];
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let ret = pipe.client.send(&mut buf);
+        // Server lost the connection state and replies to a client packet
+        // with a stateless reset.
+        let len =
+            super::stateless_reset(&scid, &reset_key, 1200, &mut buf).unwrap();
 
-        assert_eq!(pipe.client.tx_cap, 0);
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert!(pipe.client.is_closed());
 
-        assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+        // A reset derived from a different key isn't recognized.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        pipe.server = accept(
+            &scid,
+            None,
+            testing::Pipe::server_addr(),
+            testing::Pipe::client_addr(),
+            &mut config,
+        )
+        .unwrap();
 
-        let (sent, _) = ret.unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_ne!(sent, 0, "the client should at least send a pure ACK packet");
+        let len =
+            super::stateless_reset(&scid, &[0xac; 32], 1200, &mut buf).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
-        assert_eq!(1, frames.len());
-        assert!(
-            matches!(frames[0], frame::Frame::ACK { .. }),
-            "the packet sent by the client must be an ACK only packet"
-        );
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert!(!pipe.client.is_closed());
     }
 
-    /// Like sends_ack_only_pkt_when_full_cwnd_and_ack_elicited, but when
-    /// ack_eliciting is explicitly requested.
     #[test]
-    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited_despite_max_unacknowledging(
-    ) {
+    fn retry() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -12547,249 +16764,1205 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Client sends stream data bigger than cwnd (it will never arrive to the
-        // server). This exhausts the congestion window.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        testing::emit_flight(&mut pipe.client).ok();
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        // Client gets PING frames from server, which elicit ACK
-        let mut buf = [0; 2000];
-        for _ in 0..recovery::MAX_OUTSTANDING_NON_ACK_ELICITING {
-            let written = testing::encode_pkt(
-                &mut pipe.server,
-                packet::Type::Short,
-                &[frame::Frame::Ping],
-                &mut buf,
-            )
-            .unwrap();
+        let odcid = hdr.dcid.clone();
 
-            pipe.client_recv(&mut buf[..written])
-                .expect("client recv ping");
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-            // Client acknowledges despite a full congestion window
-            let ret = pipe.client.send(&mut buf);
+        let token = b"quiche test retry token";
 
-            assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
 
-            let (sent, _) = ret.unwrap();
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-            assert_ne!(
-                sent, 0,
-                "the client should at least send a pure ACK packet"
-            );
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-            let frames =
-                testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(&hdr.token.unwrap(), token);
 
-            assert_eq!(1, frames.len());
+        // Server accepts connection.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server = accept(
+            &scid,
+            Some(&odcid),
+            testing::Pipe::server_addr(),
+            from,
+            &mut config,
+        )
+        .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-            assert!(
-                matches!(frames[0], frame::Frame::ACK { .. }),
-                "the packet sent by the client must be an ACK only packet"
-            );
-        }
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // The client shouldn't need to send any more packets after the ACK only
-        // packet it just sent.
-        assert_eq!(
-            pipe.client.send(&mut buf),
-            Err(Error::Done),
-            "nothing for client to send after ACK-only packet"
-        );
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
     }
 
     #[test]
-    fn app_limited_false_no_frame() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1405);
-        config.verify_peer(false);
+    fn retry_v2() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.version = PROTOCOL_VERSION_V2;
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V2);
+
+        let odcid = hdr.dcid.clone();
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let token = b"quiche test retry token";
+
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(&hdr.token.unwrap(), token);
+
+        // Server accepts connection.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server = accept(
+            &scid,
+            Some(&odcid),
+            testing::Pipe::server_addr(),
+            from,
+            &mut config,
+        )
+        .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
+    }
+
+    #[test]
+    fn missing_retry_source_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let token = b"quiche test retry token";
+
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is ignored.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server =
+            accept(&scid, None, testing::Pipe::server_addr(), from, &mut config)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert_eq!(
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
+        );
+    }
+
+    #[test]
+    fn invalid_retry_source_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let token = b"quiche test retry token";
+
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is invalid.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        let odcid = ConnectionId::from_ref(b"bogus value");
+        pipe.server = accept(
+            &scid,
+            Some(&odcid),
+            testing::Pipe::server_addr(),
+            from,
+            &mut config,
+        )
+        .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert_eq!(
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
+        );
+    }
+
+    fn check_send(_: &mut impl Send) {}
+
+    #[test]
+    fn config_must_be_send() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_send(&mut config);
+    }
+
+    #[test]
+    fn connection_must_be_send() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        check_send(&mut pipe.client);
+    }
+
+    fn check_sync(_: &mut impl Sync) {}
+
+    #[test]
+    fn config_must_be_sync() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_sync(&mut config);
+    }
+
+    #[test]
+    fn connection_must_be_sync() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        check_sync(&mut pipe.client);
+    }
+
+    #[test]
+    fn data_blocked() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, Some(30));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.blocked_limit, None);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
+            })
+        );
+
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn stream_data_blocked() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
+            })
+        );
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            })
+        );
+
+        assert_eq!(iter.next(), None);
+
+        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
+        // again.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            })
+        );
+
+        assert_eq!(iter.next(), None);
+
+        // Send again from blocked stream and make sure it is not marked as
+        // blocked again.
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaa", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn stream_data_blocked_unblocked_flow_control() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaah", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        // Send again on blocked stream. It's blocked at the same offset as
+        // previously, so it should not be marked as blocked again.
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        // No matter how many times we try to write stream data tried, no
+        // packets containing STREAM_BLOCKED should be emitted.
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+
+        assert_eq!(pipe.client.stream_send(0, b"h", false), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+
+        // Now read some data at the server to release flow control.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 10];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, false)));
+        assert_eq!(&b[..10], b"aaaaaaaaaa");
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hhhhhhhhhh!", false), Ok(10));
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 25,
+            })
+        );
+
+        // don't care about remaining received frames
+
+        assert_eq!(pipe.client.stream_send(0, b"!", false), Err(Error::Done));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn app_limited_true() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends stream data smaller than cwnd.
+        let send_buf = [0; 10000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // app_limited should be true because we send less than cwnd.
+        assert!(pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn app_limited_false() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.server).ok();
+
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data bigger than cwnd (it will never arrive to the
+        // server).
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.client).ok();
+
+        // Server sends some stream data that will need ACKs.
+        assert_eq!(
+            pipe.server.stream_send(1, &send_buf1[..500], false),
+            Ok(500)
+        );
+
+        testing::process_flight(
+            &mut pipe.client,
+            testing::emit_flight(&mut pipe.server).unwrap(),
+        )
+        .unwrap();
+
+        let mut buf = [0; 2000];
+
+        let ret = pipe.client.send(&mut buf);
+
+        assert_eq!(pipe.client.tx_cap, 0);
+
+        assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+
+        let (sent, _) = ret.unwrap();
+
+        assert_ne!(sent, 0, "the client should at least send a pure ACK packet");
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
+        assert_eq!(1, frames.len());
+        assert!(
+            matches!(frames[0], frame::Frame::ACK { .. }),
+            "the packet sent by the client must be an ACK only packet"
+        );
+    }
+
+    /// Like sends_ack_only_pkt_when_full_cwnd_and_ack_elicited, but when
+    /// ack_eliciting is explicitly requested.
+    #[test]
+    fn sends_ack_only_pkt_when_full_cwnd_and_ack_elicited_despite_max_unacknowledging(
+    ) {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data bigger than cwnd (it will never arrive to the
+        // server). This exhausts the congestion window.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.client.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.client).ok();
+
+        // Client gets PING frames from server, which elicit ACK
+        let mut buf = [0; 2000];
+        for _ in 0..recovery::MAX_OUTSTANDING_NON_ACK_ELICITING {
+            let written = testing::encode_pkt(
+                &mut pipe.server,
+                packet::Type::Short,
+                &[frame::Frame::Ping { mtu_probe: None }],
+                &mut buf,
+            )
+            .unwrap();
+
+            pipe.client_recv(&mut buf[..written])
+                .expect("client recv ping");
+
+            // Client acknowledges despite a full congestion window
+            let ret = pipe.client.send(&mut buf);
+
+            assert!(matches!(ret, Ok((_, _))), "the client should at least send one packet to acknowledge the newly received data");
+
+            let (sent, _) = ret.unwrap();
+
+            assert_ne!(
+                sent, 0,
+                "the client should at least send a pure ACK packet"
+            );
+
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf[..sent]).unwrap();
+
+            assert_eq!(1, frames.len());
+
+            assert!(
+                matches!(frames[0], frame::Frame::ACK { .. }),
+                "the packet sent by the client must be an ACK only packet"
+            );
+        }
+
+        // The client shouldn't need to send any more packets after the ACK only
+        // packet it just sent.
+        assert_eq!(
+            pipe.client.send(&mut buf),
+            Err(Error::Done),
+            "nothing for client to send after ACK-only packet"
+        );
+    }
+
+    #[test]
+    fn app_limited_false_no_frame() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1405);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.server).ok();
+
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn app_limited_false_no_header() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1406);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.server).ok();
+
+        // We can't create a new frame because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert!(!pipe
+            .server
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn app_limited_not_changed_on_no_new_frames() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client's app_limited is true because its bytes-in-flight
+        // is much smaller than the current cwnd.
+        assert!(pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+
+        // Client has no new frames to send - returns Done.
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
+
+        // Client's app_limited should remain the same.
+        assert!(pipe
+            .client
+            .paths
+            .get_active()
+            .expect("no active")
+            .recovery
+            .app_limited());
+    }
+
+    #[test]
+    fn limit_ack_ranges() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let epoch = packet::Epoch::Application;
+
+        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
+
+        let frames = [
+            frame::Frame::Ping { mtu_probe: None },
+            frame::Frame::Padding { len: 3 },
+        ];
+
+        let pkt_type = packet::Type::Short;
+
+        let mut last_packet_sent = 0;
+
+        for _ in 0..512 {
+            let recv_count = pipe.server.recv_count;
+
+            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
+
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+                .unwrap();
+
+            assert_eq!(pipe.server.recv_count, recv_count + 1);
+
+            // Skip packet number.
+            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
+        }
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
+            MAX_ACK_RANGES
+        );
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
+            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
+        );
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
+            Some(last_packet_sent)
+        );
+    }
+
+    #[test]
+    /// Tests that streams are correctly scheduled based on their priority.
+    fn stream_priority() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 1];
+
+        let out = [b'b'; 500];
+
+        // Server prioritizes streams as follows:
+        //  * Stream 8 and 16 have the same priority but are non-incremental.
+        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
+        //    and 4 and 12 are incremental.
+        //  * Stream 0 is on its own.
+
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
+
+        pipe.server.stream_recv(16, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
+
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
+
+        pipe.server.stream_recv(20, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
+
+        // First is stream 8.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        // Then is stream 16.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        // Then is stream 20.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
+
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
+
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        // Then are stream 12 and 4, with the same priority, incrementally.
+        let mut off = 0;
+
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+            assert_eq!(
+                frames.first(),
+                Some(&frame::Frame::Stream {
+                    stream_id: 12,
+                    data: stream::RangeBuf::from(&out, off, false),
+                })
+            );
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-        testing::emit_flight(&mut pipe.server).ok();
+            let stream = frames.first().unwrap();
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-    #[test]
-    fn app_limited_false_no_header() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1406);
-        config.verify_peer(false);
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+                _ => unreachable!(),
+            };
+        }
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Final is stream 0.
+        let mut off = 0;
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let stream = frames.first().unwrap();
 
-        testing::emit_flight(&mut pipe.server).ok();
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        // We can't create a new frame because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert!(!pipe
-            .server
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
+
+                _ => unreachable!(),
+            };
+        }
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    fn app_limited_not_changed_on_no_new_frames() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+    /// Tests that changing a stream's priority is correctly propagated.
+    fn stream_reprioritize() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client's app_limited is true because its bytes-in-flight
-        // is much smaller than the current cwnd.
-        assert!(pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-
-        // Client has no new frames to send - returns Done.
-        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
-
-        // Client's app_limited should remain the same.
-        assert!(pipe
-            .client
-            .paths
-            .get_active()
-            .expect("no active")
-            .recovery
-            .app_limited());
-    }
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    fn limit_ack_ranges() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut pipe = testing::Pipe::new().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut b = [0; 1];
 
-        let epoch = packet::Epoch::Application;
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, b"b", false).unwrap();
 
-        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, b"b", false).unwrap();
 
-        let frames = [frame::Frame::Ping, frame::Frame::Padding { len: 3 }];
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
+        pipe.server.stream_send(8, b"b", false).unwrap();
 
-        let pkt_type = packet::Type::Short;
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, b"b", false).unwrap();
 
-        let mut last_packet_sent = 0;
+        // Stream 0 is re-prioritized!!!
+        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
 
-        for _ in 0..512 {
-            let recv_count = pipe.server.recv_count;
+        // First is stream 8.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-                .unwrap();
+        assert_eq!(
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
 
-            assert_eq!(pipe.server.recv_count, recv_count + 1);
+        // Then is stream 0.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-            // Skip packet number.
-            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
-        }
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
 
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
-            MAX_ACK_RANGES
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
 
+        // Then are stream 12 and 4, with the same priority.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
-            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
 
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
-            Some(last_packet_sent)
+            frames.first(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that streams are correctly scheduled based on their priority.
-    fn stream_priority() {
+    /// Tests that streams and datagrams are correctly scheduled.
+    fn stream_datagram_priority() {
         // Limit 1-RTT packet size to avoid congestion control interference.
         const MAX_TEST_PACKET_SIZE: usize = 540;
 
@@ -12811,38 +17984,27 @@ mod tests {
         config.set_initial_max_stream_data_uni(0);
         config.set_initial_max_streams_bidi(100);
         config.set_initial_max_streams_uni(0);
+        config.enable_dgram(true, 10, 10);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
         let mut b = [0; 1];
 
         let out = [b'b'; 500];
 
-        // Server prioritizes streams as follows:
-        //  * Stream 8 and 16 have the same priority but are non-incremental.
-        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
-        //    and 4 and 12 are incremental.
-        //  * Stream 0 is on its own.
+        // Server prioritizes Stream 0 and 4 with the same urgency with
+        // incremental, meaning the frames should be sent in round-robin
+        // fashion. It also sends DATAGRAMS which are always interleaved with
+        // STREAM frames. So we'll expect a mix of frame types regardless
+        // of the order that the application writes things in.
 
         pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
@@ -12850,177 +18012,277 @@ mod tests {
         pipe.server.stream_send(0, &out, false).unwrap();
         pipe.server.stream_send(0, &out, false).unwrap();
 
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
-
-        pipe.server.stream_recv(16, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
-
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
         pipe.server.stream_send(4, &out, false).unwrap();
         pipe.server.stream_send(4, &out, false).unwrap();
         pipe.server.stream_send(4, &out, false).unwrap();
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
-
-        pipe.server.stream_recv(20, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
+        for _ in 1..=6 {
+            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
+        }
 
-        // First is stream 8.
-        let mut off = 0;
+        let mut off_0 = 0;
+        let mut off_4 = 0;
 
         for _ in 1..=3 {
+            // DATAGRAM
             let (len, _) =
                 pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
             let frames =
                 testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+            let mut frame_iter = frames.iter();
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(&out, off, false),
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
             });
+            assert_eq!(frame_iter.next(), None);
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
-
-                _ => unreachable!(),
-            };
-        }
-
-        // Then is stream 16.
-        let mut off = 0;
-
-        for _ in 1..=3 {
+            // STREAM 0
             let (len, _) =
                 pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
             let frames =
                 testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
 
             assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(&out, off, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off_0, false),
             });
 
-            off = match stream {
+            off_0 = match stream {
                 frame::Frame::Stream { data, .. } => data.max_off(),
 
                 _ => unreachable!(),
             };
-        }
+            assert_eq!(frame_iter.next(), None);
 
-        // Then is stream 20.
-        let mut off = 0;
+            // DATAGRAM
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        for _ in 1..=3 {
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+            let mut frame_iter = frames.iter();
+
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
+            });
+            assert_eq!(frame_iter.next(), None);
+
+            // STREAM 4
             let (len, _) =
                 pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
             let frames =
                 testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
 
             assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(&out, off, false),
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off_4, false),
             });
 
-            off = match stream {
+            off_4 = match stream {
                 frame::Frame::Stream { data, .. } => data.max_off(),
 
                 _ => unreachable!(),
             };
+            assert_eq!(frame_iter.next(), None);
         }
+    }
 
-        // Then are stream 12 and 4, with the same priority, incrementally.
-        let mut off = 0;
+    #[test]
+    /// Tests that old data is retransmitted on PTO.
+    fn early_retransmit() {
+        let mut buf = [0; 65535];
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_eq!(
-                frames.first(),
-                Some(&frame::Frame::Stream {
-                    stream_id: 12,
-                    data: stream::RangeBuf::from(&out, off, false),
-                })
-            );
+        // Client sends more stream data, but packet is lost
+        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
+        assert!(pipe.client.send(&mut buf).is_ok());
 
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        pipe.client.on_timeout();
 
-            let stream = frames.first().unwrap();
+        let epoch = packet::Epoch::Application;
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            1,
+        );
 
-            assert_eq!(stream, &frame::Frame::Stream {
+        // Client retransmits stream data in PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            0,
+        );
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
                 stream_id: 4,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
+        assert_eq!(pipe.client.stats().retrans, 1);
+    }
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+    #[test]
+    /// Tests that PTO probe packets are not coalesced together.
+    fn dont_coalesce_probes() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        // Client sends Initial packet.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        let epoch = packet::Epoch::Initial;
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            1,
+        );
+
+        // Client sends PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            0,
+        );
+
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+
+        pipe.client.on_timeout();
+
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            2,
+        );
+
+        // Client sends first PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            1,
+        );
+
+        // Client sends second PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(
+            pipe.client
+                .paths
+                .get_active()
+                .expect("no active")
+                .recovery
+                .loss_probes[epoch],
+            0,
+        );
+    }
 
-                _ => unreachable!(),
-            };
-        }
+    #[test]
+    fn coalesce_padding_short() {
+        let mut buf = [0; 65535];
 
-        // Final is stream 0.
-        let mut off = 0;
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        // Client sends first flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let stream = frames.first().unwrap();
+        // Server sends first flight.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        // Client sends stream data.
+        assert!(pipe.client.is_established());
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
 
-                _ => unreachable!(),
-            };
-        }
+        // Client sends second flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        // None of the sent packets should have been dropped.
+        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
+        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
     }
 
     #[test]
-    /// Tests that changing a stream's priority is correctly propagated.
-    fn stream_reprioritize() {
+    /// Tests that client avoids handshake deadlock by arming PTO.
+    fn handshake_anti_deadlock() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
             .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
@@ -13028,529 +18290,660 @@ mod tests {
         config
             .set_application_protos(&[b"proto1", b"proto2"])
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(5);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(!pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(!pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server receives client's Initial and sends own Initial and Handshake
+        // until it's blocked by the anti-amplification limit.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert!(!pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
 
-        let mut b = [0; 1];
+        // Client receives the server flight and sends Handshake ACK, but it is
+        // lost.
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+        testing::emit_flight(&mut pipe.client).unwrap();
 
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, b"b", false).unwrap();
+        assert!(pipe.client.handshake_status().has_handshake_keys);
+        assert!(!pipe.client.handshake_status().peer_verified_address);
+        assert!(pipe.server.handshake_status().has_handshake_keys);
+        assert!(pipe.server.handshake_status().peer_verified_address);
 
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, b"b", false).unwrap();
+        // Make sure client's PTO timer is armed.
+        assert!(pipe.client.timeout().is_some());
+    }
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
-        pipe.server.stream_send(8, b"b", false).unwrap();
+    #[test]
+    /// Tests that packets with corrupted type (from Handshake to Initial) are
+    /// properly ignored.
+    fn handshake_packet_type_corruption() {
+        let mut buf = [0; 65535];
 
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
-        pipe.server.stream_send(4, b"b", false).unwrap();
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        // Stream 0 is re-prioritized!!!
-        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        // First is stream 8.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        // Server receives client's Initial and sends own Initial and Handshake.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        // Client sends Initial packet with ACK.
+        let active_pid =
+            pipe.client.paths.get_active_path_id().expect("no active");
+        let (ty, len) = pipe
+            .client
+            .send_single(
+                &mut buf,
+                active_pid,
+                false,
+                false,
+                None,
+                time::Instant::now(),
+            )
+            .unwrap();
+        assert_eq!(ty, Type::Initial);
 
-        // Then is stream 0.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        // Client sends Handshake packet.
+        let (ty, len) = pipe
+            .client
+            .send_single(
+                &mut buf,
+                active_pid,
+                false,
+                false,
+                None,
+                time::Instant::now(),
+            )
+            .unwrap();
+        assert_eq!(ty, Type::Handshake);
+
+        // Packet type is corrupted to Initial.
+        buf[0] &= !(0x20);
+
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, Type::Initial);
+
+        // Server receives corrupted packet without returning an error.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+    }
+
+    #[test]
+    fn dgram_send_fails_invalidstate() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
+            pipe.client.dgram_send(b"hello, world"),
+            Err(Error::InvalidState)
         );
+    }
 
-        // Then are stream 12 and 4, with the same priority.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+    #[test]
+    fn ecn_disabled() {
+        let mut pipe = testing::Pipe::new().unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight.iter().all(|(_, si)| si.ecn == Ecn::NotEct));
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
+            pipe.client.paths.get_active().unwrap().recovery.ecn_state(),
+            recovery::ecn::EcnState::Failed
         );
+    }
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+    #[test]
+    fn ecn_validation() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_ecn(true);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight.iter().all(|(_, si)| si.ecn == Ecn::Ect0));
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
         assert_eq!(
-            frames.first(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
+            pipe.client.paths.get_active().unwrap().recovery.ecn_state(),
+            recovery::ecn::EcnState::Capable
+        );
+        assert_eq!(
+            pipe.server.paths.get_active().unwrap().recovery.ecn_state(),
+            recovery::ecn::EcnState::Capable
         );
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+
+        let (_, si) = pipe.client.send(&mut [0; 1350]).unwrap();
+        assert_eq!(si.ecn, Ecn::Ect0);
     }
 
     #[test]
-    /// Tests that streams and datagrams are correctly scheduled.
-    fn stream_datagram_priority() {
-        // Limit 1-RTT packet size to avoid congestion control interference.
-        const MAX_TEST_PACKET_SIZE: usize = 540;
+    fn ecn_validation_bleached() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_ecn(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        // The path clears the ECN codepoint of all packets.
+        while !pipe.client.is_established() || !pipe.server.is_established() {
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            flight.iter_mut().for_each(|(_, si)| si.ecn = Ecn::NotEct);
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            let mut flight = testing::emit_flight(&mut pipe.server).unwrap();
+            flight.iter_mut().for_each(|(_, si)| si.ecn = Ecn::NotEct);
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
+
+        assert_eq!(
+            pipe.client.paths.get_active().unwrap().recovery.ecn_state(),
+            recovery::ecn::EcnState::Failed
+        );
+        assert_eq!(
+            pipe.server.paths.get_active().unwrap().recovery.ecn_state(),
+            recovery::ecn::EcnState::Failed
+        );
+
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
 
-        let mut buf = [0; 65535];
+        let (_, si) = pipe.client.send(&mut [0; 1350]).unwrap();
+        assert_eq!(si.ecn, Ecn::NotEct);
+    }
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
-        config.set_initial_max_data(1_000_000);
-        config.set_initial_max_stream_data_bidi_local(1_000_000);
-        config.set_initial_max_stream_data_bidi_remote(1_000_000);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(0);
-        config.enable_dgram(true, 10, 10);
-        config.verify_peer(false);
+    #[test]
+    fn ecn_ce_congestion_event() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.enable_ecn(true);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        let cwnd_prev = pipe.client.paths.get_active().unwrap().recovery.cwnd();
+
+        // The path marks the next packet with CE.
+        assert_eq!(pipe.client.stream_send(0, b"world", true), Ok(5));
+
+        let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert!(flight.iter().all(|(_, si)| si.ecn == Ecn::Ect0));
+        flight.iter_mut().for_each(|(_, si)| si.ecn = Ecn::Ce);
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::Epoch::Application]
+                .ecn_counts
+                .ecn_ce_count,
+            1
+        );
+
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 1];
+        let path = pipe.client.paths.get_active().unwrap();
+        assert_eq!(path.recovery.ecn_state(), recovery::ecn::EcnState::Capable);
+        assert!(path.recovery.cwnd() < cwnd_prev);
+    }
 
-        let out = [b'b'; 500];
+    #[test]
+    fn pmtud() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_max_recv_udp_payload_size(1500);
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
 
-        // Server prioritizes Stream 0 and 4 with the same urgency with
-        // incremental, meaning the frames should be sent in round-robin
-        // fashion. It also sends DATAGRAMS which are always interleaved with
-        // STREAM frames. So we'll expect a mix of frame types regardless
-        // of the order that the application writes things in.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1200);
 
-        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
+        // The client sends a probe once the handshake is confirmed.
 
-        for _ in 1..=6 {
-            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
-        }
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(flight.last().unwrap().0.len(), 1500);
 
-        let mut off_0 = 0;
-        let mut off_4 = 0;
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        for _ in 1..=3 {
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1500);
+        assert_eq!(pipe.client.path_stats().next().unwrap().pmtu, 1500);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
+        // The search is complete, no more probes are sent.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
 
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(flight.len(), 1);
+        assert!(flight[0].0.len() < 1500);
+    }
 
-            // STREAM 0
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+    #[test]
+    fn pmtud_probe_lost() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off_0, false),
-            });
+        let cwnd = pipe.client.paths.get_active().unwrap().recovery.cwnd();
 
-            off_0 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        // The path drops datagrams larger than 1400 bytes.
+        for _ in 0..50 {
+            assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
 
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            flight.retain(|(pkt, _)| pkt.len() <= 1400);
+            testing::process_flight(&mut pipe.server, flight).unwrap();
 
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+            let flight = testing::emit_flight(&mut pipe.server).unwrap();
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
+        let path = pipe.client.paths.get_active().unwrap();
+        assert_eq!(path.pmtud.state(), pmtud::PmtudState::SearchComplete);
+        assert_eq!(path.pmtud.current(), 1396);
+        assert_eq!(path.recovery.max_datagram_size(), 1396);
 
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
+        // Lost probes are not a congestion signal.
+        assert!(path.recovery.cwnd() >= cwnd);
+    }
 
-            // STREAM 4
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+    #[test]
+    fn pmtud_black_hole() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(&out, off_4, false),
-            });
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1500);
 
-            off_4 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        // The path starts dropping datagrams larger than the minimum size.
+        for _ in 0..10 {
+            assert_eq!(pipe.client.stream_send(0, &[0; 3000], false), Ok(3000));
 
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            flight.retain(|(pkt, _)| pkt.len() <= MIN_CLIENT_INITIAL_LEN);
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            let flight = testing::emit_flight(&mut pipe.server).unwrap();
+            testing::process_flight(&mut pipe.client, flight).unwrap();
         }
+
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1200);
+        assert_eq!(pipe.client.path_stats().next().unwrap().pmtu, 1200);
     }
 
     #[test]
-    /// Tests that old data is retransmitted on PTO.
-    fn early_retransmit() {
-        let mut buf = [0; 65535];
+    fn ack_frequency() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_min_ack_delay(1_000);
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(
+            pipe.server
+                .set_ack_frequency(2, time::Duration::from_millis(50), 0),
+            Ok(())
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends more stream data, but packet is lost
-        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
-        assert!(pipe.client.send(&mut buf).is_ok());
+        assert_eq!(
+            pipe.client.ack_freq.max_ack_delay(),
+            Some(time::Duration::from_millis(50))
+        );
 
-        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        // The packet carrying the ACK_FREQUENCY frame is already subject to
+        // it, so the client doesn't ACK it.
+        let epoch = packet::Epoch::Application;
+        assert_eq!(pipe.client.pkt_num_spaces[epoch].ack_eliciting_unacked, 1);
 
-        pipe.client.on_timeout();
+        // The client doesn't ACK the second packet either.
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Ok(1));
 
-        let epoch = packet::Epoch::Application;
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
+        assert!(
+            pipe.client.timeout().unwrap() <= time::Duration::from_millis(50)
         );
 
-        // Client retransmits stream data in PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // The third packet exceeds the threshold.
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Ok(1));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert_eq!(pipe.client.pkt_num_spaces[epoch].ack_eliciting_unacked, 0);
+        assert_eq!(pipe.client.pkt_num_spaces[epoch].ack_timer, None);
+
+        // The server now accounts for the requested delay.
         assert_eq!(
-            pipe.client
+            pipe.server
                 .paths
                 .get_active()
-                .expect("no active")
+                .unwrap()
                 .recovery
-                .loss_probes[epoch],
-            0,
+                .max_ack_delay,
+            time::Duration::from_millis(50)
         );
+    }
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf[..len]).unwrap();
-
-        let mut iter = frames.iter();
+    #[test]
+    fn ack_frequency_timeout() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_min_ack_delay(1_000);
 
-        // Skip ACK frame.
-        iter.next();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
+            pipe.server
+                .set_ack_frequency(10, time::Duration::from_millis(1), 0),
+            Ok(())
         );
-        assert_eq!(pipe.client.stats().retrans, 1);
-    }
+        assert_eq!(pipe.advance(), Ok(()));
 
-    #[test]
-    /// Tests that PTO probe packets are not coalesced together.
-    fn dont_coalesce_probes() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Ok(1));
 
-        let mut pipe = testing::Pipe::new().unwrap();
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        // Client sends Initial packet.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
 
-        // Wait for PTO to expire.
+        // The ACK is sent once the delayed ACK timer expires.
         let timer = pipe.client.timeout().unwrap();
         std::thread::sleep(timer + time::Duration::from_millis(1));
 
         pipe.client.on_timeout();
 
-        let epoch = packet::Epoch::Initial;
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
-        );
+        assert!(testing::emit_flight(&mut pipe.client).is_ok());
+    }
+
+    #[test]
+    fn immediate_ack() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_min_ack_delay(1_000);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            0,
+            pipe.server
+                .set_ack_frequency(10, time::Duration::from_millis(50), 0),
+            Ok(())
         );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Ok(1));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
 
-        pipe.client.on_timeout();
+        // IMMEDIATE_ACK forces the client to ACK right away.
+        assert_eq!(pipe.server.send_immediate_ack(), Ok(()));
 
-        assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            2,
-        );
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert!(testing::emit_flight(&mut pipe.client).is_ok());
+    }
+
+    #[test]
+    fn ack_frequency_not_negotiated() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends first PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
         assert_eq!(
             pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            1,
+                .set_ack_frequency(10, time::Duration::from_millis(50), 0),
+            Err(Error::InvalidState)
         );
+        assert_eq!(pipe.client.send_immediate_ack(), Err(Error::InvalidState));
 
-        // Client sends second PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        // The server didn't advertise the extension.
+        let frames = [frame::Frame::ImmediateAck];
+
+        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.client
-                .paths
-                .get_active()
-                .expect("no active")
-                .recovery
-                .loss_probes[epoch],
-            0,
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
         );
     }
 
     #[test]
-    fn coalesce_padding_short() {
+    fn ack_frequency_delay_too_low() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::new().unwrap();
-
-        // Client sends first flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-
-        // Server sends first flight.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_min_ack_delay(1_000);
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert!(pipe.client.is_established());
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        // The requested delay is lower than the server's min_ack_delay.
+        let frames = [frame::Frame::AckFrequency {
+            seq_num: 0,
+            ack_eliciting_threshold: 1,
+            request_max_ack_delay: 500,
+            reordering_threshold: 0,
+        }];
 
-        // Client sends second flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
+        );
+    }
 
-        // None of the sent packets should have been dropped.
-        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
-        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
+    fn flight_spin_bits(
+        flight: &mut [(Vec<u8>, SendInfo)], dcid_len: usize,
+    ) -> Vec<bool> {
+        flight
+            .iter_mut()
+            .map(|(pkt, _)| Header::from_slice(pkt, dcid_len).unwrap().spin_bit)
+            .collect()
     }
 
     #[test]
-    /// Tests that client avoids handshake deadlock by arming PTO.
-    fn handshake_anti_deadlock() {
-        let mut buf = [0; 65535];
+    fn spin_bit() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_spin_bit(true);
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(&[b"proto1", b"proto2"])
-            .unwrap();
+        // Spinning is randomly disabled on some connections, so retry until
+        // it's enabled on both sides.
+        let mut pipe = loop {
+            let pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+            if pipe.client.spin_bit && pipe.server.spin_bit {
+                break pipe;
+            }
+        };
 
-        assert!(!pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(!pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends padded Initial.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        let client_dcid_len = pipe.server.source_id().len();
+        let server_dcid_len = pipe.client.source_id().len();
 
-        // Server receives client's Initial and sends own Initial and Handshake
-        // until it's blocked by the anti-amplification limit.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let mut prev_spin: Option<bool> = None;
 
-        assert!(!pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+        for _ in 0..4 {
+            assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
 
-        // Client receives the server flight and sends Handshake ACK, but it is
-        // lost.
-        testing::process_flight(&mut pipe.client, flight).unwrap();
-        testing::emit_flight(&mut pipe.client).unwrap();
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            let spin = flight_spin_bits(&mut flight, client_dcid_len)[0];
 
-        assert!(pipe.client.handshake_status().has_handshake_keys);
-        assert!(!pipe.client.handshake_status().peer_verified_address);
-        assert!(pipe.server.handshake_status().has_handshake_keys);
-        assert!(pipe.server.handshake_status().peer_verified_address);
+            // The client's spin value flips once per round-trip.
+            if let Some(prev_spin) = prev_spin {
+                assert_eq!(spin, !prev_spin);
+            }
 
-        // Make sure client's PTO timer is armed.
-        assert!(pipe.client.timeout().is_some());
+            prev_spin = Some(spin);
+
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            // The server reflects the client's spin value.
+            let mut flight = testing::emit_flight(&mut pipe.server).unwrap();
+            assert_eq!(flight_spin_bits(&mut flight, server_dcid_len), [spin]);
+
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
     }
 
     #[test]
-    /// Tests that packets with corrupted type (from Handshake to Initial) are
-    /// properly ignored.
-    fn handshake_packet_type_corruption() {
-        let mut buf = [0; 65535];
+    fn spin_bit_disabled() {
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let client_dcid_len = pipe.server.source_id().len();
+        let server_dcid_len = pipe.client.source_id().len();
+
+        let mut client_spin = None;
+        let mut server_spin = None;
 
+        // Without spinning, the spin bit is random but doesn't change as long
+        // as the same Destination CID is used.
+        for _ in 0..4 {
+            assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            let spin = flight_spin_bits(&mut flight, client_dcid_len)[0];
+            assert_eq!(*client_spin.get_or_insert(spin), spin);
+
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            let mut flight = testing::emit_flight(&mut pipe.server).unwrap();
+            let spin = flight_spin_bits(&mut flight, server_dcid_len)[0];
+            assert_eq!(*server_spin.get_or_insert(spin), spin);
+
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
+    }
+
+    #[test]
+    fn grease_quic_bit() {
         let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends padded Initial.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        assert!(pipe.client.peer_transport_params.grease_quic_bit);
+        assert!(pipe.server.peer_transport_params.grease_quic_bit);
 
-        // Server receives client's Initial and sends own Initial and Handshake.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let dcid_len = pipe.server.source_id().len();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        let recv_count = pipe.server.recv_count;
+        let mut fixed_bits = Vec::new();
 
-        // Client sends Initial packet with ACK.
-        let active_pid =
-            pipe.client.paths.get_active_path_id().expect("no active");
-        let (ty, len) = pipe
-            .client
-            .send_single(&mut buf, active_pid, false, time::Instant::now())
-            .unwrap();
-        assert_eq!(ty, Type::Initial);
+        for _ in 0..32 {
+            assert_eq!(pipe.client.send_ack_eliciting(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        // Client sends Handshake packet.
-        let (ty, len) = pipe
-            .client
-            .send_single(&mut buf, active_pid, false, time::Instant::now())
-            .unwrap();
-        assert_eq!(ty, Type::Handshake);
+            fixed_bits.extend(flight.iter_mut().map(|(pkt, _)| {
+                Header::from_slice(pkt, dcid_len).unwrap().fixed_bit
+            }));
 
-        // Packet type is corrupted to Initial.
-        buf[0] &= !(0x20);
+            testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
-        assert_eq!(hdr.ty, Type::Initial);
+            let flight = testing::emit_flight(&mut pipe.server).unwrap();
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
 
-        // Server receives corrupted packet without returning an error.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // Packets are still processed when the fixed bit is greased.
+        assert!(fixed_bits.contains(&false));
+        assert_eq!(pipe.server.recv_count, recv_count + fixed_bits.len());
     }
 
     #[test]
-    fn dgram_send_fails_invalidstate() {
-        let mut pipe = testing::Pipe::new().unwrap();
+    fn grease_quic_bit_not_advertised() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.grease(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(
-            pipe.client.dgram_send(b"hello, world"),
-            Err(Error::InvalidState)
-        );
+        assert!(!pipe.client.peer_transport_params.grease_quic_bit);
+        assert!(!pipe.server.peer_transport_params.grease_quic_bit);
+
+        // Force the client to grease the fixed bit.
+        pipe.client.peer_transport_params.grease_quic_bit = true;
+
+        let dcid_len = pipe.server.source_id().len();
+
+        let recv_count = pipe.server.recv_count;
+        let mut dropped = 0;
+
+        for _ in 0..32 {
+            assert_eq!(pipe.client.send_ack_eliciting(), Ok(()));
+
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+
+            dropped += flight
+                .iter_mut()
+                .map(|(pkt, _)| Header::from_slice(pkt, dcid_len).unwrap())
+                .filter(|hdr| !hdr.fixed_bit)
+                .count();
+
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+        }
+
+        // Packets with the fixed bit cleared are dropped.
+        assert!(dropped > 0);
+        assert_eq!(pipe.server.recv_count, recv_count + 32 - dropped);
     }
 
     #[test]
@@ -13659,8 +19052,129 @@ mod tests {
         let result1 = pipe.server.dgram_recv(&mut buf);
         assert_eq!(result1, Ok(12));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Err(Error::Done));
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Err(Error::Done));
+    }
+
+    #[test]
+    fn dgram_send_tracked_acked() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_dgram(true, 10, 10);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+
+        assert_eq!(pipe.client.dgram_send_tracked(b"hello, world"), Ok(0));
+        assert_eq!(pipe.client.dgram_send_vec_tracked(b"ciao".to_vec()), Ok(1));
+        assert_eq!(pipe.client.dgram_send(b"hola"), Ok(()));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(12));
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(4));
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(4));
+
+        // Only the tracked DATAGRAMs are reported.
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::DatagramAcked(0))
+        );
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::DatagramAcked(1))
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+    }
+
+    #[test]
+    fn dgram_send_tracked_lost() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_dgram(true, 10, 10);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+
+        // The packet carrying the first DATAGRAM is lost.
+        assert_eq!(pipe.client.dgram_send_tracked(b"hello, world"), Ok(0));
+        assert!(testing::emit_flight(&mut pipe.client).is_ok());
+
+        // Send enough packets after that for the first one to be declared lost.
+        for id in 1..=3 {
+            assert_eq!(pipe.client.dgram_send_tracked(b"ciao"), Ok(id));
+            assert_eq!(pipe.advance(), Ok(()));
+        }
+
+        let mut events = Vec::new();
+        while let Some(ev) = pipe.client.poll_event() {
+            events.push(ev);
+        }
+
+        // The loss might be detected before or after the last DATAGRAMs are
+        // acknowledged, depending on timing.
+        assert_eq!(events.len(), 4);
+        assert!(events.contains(&ConnectionEvent::DatagramLost(0)));
+
+        for id in 1..=3 {
+            assert!(events.contains(&ConnectionEvent::DatagramAcked(id)));
+        }
+    }
+
+    #[test]
+    fn dgram_send_tracked_queue_full() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.enable_dgram(true, 10, 2);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(ConnectionEvent::HandshakeCompleted)
+        );
+
+        // Four times the send queue length can be tracked until their
+        // delivery events are polled.
+        for id in 0..8 {
+            assert_eq!(pipe.client.dgram_send_tracked(b"hello"), Ok(id));
+            assert_eq!(pipe.advance(), Ok(()));
+        }
+
+        assert_eq!(pipe.client.dgram_send_tracked(b"hello"), Err(Error::Done));
+        assert_eq!(
+            pipe.client.dgram_send_vec_tracked(b"hello".to_vec()),
+            Err(Error::Done)
+        );
+
+        // Make sure the server acknowledges all the DATAGRAMs.
+        assert_eq!(pipe.server.send_ack_eliciting(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Every DATAGRAM gets exactly one delivery event.
+        let mut acked = Vec::new();
+        while let Some(ev) = pipe.client.poll_event() {
+            match ev {
+                ConnectionEvent::DatagramAcked(id) => acked.push(id),
+
+                ev => panic!("unexpected event {:?}", ev),
+            }
+        }
+
+        assert_eq!(acked, (0..8).collect::<Vec<_>>());
+
+        // DATAGRAMs can be tracked again once events are polled.
+        assert_eq!(pipe.client.dgram_send_tracked(b"hello"), Ok(8));
     }
 
     #[test]
@@ -14579,6 +20093,76 @@ mod tests {
         );
     }
 
+    struct TestCidGenerator {
+        next: std::sync::atomic::AtomicU8,
+    }
+
+    impl ConnectionIdGenerator for TestCidGenerator {
+        fn new_connection_id(&self) -> ConnectionId<'static> {
+            let n = self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+            ConnectionId::from_vec(vec![n; 16])
+        }
+
+        fn cid_len(&self) -> usize {
+            16
+        }
+
+        fn stateless_reset_token(&self, cid: &ConnectionId) -> u128 {
+            stateless_reset_token(cid, b"reset key")
+        }
+    }
+
+    #[test]
+    fn connection_id_generator() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_active_connection_id_limit(3);
+
+        let mut server_config = testing::Pipe::default_config().unwrap();
+        server_config.set_active_connection_id_limit(3);
+        server_config.set_connection_id_generator(TestCidGenerator {
+            next: std::sync::atomic::AtomicU8::new(1),
+        });
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The server issued new Connection IDs up to the limit on its own,
+        // while the client didn't.
+        assert_eq!(pipe.server.active_source_cids(), 3);
+        assert_eq!(pipe.server.source_cids_left(), 0);
+        assert_eq!(pipe.client.available_dcids(), 2);
+
+        assert_eq!(pipe.client.active_source_cids(), 1);
+        assert_eq!(pipe.server.available_dcids(), 0);
+
+        let ids: Vec<_> =
+            pipe.server.source_ids().map(|id| id.to_vec()).collect();
+        assert!(ids.contains(&vec![1; 16]));
+        assert!(ids.contains(&vec![2; 16]));
+
+        // Once the client retires a Connection ID, the server replaces it.
+        assert_eq!(pipe.client.retire_destination_cid(1), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.retired_scid_next(),
+            Some(ConnectionId::from_vec(vec![1; 16]))
+        );
+        assert_eq!(pipe.server.active_source_cids(), 3);
+        assert_eq!(pipe.client.available_dcids(), 2);
+
+        let ids: Vec<_> =
+            pipe.server.source_ids().map(|id| id.to_vec()).collect();
+        assert!(!ids.contains(&vec![1; 16]));
+        assert!(ids.contains(&vec![3; 16]));
+    }
+
     #[test]
     /// Exercices the handling of NEW_CONNECTION_ID and RETIRE_CONNECTION_ID
     /// frames.
@@ -15297,6 +20881,7 @@ mod tests {
         let ri = RecvInfo {
             to: si.to,
             from: si.from,
+            ecn: si.ecn,
         };
         assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
 
@@ -15338,6 +20923,7 @@ mod tests {
         let ri = RecvInfo {
             to: si.to,
             from: si.from,
+            ecn: si.ecn,
         };
         assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
 
@@ -15352,6 +20938,7 @@ mod tests {
         let ri = RecvInfo {
             to: si.to,
             from: si.from,
+            ecn: si.ecn,
         };
         assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
 
@@ -15367,6 +20954,7 @@ mod tests {
         let ri = RecvInfo {
             to: si.to,
             from: si.from,
+            ecn: si.ecn,
         };
         assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
 
@@ -15381,6 +20969,7 @@ mod tests {
         let ri = RecvInfo {
             to: si.to,
             from: si.from,
+            ecn: si.ecn,
         };
         assert_eq!(pipe.server.recv(&mut buf[..sent], ri), Ok(sent));
 
@@ -15421,6 +21010,237 @@ mod tests {
         assert_eq!(v1, v2);
     }
 
+    /// Creates a multipath connection where the client probed a second path.
+    fn multipath_pipe(config: &mut Config) -> testing::Pipe {
+        let mut pipe = pipe_with_exchanged_cids(config, 16, 16, 2);
+
+        assert!(pipe.client.is_multipath_enabled());
+        assert!(pipe.server.is_multipath_enabled());
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(pipe.client.probe_path(client_addr_2, server_addr), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr_2, server_addr))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, client_addr_2))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, client_addr_2))
+        );
+
+        pipe
+    }
+
+    #[test]
+    fn multipath_negotiation() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_multipath(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(pipe.client.is_multipath_enabled());
+        assert!(pipe.server.is_multipath_enabled());
+
+        // Multipath is only used if both endpoints support it.
+        let mut server_config = testing::Pipe::default_config().unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert!(!pipe.client.is_multipath_enabled());
+        assert!(!pipe.server.is_multipath_enabled());
+
+        // Multipath frames are invalid when it was not negotiated.
+        let frames = [frame::Frame::PathStatus {
+            dcid_seq_num: 0,
+            seq_num: 0,
+            status: PathStatus::Standby.to_wire(),
+        }];
+
+        let mut buf = [0; 65535];
+
+        assert_eq!(
+            pipe.send_pkt_to_server(packet::Type::Short, &frames, &mut buf),
+            Err(Error::InvalidState)
+        );
+    }
+
+    #[test]
+    fn multipath_send() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_multipath(true);
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(100_000);
+        config.set_initial_max_stream_data_bidi_remote(100_000);
+        config.set_path_scheduler(PathScheduler::RoundRobin);
+
+        let mut pipe = multipath_pipe(&mut config);
+
+        let client_addr = testing::Pipe::client_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        // Both paths are active.
+        assert_eq!(
+            pipe.client.paths.iter().filter(|(_, p)| p.active()).count(),
+            2
+        );
+        assert_eq!(
+            pipe.server.paths.iter().filter(|(_, p)| p.active()).count(),
+            2
+        );
+
+        let recv_count = |conn: &Connection, addr| {
+            conn.paths
+                .iter()
+                .find(|(_, p)| p.peer_addr() == addr)
+                .map(|(_, p)| p.recv_count)
+                .unwrap()
+        };
+
+        let recv_count_1 = recv_count(&pipe.server, client_addr);
+        let recv_count_2 = recv_count(&pipe.server, client_addr_2);
+
+        assert_eq!(pipe.client.stream_send(0, &[0; 10_000], true), Ok(10_000));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Data was sent on both paths.
+        assert!(recv_count(&pipe.server, client_addr) > recv_count_1);
+        assert!(recv_count(&pipe.server, client_addr_2) > recv_count_2);
+
+        let mut b = [0; 10_000];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10_000, true)));
+
+        // Each path uses its own packet number space.
+        let client_space = pipe.client.pkt_num_spaces.get_app(1).unwrap();
+        assert!(client_space.next_pkt_num > 0);
+
+        let server_space = pipe.server.pkt_num_spaces.get_app(1).unwrap();
+        assert!(server_space.largest_rx_pkt_num > 0);
+
+        // All packets were acknowledged using ACK_MP frames.
+        for (_, p) in pipe.client.paths.iter() {
+            assert_eq!(p.recovery.cwnd_available(), p.recovery.cwnd());
+        }
+
+        assert_eq!(pipe.client.stats().lost, 0);
+    }
+
+    #[test]
+    fn multipath_path_status() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_multipath(true);
+
+        let mut pipe = multipath_pipe(&mut config);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(
+            pipe.client.set_path_status(
+                client_addr_2,
+                server_addr,
+                PathStatus::Standby
+            ),
+            Ok(())
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let pid = pipe
+            .server
+            .paths
+            .path_id_from_addrs(&(server_addr, client_addr_2))
+            .unwrap();
+
+        assert_eq!(
+            pipe.server.paths.get(pid).unwrap().status(),
+            PathStatus::Standby
+        );
+
+        // Standby paths are not used when another path is available.
+        let recv_count = pipe.client.paths.get(pid).unwrap().recv_count;
+
+        assert_eq!(pipe.server.stream_send(1, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.paths.get(pid).unwrap().recv_count, recv_count);
+    }
+
+    #[test]
+    fn multipath_abandon() {
+        let mut config = testing::Pipe::default_config().unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_multipath(true);
+
+        let mut pipe = multipath_pipe(&mut config);
+
+        let server_addr = testing::Pipe::server_addr();
+        let client_addr = testing::Pipe::client_addr();
+        let client_addr_2 = "127.0.0.1:5678".parse().unwrap();
+
+        for conn in [&pipe.client, &pipe.server] {
+            assert_eq!(conn.pkt_num_spaces.app_iter().count(), 2);
+        }
+
+        assert_eq!(
+            pipe.client
+                .abandon_path(client_addr_2, server_addr, 0, b"bye"),
+            Ok(())
+        );
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Closed(client_addr_2, server_addr))
+        );
+
+        // The last path can't be abandoned.
+        assert_eq!(
+            pipe.client.abandon_path(client_addr, server_addr, 0, b""),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Closed(server_addr, client_addr_2))
+        );
+
+        for conn in [&pipe.client, &pipe.server] {
+            let active: Vec<_> =
+                conn.paths.iter().filter(|(_, p)| p.active()).collect();
+
+            assert_eq!(active.len(), 1);
+            assert!(conn.paths.iter().any(|(_, p)| p.abandoned()));
+        }
+
+        // The connection keeps working on the remaining path.
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 5];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
+
+        // The packet number space of the abandoned path was dropped.
+        for conn in [&pipe.client, &pipe.server] {
+            assert_eq!(conn.pkt_num_spaces.app_iter().count(), 1);
+        }
+    }
+
     #[test]
     fn connection_migration() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -15778,6 +21598,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn preferred_address_migration() {
+        let mut client_config = testing::Pipe::default_config().unwrap();
+        let mut server_config = testing::Pipe::default_config().unwrap();
+
+        let preferred_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
+        let preferred_addr_v6 = "[::1]:5678".parse().unwrap();
+
+        server_config.set_preferred_address(
+            Some("127.0.0.1:5678".parse().unwrap()),
+            Some(preferred_addr_v6),
+        );
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let client_addr = testing::Pipe::client_addr();
+        let server_addr = testing::Pipe::server_addr();
+
+        let pa = pipe.client.peer_transport_params.preferred_address.clone();
+        assert_eq!(pa, pipe.server.local_transport_params.preferred_address);
+        assert_eq!(pa.unwrap().ipv6, Some(preferred_addr_v6));
+
+        assert_eq!(
+            pipe.client.paths.get_active().unwrap().peer_addr(),
+            server_addr
+        );
+
+        // The client reserved a spare connection ID to probe the new path
+        // with, without the application having to provide one.
+        assert_eq!(pipe.client.source_cids_left(), 0);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(client_addr, preferred_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(preferred_addr, client_addr))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(preferred_addr, client_addr))
+        );
+
+        // The client migrated to the preferred address, using the connection
+        // ID provided along with it.
+        let active_path = pipe.client.paths.get_active().unwrap();
+        assert_eq!(active_path.local_addr(), client_addr);
+        assert_eq!(active_path.peer_addr(), preferred_addr);
+        assert_eq!(active_path.active_dcid_seq, Some(1));
+
+        assert_eq!(pipe.client.stream_send(0, b"data", true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(preferred_addr, client_addr))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        let active_path = pipe.server.paths.get_active().unwrap();
+        assert_eq!(active_path.local_addr(), preferred_addr);
+        assert_eq!(active_path.peer_addr(), client_addr);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((4, true)));
+        assert_eq!(&b[..4], b"data");
+    }
+
+    #[test]
+    fn preferred_address_other_family() {
+        let mut client_config = testing::Pipe::default_config().unwrap();
+        let mut server_config = testing::Pipe::default_config().unwrap();
+
+        server_config
+            .set_preferred_address(None, Some("[::1]:5678".parse().unwrap()));
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The client only has an IPv4 address, so it neither reserves a
+        // connection ID for the preferred address nor leaves the handshake
+        // path.
+        assert_eq!(pipe.client.source_cids_left(), 1);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.path_event_next(), None);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(
+            pipe.client.paths.get_active().unwrap().peer_addr(),
+            testing::Pipe::server_addr()
+        );
+
+        // The connection ID provided with the preferred address can still be
+        // used.
+        assert_eq!(pipe.client.available_dcids(), 1);
+    }
+
     #[test]
     fn resilience_against_migration_attack() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -15912,7 +21843,7 @@ mod tests {
 
         // Client sends a bunch of PING frames, causing server to ACK (ACKs aren't
         // ack-eliciting)
-        let frames = [frame::Frame::Ping];
+        let frames = [frame::Frame::Ping { mtu_probe: None }];
         let pkt_type = packet::Type::Short;
         for _ in 0..24 {
             let len = pipe
@@ -15941,7 +21872,7 @@ mod tests {
         assert!(
             frames
                 .iter()
-                .any(|frame| matches!(frame, frame::Frame::Ping)),
+                .any(|frame| matches!(frame, frame::Frame::Ping { .. })),
             "found a PING"
         );
     }
@@ -15963,7 +21894,7 @@ mod tests {
             testing::decode_pkt(&mut pipe.client, &mut buf[..len]).unwrap();
         let mut iter = frames.iter();
 
-        assert_eq!(iter.next(), Some(&frame::Frame::Ping));
+        assert_eq!(iter.next(), Some(&frame::Frame::Ping { mtu_probe: None }));
     }
 
     #[test]
@@ -16237,6 +22168,7 @@ mod tests {
         let mut b = octets::OctetsMut::with_slice(&mut pkt_buf);
         let epoch = packet::Type::Short.to_epoch().unwrap();
         let space = &mut pipe.client.pkt_num_spaces[epoch];
+        let crypto_ctx = &mut pipe.client.crypto_ctx[epoch];
         let pn = space.next_pkt_num;
         let pn_len = 4;
 
@@ -16250,6 +22182,8 @@ mod tests {
             token: pipe.client.token.clone(),
             versions: None,
             key_phase: pipe.client.key_phase,
+            spin_bit: false,
+            fixed_bit: true,
         };
         hdr.to_bytes(&mut b).expect("encode header");
         let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
@@ -16261,10 +22195,11 @@ mod tests {
             frame.to_bytes(&mut b).expect("encode frames");
         }
 
-        let aead = space.crypto_seal.as_ref().expect("crypto seal");
+        let aead = crypto_ctx.crypto_seal.as_ref().expect("crypto seal");
 
         let written = packet::encrypt_pkt(
             &mut b,
+            0,
             pn,
             pn_len,
             payload_len,
@@ -16279,6 +22214,7 @@ mod tests {
             .recv(&mut pkt_buf[..written], RecvInfo {
                 to: server_addr,
                 from: client_addr_2,
+                ecn: Ecn::NotEct,
             })
             .expect("server receive path challenge");
 
@@ -16290,6 +22226,16 @@ mod tests {
     }
 }
 
+pub use crate::cid::ConnectionIdGenerator;
+
+pub use crate::endpoint::ConnectionHandle;
+pub use crate::endpoint::Endpoint;
+
+pub use crate::events::ConnectionEvent;
+
+pub use crate::multipath::PathScheduler;
+pub use crate::multipath::PathStatus;
+
 pub use crate::packet::ConnectionId;
 pub use crate::packet::Header;
 pub use crate::packet::Type;
@@ -16300,21 +22246,35 @@ pub use crate::path::SocketAddrIter;
 
 pub use crate::recovery::CongestionControlAlgorithm;
 
+pub use crate::stream::SharedBuf;
+pub use crate::stream::StreamChunks;
 pub use crate::stream::StreamIter;
+pub use crate::stream::StreamPriorityScheduler;
+pub use crate::stream::StreamScheduler;
+pub use crate::stream::StreamStats;
+
+pub use crate::token::TokenKey;
 
+mod ackfreq;
 mod cid;
 mod crypto;
 mod dgram;
+mod endpoint;
+mod events;
 #[cfg(feature = "ffi")]
 mod ffi;
 mod flowcontrol;
 mod frame;
 pub mod h3;
+pub mod lb;
 mod minmax;
+mod multipath;
 mod packet;
 mod path;
+mod pmtud;
 mod rand;
 mod ranges;
 mod recovery;
 mod stream;
 mod tls;
+mod token;
@@ -0,0 +1,308 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Multipath extension for QUIC.
+//!
+//! This implementation is based on the following draft:
+//!
+//! <https://datatracker.ietf.org/doc/html/draft-ietf-quic-multipath-04>
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::Error;
+use crate::Result;
+
+use crate::frame;
+use crate::path;
+
+/// The algorithm used to select the path on which packets are sent when
+/// multipath is in use.
+///
+/// Only active paths are considered. Paths whose status is
+/// [`PathStatus::Standby`] are only used when there is no other path
+/// available.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum PathScheduler {
+    /// Sends on the path with the lowest RTT among the ones that have
+    /// congestion window available (default). `minrtt` in a string form.
+    MinRtt     = 0,
+    /// Sends on each path that has congestion window available in turn.
+    /// `roundrobin` in a string form.
+    RoundRobin = 1,
+    /// Sends on the first available path only, the others being used as
+    /// backups when it can't be used anymore. `backup` in a string form.
+    Backup     = 2,
+}
+
+impl FromStr for PathScheduler {
+    type Err = crate::Error;
+
+    /// Converts a string to `PathScheduler`.
+    ///
+    /// If `name` is not valid, `Error::InvalidMultipathScheduler` is returned.
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        match name {
+            "minrtt" => Ok(PathScheduler::MinRtt),
+            "roundrobin" => Ok(PathScheduler::RoundRobin),
+            "backup" => Ok(PathScheduler::Backup),
+
+            _ => Err(crate::Error::InvalidMultipathScheduler),
+        }
+    }
+}
+
+/// The status of a path, as advertised to the peer using PATH_STATUS frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum PathStatus {
+    /// The path should only be used when no other path is available.
+    Standby   = 1,
+    /// The path can be used to send packets (default).
+    Available = 2,
+}
+
+impl PathStatus {
+    pub(crate) fn from_wire(v: u64) -> Result<PathStatus> {
+        match v {
+            1 => Ok(PathStatus::Standby),
+            2 => Ok(PathStatus::Available),
+
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    pub(crate) fn to_wire(self) -> u64 {
+        self as u64
+    }
+}
+
+/// Connection-level multipath state.
+pub struct Multipath {
+    /// Whether multipath was negotiated with the peer.
+    enabled: bool,
+
+    scheduler: PathScheduler,
+
+    /// The path on which the last packet was sent, used by the round-robin
+    /// scheduler.
+    last_pid: Option<usize>,
+
+    /// PATH_ABANDON and PATH_STATUS frames waiting to be sent.
+    frames: VecDeque<frame::Frame>,
+}
+
+impl Multipath {
+    pub fn new(scheduler: PathScheduler) -> Multipath {
+        Multipath {
+            enabled: false,
+            scheduler,
+            last_pid: None,
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, v: bool) {
+        self.enabled = v;
+    }
+
+    /// Selects the path to send the next packet on among the given ones.
+    pub fn select_path<'a>(
+        &self, paths: impl Iterator<Item = (usize, &'a path::Path)>,
+    ) -> Option<usize> {
+        let paths: Vec<(usize, &path::Path)> = paths.collect();
+
+        // Standby paths are only considered when there is no other choice.
+        let has_available = paths
+            .iter()
+            .any(|(_, p)| p.status() == PathStatus::Available);
+
+        let candidates = paths.iter().filter(|(_, p)| {
+            !has_available || p.status() == PathStatus::Available
+        });
+
+        let lowest_rtt = candidates
+            .clone()
+            .min_by_key(|(_, p)| p.recovery.rtt())
+            .map(|(pid, _)| *pid);
+
+        let with_cwnd = candidates
+            .clone()
+            .filter(|(_, p)| p.recovery.cwnd_available() > 0);
+
+        match self.scheduler {
+            PathScheduler::MinRtt => with_cwnd
+                .min_by_key(|(_, p)| p.recovery.rtt())
+                .map(|(pid, _)| *pid)
+                .or(lowest_rtt),
+
+            PathScheduler::RoundRobin => {
+                let with_cwnd: Vec<usize> =
+                    with_cwnd.map(|(pid, _)| *pid).collect();
+
+                // Pick the first path following the one used last, wrapping
+                // around if needed.
+                with_cwnd
+                    .iter()
+                    .find(|pid| Some(**pid) > self.last_pid)
+                    .or_else(|| with_cwnd.first())
+                    .copied()
+                    .or(lowest_rtt)
+            },
+
+            PathScheduler::Backup => candidates.map(|(pid, _)| *pid).next(),
+        }
+    }
+
+    /// Records the path the last packet was sent on.
+    pub fn on_path_used(&mut self, pid: usize) {
+        self.last_pid = Some(pid);
+    }
+
+    /// Queues a PATH_ABANDON or PATH_STATUS frame to be sent.
+    pub fn queue_frame(&mut self, frame: frame::Frame) {
+        self.frames.push_back(frame);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Returns the next frame to be sent, if any.
+    pub fn peek_frame(&self) -> Option<&frame::Frame> {
+        self.frames.front()
+    }
+
+    pub fn on_frame_sent(&mut self) {
+        self.frames.pop_front();
+    }
+
+    /// Handles the loss of a PATH_ABANDON or PATH_STATUS frame. Lost frames
+    /// are always retransmitted, as the peer ignores outdated PATH_STATUS
+    /// frames.
+    pub fn on_frame_lost(&mut self, frame: frame::Frame) {
+        self.frames.push_back(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::recovery;
+
+    fn new_path(port: u16) -> path::Path {
+        let config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let recovery_config = recovery::RecoveryConfig::from_config(&config);
+
+        let local_addr = "127.0.0.1:1234".parse().unwrap();
+        let peer_addr = format!("127.0.0.1:{port}").parse().unwrap();
+
+        path::Path::new(local_addr, peer_addr, &recovery_config, true)
+    }
+
+    #[test]
+    fn status_wire() {
+        assert_eq!(PathStatus::from_wire(1), Ok(PathStatus::Standby));
+        assert_eq!(PathStatus::from_wire(2), Ok(PathStatus::Available));
+        assert_eq!(PathStatus::from_wire(3), Err(Error::InvalidFrame));
+
+        assert_eq!(PathStatus::Standby.to_wire(), 1);
+        assert_eq!(PathStatus::Available.to_wire(), 2);
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut mp = Multipath::new(PathScheduler::RoundRobin);
+
+        let paths = [new_path(1), new_path(2), new_path(3)];
+
+        let mut selected = Vec::new();
+
+        for _ in 0..4 {
+            let pid = mp.select_path(paths.iter().enumerate()).unwrap();
+            mp.on_path_used(pid);
+
+            selected.push(pid);
+        }
+
+        assert_eq!(selected, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn standby() {
+        let mut paths = [new_path(1), new_path(2)];
+        paths[0].set_status(PathStatus::Standby);
+
+        for scheduler in [
+            PathScheduler::MinRtt,
+            PathScheduler::RoundRobin,
+            PathScheduler::Backup,
+        ] {
+            let mut mp = Multipath::new(scheduler);
+
+            for _ in 0..3 {
+                let pid = mp.select_path(paths.iter().enumerate());
+                assert_eq!(pid, Some(1));
+
+                mp.on_path_used(1);
+            }
+
+            // Standby paths are used when there is nothing else.
+            let pid = mp.select_path(paths.iter().enumerate().take(1));
+            assert_eq!(pid, Some(0));
+        }
+    }
+
+    #[test]
+    fn backup() {
+        let mp = Multipath::new(PathScheduler::Backup);
+
+        let paths = [new_path(1), new_path(2)];
+
+        assert_eq!(mp.select_path(paths.iter().enumerate()), Some(0));
+        assert_eq!(mp.select_path(paths.iter().enumerate().skip(1)), Some(1));
+    }
+
+    #[test]
+    fn scheduler_from_str() {
+        assert_eq!(PathScheduler::from_str("minrtt"), Ok(PathScheduler::MinRtt));
+        assert_eq!(
+            PathScheduler::from_str("roundrobin"),
+            Ok(PathScheduler::RoundRobin)
+        );
+        assert_eq!(PathScheduler::from_str("backup"), Ok(PathScheduler::Backup));
+        assert_eq!(
+            PathScheduler::from_str("foo"),
+            Err(crate::Error::InvalidMultipathScheduler)
+        );
+    }
+}
@@ -24,6 +24,9 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::cmp;
+
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::ops::Index;
 use std::ops::IndexMut;
@@ -31,17 +34,20 @@ use std::ops::RangeInclusive;
 use std::time;
 
 use ring::aead;
+use ring::hmac;
 
 use crate::Error;
 use crate::Result;
 
 use crate::crypto;
+use crate::frame;
 use crate::rand;
 use crate::ranges;
 use crate::stream;
 
 const FORM_BIT: u8 = 0x80;
 const FIXED_BIT: u8 = 0x40;
+const SPIN_BIT: u8 = 0x20;
 const KEY_PHASE_BIT: u8 = 0x04;
 
 const TYPE_MASK: u8 = 0x30;
@@ -53,6 +59,16 @@ pub const MAX_PKT_NUM_LEN: usize = 4;
 
 const SAMPLE_LEN: usize = 16;
 
+const RESET_TOKEN_LEN: usize = 16;
+
+/// The minimum length of a packet for it to be recognized as a stateless
+/// reset.
+pub const MIN_STATELESS_RESET_LEN: usize = 21;
+
+// Stateless resets sent in response to packets larger than this are
+// truncated to this length, as recommended by RFC 9000 Section 10.3.
+const MAX_STATELESS_RESET_LEN: usize = 43;
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum Epoch {
     Initial     = 0,
@@ -154,6 +170,46 @@ impl Type {
         }
     }
 
+    /// Decodes the long header packet type from the type bits of the first
+    /// byte, as the mapping depends on the QUIC version.
+    fn from_long_header_bits(bits: u8, version: u32) -> Result<Type> {
+        let ty = match (version, bits) {
+            (crate::PROTOCOL_VERSION_V2, 0x00) => Type::Retry,
+            (crate::PROTOCOL_VERSION_V2, 0x01) => Type::Initial,
+            (crate::PROTOCOL_VERSION_V2, 0x02) => Type::ZeroRTT,
+            (crate::PROTOCOL_VERSION_V2, 0x03) => Type::Handshake,
+
+            (_, 0x00) => Type::Initial,
+            (_, 0x01) => Type::ZeroRTT,
+            (_, 0x02) => Type::Handshake,
+            (_, 0x03) => Type::Retry,
+
+            _ => return Err(Error::InvalidPacket),
+        };
+
+        Ok(ty)
+    }
+
+    /// Encodes the long header packet type into the type bits of the first
+    /// byte, as the mapping depends on the QUIC version.
+    fn to_long_header_bits(self, version: u32) -> Result<u8> {
+        let bits = match (version, self) {
+            (crate::PROTOCOL_VERSION_V2, Type::Retry) => 0x00,
+            (crate::PROTOCOL_VERSION_V2, Type::Initial) => 0x01,
+            (crate::PROTOCOL_VERSION_V2, Type::ZeroRTT) => 0x02,
+            (crate::PROTOCOL_VERSION_V2, Type::Handshake) => 0x03,
+
+            (_, Type::Initial) => 0x00,
+            (_, Type::ZeroRTT) => 0x01,
+            (_, Type::Handshake) => 0x02,
+            (_, Type::Retry) => 0x03,
+
+            _ => return Err(Error::InvalidPacket),
+        };
+
+        Ok(bits)
+    }
+
     #[cfg(feature = "qlog")]
     pub(crate) fn to_qlog(self) -> qlog::events::quic::PacketType {
         match self {
@@ -315,6 +371,14 @@ pub struct Header<'a> {
     /// The key phase bit of the packet. It's only meaningful after the header
     /// protection is removed.
     pub(crate) key_phase: bool,
+
+    /// The latency spin bit of the packet. Only present in short header
+    /// packets.
+    pub(crate) spin_bit: bool,
+
+    /// The fixed bit of the packet, which can be cleared when the
+    /// `grease_quic_bit` transport parameter is used.
+    pub(crate) fixed_bit: bool,
 }
 
 impl<'a> Header<'a> {
@@ -362,6 +426,8 @@ impl<'a> Header<'a> {
                 token: None,
                 versions: None,
                 key_phase: false,
+                spin_bit: (first & SPIN_BIT) != 0,
+                fixed_bit: (first & FIXED_BIT) != 0,
             });
         }
 
@@ -371,13 +437,7 @@ impl<'a> Header<'a> {
         let ty = if version == 0 {
             Type::VersionNegotiation
         } else {
-            match (first & TYPE_MASK) >> 4 {
-                0x00 => Type::Initial,
-                0x01 => Type::ZeroRTT,
-                0x02 => Type::Handshake,
-                0x03 => Type::Retry,
-                _ => return Err(Error::InvalidPacket),
-            }
+            Type::from_long_header_bits((first & TYPE_MASK) >> 4, version)?
         };
 
         let dcid_len = b.get_u8()?;
@@ -436,6 +496,8 @@ impl<'a> Header<'a> {
             token,
             versions,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: (first & FIXED_BIT) != 0,
         })
     }
 
@@ -451,7 +513,14 @@ impl<'a> Header<'a> {
             first &= !FORM_BIT;
 
             // Set fixed bit.
-            first |= FIXED_BIT;
+            if self.fixed_bit {
+                first |= FIXED_BIT;
+            }
+
+            // Set spin bit.
+            if self.spin_bit {
+                first |= SPIN_BIT;
+            }
 
             // Set key phase bit.
             if self.key_phase {
@@ -467,15 +536,13 @@ impl<'a> Header<'a> {
         }
 
         // Encode long header.
-        let ty: u8 = match self.ty {
-            Type::Initial => 0x00,
-            Type::ZeroRTT => 0x01,
-            Type::Handshake => 0x02,
-            Type::Retry => 0x03,
-            _ => return Err(Error::InvalidPacket),
-        };
+        let ty = self.ty.to_long_header_bits(self.version)?;
 
-        first |= FORM_BIT | FIXED_BIT | (ty << 4);
+        first |= FORM_BIT | (ty << 4);
+
+        if self.fixed_bit {
+            first |= FIXED_BIT;
+        }
 
         out.put_u8(first)?;
 
@@ -549,6 +616,7 @@ impl<'a> std::fmt::Debug for Header<'a> {
 
         if self.ty == Type::Short {
             write!(f, " key_phase={}", self.key_phase)?;
+            write!(f, " spin_bit={}", self.spin_bit)?;
         }
 
         Ok(())
@@ -649,8 +717,8 @@ pub fn decode_pkt_num(largest_pn: u64, truncated_pn: u64, pn_len: usize) -> u64
 }
 
 pub fn decrypt_pkt<'a>(
-    b: &'a mut octets::OctetsMut, pn: u64, pn_len: usize, payload_len: usize,
-    aead: &crypto::Open,
+    b: &'a mut octets::OctetsMut, space_id: u64, pn: u64, pn_len: usize,
+    payload_len: usize, aead: &crypto::Open,
 ) -> Result<octets::Octets<'a>> {
     let payload_offset = b.off();
 
@@ -662,8 +730,12 @@ pub fn decrypt_pkt<'a>(
 
     let mut ciphertext = payload.peek_bytes_mut(payload_len)?;
 
-    let payload_len =
-        aead.open_with_u64_counter(pn, header.as_ref(), ciphertext.as_mut())?;
+    let payload_len = aead.open_with_u64_counter(
+        space_id,
+        pn,
+        header.as_ref(),
+        ciphertext.as_mut(),
+    )?;
 
     Ok(b.get_bytes(payload_len)?)
 }
@@ -694,13 +766,16 @@ pub fn encrypt_hdr(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn encrypt_pkt(
-    b: &mut octets::OctetsMut, pn: u64, pn_len: usize, payload_len: usize,
-    payload_offset: usize, extra_in: Option<&[u8]>, aead: &crypto::Seal,
+    b: &mut octets::OctetsMut, space_id: u64, pn: u64, pn_len: usize,
+    payload_len: usize, payload_offset: usize, extra_in: Option<&[u8]>,
+    aead: &crypto::Seal,
 ) -> Result<usize> {
     let (mut header, mut payload) = b.split_at(payload_offset)?;
 
     let ciphertext_len = aead.seal_with_u64_counter(
+        space_id,
         pn,
         header.as_ref(),
         payload.as_mut(),
@@ -746,6 +821,7 @@ pub fn negotiate_version(
     b.put_u8(dcid.len() as u8)?;
     b.put_bytes(dcid)?;
     b.put_u32(crate::PROTOCOL_VERSION_V1)?;
+    b.put_u32(crate::PROTOCOL_VERSION_V2)?;
 
     Ok(b.off())
 }
@@ -770,6 +846,8 @@ pub fn retry(
         token: Some(token.to_vec()),
         versions: None,
         key_phase: false,
+        spin_bit: false,
+        fixed_bit: true,
     };
 
     hdr.to_bytes(&mut b)?;
@@ -781,6 +859,46 @@ pub fn retry(
     Ok(b.off())
 }
 
+pub fn stateless_reset(
+    dcid: &[u8], key: &[u8], received_len: usize, out: &mut [u8],
+) -> Result<usize> {
+    // The stateless reset needs to be smaller than the packet that triggered
+    // it in order to prevent loops between endpoints, but it can't be smaller
+    // than the minimum length required for the peer to detect it.
+    if received_len <= MIN_STATELESS_RESET_LEN {
+        return Err(Error::Done);
+    }
+
+    let len = cmp::min(received_len - 1, MAX_STATELESS_RESET_LEN);
+
+    if out.len() < len {
+        return Err(Error::BufferTooShort);
+    }
+
+    let out = &mut out[..len];
+
+    // The packet looks like a short header packet with unpredictable bits,
+    // followed by the stateless reset token.
+    rand::rand_bytes(out);
+
+    out[0] = (out[0] & !FORM_BIT) | FIXED_BIT;
+
+    let token = stateless_reset_token(dcid, key).to_be_bytes();
+    out[len - RESET_TOKEN_LEN..].copy_from_slice(&token);
+
+    Ok(len)
+}
+
+pub fn stateless_reset_token(cid: &[u8], key: &[u8]) -> u128 {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let tag = hmac::sign(&key, cid);
+
+    let mut token = [0; RESET_TOKEN_LEN];
+    token.copy_from_slice(&tag.as_ref()[..RESET_TOKEN_LEN]);
+
+    u128::from_be_bytes(token)
+}
+
 pub fn verify_retry_integrity(
     b: &octets::OctetsMut, odcid: &[u8], version: u32,
 ) -> Result<()> {
@@ -807,10 +925,22 @@ fn compute_retry_integrity_tag(
         0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
     ];
 
+    const RETRY_INTEGRITY_KEY_V2: [u8; 16] = [
+        0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce,
+        0xad, 0x7c, 0xcc, 0x92,
+    ];
+
+    const RETRY_INTEGRITY_NONCE_V2: [u8; aead::NONCE_LEN] = [
+        0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+    ];
+
     let (key, nonce) = match version {
         crate::PROTOCOL_VERSION_V1 =>
             (&RETRY_INTEGRITY_KEY_V1, RETRY_INTEGRITY_NONCE_V1),
 
+        crate::PROTOCOL_VERSION_V2 =>
+            (&RETRY_INTEGRITY_KEY_V2, RETRY_INTEGRITY_NONCE_V2),
+
         _ => (&RETRY_INTEGRITY_KEY_V1, RETRY_INTEGRITY_NONCE_V1),
     };
 
@@ -849,8 +979,9 @@ pub struct KeyUpdate {
     /// Whether ACK frame for key-update has been sent.
     pub update_acked: bool,
 
-    /// When the old key should be discarded.
-    pub timer: time::Instant,
+    /// When the old key should be discarded. This is only set once the key
+    /// update is confirmed.
+    pub timer: Option<time::Instant>,
 }
 
 pub struct PktNumSpace {
@@ -868,15 +999,16 @@ pub struct PktNumSpace {
 
     pub ack_elicited: bool,
 
-    pub key_update: Option<KeyUpdate>,
+    /// Number of ack-eliciting packets received since the last ACK was sent.
+    pub ack_eliciting_unacked: u64,
 
-    pub crypto_open: Option<crypto::Open>,
-    pub crypto_seal: Option<crypto::Seal>,
+    /// When a delayed ACK needs to be sent.
+    pub ack_timer: Option<time::Instant>,
 
-    pub crypto_0rtt_open: Option<crypto::Open>,
-    pub crypto_0rtt_seal: Option<crypto::Seal>,
+    /// The largest packet number acknowledged by the last ACK sent.
+    pub largest_reported_ack: Option<u64>,
 
-    pub crypto_stream: stream::Stream,
+    pub ecn_counts: frame::EcnCounts,
 }
 
 impl PktNumSpace {
@@ -896,6 +1028,163 @@ impl PktNumSpace {
 
             ack_elicited: false,
 
+            ack_eliciting_unacked: 0,
+
+            ack_timer: None,
+
+            largest_reported_ack: None,
+
+            ecn_counts: frame::EcnCounts::default(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.ack_elicited = false;
+        self.ack_eliciting_unacked = 0;
+        self.ack_timer = None;
+    }
+
+    pub fn ready(&self) -> bool {
+        self.ack_elicited
+    }
+}
+
+/// The packet number spaces of a connection.
+///
+/// When multipath is in use, each path has its own Application Data packet
+/// number space, identified by the sequence number of the connection ID used
+/// on that path. The space with identifier 0 is the one used by the initial
+/// path, and is the one returned when indexing by [`Epoch::Application`].
+pub struct PktNumSpaceMap {
+    spaces: [PktNumSpace; Epoch::count()],
+
+    app_spaces: BTreeMap<u64, PktNumSpace>,
+}
+
+impl PktNumSpaceMap {
+    pub fn new() -> PktNumSpaceMap {
+        PktNumSpaceMap {
+            spaces: [PktNumSpace::new(), PktNumSpace::new(), PktNumSpace::new()],
+
+            app_spaces: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the Application Data packet number space with the given
+    /// identifier, if it exists.
+    pub fn get_app(&self, space_id: u64) -> Option<&PktNumSpace> {
+        if space_id == 0 {
+            return Some(&self.spaces[Epoch::Application]);
+        }
+
+        self.app_spaces.get(&space_id)
+    }
+
+    /// Returns the Application Data packet number space with the given
+    /// identifier, if it exists.
+    pub fn get_app_mut(&mut self, space_id: u64) -> Option<&mut PktNumSpace> {
+        if space_id == 0 {
+            return Some(&mut self.spaces[Epoch::Application]);
+        }
+
+        self.app_spaces.get_mut(&space_id)
+    }
+
+    /// Returns the packet number space used by packets of the given epoch
+    /// and space identifier, creating it if needed.
+    pub fn get_mut(&mut self, epoch: Epoch, space_id: u64) -> &mut PktNumSpace {
+        match epoch {
+            Epoch::Application if space_id != 0 => self
+                .app_spaces
+                .entry(space_id)
+                .or_insert_with(PktNumSpace::new),
+
+            _ => &mut self.spaces[epoch],
+        }
+    }
+
+    /// Removes the Application Data packet number spaces, other than the
+    /// initial one, for which `f` returns `false`.
+    pub fn retain_app<F>(&mut self, mut f: F)
+    where
+        F: FnMut(u64, &PktNumSpace) -> bool,
+    {
+        self.app_spaces.retain(|id, s| f(*id, s));
+    }
+
+    /// Returns an iterator over all Application Data packet number spaces and
+    /// their identifiers.
+    pub fn app_iter(&self) -> impl Iterator<Item = (u64, &PktNumSpace)> {
+        std::iter::once((0, &self.spaces[Epoch::Application]))
+            .chain(self.app_spaces.iter().map(|(id, s)| (*id, s)))
+    }
+
+    /// Returns whether any packet number space of the given epoch has ACKs
+    /// to be sent.
+    pub fn ready(&self, epoch: Epoch) -> bool {
+        match epoch {
+            Epoch::Application => self.app_iter().any(|(_, s)| s.ready()),
+
+            _ => self.spaces[epoch].ready(),
+        }
+    }
+
+    /// Returns a mutable iterator over the packet number spaces of the given
+    /// epoch and their identifiers. The Application Data spaces other than
+    /// the initial one are only included if `multipath` is set.
+    pub fn epoch_iter_mut(
+        &mut self, epoch: Epoch, multipath: bool,
+    ) -> impl Iterator<Item = (u64, &mut PktNumSpace)> {
+        let app_spaces = if epoch == Epoch::Application && multipath {
+            Some(self.app_spaces.iter_mut())
+        } else {
+            None
+        };
+
+        std::iter::once((0, &mut self.spaces[epoch]))
+            .chain(app_spaces.into_iter().flatten().map(|(id, s)| (*id, s)))
+    }
+
+    /// Returns a mutable iterator over all Application Data packet number
+    /// spaces and their identifiers.
+    pub fn app_iter_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (u64, &mut PktNumSpace)> {
+        std::iter::once((0, &mut self.spaces[Epoch::Application]))
+            .chain(self.app_spaces.iter_mut().map(|(id, s)| (*id, s)))
+    }
+}
+
+impl Index<Epoch> for PktNumSpaceMap {
+    type Output = PktNumSpace;
+
+    fn index(&self, index: Epoch) -> &Self::Output {
+        &self.spaces[index]
+    }
+}
+
+impl IndexMut<Epoch> for PktNumSpaceMap {
+    fn index_mut(&mut self, index: Epoch) -> &mut Self::Output {
+        &mut self.spaces[index]
+    }
+}
+
+/// The cryptographic state of an encryption level.
+pub struct CryptoContext {
+    pub key_update: Option<KeyUpdate>,
+
+    pub crypto_open: Option<crypto::Open>,
+    pub crypto_seal: Option<crypto::Seal>,
+
+    pub crypto_0rtt_open: Option<crypto::Open>,
+    pub crypto_0rtt_seal: Option<crypto::Seal>,
+
+    pub crypto_stream: stream::Stream,
+}
+
+impl CryptoContext {
+    pub fn new() -> CryptoContext {
+        CryptoContext {
             key_update: None,
 
             crypto_open: None,
@@ -924,16 +1213,14 @@ impl PktNumSpace {
             true,
             stream::MAX_STREAM_WINDOW,
         );
-
-        self.ack_elicited = false;
     }
 
     pub fn crypto_overhead(&self) -> Option<usize> {
         Some(self.crypto_seal.as_ref()?.alg().tag_len())
     }
 
-    pub fn ready(&self) -> bool {
-        self.crypto_stream.is_flushable() || self.ack_elicited
+    pub fn data_available(&self) -> bool {
+        self.crypto_stream.is_flushable()
     }
 
     pub fn has_keys(&self) -> bool {
@@ -1007,6 +1294,8 @@ mod tests {
             token: Some(vec![0xba; 24]),
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 63];
@@ -1021,6 +1310,103 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn stateless_reset() {
+        let dcid = [0xba; 16];
+        let key = [0xab; 32];
+
+        let token = stateless_reset_token(&dcid, &key);
+
+        // The token only depends on the connection ID and the key.
+        assert_eq!(stateless_reset_token(&dcid, &key), token);
+        assert_ne!(stateless_reset_token(&[0xbb; 16], &key), token);
+        assert_ne!(stateless_reset_token(&dcid, &[0xac; 32]), token);
+
+        let mut out = [0; 1500];
+
+        // The reset is always smaller than the received packet.
+        let len = super::stateless_reset(&dcid, &key, 30, &mut out).unwrap();
+        assert_eq!(len, 29);
+
+        assert_eq!(out[0] & FORM_BIT, 0);
+        assert_eq!(out[0] & FIXED_BIT, FIXED_BIT);
+        assert_eq!(&out[len - 16..len], &token.to_be_bytes());
+
+        // Resets for large packets are truncated.
+        assert_eq!(
+            super::stateless_reset(&dcid, &key, 1200, &mut out),
+            Ok(MAX_STATELESS_RESET_LEN)
+        );
+
+        assert_eq!(
+            super::stateless_reset(&dcid, &key, 22, &mut out),
+            Ok(MIN_STATELESS_RESET_LEN)
+        );
+
+        // Packets too small to reply to with a smaller reset are ignored.
+        assert_eq!(
+            super::stateless_reset(
+                &dcid,
+                &key,
+                MIN_STATELESS_RESET_LEN,
+                &mut out
+            ),
+            Err(Error::Done)
+        );
+
+        assert_eq!(
+            super::stateless_reset(&dcid, &key, 1200, &mut out[..30]),
+            Err(Error::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn retry_integrity_v1() {
+        let mut pkt = [
+            0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
+            0x2a, 0x42, 0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0x04, 0xa2,
+            0x65, 0xba, 0x2e, 0xff, 0x4d, 0x82, 0x90, 0x58, 0xfb, 0x3f, 0x0f,
+            0x24, 0x96, 0xba,
+        ];
+
+        let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+        let hdr = Header::from_bytes(&mut b, 0).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+        assert_eq!(hdr.token, Some(b"token".to_vec()));
+
+        assert_eq!(verify_retry_integrity(&b, &odcid, hdr.version), Ok(()));
+    }
+
+    #[test]
+    fn retry_integrity_v2() {
+        let mut pkt = [
+            0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
+            0x2a, 0x42, 0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xc8, 0x64,
+            0x6c, 0xe8, 0xbf, 0xe3, 0x39, 0x52, 0xd9, 0x55, 0x54, 0x36, 0x65,
+            0xdc, 0xc7, 0xb6,
+        ];
+
+        let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+        let hdr = Header::from_bytes(&mut b, 0).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+        assert_eq!(hdr.version, crate::PROTOCOL_VERSION_V2);
+        assert_eq!(hdr.token, Some(b"token".to_vec()));
+
+        assert_eq!(verify_retry_integrity(&b, &odcid, hdr.version), Ok(()));
+
+        // The same packet doesn't verify with the v1 retry key.
+        assert_eq!(
+            verify_retry_integrity(&b, &odcid, crate::PROTOCOL_VERSION_V1),
+            Err(Error::CryptoFail)
+        );
+    }
+
     #[test]
     fn initial() {
         let hdr = Header {
@@ -1034,6 +1420,8 @@ mod tests {
             token: Some(vec![0x05, 0x06, 0x07, 0x08]),
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1045,6 +1433,64 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn initial_v2() {
+        let hdr = Header {
+            ty: Type::Initial,
+            version: crate::PROTOCOL_VERSION_V2,
+            dcid: vec![0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba]
+                .into(),
+            scid: vec![0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb].into(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: Some(vec![0x05, 0x06, 0x07, 0x08]),
+            versions: None,
+            key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
+        };
+
+        let mut d = [0; 50];
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes(&mut b).is_ok());
+
+        // Initial packets use the 0b01 type bits in QUIC v2.
+        assert_eq!(d[0] & TYPE_MASK, 0x10);
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
+    }
+
+    #[test]
+    fn handshake_v2() {
+        let hdr = Header {
+            ty: Type::Handshake,
+            version: crate::PROTOCOL_VERSION_V2,
+            dcid: vec![0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba]
+                .into(),
+            scid: vec![0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb, 0xbb].into(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: None,
+            versions: None,
+            key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
+        };
+
+        let mut d = [0; 50];
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes(&mut b).is_ok());
+
+        // Handshake packets use the 0b11 type bits in QUIC v2.
+        assert_eq!(d[0] & TYPE_MASK, 0x30);
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
+    }
+
     #[test]
     fn initial_v1_dcid_too_long() {
         let hdr = Header {
@@ -1061,6 +1507,8 @@ mod tests {
             token: Some(vec![0x05, 0x06, 0x07, 0x08]),
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1089,6 +1537,8 @@ mod tests {
             token: Some(vec![0x05, 0x06, 0x07, 0x08]),
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1117,6 +1567,8 @@ mod tests {
             token: Some(vec![0x05, 0x06, 0x07, 0x08]),
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1141,6 +1593,8 @@ mod tests {
             token: None,
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1165,6 +1619,8 @@ mod tests {
             token: None,
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         let mut d = [0; 50];
@@ -1176,6 +1632,33 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn application_spin_bit() {
+        let hdr = Header {
+            ty: Type::Short,
+            version: 0,
+            dcid: vec![0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba, 0xba]
+                .into(),
+            scid: ConnectionId::default(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: None,
+            versions: None,
+            key_phase: false,
+            spin_bit: true,
+            fixed_bit: true,
+        };
+
+        let mut d = [0; 50];
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert!(hdr.to_bytes(&mut b).is_ok());
+        assert_eq!(d[0] & SPIN_BIT, SPIN_BIT);
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
+    }
+
     #[test]
     fn pkt_num_decode() {
         let pn = decode_pkt_num(0xa82f30ea, 0x9b32, 2);
@@ -1310,7 +1793,8 @@ mod tests {
         assert_eq!(pn, expected_pn);
 
         let payload =
-            decrypt_pkt(&mut b, pn, hdr.pkt_num_len, payload_len, &aead).unwrap();
+            decrypt_pkt(&mut b, 0, pn, hdr.pkt_num_len, payload_len, &aead)
+                .unwrap();
 
         let payload = payload.as_ref();
         assert_eq!(&payload[..expected_frames.len()], expected_frames);
@@ -1514,7 +1998,9 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Open::from_secret(alg, &secret).unwrap();
+        let aead =
+            crypto::Open::from_secret(alg, &secret, crate::PROTOCOL_VERSION)
+                .unwrap();
 
         let mut hdr = Header::from_bytes(&mut b, 0).unwrap();
         assert_eq!(hdr.ty, Type::Short);
@@ -1528,7 +2014,8 @@ mod tests {
         assert_eq!(pn, 654_360_564);
 
         let payload =
-            decrypt_pkt(&mut b, pn, hdr.pkt_num_len, payload_len, &aead).unwrap();
+            decrypt_pkt(&mut b, 0, pn, hdr.pkt_num_len, payload_len, &aead)
+                .unwrap();
 
         let payload = payload.as_ref();
         assert_eq!(&payload, &[0x01]);
@@ -1560,6 +2047,7 @@ mod tests {
 
         let written = encrypt_pkt(
             &mut b,
+            0,
             pn,
             pn_len,
             payload_len,
@@ -1882,7 +2370,9 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Seal::from_secret(alg, &secret).unwrap();
+        let aead =
+            crypto::Seal::from_secret(alg, &secret, crate::PROTOCOL_VERSION)
+                .unwrap();
 
         let pn = 654_360_564;
         let pn_len = 3;
@@ -1897,6 +2387,7 @@ mod tests {
 
         let written = encrypt_pkt(
             &mut b,
+            0,
             pn,
             pn_len,
             payload_len,
@@ -1910,6 +2401,78 @@ mod tests {
         assert_eq!(&out[..written], &expected_pkt[..]);
     }
 
+    #[test]
+    fn encrypt_decrypt_space_id() {
+        let secret = [0x42; 32];
+
+        let alg = crypto::Algorithm::ChaCha20_Poly1305;
+
+        let seal =
+            crypto::Seal::from_secret(alg, &secret, crate::PROTOCOL_VERSION)
+                .unwrap();
+        let open =
+            crypto::Open::from_secret(alg, &secret, crate::PROTOCOL_VERSION)
+                .unwrap();
+
+        let hdr = Header {
+            ty: Type::Short,
+            version: 0,
+            dcid: ConnectionId::default(),
+            scid: ConnectionId::default(),
+            pkt_num: 0,
+            pkt_num_len: 0,
+            token: None,
+            versions: None,
+            key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
+        };
+
+        let pn = 42;
+        let pn_len = pkt_num_len(pn).unwrap();
+
+        let mut buf = [0; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        hdr.to_bytes(&mut b).unwrap();
+        encode_pkt_num(pn, &mut b).unwrap();
+
+        let payload_offset = b.off();
+
+        let frames = [0x01; 32];
+        b.put_bytes(&frames).unwrap();
+
+        // Encrypt the packet in the packet number space with identifier 1.
+        let written = encrypt_pkt(
+            &mut b,
+            1,
+            pn,
+            pn_len,
+            frames.len(),
+            payload_offset,
+            None,
+            &seal,
+        )
+        .unwrap();
+
+        let decrypt = |space_id| {
+            let mut pkt = buf[..written].to_vec();
+            let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+            let mut hdr = Header::from_bytes(&mut b, 0).unwrap();
+            let payload_len = b.cap();
+
+            decrypt_hdr(&mut b, &mut hdr, &open).unwrap();
+
+            decrypt_pkt(&mut b, space_id, pn, pn_len, payload_len, &open)
+                .map(|payload| payload.to_vec())
+        };
+
+        // The nonce depends on the packet number space.
+        assert_eq!(decrypt(0), Err(Error::CryptoFail));
+        assert_eq!(decrypt(1), Ok(frames.to_vec()));
+    }
+
     #[test]
     fn decrypt_pkt_underflow() {
         let mut buf = [0; 65535];
@@ -1925,6 +2488,8 @@ mod tests {
             token: None,
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         hdr.to_bytes(&mut b).unwrap();
@@ -1937,7 +2502,7 @@ mod tests {
             crypto::derive_initial_key_material(b"", hdr.version, true).unwrap();
 
         assert_eq!(
-            decrypt_pkt(&mut b, 0, 1, payload_len, &aead),
+            decrypt_pkt(&mut b, 0, 0, 1, payload_len, &aead),
             Err(Error::InvalidPacket)
         );
     }
@@ -1957,6 +2522,8 @@ mod tests {
             token: None,
             versions: None,
             key_phase: false,
+            spin_bit: false,
+            fixed_bit: true,
         };
 
         hdr.to_bytes(&mut b).unwrap();
@@ -1970,7 +2537,7 @@ mod tests {
             crypto::derive_initial_key_material(b"", hdr.version, true).unwrap();
 
         assert_eq!(
-            decrypt_pkt(&mut b, 0, 1, payload_len, &aead),
+            decrypt_pkt(&mut b, 0, 0, 1, payload_len, &aead),
             Err(Error::CryptoFail)
         );
     }
@@ -37,9 +37,15 @@ use slab::Slab;
 use crate::Error;
 use crate::Result;
 
+use crate::multipath;
+use crate::pmtud;
+use crate::rand;
 use crate::recovery;
 use crate::recovery::HandshakeStatus;
 
+#[cfg(feature = "qlog")]
+use qlog::events::EventData;
+
 /// The different states of the path validation.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PathState {
@@ -135,6 +141,13 @@ pub struct Path {
     /// Loss recovery and congestion control state.
     pub recovery: recovery::Recovery,
 
+    /// Path MTU discovery state.
+    pub pmtud: pmtud::Pmtud,
+
+    /// The last PMTU that was logged to qlog.
+    #[cfg(feature = "qlog")]
+    qlog_pmtu: usize,
+
     /// Pending challenge data with the size of the packet containing them and
     /// when they were sent.
     in_flight_challenges: VecDeque<([u8; 8], usize, time::Instant)>,
@@ -192,6 +205,31 @@ pub struct Path {
 
     /// Whether or not we should force eliciting of an ACK (e.g. via PING frame)
     pub needs_ack_eliciting: bool,
+
+    /// Whether the latency spin bit is enabled on this path.
+    spin_enabled: bool,
+
+    /// The current spin value.
+    spin_value: bool,
+
+    /// The largest packet number received on this path, used to update the
+    /// spin value.
+    spin_largest_rx_pkt_num: Option<u64>,
+
+    /// The Destination CID sequence number the spin value was last sent with.
+    spin_dcid_seq: Option<u64>,
+
+    /// The multipath status of the path.
+    status: multipath::PathStatus,
+
+    /// The sequence number of the next PATH_STATUS frame to send.
+    next_status_seq_num: u64,
+
+    /// The sequence number of the last PATH_STATUS frame received.
+    peer_status_seq_num: Option<u64>,
+
+    /// Whether the path was abandoned, either locally or by the peer.
+    abandoned: bool,
 }
 
 impl Path {
@@ -207,6 +245,8 @@ impl Path {
             (PathState::Unknown, None, None)
         };
 
+        let recovery = recovery::Recovery::new_with_config(recovery_config);
+
         Self {
             local_addr,
             peer_addr,
@@ -214,7 +254,13 @@ impl Path {
             active_dcid_seq,
             state,
             active: false,
-            recovery: recovery::Recovery::new_with_config(recovery_config),
+            pmtud: pmtud::Pmtud::new(
+                recovery_config.pmtud,
+                recovery_config.max_send_udp_payload_size,
+            ),
+            #[cfg(feature = "qlog")]
+            qlog_pmtu: recovery.max_datagram_size(),
+            recovery,
             in_flight_challenges: VecDeque::new(),
             max_challenge_size: 0,
             probing_lost: 0,
@@ -233,6 +279,14 @@ impl Path {
             failure_notified: false,
             migrating: false,
             needs_ack_eliciting: false,
+            spin_enabled: false,
+            spin_value: false,
+            spin_largest_rx_pkt_num: None,
+            spin_dcid_seq: None,
+            status: multipath::PathStatus::Available,
+            next_status_seq_num: 0,
+            peer_status_seq_num: None,
+            abandoned: false,
         }
     }
 
@@ -248,10 +302,10 @@ impl Path {
         self.peer_addr
     }
 
-    /// Returns whether the path is working (i.e., not failed).
+    /// Returns whether the path is working (i.e., not failed nor abandoned).
     #[inline]
     fn working(&self) -> bool {
-        self.state > PathState::Failed
+        self.state > PathState::Failed && !self.abandoned
     }
 
     /// Returns whether the path is active.
@@ -265,6 +319,7 @@ impl Path {
     pub fn usable(&self) -> bool {
         self.active() ||
             (self.state == PathState::Validated &&
+                !self.abandoned &&
                 self.active_dcid_seq.is_some())
     }
 
@@ -297,7 +352,7 @@ impl Path {
 
     /// Returns whether this path failed its validation.
     #[inline]
-    fn validation_failed(&self) -> bool {
+    pub fn validation_failed(&self) -> bool {
         self.state == PathState::Failed
     }
 
@@ -397,6 +452,8 @@ impl Path {
             trace_id,
         );
 
+        self.pmtud_check_black_hole(now);
+
         let mut lost_probe_time = None;
         self.in_flight_challenges.retain(|(_, _, sent_time)| {
             if *sent_time <= now {
@@ -442,6 +499,155 @@ impl Path {
         (lost_packets, lost_bytes)
     }
 
+    /// Returns the size of the PMTUD probe to send on this path, if any.
+    pub fn pmtud_probe_size(&mut self, now: time::Instant) -> Option<usize> {
+        if !self.active() {
+            return None;
+        }
+
+        let size = self.pmtud.probe_size(now)?;
+
+        // Probes are subject to congestion control like any other packet.
+        if size > self.recovery.cwnd_available() {
+            return None;
+        }
+
+        Some(size)
+    }
+
+    /// Handles the acknowledgment of a PMTUD probe of the given size.
+    pub fn on_pmtud_probe_acked(&mut self, size: usize, now: time::Instant) {
+        if let Some(pmtu) = self.pmtud.on_probe_acked(size, now) {
+            self.recovery.pmtud_update_max_datagram_size(pmtu);
+        }
+    }
+
+    /// Falls back to the base PMTU if too many packets larger than it were
+    /// lost, as the path might have become a black hole for them.
+    pub fn pmtud_check_black_hole(&mut self, now: time::Instant) {
+        if self.pmtud.state() == pmtud::PmtudState::Disabled ||
+            self.recovery.large_lost_count() < pmtud::BLACK_HOLE_THRESHOLD
+        {
+            return;
+        }
+
+        if let Some(pmtu) = self.pmtud.on_black_hole(now) {
+            self.recovery.pmtud_update_max_datagram_size(pmtu);
+        }
+    }
+
+    #[cfg(feature = "qlog")]
+    pub fn pmtud_maybe_qlog(&mut self) -> Option<EventData> {
+        let pmtu = self.recovery.max_datagram_size();
+
+        if pmtu == self.qlog_pmtu {
+            return None;
+        }
+
+        let ev_data =
+            EventData::MtuUpdated(qlog::events::connectivity::MtuUpdated {
+                old: Some(self.qlog_pmtu as u16),
+                new: pmtu as u16,
+                done: Some(
+                    self.pmtud.state() == pmtud::PmtudState::SearchComplete,
+                ),
+            });
+
+        self.qlog_pmtu = pmtu;
+
+        Some(ev_data)
+    }
+
+    /// Updates the spin value with the spin bit of a received short header
+    /// packet, and returns the new value if it changed.
+    ///
+    /// Only packets that increase the largest packet number received on the
+    /// path are taken into account.
+    pub fn on_spin_bit_received(
+        &mut self, pkt_num: u64, spin_bit: bool, is_server: bool,
+    ) -> Option<bool> {
+        if !self.spin_enabled ||
+            self.spin_largest_rx_pkt_num
+                .map_or(false, |pn| pkt_num <= pn)
+        {
+            return None;
+        }
+
+        self.spin_largest_rx_pkt_num = Some(pkt_num);
+
+        // The server reflects the spin value it receives, while the client
+        // inverts it.
+        let spin_value = if is_server { spin_bit } else { !spin_bit };
+
+        if spin_value == self.spin_value {
+            return None;
+        }
+
+        self.spin_value = spin_value;
+
+        Some(spin_value)
+    }
+
+    /// Configures whether the latency spin bit is enabled on this path.
+    pub fn enable_spin_bit(&mut self, v: bool) {
+        self.spin_enabled = v;
+    }
+
+    /// Returns the spin bit to set on the next short header packet.
+    pub fn spin_bit(&mut self) -> bool {
+        // The spin value is reset when the Destination CID changes. When
+        // spinning is disabled, a random value is used for each Destination
+        // CID instead, as recommended by RFC 9000.
+        if self.spin_dcid_seq != self.active_dcid_seq {
+            self.spin_dcid_seq = self.active_dcid_seq;
+            self.spin_value = !self.spin_enabled && rand::rand_u8() % 2 == 0;
+        }
+
+        self.spin_value
+    }
+
+    /// Returns the multipath status of the path.
+    #[inline]
+    pub fn status(&self) -> multipath::PathStatus {
+        self.status
+    }
+
+    /// Sets the multipath status of the path, and returns the sequence number
+    /// to advertise it with.
+    pub fn set_status(&mut self, status: multipath::PathStatus) -> u64 {
+        self.status = status;
+
+        let seq_num = self.next_status_seq_num;
+        self.next_status_seq_num += 1;
+
+        seq_num
+    }
+
+    /// Handles a PATH_STATUS frame received from the peer. Frames that are
+    /// older than the last one processed are ignored.
+    pub fn on_status_received(
+        &mut self, seq_num: u64, status: multipath::PathStatus,
+    ) {
+        if self.peer_status_seq_num.map_or(false, |s| seq_num <= s) {
+            return;
+        }
+
+        self.peer_status_seq_num = Some(seq_num);
+        self.status = status;
+    }
+
+    /// Returns whether the path was abandoned.
+    #[inline]
+    pub fn abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    /// Abandons the path, so it is not used to send packets anymore.
+    pub fn abandon(&mut self) {
+        self.abandoned = true;
+        self.active = false;
+    }
+
     pub fn stats(&self) -> PathStats {
         PathStats {
             local_addr: self.local_addr,
@@ -509,6 +715,10 @@ pub struct PathMap {
 
     /// Whether this manager serves a connection as a server.
     is_server: bool,
+
+    /// Whether multipath is in use, in which case several paths can be active
+    /// at the same time.
+    multipath: bool,
 }
 
 impl PathMap {
@@ -535,6 +745,7 @@ impl PathMap {
             addrs_to_paths,
             events: VecDeque::new(),
             is_server,
+            multipath: false,
         }
     }
 
@@ -714,9 +925,16 @@ impl PathMap {
             .map(|(pid, _)| pid)
     }
 
+    /// Enables multipath, so that validated paths become active alongside
+    /// the existing ones.
+    pub fn set_multipath(&mut self, v: bool) {
+        self.multipath = v;
+    }
+
     /// Handles incoming PATH_RESPONSE data.
     pub fn on_response_received(&mut self, data: [u8; 8]) -> Result<()> {
         let active_pid = self.get_active_path_id()?;
+        let multipath = self.multipath;
 
         let challenge_pending =
             self.iter_mut().find(|(_, p)| p.has_pending_challenge(data));
@@ -729,6 +947,11 @@ impl PathMap {
 
                 p.migrating = false;
 
+                // With multipath, validated paths are used right away.
+                if multipath && !p.abandoned {
+                    p.active = true;
+                }
+
                 // Notifies the application.
                 self.notify_event(PathEvent::Validated(local_addr, peer_addr));
 
@@ -0,0 +1,345 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Datagram Packetization Layer Path MTU Discovery.
+//!
+//! This implementation is based on the following RFC:
+//!
+//! <https://www.rfc-editor.org/rfc/rfc8899.html>
+
+use std::cmp;
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// The PMTU that is assumed to work on every path, as QUIC requires it.
+pub const BASE_PLPMTU: usize = crate::MIN_CLIENT_INITIAL_LEN;
+
+/// Number of consecutive losses of a probe before its size is considered
+/// larger than the PMTU.
+const MAX_PROBES: usize = 3;
+
+/// Number of packets larger than the base PMTU that can be lost, without any
+/// of them being acknowledged, before a black hole is assumed.
+pub const BLACK_HOLE_THRESHOLD: usize = 3;
+
+/// The search is complete once the distance between the confirmed PMTU and
+/// the smallest size known to fail is lower than this.
+const SEARCH_GRANULARITY: usize = 16;
+
+/// How long to wait after the search completed before probing again for a
+/// larger PMTU.
+const PMTU_RAISE_TIMER: Duration = Duration::from_secs(600);
+
+/// State of the PMTU discovery on a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PmtudState {
+    /// PMTU discovery is not enabled.
+    Disabled,
+
+    /// Probes are sent to find a larger PMTU.
+    Searching,
+
+    /// The largest PMTU was found, no probes are sent until the raise timer
+    /// expires.
+    SearchComplete,
+}
+
+#[derive(Debug)]
+pub struct Pmtud {
+    state: PmtudState,
+
+    /// The largest size confirmed by an acknowledged probe.
+    current: usize,
+
+    /// The largest size allowed by the local and peer's configurations.
+    max: usize,
+
+    /// The smallest size that is known to exceed the PMTU, if any.
+    failed: Option<usize>,
+
+    /// The size of the probe currently in flight.
+    probe_in_flight: Option<usize>,
+
+    /// Number of consecutive lost probes of the current probe size.
+    probe_count: usize,
+
+    /// When to restart the search after it completed.
+    raise_time: Option<Instant>,
+}
+
+impl Pmtud {
+    pub fn new(enabled: bool, max: usize) -> Self {
+        let state = if enabled && max > BASE_PLPMTU {
+            PmtudState::Searching
+        } else {
+            PmtudState::Disabled
+        };
+
+        Pmtud {
+            state,
+
+            current: BASE_PLPMTU,
+
+            max,
+
+            failed: None,
+
+            probe_in_flight: None,
+
+            probe_count: 0,
+
+            raise_time: None,
+        }
+    }
+
+    pub fn state(&self) -> PmtudState {
+        self.state
+    }
+
+    /// Returns the largest PMTU confirmed on the path.
+    #[cfg(test)]
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Lowers the largest size that can be probed, e.g. following the peer's
+    /// `max_udp_payload_size` transport parameter.
+    pub fn update_max(&mut self, max: usize) {
+        self.max = cmp::min(self.max, max);
+
+        if self.state != PmtudState::Disabled && self.max <= self.current {
+            self.state = PmtudState::SearchComplete;
+        }
+    }
+
+    /// Returns the size of the next probe to send, if any.
+    pub fn probe_size(&mut self, now: Instant) -> Option<usize> {
+        if self.state == PmtudState::SearchComplete &&
+            self.raise_time.map_or(false, |t| t <= now) &&
+            self.current < self.max
+        {
+            self.state = PmtudState::Searching;
+            self.failed = None;
+            self.raise_time = None;
+        }
+
+        if self.state != PmtudState::Searching || self.probe_in_flight.is_some() {
+            return None;
+        }
+
+        let size = match self.failed {
+            // Try the largest allowed size first, as it is the most likely to
+            // succeed on common paths.
+            None => self.max,
+
+            Some(failed) => self.current + (failed - self.current) / 2,
+        };
+
+        Some(size)
+    }
+
+    pub fn on_probe_sent(&mut self, size: usize) {
+        self.probe_in_flight = Some(size);
+    }
+
+    /// Handles the acknowledgment of a probe, and returns the new PMTU if it
+    /// increased.
+    pub fn on_probe_acked(&mut self, size: usize, now: Instant) -> Option<usize> {
+        if self.probe_in_flight == Some(size) {
+            self.probe_in_flight = None;
+            self.probe_count = 0;
+        }
+
+        if self.state == PmtudState::Disabled || size <= self.current {
+            return None;
+        }
+
+        self.current = size;
+
+        self.update_search(now);
+
+        Some(self.current)
+    }
+
+    /// Handles the loss of a probe. This is not considered a congestion
+    /// signal.
+    pub fn on_probe_lost(&mut self, size: usize, now: Instant) {
+        if self.probe_in_flight != Some(size) {
+            return;
+        }
+
+        self.probe_in_flight = None;
+        self.probe_count += 1;
+
+        if self.probe_count >= MAX_PROBES {
+            self.probe_count = 0;
+            self.failed = Some(size);
+
+            self.update_search(now);
+        }
+    }
+
+    /// Handles the detection of a black hole, and returns the new PMTU if it
+    /// decreased.
+    pub fn on_black_hole(&mut self, now: Instant) -> Option<usize> {
+        if self.state == PmtudState::Disabled || self.current <= BASE_PLPMTU {
+            return None;
+        }
+
+        // Fall back to the base PMTU, and search again below the size that
+        // stopped working.
+        self.failed = Some(self.current);
+        self.current = BASE_PLPMTU;
+        self.probe_in_flight = None;
+        self.probe_count = 0;
+        self.state = PmtudState::Searching;
+
+        self.update_search(now);
+
+        Some(self.current)
+    }
+
+    fn update_search(&mut self, now: Instant) {
+        let upper = self.failed.unwrap_or(self.max + 1);
+
+        if self.current >= self.max ||
+            upper.saturating_sub(self.current) <= SEARCH_GRANULARITY
+        {
+            self.state = PmtudState::SearchComplete;
+            self.raise_time = Some(now + PMTU_RAISE_TIMER);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let mut pmtud = Pmtud::new(false, 1500);
+        let now = Instant::now();
+
+        assert_eq!(pmtud.state(), PmtudState::Disabled);
+        assert_eq!(pmtud.probe_size(now), None);
+
+        let mut pmtud = Pmtud::new(true, BASE_PLPMTU);
+
+        assert_eq!(pmtud.state(), PmtudState::Disabled);
+        assert_eq!(pmtud.probe_size(now), None);
+    }
+
+    #[test]
+    fn probe_max() {
+        let mut pmtud = Pmtud::new(true, 1500);
+        let now = Instant::now();
+
+        assert_eq!(pmtud.probe_size(now), Some(1500));
+        pmtud.on_probe_sent(1500);
+
+        // Only one probe is in flight at a time.
+        assert_eq!(pmtud.probe_size(now), None);
+
+        assert_eq!(pmtud.on_probe_acked(1500, now), Some(1500));
+        assert_eq!(pmtud.current(), 1500);
+        assert_eq!(pmtud.state(), PmtudState::SearchComplete);
+        assert_eq!(pmtud.probe_size(now), None);
+    }
+
+    #[test]
+    fn binary_search() {
+        let mut pmtud = Pmtud::new(true, 1500);
+        let now = Instant::now();
+
+        for _ in 0..MAX_PROBES {
+            assert_eq!(pmtud.probe_size(now), Some(1500));
+            pmtud.on_probe_sent(1500);
+            pmtud.on_probe_lost(1500, now);
+        }
+
+        assert_eq!(pmtud.state(), PmtudState::Searching);
+        assert_eq!(pmtud.probe_size(now), Some(1350));
+        pmtud.on_probe_sent(1350);
+        assert_eq!(pmtud.on_probe_acked(1350, now), Some(1350));
+
+        assert_eq!(pmtud.probe_size(now), Some(1425));
+        pmtud.on_probe_sent(1425);
+        assert_eq!(pmtud.on_probe_acked(1425, now), Some(1425));
+
+        assert_eq!(pmtud.probe_size(now), Some(1462));
+        pmtud.on_probe_sent(1462);
+        assert_eq!(pmtud.on_probe_acked(1462, now), Some(1462));
+
+        assert_eq!(pmtud.probe_size(now), Some(1481));
+        pmtud.on_probe_sent(1481);
+        assert_eq!(pmtud.on_probe_acked(1481, now), Some(1481));
+
+        assert_eq!(pmtud.probe_size(now), Some(1490));
+        pmtud.on_probe_sent(1490);
+        assert_eq!(pmtud.on_probe_acked(1490, now), Some(1490));
+
+        // The search converged.
+        assert_eq!(pmtud.state(), PmtudState::SearchComplete);
+        assert_eq!(pmtud.probe_size(now), None);
+
+        // The search restarts once the raise timer expires.
+        let now = now + PMTU_RAISE_TIMER;
+        assert_eq!(pmtud.probe_size(now), Some(1500));
+    }
+
+    #[test]
+    fn update_max() {
+        let mut pmtud = Pmtud::new(true, 1500);
+        let now = Instant::now();
+
+        pmtud.update_max(1400);
+        assert_eq!(pmtud.probe_size(now), Some(1400));
+
+        pmtud.update_max(1000);
+        assert_eq!(pmtud.state(), PmtudState::SearchComplete);
+        assert_eq!(pmtud.probe_size(now), None);
+    }
+
+    #[test]
+    fn black_hole() {
+        let mut pmtud = Pmtud::new(true, 1500);
+        let now = Instant::now();
+
+        assert_eq!(pmtud.on_black_hole(now), None);
+
+        assert_eq!(pmtud.probe_size(now), Some(1500));
+        pmtud.on_probe_sent(1500);
+        assert_eq!(pmtud.on_probe_acked(1500, now), Some(1500));
+
+        assert_eq!(pmtud.on_black_hole(now), Some(BASE_PLPMTU));
+        assert_eq!(pmtud.current(), BASE_PLPMTU);
+        assert_eq!(pmtud.state(), PmtudState::Searching);
+
+        // Probe below the size that stopped working.
+        assert_eq!(pmtud.probe_size(now), Some(1350));
+    }
+}
@@ -432,6 +432,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -454,6 +456,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -502,6 +505,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -525,6 +530,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -571,6 +577,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -594,6 +602,7 @@ mod tests {
                 r.on_ack_received(
                     &acked,
                     25,
+                    None,
                     packet::Epoch::Application,
                     HandshakeStatus::default(),
                     now,
@@ -622,6 +631,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -648,6 +659,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -694,6 +706,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -714,6 +728,7 @@ mod tests {
                 r.on_ack_received(
                     &acked,
                     25,
+                    None,
                     packet::Epoch::Application,
                     HandshakeStatus::default(),
                     now,
@@ -765,6 +780,8 @@ mod tests {
                 tx_in_flight: 0,
                 lost: 0,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
             };
 
             r.on_packet_sent(
@@ -787,6 +804,7 @@ mod tests {
                 r.on_ack_received(
                     &acked,
                     25,
+                    None,
                     packet::Epoch::Application,
                     HandshakeStatus::default(),
                     now,
@@ -819,6 +837,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -843,6 +863,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
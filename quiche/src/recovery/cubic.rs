@@ -490,6 +490,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -542,6 +544,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -619,6 +623,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -663,6 +669,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -739,6 +747,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -806,6 +816,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // 1st round.
@@ -962,6 +974,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // 1st round.
@@ -1114,6 +1128,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -1175,6 +1191,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -1250,6 +1268,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -1316,6 +1336,8 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -242,6 +242,8 @@ mod tests {
                 first_sent_time: now,
                 is_app_limited: false,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
                 tx_in_flight: 0,
                 lost: 0,
             };
@@ -310,6 +312,8 @@ mod tests {
                 first_sent_time: now,
                 is_app_limited: false,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
                 tx_in_flight: 0,
                 lost: 0,
             };
@@ -351,6 +355,8 @@ mod tests {
                 first_sent_time: now,
                 is_app_limited: false,
                 has_data: false,
+                ecn_marked: false,
+                pmtud_probe: false,
                 tx_in_flight: 0,
                 lost: 0,
             };
@@ -374,6 +380,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -0,0 +1,311 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! ECN validation.
+//!
+//! This implementation is based on the following RFC:
+//!
+//! <https://www.rfc-editor.org/rfc/rfc9000.html#section-13.4.2>
+
+use crate::frame::EcnCounts;
+use crate::packet;
+
+/// Number of packets marked with ECT(0) while testing the path, before
+/// waiting for the peer to acknowledge them.
+const ECN_TESTING_PACKETS: usize = 10;
+
+/// State of ECN validation on a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EcnState {
+    /// Outgoing packets are marked to test the path.
+    Testing,
+
+    /// Outgoing packets are not marked until the testing packets are
+    /// acknowledged.
+    Unknown,
+
+    /// The path and the peer support ECN, outgoing packets are marked.
+    Capable,
+
+    /// Validation failed, or ECN is disabled, outgoing packets are not marked.
+    Failed,
+}
+
+pub struct EcnValidation {
+    state: EcnState,
+
+    testing_sent: usize,
+
+    testing_lost: usize,
+
+    ect0_sent: [u64; packet::Epoch::count()],
+
+    peer_counts: [EcnCounts; packet::Epoch::count()],
+}
+
+impl std::fmt::Debug for EcnValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "state={:?} ", self.state)?;
+        write!(f, "testing_sent={} ", self.testing_sent)?;
+        write!(f, "testing_lost={}", self.testing_lost)?;
+
+        Ok(())
+    }
+}
+
+impl EcnValidation {
+    pub fn new(enabled: bool) -> Self {
+        EcnValidation {
+            state: if enabled {
+                EcnState::Testing
+            } else {
+                EcnState::Failed
+            },
+
+            testing_sent: 0,
+
+            testing_lost: 0,
+
+            ect0_sent: [0; packet::Epoch::count()],
+
+            peer_counts: [EcnCounts::default(); packet::Epoch::count()],
+        }
+    }
+
+    pub fn state(&self) -> EcnState {
+        self.state
+    }
+
+    /// Returns whether the next outgoing packet should be marked with ECT(0).
+    pub fn should_mark(&self) -> bool {
+        matches!(self.state, EcnState::Testing | EcnState::Capable)
+    }
+
+    pub fn on_packet_sent(&mut self, marked: bool, epoch: packet::Epoch) {
+        if !marked {
+            return;
+        }
+
+        self.ect0_sent[epoch] += 1;
+
+        if self.state == EcnState::Testing {
+            self.testing_sent += 1;
+
+            if self.testing_sent >= ECN_TESTING_PACKETS {
+                self.state = EcnState::Unknown;
+            }
+        }
+    }
+
+    pub fn on_packets_lost(&mut self, marked_lost: usize) {
+        if !matches!(self.state, EcnState::Testing | EcnState::Unknown) {
+            return;
+        }
+
+        self.testing_lost += marked_lost;
+
+        // Validation fails if all the testing packets are lost, as marked
+        // packets might be dropped by the network.
+        if self.state == EcnState::Unknown &&
+            self.testing_lost >= self.testing_sent
+        {
+            self.state = EcnState::Failed;
+        }
+    }
+
+    /// Validates the ECN counts carried by an ACK frame that newly acknowledged
+    /// `marked_acked` packets sent with ECT(0).
+    ///
+    /// Returns the increase in the number of CE-marked packets reported by the
+    /// peer.
+    pub fn on_ack_received(
+        &mut self, marked_acked: u64, counts: Option<&EcnCounts>,
+        epoch: packet::Epoch,
+    ) -> u64 {
+        if self.state == EcnState::Failed {
+            return 0;
+        }
+
+        let counts = match counts {
+            Some(v) => v,
+
+            // Acknowledging marked packets without ECN counts means that
+            // either the network or the peer doesn't support ECN.
+            None if marked_acked > 0 => {
+                self.state = EcnState::Failed;
+                return 0;
+            },
+
+            None => return 0,
+        };
+
+        let prev = &self.peer_counts[epoch];
+
+        // Ignore reordered ACK frames carrying stale counts.
+        if counts.ect0_count < prev.ect0_count ||
+            counts.ect1_count < prev.ect1_count ||
+            counts.ecn_ce_count < prev.ecn_ce_count
+        {
+            return 0;
+        }
+
+        let ect0_increase = counts.ect0_count - prev.ect0_count;
+        let ce_increase = counts.ecn_ce_count - prev.ecn_ce_count;
+
+        // The counts must account for all newly acknowledged marked packets,
+        // and can't exceed the number of packets sent with each codepoint.
+        if ect0_increase + ce_increase < marked_acked ||
+            counts.ect0_count + counts.ecn_ce_count > self.ect0_sent[epoch] ||
+            counts.ect1_count > 0
+        {
+            self.state = EcnState::Failed;
+            return 0;
+        }
+
+        self.peer_counts[epoch] = *counts;
+
+        if marked_acked > 0 &&
+            matches!(self.state, EcnState::Testing | EcnState::Unknown)
+        {
+            self.state = EcnState::Capable;
+        }
+
+        ce_increase
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(ect0_count: u64, ect1_count: u64, ecn_ce_count: u64) -> EcnCounts {
+        EcnCounts {
+            ect0_count,
+            ect1_count,
+            ecn_ce_count,
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let ecn = EcnValidation::new(false);
+
+        assert_eq!(ecn.state(), EcnState::Failed);
+        assert!(!ecn.should_mark());
+    }
+
+    #[test]
+    fn validation_success() {
+        let mut ecn = EcnValidation::new(true);
+
+        for _ in 0..ECN_TESTING_PACKETS {
+            assert!(ecn.should_mark());
+            ecn.on_packet_sent(true, packet::Epoch::Application);
+        }
+
+        assert_eq!(ecn.state(), EcnState::Unknown);
+        assert!(!ecn.should_mark());
+
+        let ce = ecn.on_ack_received(
+            2,
+            Some(&counts(2, 0, 0)),
+            packet::Epoch::Application,
+        );
+        assert_eq!(ce, 0);
+
+        assert_eq!(ecn.state(), EcnState::Capable);
+        assert!(ecn.should_mark());
+
+        // CE marks are reported to the caller.
+        let ce = ecn.on_ack_received(
+            3,
+            Some(&counts(3, 0, 2)),
+            packet::Epoch::Application,
+        );
+        assert_eq!(ce, 2);
+
+        assert_eq!(ecn.state(), EcnState::Capable);
+    }
+
+    #[test]
+    fn validation_missing_counts() {
+        let mut ecn = EcnValidation::new(true);
+
+        ecn.on_packet_sent(true, packet::Epoch::Application);
+
+        assert_eq!(ecn.on_ack_received(1, None, packet::Epoch::Application), 0);
+
+        assert_eq!(ecn.state(), EcnState::Failed);
+        assert!(!ecn.should_mark());
+    }
+
+    #[test]
+    fn validation_bleached() {
+        let mut ecn = EcnValidation::new(true);
+
+        ecn.on_packet_sent(true, packet::Epoch::Application);
+        ecn.on_packet_sent(true, packet::Epoch::Application);
+
+        // The peer received the packets without ECN marks.
+        ecn.on_ack_received(
+            2,
+            Some(&counts(0, 0, 0)),
+            packet::Epoch::Application,
+        );
+
+        assert_eq!(ecn.state(), EcnState::Failed);
+    }
+
+    #[test]
+    fn validation_too_many_marks() {
+        let mut ecn = EcnValidation::new(true);
+
+        ecn.on_packet_sent(true, packet::Epoch::Application);
+
+        ecn.on_ack_received(
+            1,
+            Some(&counts(0, 1, 0)),
+            packet::Epoch::Application,
+        );
+
+        assert_eq!(ecn.state(), EcnState::Failed);
+    }
+
+    #[test]
+    fn validation_all_lost() {
+        let mut ecn = EcnValidation::new(true);
+
+        for _ in 0..ECN_TESTING_PACKETS {
+            ecn.on_packet_sent(true, packet::Epoch::Application);
+        }
+
+        ecn.on_packets_lost(ECN_TESTING_PACKETS - 1);
+        assert_eq!(ecn.state(), EcnState::Unknown);
+
+        ecn.on_packets_lost(1);
+        assert_eq!(ecn.state(), EcnState::Failed);
+    }
+}
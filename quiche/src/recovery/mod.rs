@@ -39,6 +39,7 @@ use crate::Result;
 use crate::frame;
 use crate::minmax;
 use crate::packet;
+use crate::pmtud;
 use crate::ranges;
 
 #[cfg(feature = "qlog")]
@@ -153,6 +154,13 @@ pub struct Recovery {
     // RFC6937 PRR.
     prr: prr::PRR,
 
+    // ECN validation.
+    ecn: ecn::EcnValidation,
+
+    // Number of packets larger than the base PMTU that were lost since one of
+    // them was last acknowledged, used to detect PMTU black holes.
+    large_lost_count: usize,
+
     #[cfg(feature = "qlog")]
     qlog_metrics: QlogMetrics,
 
@@ -174,13 +182,15 @@ pub struct Recovery {
 }
 
 pub struct RecoveryConfig {
-    max_send_udp_payload_size: usize,
+    pub max_send_udp_payload_size: usize,
     pub max_ack_delay: Duration,
     cc_ops: &'static CongestionControlOps,
     hystart: bool,
     pacing: bool,
     max_pacing_rate: Option<u64>,
     initial_congestion_window_packets: usize,
+    ecn: bool,
+    pub pmtud: bool,
 }
 
 impl RecoveryConfig {
@@ -194,14 +204,27 @@ impl RecoveryConfig {
             max_pacing_rate: config.max_pacing_rate,
             initial_congestion_window_packets: config
                 .initial_congestion_window_packets,
+            ecn: config.ecn,
+            pmtud: config.pmtud,
         }
     }
 }
 
 impl Recovery {
     pub fn new_with_config(recovery_config: &RecoveryConfig) -> Self {
-        let initial_congestion_window = recovery_config.max_send_udp_payload_size *
-            recovery_config.initial_congestion_window_packets;
+        // When PMTUD is enabled, start from the base PMTU and let it discover
+        // larger sizes.
+        let max_datagram_size = if recovery_config.pmtud {
+            cmp::min(
+                recovery_config.max_send_udp_payload_size,
+                pmtud::BASE_PLPMTU,
+            )
+        } else {
+            recovery_config.max_send_udp_payload_size
+        };
+
+        let initial_congestion_window =
+            max_datagram_size * recovery_config.initial_congestion_window_packets;
 
         Recovery {
             loss_detection_timer: None,
@@ -265,7 +288,7 @@ impl Recovery {
 
             congestion_recovery_start_time: None,
 
-            max_datagram_size: recovery_config.max_send_udp_payload_size,
+            max_datagram_size,
 
             cc_ops: recovery_config.cc_ops,
 
@@ -281,12 +304,16 @@ impl Recovery {
                 recovery_config.pacing,
                 initial_congestion_window,
                 0,
-                recovery_config.max_send_udp_payload_size,
+                max_datagram_size,
                 recovery_config.max_pacing_rate,
             ),
 
             prr: prr::PRR::default(),
 
+            ecn: ecn::EcnValidation::new(recovery_config.ecn),
+
+            large_lost_count: 0,
+
             send_quantum: initial_congestion_window,
 
             #[cfg(feature = "qlog")]
@@ -339,6 +366,8 @@ impl Recovery {
         let sent_bytes = pkt.size;
         let pkt_num = pkt.pkt_num;
 
+        self.ecn.on_packet_sent(pkt.ecn_marked, epoch);
+
         if ack_eliciting {
             self.outstanding_non_ack_eliciting = 0;
         } else {
@@ -437,8 +466,9 @@ impl Recovery {
     #[allow(clippy::too_many_arguments)]
     pub fn on_ack_received(
         &mut self, ranges: &ranges::RangeSet, ack_delay: u64,
-        epoch: packet::Epoch, handshake_status: HandshakeStatus, now: Instant,
-        trace_id: &str, newly_acked: &mut Vec<Acked>,
+        ecn_counts: Option<&frame::EcnCounts>, epoch: packet::Epoch,
+        handshake_status: HandshakeStatus, now: Instant, trace_id: &str,
+        newly_acked: &mut Vec<Acked>,
     ) -> Result<(usize, usize)> {
         let largest_acked = ranges.last().unwrap();
 
@@ -463,6 +493,10 @@ impl Recovery {
 
         let mut undo_cwnd = false;
 
+        let mut ecn_marked_acked = 0;
+
+        let mut large_acked = false;
+
         let max_rtt = cmp::max(self.latest_rtt, self.rtt());
 
         let sent = &mut self.sent[epoch];
@@ -497,6 +531,10 @@ impl Recovery {
             for unacked in unacked_iter {
                 unacked.time_acked = Some(now);
 
+                if unacked.ecn_marked {
+                    ecn_marked_acked += 1;
+                }
+
                 // Check if acked packet was already declared lost.
                 if unacked.time_lost.is_some() {
                     // Calculate new packet reordering threshold.
@@ -533,6 +571,10 @@ impl Recovery {
                 largest_newly_acked_pkt_num = unacked.pkt_num;
                 largest_newly_acked_sent_time = unacked.time_sent;
 
+                if !unacked.pmtud_probe && unacked.size > pmtud::BASE_PLPMTU {
+                    large_acked = true;
+                }
+
                 self.acked[epoch].extend(unacked.frames.drain(..));
 
                 if unacked.in_flight {
@@ -593,11 +635,27 @@ impl Recovery {
             }
         }
 
+        // Validate ECN counts, and treat newly reported CE marks as a
+        // congestion signal.
+        let ce_increase =
+            self.ecn
+                .on_ack_received(ecn_marked_acked, ecn_counts, epoch);
+
+        if ce_increase > 0 {
+            self.on_ecn_ce(largest_newly_acked_pkt_num, epoch, now);
+        }
+
         // Detect and mark lost packets without removing them from the sent
         // packets list.
         let (lost_packets, lost_bytes) =
             self.detect_lost_packets(epoch, now, trace_id);
 
+        // Large packets still go through, so losses are not due to a black
+        // hole.
+        if large_acked {
+            self.large_lost_count = 0;
+        }
+
         self.on_packets_acked(newly_acked, epoch, now);
 
         self.pto_count = 0;
@@ -664,8 +722,17 @@ impl Recovery {
         // This will also trigger sending an ACK and retransmitting frames like
         // HANDSHAKE_DONE and MAX_DATA / MAX_STREAM_DATA as well, in addition
         // to CRYPTO and STREAM, if the original packet carried them.
+        //
+        // DATAGRAM frames are never retransmitted, and since the packet might
+        // still be acked they are not reported as lost either.
         for unacked in unacked_iter {
-            self.lost[epoch].extend_from_slice(&unacked.frames);
+            self.lost[epoch].extend(
+                unacked
+                    .frames
+                    .iter()
+                    .filter(|f| !matches!(f, frame::Frame::DatagramHeader { .. }))
+                    .cloned(),
+            );
         }
 
         self.set_loss_detection_timer(handshake_status, now);
@@ -688,6 +755,17 @@ impl Recovery {
 
         self.bytes_in_flight = self.bytes_in_flight.saturating_sub(unacked_bytes);
 
+        // Marked packets that will never be acknowledged can't be used for
+        // ECN validation, so consider them lost.
+        let ecn_marked_unacked = self.sent[epoch]
+            .iter()
+            .filter(|p| {
+                p.ecn_marked && p.time_acked.is_none() && p.time_lost.is_none()
+            })
+            .count();
+
+        self.ecn.on_packets_lost(ecn_marked_unacked);
+
         self.sent[epoch].clear();
         self.lost[epoch].clear();
         self.acked[epoch].clear();
@@ -754,9 +832,15 @@ impl Recovery {
     }
 
     pub fn update_max_datagram_size(&mut self, new_max_datagram_size: usize) {
-        let max_datagram_size =
-            cmp::min(self.max_datagram_size, new_max_datagram_size);
+        self.pmtud_update_max_datagram_size(cmp::min(
+            self.max_datagram_size,
+            new_max_datagram_size,
+        ));
+    }
 
+    /// Sets the max datagram size to the PMTU found by PMTUD, which can be
+    /// larger or smaller than the current one.
+    pub fn pmtud_update_max_datagram_size(&mut self, max_datagram_size: usize) {
         // Update cwnd if it hasn't been updated yet.
         if self.congestion_window ==
             self.max_datagram_size * self.initial_congestion_window_packets
@@ -774,6 +858,12 @@ impl Recovery {
         );
 
         self.max_datagram_size = max_datagram_size;
+
+        self.large_lost_count = 0;
+    }
+
+    pub fn large_lost_count(&self) -> usize {
+        self.large_lost_count
     }
 
     fn update_rtt(
@@ -920,6 +1010,8 @@ impl Recovery {
         let mut lost_packets = 0;
         let mut lost_bytes = 0;
 
+        let mut ecn_marked_lost = 0;
+
         let mut largest_lost_pkt = None;
 
         let unacked_iter = self.sent[epoch]
@@ -938,7 +1030,22 @@ impl Recovery {
 
                 unacked.time_lost = Some(now);
 
-                if unacked.in_flight {
+                if unacked.ecn_marked {
+                    ecn_marked_lost += 1;
+                }
+
+                if unacked.pmtud_probe {
+                    // The loss of a PMTUD probe is not a congestion signal.
+                    self.bytes_in_flight =
+                        self.bytes_in_flight.saturating_sub(unacked.size);
+
+                    self.in_flight_count[epoch] =
+                        self.in_flight_count[epoch].saturating_sub(1);
+                } else if unacked.in_flight {
+                    if unacked.size > pmtud::BASE_PLPMTU {
+                        self.large_lost_count += 1;
+                    }
+
                     lost_bytes += unacked.size;
 
                     // Frames have already been removed from the packet, so
@@ -973,6 +1080,8 @@ impl Recovery {
 
         self.bytes_lost += lost_bytes as u64;
 
+        self.ecn.on_packets_lost(ecn_marked_lost);
+
         if let Some(pkt) = largest_lost_pkt {
             self.on_packets_lost(lost_bytes, &pkt, epoch, now);
         }
@@ -1076,6 +1185,22 @@ impl Recovery {
         );
     }
 
+    fn on_ecn_ce(
+        &mut self, largest_acked_pkt_num: u64, epoch: packet::Epoch, now: Instant,
+    ) {
+        let largest_acked_pkt = match self.sent[epoch]
+            .binary_search_by_key(&largest_acked_pkt_num, |p| p.pkt_num)
+        {
+            // Frames have already been removed from the packet, so cloning
+            // the whole packet should be relatively cheap.
+            Ok(i) => self.sent[epoch][i].clone(),
+
+            Err(_) => return,
+        };
+
+        self.congestion_event(0, &largest_acked_pkt, epoch, now);
+    }
+
     fn collapse_cwnd(&mut self) {
         (self.cc_ops.collapse_cwnd)(self);
     }
@@ -1111,6 +1236,15 @@ impl Recovery {
     pub fn send_quantum(&self) -> usize {
         self.send_quantum
     }
+
+    /// Returns whether the next outgoing packet should be marked with ECT(0).
+    pub fn ecn_should_mark(&self) -> bool {
+        self.ecn.should_mark()
+    }
+
+    pub fn ecn_state(&self) -> ecn::EcnState {
+        self.ecn.state()
+    }
 }
 
 /// Available congestion control algorithms.
@@ -1229,6 +1363,7 @@ impl std::fmt::Debug for Recovery {
         )?;
         write!(f, "{:?} ", self.delivery_rate)?;
         write!(f, "pacer={:?} ", self.pacer)?;
+        write!(f, "ecn={:?} ", self.ecn)?;
 
         if self.hystart.enabled() {
             write!(f, "hystart={:?} ", self.hystart)?;
@@ -1272,6 +1407,10 @@ pub struct Sent {
     pub lost: u64,
 
     pub has_data: bool,
+
+    pub ecn_marked: bool,
+
+    pub pmtud_probe: bool,
 }
 
 impl std::fmt::Debug for Sent {
@@ -1286,6 +1425,8 @@ impl std::fmt::Debug for Sent {
         write!(f, "tx_in_flight={} ", self.tx_in_flight)?;
         write!(f, "lost={} ", self.lost)?;
         write!(f, "has_data={} ", self.has_data)?;
+        write!(f, "ecn_marked={} ", self.ecn_marked)?;
+        write!(f, "pmtud_probe={} ", self.pmtud_probe)?;
 
         Ok(())
     }
@@ -1517,6 +1658,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1545,6 +1688,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1573,6 +1718,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1601,6 +1748,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1624,6 +1773,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -1662,6 +1812,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1690,6 +1842,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1714,6 +1868,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -1764,6 +1919,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1792,6 +1949,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1820,6 +1979,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1848,6 +2009,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1872,6 +2035,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -1933,6 +2097,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1961,6 +2127,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -1989,6 +2157,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2017,6 +2187,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2040,6 +2212,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -2060,6 +2233,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -2115,6 +2289,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2142,6 +2318,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 10,
+                None,
                 packet::Epoch::Application,
                 HandshakeStatus::default(),
                 now,
@@ -2175,6 +2352,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2208,6 +2387,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2238,6 +2419,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         r.on_packet_sent(
@@ -2268,6 +2451,7 @@ mod bbr;
 mod bbr2;
 mod cubic;
 mod delivery_rate;
+pub mod ecn;
 mod hystart;
 mod pacer;
 mod prr;
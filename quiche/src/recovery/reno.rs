@@ -220,6 +220,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -273,6 +275,8 @@ mod tests {
             tx_in_flight: 0,
             lost: 0,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -352,6 +356,8 @@ mod tests {
             first_sent_time: std::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
@@ -393,6 +399,8 @@ mod tests {
             first_sent_time: std::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
+            pmtud_probe: false,
             tx_in_flight: 0,
             lost: 0,
         };
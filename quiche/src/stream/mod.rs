@@ -27,6 +27,7 @@
 use std::cmp;
 
 use std::sync::Arc;
+use std::time;
 
 use std::collections::hash_map;
 use std::collections::HashMap;
@@ -96,6 +97,10 @@ pub struct StreamMap {
     /// created streams, to prevent peers from re-creating them.
     collected: StreamIdHashSet,
 
+    /// Final statistics of collected streams, retained until the application
+    /// reads them.
+    collected_stats: StreamIdHashMap<StreamStats>,
+
     /// Peer's maximum bidirectional stream count limit.
     peer_max_streams_bidi: u64,
 
@@ -122,10 +127,18 @@ pub struct StreamMap {
     /// The total number of unidirectional streams opened by the local endpoint.
     local_opened_streams_uni: u64,
 
-    /// Queue of stream IDs corresponding to streams that have buffered data
+    /// Set of stream IDs corresponding to streams that have buffered data
     /// ready to be sent to the peer. This also implies that the stream has
     /// enough flow control credits to send at least some of that data.
-    flushable: RBTree<StreamFlushablePriorityAdapter>,
+    flushable: StreamIdHashSet,
+
+    /// Decides the order in which flushable streams send data, if configured
+    /// by the application.
+    scheduler: Option<Box<dyn StreamScheduler>>,
+
+    /// Orders flushable streams by priority when no scheduler is configured,
+    /// using the streams' own priority keys.
+    priority_scheduler: StreamPriorityScheduler,
 
     /// Set of stream IDs corresponding to streams that have outstanding data
     /// to read. This is used to generate a `StreamIter` of streams without
@@ -150,9 +163,10 @@ pub struct StreamMap {
     blocked: StreamIdHashMap<u64>,
 
     /// Set of stream IDs corresponding to streams that are reset. The value
-    /// of the map elements is a tuple of the error code and final size values
-    /// to include in the RESET_STREAM frame.
-    reset: StreamIdHashMap<(u64, u64)>,
+    /// of the map elements is a tuple of the error code, final size and
+    /// reliable size values to include in the RESET_STREAM or RESET_STREAM_AT
+    /// frame.
+    reset: StreamIdHashMap<(u64, u64, u64)>,
 
     /// Set of stream IDs corresponding to streams that are shutdown on the
     /// receive side, and need to send a STOP_SENDING frame. The value of the
@@ -376,31 +390,90 @@ impl StreamMap {
         c.remove();
     }
 
+    /// Sets the scheduler deciding the order in which flushable streams send
+    /// data.
+    ///
+    /// This should only be called before any stream becomes flushable.
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn StreamScheduler>) {
+        self.scheduler = Some(scheduler);
+    }
+
     /// Adds the stream ID to the flushable streams set.
     ///
     /// If the stream was already in the list, this does nothing.
     pub fn insert_flushable(&mut self, priority_key: &Arc<StreamPriorityKey>) {
-        if !priority_key.flushable.is_linked() {
-            self.flushable.insert(Arc::clone(priority_key));
+        if !self.flushable.insert(priority_key.id) {
+            return;
+        }
+
+        match &mut self.scheduler {
+            Some(scheduler) => scheduler.insert(
+                priority_key.id,
+                priority_key.urgency,
+                priority_key.incremental,
+            ),
+
+            None => self.priority_scheduler.insert_key(priority_key),
         }
     }
 
     /// Removes the stream ID from the flushable streams set.
-    pub fn remove_flushable(&mut self, priority_key: &Arc<StreamPriorityKey>) {
-        if !priority_key.flushable.is_linked() {
-            return;
+    ///
+    /// The scheduler is always notified, even if the stream wasn't flushable,
+    /// so that it doesn't keep returning it.
+    pub fn remove_flushable(&mut self, stream_id: u64) {
+        self.flushable.remove(&stream_id);
+
+        match &mut self.scheduler {
+            Some(scheduler) => scheduler.remove(stream_id),
+
+            None =>
+                if let Some(stream) = self.streams.get(&stream_id) {
+                    self.priority_scheduler.remove_key(&stream.priority_key);
+                },
         }
+    }
 
-        let mut c = {
-            let ptr = Arc::as_ptr(priority_key);
-            unsafe { self.flushable.cursor_mut_from_ptr(ptr) }
-        };
+    /// Returns the ID of the next flushable stream to send data from, as
+    /// decided by the scheduler.
+    ///
+    /// Streams returned by the scheduler that are not flushable, or that were
+    /// already collected, are removed from it and skipped.
+    pub fn peek_flushable(&mut self) -> Option<u64> {
+        loop {
+            let stream_id = match &mut self.scheduler {
+                Some(scheduler) => scheduler.peek()?,
+
+                None => self.priority_scheduler.peek()?,
+            };
+
+            if self.flushable.contains(&stream_id) &&
+                self.streams.contains_key(&stream_id)
+            {
+                return Some(stream_id);
+            }
 
-        c.remove();
+            self.flushable.remove(&stream_id);
+
+            match &mut self.scheduler {
+                Some(scheduler) => scheduler.remove(stream_id),
+
+                None => self.priority_scheduler.remove_front(),
+            }
+        }
     }
 
-    pub fn peek_flushable(&self) -> Option<Arc<StreamPriorityKey>> {
-        self.flushable.front().clone_pointer()
+    /// Notifies the scheduler that `len` bytes were sent from the stream,
+    /// which is still flushable.
+    pub fn on_flushable_sent(&mut self, stream_id: u64, len: usize) {
+        match &mut self.scheduler {
+            Some(scheduler) => scheduler.on_sent(stream_id, len),
+
+            None =>
+                if let Some(stream) = self.streams.get(&stream_id) {
+                    self.priority_scheduler.on_key_sent(&stream.priority_key);
+                },
+        }
     }
 
     /// Updates the priorities of a stream.
@@ -417,9 +490,19 @@ impl StreamMap {
             self.writable.insert(Arc::clone(new));
         }
 
-        if old.flushable.is_linked() {
-            self.remove_flushable(old);
-            self.flushable.insert(Arc::clone(new));
+        if self.flushable.contains(&old.id) {
+            match &mut self.scheduler {
+                Some(scheduler) => scheduler.update_priority(
+                    new.id,
+                    new.urgency,
+                    new.incremental,
+                ),
+
+                None => {
+                    self.priority_scheduler.remove_key(old);
+                    self.priority_scheduler.insert_key(new);
+                },
+            }
         }
     }
 
@@ -449,13 +532,15 @@ impl StreamMap {
     }
 
     /// Adds the stream ID to the reset streams set with the
-    /// given error code and final size values.
+    /// given error code, final size and reliable size values.
     ///
     /// If the stream was already in the list, this does nothing.
     pub fn insert_reset(
         &mut self, stream_id: u64, error_code: u64, final_size: u64,
+        reliable_size: u64,
     ) {
-        self.reset.insert(stream_id, (error_code, final_size));
+        self.reset
+            .insert(stream_id, (error_code, final_size, reliable_size));
     }
 
     /// Removes the stream ID from the reset streams set.
@@ -540,17 +625,30 @@ impl StreamMap {
             }
         }
 
+        self.remove_flushable(stream_id);
+
         let s = self.streams.remove(&stream_id).unwrap();
 
+        self.collected_stats.insert(
+            stream_id,
+            s.stats.stats(s.send.acked_bytes(), time::Instant::now()),
+        );
+
         self.remove_readable(&s.priority_key);
 
         self.remove_writable(&s.priority_key);
 
-        self.remove_flushable(&s.priority_key);
-
         self.collected.insert(stream_id);
     }
 
+    /// Returns the final statistics of a collected stream, if they weren't
+    /// returned already.
+    pub fn take_collected_stats(
+        &mut self, stream_id: u64,
+    ) -> Option<StreamStats> {
+        self.collected_stats.remove(&stream_id)
+    }
+
     /// Creates an iterator over streams that have outstanding data to read.
     pub fn readable(&self) -> StreamIter {
         StreamIter {
@@ -577,8 +675,9 @@ impl StreamMap {
         self.blocked.iter()
     }
 
-    /// Creates an iterator over streams that need to send RESET_STREAM.
-    pub fn reset(&self) -> hash_map::Iter<u64, (u64, u64)> {
+    /// Creates an iterator over streams that need to send RESET_STREAM or
+    /// RESET_STREAM_AT.
+    pub fn reset(&self) -> hash_map::Iter<u64, (u64, u64, u64)> {
         self.reset.iter()
     }
 
@@ -669,6 +768,9 @@ pub struct Stream {
     pub incremental: bool,
 
     pub priority_key: Arc<StreamPriorityKey>,
+
+    /// Statistics about the stream.
+    pub stats: StreamStatsRecorder,
 }
 
 impl Stream {
@@ -691,6 +793,7 @@ impl Stream {
             urgency: priority_key.urgency,
             incremental: priority_key.incremental,
             priority_key,
+            stats: StreamStatsRecorder::new(time::Instant::now()),
         }
     }
 
@@ -889,17 +992,164 @@ impl ExactSizeIterator for StreamIter {
     }
 }
 
+/// An iterator over the contiguous chunks of data that can be read from a
+/// stream.
+pub struct StreamChunks<'a> {
+    bufs: std::collections::btree_map::Values<'a, u64, RangeBuf>,
+    off: u64,
+}
+
+impl<'a> Iterator for StreamChunks<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let buf = self.bufs.next()?;
+
+            // Stop at the first gap in the data.
+            if buf.off() != self.off {
+                return None;
+            }
+
+            // Skip the empty buffer that carries the fin flag.
+            if buf.is_empty() {
+                continue;
+            }
+
+            self.off = buf.max_off();
+
+            return Some(buf);
+        }
+    }
+}
+
+/// A reference-counted, immutable buffer.
+///
+/// Stream data written with [`stream_send_zc()`] is stored as a `SharedBuf`,
+/// which lets the stream hold on to the application's data until it is
+/// acknowledged by the peer, without copying it.
+///
+/// A `SharedBuf` can wrap any type that can be viewed as a byte slice, and
+/// cloning or slicing it only increments a reference count.
+///
+/// [`stream_send_zc()`]: ../struct.Connection.html#method.stream_send_zc
+#[derive(Clone)]
+pub struct SharedBuf {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+
+    start: usize,
+
+    end: usize,
+}
+
+impl SharedBuf {
+    /// Creates a new `SharedBuf` that takes ownership of the given buffer.
+    pub fn new<B>(buf: B) -> SharedBuf
+    where
+        B: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let end = buf.as_ref().len();
+
+        SharedBuf {
+            data: Arc::new(buf),
+            start: 0,
+            end,
+        }
+    }
+
+    /// Returns a `SharedBuf` holding the given range of `self`, sharing the
+    /// same underlying buffer.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, range: impl std::ops::RangeBounds<usize>) -> SharedBuf {
+        use std::ops::Bound;
+
+        let start = match range.start_bound() {
+            Bound::Included(&v) => v,
+            Bound::Excluded(&v) => v + 1,
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(&v) => v + 1,
+            Bound::Excluded(&v) => v,
+            Bound::Unbounded => self.len(),
+        };
+
+        assert!(
+            start <= end && end <= self.len(),
+            "range {}..{} out of bounds for buffer of length {}",
+            start,
+            end,
+            self.len()
+        );
+
+        SharedBuf {
+            data: self.data.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+
+    /// Returns the length of `self`.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns true if `self` has a length of zero bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SharedBuf {
+    fn default() -> SharedBuf {
+        SharedBuf::new(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for SharedBuf {
+    fn from(v: Vec<u8>) -> SharedBuf {
+        SharedBuf::new(v)
+    }
+}
+
+impl std::ops::Deref for SharedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.start..self.end]
+    }
+}
+
+impl std::fmt::Debug for SharedBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SharedBuf")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl PartialEq for SharedBuf {
+    fn eq(&self, other: &SharedBuf) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for SharedBuf {}
+
 /// Buffer holding data at a specific offset.
 ///
-/// The data is stored in a `Vec<u8>` in such a way that it can be shared
-/// between multiple `RangeBuf` objects.
+/// The data is stored in a `SharedBuf` in such a way that it can be shared
+/// between multiple `RangeBuf` objects, as well as with the application.
 ///
 /// Each `RangeBuf` will have its own view of that buffer, where the `start`
-/// value indicates the initial offset within the `Vec`, and `len` indicates the
-/// number of bytes, starting from `start` that are included.
+/// value indicates the initial offset within the `SharedBuf`, and `len`
+/// indicates the number of bytes, starting from `start` that are included.
 ///
-/// In addition, `pos` indicates the current offset within the `Vec`, starting
-/// from the very beginning of the `Vec`.
+/// In addition, `pos` indicates the current offset within the `SharedBuf`,
+/// starting from the very beginning of the `SharedBuf`.
 ///
 /// Finally, `off` is the starting offset for the specific `RangeBuf` within the
 /// stream the buffer belongs to.
@@ -910,7 +1160,7 @@ pub struct RangeBuf {
     /// To avoid needless allocations when a RangeBuf is split, this field is
     /// reference-counted and can be shared between multiple RangeBuf objects,
     /// and sliced using the `start` and `len` values.
-    data: Arc<Vec<u8>>,
+    data: SharedBuf,
 
     /// The initial offset within the internal buffer.
     start: usize,
@@ -932,7 +1182,7 @@ impl RangeBuf {
     /// Creates a new `RangeBuf` from the given slice.
     pub fn from(buf: &[u8], off: u64, fin: bool) -> RangeBuf {
         RangeBuf {
-            data: Arc::new(Vec::from(buf)),
+            data: SharedBuf::new(Vec::from(buf)),
             start: 0,
             pos: 0,
             len: buf.len(),
@@ -941,6 +1191,21 @@ impl RangeBuf {
         }
     }
 
+    /// Creates a new `RangeBuf` from the given shared buffer, without copying
+    /// its data.
+    pub fn from_shared(buf: SharedBuf, off: u64, fin: bool) -> RangeBuf {
+        let len = buf.len();
+
+        RangeBuf {
+            data: buf,
+            start: 0,
+            pos: 0,
+            len,
+            off,
+            fin,
+        }
+    }
+
     /// Returns whether `self` holds the final offset in the stream.
     pub fn fin(&self) -> bool {
         self.fin
@@ -2209,4 +2474,11 @@ mod tests {
 }
 
 mod recv_buf;
+mod scheduler;
 mod send_buf;
+mod stats;
+
+pub use scheduler::StreamPriorityScheduler;
+pub use scheduler::StreamScheduler;
+pub use stats::StreamStats;
+use stats::StreamStatsRecorder;
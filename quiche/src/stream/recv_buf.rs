@@ -36,6 +36,7 @@ use crate::Result;
 use crate::flowcontrol;
 
 use super::RangeBuf;
+use super::StreamChunks;
 use super::DEFAULT_STREAM_WINDOW;
 
 /// Receive-side stream buffer.
@@ -64,6 +65,10 @@ pub struct RecvBuf {
     /// The error code received via RESET_STREAM.
     error: Option<u64>,
 
+    /// The error code and reliable size received via RESET_STREAM_AT, if the
+    /// application has yet to read all data up to the reliable size.
+    reset_at: Option<(u64, u64)>,
+
     /// Whether incoming data is validated but not buffered.
     drain: bool,
 }
@@ -86,7 +91,7 @@ impl RecvBuf {
     /// This also takes care of enforcing stream flow control limits, as well
     /// as handling incoming data that overlaps data that is already in the
     /// buffer.
-    pub fn write(&mut self, buf: RangeBuf) -> Result<()> {
+    pub fn write(&mut self, mut buf: RangeBuf) -> Result<()> {
         if buf.max_off() > self.max_data() {
             return Err(Error::FlowControl);
         }
@@ -118,6 +123,18 @@ impl RecvBuf {
             self.fin_off = Some(buf.max_off());
         }
 
+        // Data past the reliable size of a stream reset using RESET_STREAM_AT
+        // is not delivered to the application.
+        if let Some((_, reliable_size)) = self.reset_at {
+            if buf.off() >= reliable_size {
+                return Ok(());
+            }
+
+            if buf.max_off() > reliable_size {
+                buf.split_off((reliable_size - buf.off) as usize);
+            }
+        }
+
         // No need to store empty buffer that doesn't carry the fin flag.
         if !buf.fin() && buf.is_empty() {
             return Ok(());
@@ -203,12 +220,65 @@ impl RecvBuf {
     /// no more data in the buffer, are returned as a tuple.
     pub fn emit(&mut self, out: &mut [u8]) -> Result<(usize, bool)> {
         let mut len = 0;
-        let mut cap = out.len();
+
+        self.emit_with(out.len(), |buf| {
+            out[len..len + buf.len()].copy_from_slice(buf);
+            len += buf.len();
+        })
+    }
+
+    /// Discards up to `len` bytes of contiguous data from the receive buffer,
+    /// as if they were read with [`emit()`].
+    ///
+    /// [`emit()`]: struct.RecvBuf.html#method.emit
+    pub fn consume(&mut self, len: usize) -> Result<(usize, bool)> {
+        self.emit_with(len, |_| ())
+    }
+
+    /// Returns an iterator over the contiguous data that can be read from the
+    /// receive buffer, without consuming it.
+    ///
+    /// If the stream was reset, the `StreamReset` error is returned instead.
+    pub fn chunks(&self) -> Result<StreamChunks<'_>> {
+        if let Some(e) = self.error {
+            return Err(Error::StreamReset(e));
+        }
+
+        if let Some((e, reliable_size)) = self.reset_at {
+            if self.off >= reliable_size {
+                return Err(Error::StreamReset(e));
+            }
+        }
+
+        Ok(StreamChunks {
+            bufs: self.data.values(),
+            off: self.off,
+        })
+    }
+
+    /// Passes up to `cap` bytes of contiguous data from the receive buffer to
+    /// the given function, one chunk at a time, and consumes them.
+    fn emit_with<F>(&mut self, mut cap: usize, mut f: F) -> Result<(usize, bool)>
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut len = 0;
 
         if !self.ready() {
             return Err(Error::Done);
         }
 
+        // All data up to the reliable size was read, so the stream can now be
+        // reset.
+        if let Some((error_code, reliable_size)) = self.reset_at {
+            if self.off >= reliable_size {
+                self.reset_at = None;
+
+                self.error = Some(error_code);
+                self.off = self.fin_off.unwrap_or(self.off);
+            }
+        }
+
         // The stream was reset, so clear its data and return the error code
         // instead.
         if let Some(e) = self.error {
@@ -226,7 +296,7 @@ impl RecvBuf {
 
             let buf_len = cmp::min(buf.len(), cap);
 
-            out[len..len + buf_len].copy_from_slice(&buf[..buf_len]);
+            f(&buf[..buf_len]);
 
             self.off += buf_len as u64;
 
@@ -250,7 +320,18 @@ impl RecvBuf {
     }
 
     /// Resets the stream at the given offset.
+    #[allow(dead_code)]
     pub fn reset(&mut self, error_code: u64, final_size: u64) -> Result<usize> {
+        self.reset_at(error_code, final_size, 0)
+    }
+
+    /// Resets the stream at the given offset, after the application has read
+    /// all data up to `reliable_size`.
+    ///
+    /// The reliable size can only be reduced by subsequent calls.
+    pub fn reset_at(
+        &mut self, error_code: u64, final_size: u64, reliable_size: u64,
+    ) -> Result<usize> {
         // Stream's size is already known, forbid changing it.
         if let Some(fin_off) = self.fin_off {
             if fin_off != final_size {
@@ -271,6 +352,34 @@ impl RecvBuf {
             return Ok(max_data_delta as usize);
         }
 
+        let reliable_size = match self.reset_at {
+            Some((_, v)) => cmp::min(v, reliable_size),
+
+            None => reliable_size,
+        };
+
+        // Data up to the reliable size still needs to be read, so only record
+        // the reset for now, and discard any data past the reliable size.
+        if reliable_size > self.off && !self.drain {
+            self.fin_off = Some(final_size);
+            self.len = final_size;
+
+            self.reset_at = Some((error_code, reliable_size));
+
+            let mut trimmed = self.data.split_off(&(reliable_size + 1));
+
+            if let Some((_, mut buf)) = trimmed.pop_first() {
+                if buf.off() < reliable_size {
+                    buf.split_off((reliable_size - buf.off) as usize);
+                    self.data.insert(buf.max_off(), buf);
+                }
+            }
+
+            return Ok(max_data_delta as usize);
+        }
+
+        self.reset_at = None;
+
         self.error = Some(error_code);
 
         // Clear all data already buffered.
@@ -321,6 +430,11 @@ impl RecvBuf {
 
         self.data.clear();
 
+        // No data needs to be read anymore, so reset the stream right away.
+        if let Some((error_code, _)) = self.reset_at.take() {
+            self.error = Some(error_code);
+        }
+
         self.off = self.max_off();
 
         Ok(())
@@ -346,13 +460,18 @@ impl RecvBuf {
     /// This happens when the stream's receive final size is known, and the
     /// application has read all data from the stream.
     pub fn is_fin(&self) -> bool {
-        if self.fin_off == Some(self.off) {
+        if self.fin_off == Some(self.off) && self.reset_at.is_none() {
             return true;
         }
 
         false
     }
 
+    /// Returns true if the stream was reset by the peer.
+    pub fn is_reset(&self) -> bool {
+        self.error.is_some() || self.reset_at.is_some()
+    }
+
     /// Returns true if the stream is not storing incoming data.
     pub fn is_draining(&self) -> bool {
         self.drain
@@ -360,6 +479,14 @@ impl RecvBuf {
 
     /// Returns true if the stream has data to be read.
     pub fn ready(&self) -> bool {
+        // The reset error needs to be returned once the data up to the
+        // reliable size is read.
+        if let Some((_, reliable_size)) = self.reset_at {
+            if self.off >= reliable_size {
+                return true;
+            }
+        }
+
         let (_, buf) = match self.data.first_key_value() {
             Some(v) => v,
             None => return false,
@@ -476,6 +603,49 @@ mod tests {
         assert_eq!(recv.emit(&mut buf), Err(Error::Done));
     }
 
+    #[test]
+    fn chunked_read() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, false);
+        let third = RangeBuf::from(b"something", 15, true);
+
+        assert!(recv.write(second).is_ok());
+        assert!(recv.write(third).is_ok());
+
+        // No data can be read yet.
+        assert_eq!(recv.chunks().unwrap().next(), None);
+        assert_eq!(recv.consume(5), Err(Error::Done));
+
+        assert!(recv.write(first).is_ok());
+
+        // Data past the gap is not returned.
+        let chunks: Vec<&[u8]> = recv.chunks().unwrap().collect();
+        assert_eq!(chunks, vec![&b"hello"[..], &b"world"[..]]);
+
+        // Reading chunks doesn't consume them.
+        assert_eq!(recv.chunks().unwrap().count(), 2);
+        assert_eq!(recv.off, 0);
+
+        assert_eq!(recv.consume(7), Ok((7, false)));
+        assert_eq!(recv.off, 7);
+
+        let chunks: Vec<&[u8]> = recv.chunks().unwrap().collect();
+        assert_eq!(chunks, vec![&b"rld"[..]]);
+
+        assert_eq!(recv.consume(10), Ok((3, false)));
+        assert_eq!(recv.chunks().unwrap().next(), None);
+
+        assert!(recv.write(RangeBuf::from(b"12345", 10, false)).is_ok());
+
+        let chunks: Vec<&[u8]> = recv.chunks().unwrap().collect();
+        assert_eq!(chunks, vec![&b"12345"[..], &b"something"[..]]);
+
+        assert_eq!(recv.consume(14), Ok((14, true)));
+        assert!(recv.is_fin());
+    }
+
     #[test]
     fn split_read() {
         let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
@@ -976,4 +1146,57 @@ mod tests {
 
         assert_eq!(recv.emit(&mut buf), Err(Error::Done));
     }
+
+    #[test]
+    fn reset_at() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, false);
+
+        assert!(recv.write(first).is_ok());
+
+        let (len, fin) = recv.emit(&mut buf[..2]).unwrap();
+        assert_eq!(len, 2);
+        assert!(!fin);
+
+        // The reset is only applied once the reliable data is read.
+        assert_eq!(recv.reset_at(42, 10, 7), Ok(5));
+        assert!(recv.is_reset());
+        assert!(recv.ready());
+
+        // Data past the reliable size is discarded.
+        assert!(recv.write(second).is_ok());
+
+        let (len, fin) = recv.emit(&mut buf).unwrap();
+        assert_eq!(len, 5);
+        assert!(!fin);
+        assert_eq!(&buf[..len], b"llowo");
+
+        assert!(recv.ready());
+        assert!(!recv.is_fin());
+
+        assert_eq!(recv.emit(&mut buf), Err(Error::StreamReset(42)));
+        assert!(recv.is_fin());
+        assert!(!recv.ready());
+    }
+
+    #[test]
+    fn reset_at_already_read() {
+        let mut recv = RecvBuf::new(u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+
+        assert!(recv.write(first).is_ok());
+
+        let (len, _) = recv.emit(&mut buf).unwrap();
+        assert_eq!(len, 5);
+
+        // All reliable data was already read, so the stream is reset right
+        // away.
+        assert_eq!(recv.reset_at(42, 10, 3), Ok(5));
+        assert_eq!(recv.emit(&mut buf), Err(Error::StreamReset(42)));
+    }
 }
@@ -0,0 +1,208 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::sync::Arc;
+
+use intrusive_collections::RBTree;
+
+use super::StreamFlushablePriorityAdapter;
+use super::StreamIdHashMap;
+use super::StreamPriorityKey;
+
+/// A policy deciding the order in which streams send data.
+///
+/// A stream is inserted in the scheduler when it has buffered data to send and
+/// enough flow control credit to send at least some of it, and removed once it
+/// doesn't anymore. Every time a `STREAM` frame can be added to a packet, data
+/// is sent from the stream returned by [`peek()`], after which [`on_sent()`]
+/// is called if the stream still has data to send.
+///
+/// The stream priorities set by the application with [`stream_priority()`]
+/// are passed to the scheduler, which is free to ignore them.
+///
+/// The default scheduler is [`StreamPriorityScheduler`]. A different one can
+/// be configured with [`set_stream_scheduler()`].
+///
+/// [`peek()`]: trait.StreamScheduler.html#tymethod.peek
+/// [`on_sent()`]: trait.StreamScheduler.html#tymethod.on_sent
+/// [`stream_priority()`]: struct.Connection.html#method.stream_priority
+/// [`StreamPriorityScheduler`]: struct.StreamPriorityScheduler.html
+/// [`set_stream_scheduler()`]: struct.Config.html#method.set_stream_scheduler
+pub trait StreamScheduler: Send + Sync {
+    /// Inserts a stream that has data to send.
+    ///
+    /// This is only called for streams that are not in the scheduler already.
+    fn insert(&mut self, stream_id: u64, urgency: u8, incremental: bool);
+
+    /// Removes a stream that doesn't have data to send anymore.
+    ///
+    /// This can also be called for streams that are not in the scheduler, in
+    /// which case it should do nothing.
+    fn remove(&mut self, stream_id: u64);
+
+    /// Returns the ID of the stream to send data from next, without removing
+    /// it from the scheduler.
+    ///
+    /// If the returned stream doesn't have data to send anymore, it is skipped
+    /// and [`remove()`] is called for it.
+    ///
+    /// [`remove()`]: trait.StreamScheduler.html#tymethod.remove
+    fn peek(&mut self) -> Option<u64>;
+
+    /// Notifies the scheduler that `len` bytes of the stream's data were just
+    /// written to a packet, and that the stream still has data to send.
+    fn on_sent(&mut self, stream_id: u64, len: usize);
+
+    /// Updates the priority of a stream that is in the scheduler.
+    ///
+    /// By default the stream is removed and inserted again.
+    fn update_priority(
+        &mut self, stream_id: u64, urgency: u8, incremental: bool,
+    ) {
+        self.remove(stream_id);
+        self.insert(stream_id, urgency, incremental);
+    }
+}
+
+/// The default stream scheduler, based on stream priorities.
+///
+/// Streams are sent in order of urgency, the ones with lower urgency first.
+/// Among streams with the same urgency, non-incremental streams are sent one
+/// at a time in order of stream ID, and before incremental ones. Incremental
+/// streams are sent in a round-robin fashion, one `STREAM` frame at a time.
+#[derive(Default)]
+pub struct StreamPriorityScheduler {
+    /// Streams ordered by priority.
+    queue: RBTree<StreamFlushablePriorityAdapter>,
+
+    /// Priority keys of the streams in the queue, indexed by stream ID.
+    keys: StreamIdHashMap<Arc<StreamPriorityKey>>,
+}
+
+impl StreamPriorityScheduler {
+    /// Inserts the stream's own priority key in the queue, instead of
+    /// allocating a new one like `insert()` does.
+    pub(crate) fn insert_key(&mut self, key: &Arc<StreamPriorityKey>) {
+        if !key.flushable.is_linked() {
+            self.queue.insert(Arc::clone(key));
+        }
+    }
+
+    /// Removes the stream's own priority key from the queue.
+    pub(crate) fn remove_key(&mut self, key: &Arc<StreamPriorityKey>) {
+        if !key.flushable.is_linked() {
+            return;
+        }
+
+        let mut c = {
+            let ptr = Arc::as_ptr(key);
+            unsafe { self.queue.cursor_mut_from_ptr(ptr) }
+        };
+
+        c.remove();
+    }
+
+    /// Removes the stream at the front of the queue.
+    pub(crate) fn remove_front(&mut self) {
+        self.queue.front_mut().remove();
+    }
+
+    /// Shuffles the incremental stream to the back of the queue.
+    pub(crate) fn on_key_sent(&mut self, key: &Arc<StreamPriorityKey>) {
+        if key.incremental && key.flushable.is_linked() {
+            self.remove_key(key);
+            self.queue.insert(Arc::clone(key));
+        }
+    }
+}
+
+impl StreamScheduler for StreamPriorityScheduler {
+    fn insert(&mut self, stream_id: u64, urgency: u8, incremental: bool) {
+        let key = Arc::new(StreamPriorityKey {
+            urgency,
+            incremental,
+            id: stream_id,
+            ..Default::default()
+        });
+
+        self.insert_key(&key);
+        self.keys.insert(stream_id, key);
+    }
+
+    fn remove(&mut self, stream_id: u64) {
+        if let Some(key) = self.keys.remove(&stream_id) {
+            self.remove_key(&key);
+        }
+    }
+
+    fn peek(&mut self) -> Option<u64> {
+        self.queue.front().get().map(|key| key.id)
+    }
+
+    fn on_sent(&mut self, stream_id: u64, _len: usize) {
+        if let Some(key) = self.keys.get(&stream_id).cloned() {
+            self.on_key_sent(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_scheduler() {
+        let mut scheduler = StreamPriorityScheduler::default();
+
+        scheduler.insert(0, 3, true);
+        scheduler.insert(4, 3, true);
+        scheduler.insert(8, 3, false);
+        scheduler.insert(12, 1, true);
+
+        assert_eq!(scheduler.peek(), Some(12));
+        scheduler.remove(12);
+
+        // Non-incremental streams go first, and are not shuffled.
+        assert_eq!(scheduler.peek(), Some(8));
+        scheduler.on_sent(8, 100);
+        assert_eq!(scheduler.peek(), Some(8));
+        scheduler.remove(8);
+
+        // Incremental streams are sent in turn.
+        assert_eq!(scheduler.peek(), Some(0));
+        scheduler.on_sent(0, 100);
+        assert_eq!(scheduler.peek(), Some(4));
+        scheduler.on_sent(4, 100);
+        assert_eq!(scheduler.peek(), Some(0));
+
+        scheduler.update_priority(4, 2, true);
+        assert_eq!(scheduler.peek(), Some(4));
+
+        scheduler.remove(4);
+        scheduler.remove(0);
+        assert_eq!(scheduler.peek(), None);
+    }
+}
@@ -34,6 +34,7 @@ use crate::Result;
 use crate::ranges;
 
 use super::RangeBuf;
+use super::SharedBuf;
 
 #[cfg(test)]
 const SEND_BUFFER_SIZE: usize = 5;
@@ -80,6 +81,10 @@ pub struct SendBuf {
     /// Whether the stream's send-side has been shut down.
     shutdown: bool,
 
+    /// The amount of data that still needs to be delivered to the peer after
+    /// the stream was reset using RESET_STREAM_AT, if any.
+    reliable_size: Option<u64>,
+
     /// Ranges of data offsets that have been acked.
     acked: ranges::RangeSet,
 
@@ -101,21 +106,84 @@ impl SendBuf {
     /// The number of bytes that were actually stored in the buffer is returned
     /// (this may be lower than the size of the input buffer, in case of partial
     /// writes).
-    pub fn write(&mut self, mut data: &[u8], mut fin: bool) -> Result<usize> {
-        let max_off = self.off + data.len() as u64;
+    pub fn write(&mut self, data: &[u8], fin: bool) -> Result<usize> {
+        let (data_len, fin) = match self.prepare_write(data.len(), fin)? {
+            (len, Some(fin)) => (len, fin),
+
+            (len, None) => return Ok(len),
+        };
+
+        let data = &data[..data_len];
+
+        let mut len = 0;
+
+        // Split the remaining input data into consistently-sized buffers to
+        // avoid fragmentation.
+        for chunk in data.chunks(SEND_BUFFER_SIZE) {
+            len += chunk.len();
+
+            let fin = len == data.len() && fin;
+
+            let buf = RangeBuf::from(chunk, self.off, fin);
+
+            // The new data can simply be appended at the end of the send buffer.
+            self.data.push_back(buf);
+
+            self.off += chunk.len() as u64;
+            self.len += chunk.len() as u64;
+        }
+
+        Ok(len)
+    }
+
+    /// Inserts the given shared buffer at the end of the buffer, without
+    /// copying its data.
+    ///
+    /// The number of bytes that were actually stored in the buffer is returned
+    /// (this may be lower than the size of the input buffer, in case of partial
+    /// writes).
+    pub fn write_shared(&mut self, data: SharedBuf, fin: bool) -> Result<usize> {
+        let (len, fin) = match self.prepare_write(data.len(), fin)? {
+            (len, Some(fin)) => (len, fin),
+
+            (len, None) => return Ok(len),
+        };
+
+        // As no copy is involved, the whole input is kept in a single buffer,
+        // which is split later as needed.
+        let buf = RangeBuf::from_shared(data.slice(..len), self.off, fin);
+
+        self.data.push_back(buf);
+
+        self.off += len as u64;
+        self.len += len as u64;
+
+        Ok(len)
+    }
+
+    /// Validates a write of `len` bytes against the stream's capacity and
+    /// final offset.
+    ///
+    /// Returns the number of bytes that can be written, and the `fin` flag to
+    /// buffer them with, or `None` if nothing needs to be buffered.
+    fn prepare_write(
+        &mut self, len: usize, mut fin: bool,
+    ) -> Result<(usize, Option<bool>)> {
+        let max_off = self.off + len as u64;
 
         // Get the stream send capacity. This will return an error if the stream
         // was stopped.
         let capacity = self.cap()?;
 
-        if data.len() > capacity {
-            // Truncate the input buffer according to the stream's capacity.
-            let len = capacity;
-            data = &data[..len];
-
+        let len = if len > capacity {
             // We are not buffering the full input, so clear the fin flag.
             fin = false;
-        }
+
+            // Truncate the input buffer according to the stream's capacity.
+            capacity
+        } else {
+            len
+        };
 
         if let Some(fin_off) = self.fin_off {
             // Can't write past final offset.
@@ -135,34 +203,16 @@ impl SendBuf {
 
         // Don't queue data that was already fully acked.
         if self.ack_off() >= max_off {
-            return Ok(data.len());
+            return Ok((len, None));
         }
 
         // We already recorded the final offset, so we can just discard the
         // empty buffer now.
-        if data.is_empty() {
-            return Ok(data.len());
-        }
-
-        let mut len = 0;
-
-        // Split the remaining input data into consistently-sized buffers to
-        // avoid fragmentation.
-        for chunk in data.chunks(SEND_BUFFER_SIZE) {
-            len += chunk.len();
-
-            let fin = len == data.len() && fin;
-
-            let buf = RangeBuf::from(chunk, self.off, fin);
-
-            // The new data can simply be appended at the end of the send buffer.
-            self.data.push_back(buf);
-
-            self.off += chunk.len() as u64;
-            self.len += chunk.len() as u64;
+        if len == 0 {
+            return Ok((len, None));
         }
 
-        Ok(len)
+        Ok((len, Some(fin)))
     }
 
     /// Writes data from the send buffer into the given output buffer.
@@ -246,6 +296,11 @@ impl SendBuf {
         self.acked.insert(off..off + len as u64);
     }
 
+    /// Returns the number of bytes that have been acked.
+    pub fn acked_bytes(&self) -> u64 {
+        self.acked.iter().map(|r| r.end - r.start).sum()
+    }
+
     pub fn ack_and_drop(&mut self, off: u64, len: usize) {
         self.ack(off, len);
 
@@ -342,7 +397,14 @@ impl SendBuf {
 
     /// Resets the stream at the current offset and clears all buffered data.
     pub fn reset(&mut self) -> (u64, u64) {
-        let unsent_off = cmp::max(self.off_front(), self.emit_off);
+        let (final_size, unsent_off) = match self.reliable_size {
+            // The final size was already decided when the stream was reset
+            // using RESET_STREAM_AT, so it can't change.
+            Some(_) => (self.off, self.off),
+
+            None => (self.emit_off, cmp::max(self.off_front(), self.emit_off)),
+        };
+
         let unsent_len = self.off_back().saturating_sub(unsent_off);
 
         self.fin_off = Some(unsent_off);
@@ -357,7 +419,7 @@ impl SendBuf {
         self.len = 0;
         self.off = unsent_off;
 
-        (self.emit_off, unsent_len)
+        (final_size, unsent_len)
     }
 
     /// Resets the streams and records the received error code.
@@ -386,6 +448,61 @@ impl SendBuf {
         Ok(self.reset())
     }
 
+    /// Shuts down sending data, but keeps the data below `reliable_size` so
+    /// that it is still delivered to the peer.
+    ///
+    /// Returns the stream's final size and the amount of buffered data that
+    /// won't be sent, as [`shutdown()`] does.
+    ///
+    /// [`shutdown()`]: struct.SendBuf.html#method.shutdown
+    pub fn shutdown_at(&mut self, reliable_size: u64) -> Result<(u64, u64)> {
+        if self.shutdown {
+            return Err(Error::Done);
+        }
+
+        if reliable_size > self.off {
+            return Err(Error::FinalSize);
+        }
+
+        self.shutdown = true;
+
+        if reliable_size == 0 {
+            return Ok(self.reset());
+        }
+
+        // Data that was already sent beyond the reliable size still counts
+        // towards the final size.
+        let final_size =
+            cmp::max(cmp::max(self.off_front(), self.emit_off), reliable_size);
+        let unsent_len = self.off - final_size;
+
+        // Drop buffered data past the reliable size.
+        while let Some(buf) = self.data.back_mut() {
+            if buf.off >= reliable_size {
+                self.data.pop_back();
+                continue;
+            }
+
+            if buf.off + buf.len as u64 > reliable_size {
+                buf.split_off((reliable_size - buf.off) as usize);
+            }
+
+            break;
+        }
+
+        self.pos = cmp::min(self.pos, self.data.len());
+        self.len = self.data.iter().map(|b| b.len() as u64).sum();
+
+        self.off = final_size;
+        self.fin_off = Some(final_size);
+        self.reliable_size = Some(reliable_size);
+
+        // Data past the reliable size doesn't need to be acked anymore.
+        self.ack(reliable_size, (final_size - reliable_size) as usize);
+
+        Ok((final_size, unsent_len))
+    }
+
     /// Returns the largest offset of data buffered.
     pub fn off_back(&self) -> u64 {
         self.off
@@ -626,6 +743,67 @@ mod tests {
         assert_eq!(send.off_front(), 19);
     }
 
+    #[test]
+    fn write_shared() {
+        struct Tracked {
+            _refs: std::sync::Arc<()>,
+            data: Vec<u8>,
+        }
+
+        impl AsRef<[u8]> for Tracked {
+            fn as_ref(&self) -> &[u8] {
+                &self.data
+            }
+        }
+
+        let mut buf = [0; 15];
+
+        let mut send = SendBuf::new(u64::MAX);
+
+        let refs = std::sync::Arc::new(());
+        let data = SharedBuf::new(Tracked {
+            _refs: refs.clone(),
+            data: b"somethinghelloworld".to_vec(),
+        });
+
+        assert_eq!(send.write_shared(data.slice(..9), false), Ok(9));
+        assert_eq!(send.write_shared(data.slice(9..), true), Ok(10));
+        assert_eq!(send.len, 19);
+
+        // The data is not copied, so no more buffers than writes are created.
+        assert_eq!(send.bufs_count(), 2);
+
+        drop(data);
+
+        let (written, fin) = send.emit(&mut buf[..4]).unwrap();
+        assert_eq!(written, 4);
+        assert!(!fin);
+        assert_eq!(&buf[..written], b"some");
+
+        let (written, fin) = send.emit(&mut buf[..15]).unwrap();
+        assert_eq!(written, 15);
+        assert!(fin);
+        assert_eq!(&buf[..written], b"thinghelloworld");
+        assert_eq!(send.len, 0);
+
+        // Lost data is retransmitted from the same buffers.
+        send.retransmit(4, 7);
+        assert_eq!(send.len, 7);
+
+        let (written, fin) = send.emit(&mut buf[..15]).unwrap();
+        assert_eq!(written, 7);
+        assert!(!fin);
+        assert_eq!(&buf[..written], b"thinghe");
+
+        send.ack_and_drop(0, 9);
+        assert_eq!(std::sync::Arc::strong_count(&refs), 2);
+
+        // The buffer is released once all the data is acked.
+        send.ack_and_drop(9, 10);
+        assert_eq!(send.bufs_count(), 0);
+        assert_eq!(std::sync::Arc::strong_count(&refs), 1);
+    }
+
     #[test]
     fn write_blocked_by_off() {
         let mut buf = [0; 10];
@@ -772,4 +950,71 @@ mod tests {
         assert_eq!(fin_off, 50);
         assert_eq!(unsent, 0);
     }
+
+    #[test]
+    fn shutdown_at() {
+        let mut buf = [0; 50];
+
+        let mut send = SendBuf::new(u64::MAX);
+
+        send.write(b"headerpayload", false).unwrap();
+        send.write(b"morepayload", false).unwrap();
+
+        // Only part of the header was sent.
+        let (written, fin) = send.emit(&mut buf[..4]).unwrap();
+        assert_eq!(written, 4);
+        assert!(!fin);
+
+        assert_eq!(send.shutdown_at(25), Err(Error::FinalSize));
+
+        // Data past the reliable size is dropped, but the rest of the header
+        // is still sent.
+        assert_eq!(send.shutdown_at(6), Ok((6, 18)));
+        assert_eq!(send.shutdown_at(6), Err(Error::Done));
+        assert_eq!(send.reliable_size, Some(6));
+        assert_eq!(send.len, 2);
+
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 2);
+        assert!(fin);
+        assert_eq!(&buf[..written], b"er");
+        assert!(!send.ready());
+
+        // The stream is only complete once the header is acked.
+        send.ack_and_drop(0, 4);
+        assert!(!send.is_complete());
+
+        send.retransmit(4, 2);
+        assert!(send.ready());
+
+        send.ack_and_drop(4, 2);
+        assert!(send.is_complete());
+        assert_eq!(send.bufs_count(), 0);
+    }
+
+    #[test]
+    fn shutdown_at_after_sent() {
+        let mut buf = [0; 50];
+
+        let mut send = SendBuf::new(u64::MAX);
+
+        send.write(b"headerpayload", false).unwrap();
+
+        let (written, _) = send.emit(&mut buf[..10]).unwrap();
+        assert_eq!(written, 10);
+
+        // Data that was sent past the reliable size is part of the final
+        // size, but isn't retransmitted.
+        assert_eq!(send.shutdown_at(6), Ok((10, 3)));
+        assert!(!send.ready());
+
+        send.retransmit(0, 10);
+        let (written, fin) = send.emit(&mut buf).unwrap();
+        assert_eq!(written, 6);
+        assert!(!fin);
+        assert_eq!(&buf[..written], b"header");
+
+        // STOP_SENDING doesn't change the final size.
+        assert_eq!(send.stop(0), Ok((10, 0)));
+    }
 }
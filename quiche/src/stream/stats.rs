@@ -0,0 +1,196 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::time;
+
+/// Statistics about a stream.
+///
+/// A stream's statistics can be collected using the [`stream_stats()`]
+/// method. Once the stream is collected, its final statistics can be
+/// collected once more.
+///
+/// [`stream_stats()`]: struct.Connection.html#method.stream_stats
+#[derive(Clone, Default)]
+pub struct StreamStats {
+    /// The number of stream bytes sent, including retransmissions.
+    pub sent_bytes: u64,
+
+    /// The number of stream bytes acknowledged by the peer.
+    pub acked_bytes: u64,
+
+    /// The number of stream bytes retransmitted.
+    pub retrans_bytes: u64,
+
+    /// The number of stream bytes received, including duplicates.
+    pub recv_bytes: u64,
+
+    /// The time elapsed between the creation of the stream and the first
+    /// byte of data being sent, if any.
+    pub first_byte_sent: Option<time::Duration>,
+
+    /// The time elapsed between the creation of the stream and the first
+    /// byte of data being acknowledged by the peer, if any.
+    pub first_byte_acked: Option<time::Duration>,
+
+    /// The time the application was blocked from writing to the stream by the
+    /// stream's or the connection's flow control limits.
+    pub flow_control_blocked: time::Duration,
+}
+
+impl std::fmt::Debug for StreamStats {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "sent_bytes={} acked_bytes={} retrans_bytes={} recv_bytes={}",
+            self.sent_bytes,
+            self.acked_bytes,
+            self.retrans_bytes,
+            self.recv_bytes,
+        )?;
+
+        write!(
+            f,
+            " first_byte_sent={:?} first_byte_acked={:?}",
+            self.first_byte_sent, self.first_byte_acked,
+        )?;
+
+        write!(f, " flow_control_blocked={:?}", self.flow_control_blocked)
+    }
+}
+
+/// Records the statistics of a stream as it sends and receives data.
+pub struct StreamStatsRecorder {
+    /// The time the stream was created at.
+    created: time::Instant,
+
+    /// The statistics recorded so far.
+    stats: StreamStats,
+
+    /// The time the stream started being blocked by flow control, if it is.
+    flow_control_blocked_since: Option<time::Instant>,
+}
+
+impl StreamStatsRecorder {
+    pub fn new(created: time::Instant) -> Self {
+        StreamStatsRecorder {
+            created,
+            stats: StreamStats::default(),
+            flow_control_blocked_since: None,
+        }
+    }
+
+    pub fn on_data_sent(&mut self, len: usize, now: time::Instant) {
+        if len == 0 {
+            return;
+        }
+
+        self.stats.sent_bytes += len as u64;
+
+        if self.stats.first_byte_sent.is_none() {
+            self.stats.first_byte_sent =
+                Some(now.saturating_duration_since(self.created));
+        }
+    }
+
+    pub fn on_data_acked(&mut self, len: usize, now: time::Instant) {
+        if len > 0 && self.stats.first_byte_acked.is_none() {
+            self.stats.first_byte_acked =
+                Some(now.saturating_duration_since(self.created));
+        }
+    }
+
+    pub fn on_data_lost(&mut self, len: usize) {
+        self.stats.retrans_bytes += len as u64;
+    }
+
+    pub fn on_data_received(&mut self, len: usize) {
+        self.stats.recv_bytes += len as u64;
+    }
+
+    pub fn on_flow_control_blocked(&mut self, now: time::Instant) {
+        self.flow_control_blocked_since.get_or_insert(now);
+    }
+
+    pub fn on_flow_control_unblocked(&mut self, now: time::Instant) {
+        if let Some(since) = self.flow_control_blocked_since.take() {
+            self.stats.flow_control_blocked +=
+                now.saturating_duration_since(since);
+        }
+    }
+
+    /// Returns the statistics recorded so far, including the time spent in
+    /// the current blocked period, if any.
+    pub fn stats(&self, acked_bytes: u64, now: time::Instant) -> StreamStats {
+        let mut stats = self.stats.clone();
+
+        stats.acked_bytes = acked_bytes;
+
+        if let Some(since) = self.flow_control_blocked_since {
+            stats.flow_control_blocked += now.saturating_duration_since(since);
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocked_periods() {
+        let created = time::Instant::now();
+        let ms = time::Duration::from_millis(1);
+
+        let mut recorder = StreamStatsRecorder::new(created);
+
+        recorder.on_flow_control_blocked(created + ms * 10);
+        recorder.on_flow_control_blocked(created + ms * 20);
+        recorder.on_flow_control_unblocked(created + ms * 30);
+        recorder.on_flow_control_unblocked(created + ms * 40);
+
+        recorder.on_data_sent(100, created + ms * 45);
+        recorder.on_data_acked(100, created + ms * 60);
+
+        recorder.on_flow_control_blocked(created + ms * 70);
+
+        let stats = recorder.stats(100, created + ms * 100);
+        assert_eq!(stats.sent_bytes, 100);
+        assert_eq!(stats.acked_bytes, 100);
+        assert_eq!(stats.first_byte_sent, Some(ms * 45));
+        assert_eq!(stats.first_byte_acked, Some(ms * 60));
+
+        // The ongoing blocked period is included.
+        assert_eq!(stats.flow_control_blocked, ms * 50);
+
+        recorder.on_flow_control_unblocked(created + ms * 80);
+        assert_eq!(
+            recorder.stats(100, created + ms * 100).flow_control_blocked,
+            ms * 30
+        );
+    }
+}
@@ -97,6 +97,13 @@ struct STACK_OF(c_void);
 #[repr(transparent)]
 struct CRYPTO_BUFFER(c_void);
 
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct SSL_CLIENT_HELLO {
+    ssl: *mut SSL,
+    // The remaining fields are not accessed directly.
+}
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 struct SSL_QUIC_METHOD {
@@ -339,6 +346,21 @@ impl Context {
         };
     }
 
+    pub fn enable_compatible_version_negotiation(&mut self, v: bool) {
+        // This is used by servers to perform compatible version negotiation
+        // as soon as the ClientHello is received. On clients it doesn't do
+        // anything.
+        let cb = if v {
+            Some(select_certificate as _)
+        } else {
+            None
+        };
+
+        unsafe {
+            SSL_CTX_set_select_certificate_cb(self.as_mut_ptr(), cb);
+        };
+    }
+
     pub fn set_verify(&mut self, verify: bool) {
         // true  -> 0x01 SSL_VERIFY_PEER
         // false -> 0x00 SSL_VERIFY_NONE
@@ -817,6 +839,10 @@ impl Handshake {
         unsafe { SSL_in_early_data(self.as_ptr()) == 1 }
     }
 
+    pub fn is_early_data_accepted(&self) -> bool {
+        unsafe { SSL_early_data_accepted(self.as_ptr()) == 1 }
+    }
+
     pub fn clear(&mut self) -> Result<()> {
         let rc = unsafe { SSL_clear(self.as_mut_ptr()) };
         self.map_result_ssl(rc)
@@ -917,7 +943,7 @@ impl Drop for Handshake {
 pub struct ExData<'a> {
     pub application_protos: &'a Vec<Vec<u8>>,
 
-    pub pkt_num_spaces: &'a mut [packet::PktNumSpace; packet::Epoch::count()],
+    pub crypto_ctx: &'a mut [packet::CryptoContext; packet::Epoch::count()],
 
     pub session: &'a mut Option<Vec<u8>>,
 
@@ -928,6 +954,12 @@ pub struct ExData<'a> {
     pub trace_id: &'a str,
 
     pub is_server: bool,
+
+    pub version: &'a mut u32,
+
+    pub available_versions: &'a [u32],
+
+    pub local_transport_params: &'a mut super::TransportParams,
 }
 
 fn get_ex_data_from_ptr<'a, T>(ptr: *const SSL, idx: c_int) -> Option<&'a mut T> {
@@ -964,14 +996,13 @@ extern fn set_read_secret(
     trace!("{} set read secret lvl={:?}", ex_data.trace_id, level);
 
     let space = match level {
-        crypto::Level::Initial =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Initial],
+        crypto::Level::Initial => &mut ex_data.crypto_ctx[packet::Epoch::Initial],
         crypto::Level::ZeroRTT =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
+            &mut ex_data.crypto_ctx[packet::Epoch::Application],
         crypto::Level::Handshake =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Handshake],
+            &mut ex_data.crypto_ctx[packet::Epoch::Handshake],
         crypto::Level::OneRTT =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
+            &mut ex_data.crypto_ctx[packet::Epoch::Application],
     };
 
     let aead = match get_cipher_from_ptr(cipher) {
@@ -984,7 +1015,8 @@ extern fn set_read_secret(
     if level != crypto::Level::ZeroRTT || ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let open = match crypto::Open::from_secret(aead, secret) {
+        let open = match crypto::Open::from_secret(aead, secret, *ex_data.version)
+        {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -1015,14 +1047,13 @@ extern fn set_write_secret(
     trace!("{} set write secret lvl={:?}", ex_data.trace_id, level);
 
     let space = match level {
-        crypto::Level::Initial =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Initial],
+        crypto::Level::Initial => &mut ex_data.crypto_ctx[packet::Epoch::Initial],
         crypto::Level::ZeroRTT =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
+            &mut ex_data.crypto_ctx[packet::Epoch::Application],
         crypto::Level::Handshake =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Handshake],
+            &mut ex_data.crypto_ctx[packet::Epoch::Handshake],
         crypto::Level::OneRTT =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
+            &mut ex_data.crypto_ctx[packet::Epoch::Application],
     };
 
     let aead = match get_cipher_from_ptr(cipher) {
@@ -1035,7 +1066,8 @@ extern fn set_write_secret(
     if level != crypto::Level::ZeroRTT || !ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let seal = match crypto::Seal::from_secret(aead, secret) {
+        let seal = match crypto::Seal::from_secret(aead, secret, *ex_data.version)
+        {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -1067,13 +1099,12 @@ extern fn add_handshake_data(
     let buf = unsafe { slice::from_raw_parts(data, len) };
 
     let space = match level {
-        crypto::Level::Initial =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Initial],
+        crypto::Level::Initial => &mut ex_data.crypto_ctx[packet::Epoch::Initial],
         crypto::Level::ZeroRTT => unreachable!(),
         crypto::Level::Handshake =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Handshake],
+            &mut ex_data.crypto_ctx[packet::Epoch::Handshake],
         crypto::Level::OneRTT =>
-            &mut ex_data.pkt_num_spaces[packet::Epoch::Application],
+            &mut ex_data.crypto_ctx[packet::Epoch::Application],
     };
 
     if space.crypto_stream.send.write(buf, false).is_err() {
@@ -1245,6 +1276,124 @@ extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
     0
 }
 
+extern fn select_certificate(client_hello: *const SSL_CLIENT_HELLO) -> c_int {
+    const SSL_SELECT_CERT_SUCCESS: c_int = 1;
+    const SSL_SELECT_CERT_ERROR: c_int = -1;
+
+    let ssl = unsafe { (*client_hello).ssl };
+
+    let ex_data = match get_ex_data_from_ptr::<ExData>(ssl, *QUICHE_EX_DATA_INDEX)
+    {
+        Some(v) => v,
+
+        None => return SSL_SELECT_CERT_ERROR,
+    };
+
+    if !ex_data.is_server {
+        return SSL_SELECT_CERT_SUCCESS;
+    }
+
+    let get_extension = |ty: u16| unsafe {
+        let mut ptr: *const u8 = ptr::null();
+        let mut len: usize = 0;
+
+        if SSL_early_callback_ctx_extension_get(
+            client_hello,
+            ty,
+            &mut ptr,
+            &mut len,
+        ) == 1
+        {
+            Some(slice::from_raw_parts(ptr, len))
+        } else {
+            None
+        }
+    };
+
+    // Look for the QUIC transport parameters extension, using the legacy
+    // codepoint as fallback.
+    let raw_params = match get_extension(0x39).or_else(|| get_extension(0xffa5)) {
+        Some(v) => v,
+
+        // Let the handshake fail later on.
+        None => return SSL_SELECT_CERT_SUCCESS,
+    };
+
+    let peer_params = match super::TransportParams::decode(raw_params, true) {
+        Ok(v) => v,
+
+        // Let the handshake fail later on.
+        Err(_) => return SSL_SELECT_CERT_SUCCESS,
+    };
+
+    let version_info = match peer_params.version_information {
+        Some(v) => v,
+
+        // The client doesn't support compatible version negotiation.
+        None => return SSL_SELECT_CERT_SUCCESS,
+    };
+
+    // The client's chosen version must match the version of the packet that
+    // carried the ClientHello. Don't switch version, and let the handshake
+    // fail once the transport parameters are validated.
+    if version_info.chosen_version != *ex_data.version {
+        return SSL_SELECT_CERT_SUCCESS;
+    }
+
+    // Switching version would invalidate 0-RTT packets that were already
+    // sent by the client using the original version.
+    if get_extension(0x2a).is_some() {
+        return SSL_SELECT_CERT_SUCCESS;
+    }
+
+    let version = match ex_data.available_versions.iter().find(|v| {
+        version_info.available_versions.contains(v) &&
+            super::version_is_supported(**v)
+    }) {
+        Some(&v) => v,
+
+        None => return SSL_SELECT_CERT_SUCCESS,
+    };
+
+    if version == *ex_data.version {
+        return SSL_SELECT_CERT_SUCCESS;
+    }
+
+    trace!(
+        "{} switching to compatible version {:x}",
+        ex_data.trace_id,
+        version
+    );
+
+    *ex_data.version = version;
+
+    // Advertise the new version to the client.
+    if let Some(v) = ex_data.local_transport_params.version_information.as_mut() {
+        v.chosen_version = version;
+    }
+
+    let mut raw_params = [0; 256];
+
+    let raw_params = match super::TransportParams::encode(
+        ex_data.local_transport_params,
+        true,
+        &mut raw_params,
+    ) {
+        Ok(v) => v,
+
+        Err(_) => return SSL_SELECT_CERT_ERROR,
+    };
+
+    if unsafe {
+        SSL_set_quic_transport_params(ssl, raw_params.as_ptr(), raw_params.len())
+    } != 1
+    {
+        return SSL_SELECT_CERT_ERROR;
+    }
+
+    SSL_SELECT_CERT_SUCCESS
+}
+
 fn map_result(bssl_result: c_int) -> Result<()> {
     match bssl_result {
         1 => Ok(()),
@@ -1340,6 +1489,11 @@ extern {
 
     fn SSL_CTX_set_early_data_enabled(ctx: *mut SSL_CTX, enabled: i32);
 
+    fn SSL_CTX_set_select_certificate_cb(
+        ctx: *mut SSL_CTX,
+        cb: Option<extern fn(client_hello: *const SSL_CLIENT_HELLO) -> c_int>,
+    );
+
     fn SSL_CTX_set_session_cache_mode(ctx: *mut SSL_CTX, mode: c_int) -> c_int;
 
     fn SSL_CTX_sess_set_new_cb(
@@ -1390,6 +1544,11 @@ extern {
 
     fn SSL_set_tlsext_host_name(ssl: *mut SSL, name: *const c_char) -> c_int;
 
+    fn SSL_early_callback_ctx_extension_get(
+        client_hello: *const SSL_CLIENT_HELLO, extension_type: u16,
+        out_data: *mut *const u8, out_len: *mut usize,
+    ) -> c_int;
+
     fn SSL_set_quic_transport_params(
         ssl: *mut SSL, params: *const u8, params_len: usize,
     ) -> c_int;
@@ -1440,6 +1599,8 @@ extern {
 
     fn SSL_in_early_data(ssl: *const SSL) -> c_int;
 
+    fn SSL_early_data_accepted(ssl: *const SSL) -> c_int;
+
     fn SSL_clear(ssl: *mut SSL) -> c_int;
 
     fn SSL_free(ssl: *mut SSL);
@@ -0,0 +1,519 @@
+// Copyright (C) 2023, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Address validation tokens.
+//!
+//! Servers can use a [`TokenKey`] to authenticate the tokens they send in
+//! Retry packets and NEW_TOKEN frames, and to validate the tokens echoed
+//! back by clients in Initial packets, as described in [RFC 9000 §8.1].
+//!
+//! Tokens are encrypted and authenticated using AES-128-GCM with a key
+//! derived from the server secret and a random per-token nonce, and bind
+//! the client's address, the original destination connection ID (for Retry
+//! tokens) and an expiry time.
+//!
+//! [`TokenKey`]: struct.TokenKey.html
+//! [RFC 9000 §8.1]: https://www.rfc-editor.org/rfc/rfc9000.html#section-8.1
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use std::time;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use ring::aead;
+use ring::hkdf;
+
+use crate::Error;
+use crate::Result;
+
+use crate::packet::ConnectionId;
+
+/// The default lifetime of tokens sent in Retry packets.
+const DEFAULT_RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The default lifetime of tokens sent in NEW_TOKEN frames.
+const DEFAULT_NEW_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+const TOKEN_NONCE_LEN: usize = 16;
+
+const TOKEN_HDR_LEN: usize = 2 + TOKEN_NONCE_LEN;
+
+const TOKEN_SALT: &[u8] = b"quiche address validation token";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TokenType {
+    Retry    = 0,
+    NewToken = 1,
+}
+
+impl TokenType {
+    fn from_u8(v: u8) -> Result<TokenType> {
+        match v {
+            0 => Ok(TokenType::Retry),
+
+            1 => Ok(TokenType::NewToken),
+
+            _ => Err(Error::InvalidToken),
+        }
+    }
+}
+
+struct SecretKey {
+    id: u8,
+
+    prk: hkdf::Prk,
+}
+
+impl SecretKey {
+    fn new(id: u8, secret: &[u8]) -> SecretKey {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, TOKEN_SALT).extract(secret);
+
+        SecretKey { id, prk }
+    }
+
+    /// Derives the AEAD key used to protect a single token.
+    fn token_key(&self, nonce: &[u8]) -> Result<aead::LessSafeKey> {
+        let info = [nonce];
+
+        let okm = self
+            .prk
+            .expand(&info, &aead::AES_128_GCM)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+    }
+}
+
+/// A key used to seal and open address validation tokens.
+///
+/// The key is derived from a secret that should be shared by all server
+/// instances that might receive packets from the same client, and that can
+/// be rotated periodically using [`rotate()`]. Tokens sealed using the
+/// previous secret are still accepted until the next rotation.
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut config = quiche::Config::new(0xbabababa)?;
+/// # let mut buf = [0; 512];
+/// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// # let local = socket.local_addr().unwrap();
+/// let token_key = std::sync::Arc::new(quiche::TokenKey::new(b"secret"));
+///
+/// // Send tokens in NEW_TOKEN frames to clients once the handshake completes.
+/// let key = token_key.clone();
+/// config.set_new_token_generator(move |peer| {
+///     key.seal_new_token(&peer).unwrap_or_default()
+/// });
+///
+/// let (len, peer) = socket.recv_from(&mut buf).unwrap();
+///
+/// let hdr =
+///     quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
+///
+/// let token = hdr.token.as_ref().unwrap();
+///
+/// let odcid = match token_key.open(&peer, token) {
+///     Ok(v) => v,
+///
+///     // Invalid or expired address validation token.
+///     Err(_) => return Ok(()),
+/// };
+///
+/// let mut conn =
+///     quiche::accept(&scid, odcid.as_ref(), local, peer, &mut config)?;
+///
+/// // The token was sent in a NEW_TOKEN frame, so the peer address doesn't
+/// // need to be validated again.
+/// if odcid.is_none() {
+///     conn.set_peer_address_verified()?;
+/// }
+/// # Ok::<(), quiche::Error>(())
+/// ```
+///
+/// [`rotate()`]: struct.TokenKey.html#method.rotate
+pub struct TokenKey {
+    current: SecretKey,
+
+    previous: Option<SecretKey>,
+
+    retry_token_lifetime: Duration,
+
+    new_token_lifetime: Duration,
+}
+
+impl TokenKey {
+    /// Creates a new token key from the given secret.
+    pub fn new(secret: &[u8]) -> TokenKey {
+        TokenKey {
+            current: SecretKey::new(0, secret),
+
+            previous: None,
+
+            retry_token_lifetime: DEFAULT_RETRY_TOKEN_LIFETIME,
+
+            new_token_lifetime: DEFAULT_NEW_TOKEN_LIFETIME,
+        }
+    }
+
+    /// Replaces the secret used to seal new tokens.
+    ///
+    /// Tokens sealed using the current secret can still be opened until the
+    /// next rotation, while tokens sealed using the previous one are
+    /// rejected.
+    pub fn rotate(&mut self, secret: &[u8]) {
+        let id = self.current.id.wrapping_add(1);
+
+        let previous =
+            std::mem::replace(&mut self.current, SecretKey::new(id, secret));
+
+        self.previous = Some(previous);
+    }
+
+    /// Sets how long tokens sent in Retry packets remain valid.
+    ///
+    /// The default value is 10 seconds.
+    pub fn set_retry_token_lifetime(&mut self, v: Duration) {
+        self.retry_token_lifetime = v;
+    }
+
+    /// Sets how long tokens sent in NEW_TOKEN frames remain valid.
+    ///
+    /// The default value is 24 hours.
+    pub fn set_new_token_lifetime(&mut self, v: Duration) {
+        self.new_token_lifetime = v;
+    }
+
+    /// Seals a token to be sent in a Retry packet to the given peer.
+    ///
+    /// The `odcid` is the destination connection ID of the client's Initial
+    /// packet, and is returned by [`open()`] once the client echoes the token
+    /// back.
+    ///
+    /// [`open()`]: struct.TokenKey.html#method.open
+    pub fn seal_retry_token(
+        &self, peer: &SocketAddr, odcid: &ConnectionId,
+    ) -> Result<Vec<u8>> {
+        self.seal(TokenType::Retry, peer, odcid, SystemTime::now())
+    }
+
+    /// Seals a token to be sent to the given peer in a NEW_TOKEN frame.
+    ///
+    /// This can be used together with [`set_new_token_generator()`].
+    ///
+    /// [`set_new_token_generator()`]:
+    /// struct.Config.html#method.set_new_token_generator
+    pub fn seal_new_token(&self, peer: &SocketAddr) -> Result<Vec<u8>> {
+        let odcid = ConnectionId::default();
+
+        self.seal(TokenType::NewToken, peer, &odcid, SystemTime::now())
+    }
+
+    /// Opens a token received from the given peer in an Initial packet.
+    ///
+    /// On success, the original destination connection ID is returned for
+    /// tokens sent in Retry packets, which should be passed to [`accept()`].
+    /// `None` is returned for tokens sent in NEW_TOKEN frames instead, in
+    /// which case the peer address can be marked as validated using
+    /// [`set_peer_address_verified()`].
+    ///
+    /// [`TokenExpired`] is returned if the token is authentic but expired,
+    /// and [`InvalidToken`] if it could not be authenticated, e.g. because
+    /// it was forged, sealed for a different address, or sealed using a
+    /// secret that was since rotated out.
+    ///
+    /// [`accept()`]: fn.accept.html
+    /// [`set_peer_address_verified()`]:
+    /// struct.Connection.html#method.set_peer_address_verified
+    /// [`TokenExpired`]: enum.Error.html#variant.TokenExpired
+    /// [`InvalidToken`]: enum.Error.html#variant.InvalidToken
+    pub fn open(
+        &self, peer: &SocketAddr, token: &[u8],
+    ) -> Result<Option<ConnectionId<'static>>> {
+        self.open_at(peer, token, SystemTime::now())
+    }
+
+    fn seal(
+        &self, ty: TokenType, peer: &SocketAddr, odcid: &ConnectionId,
+        now: SystemTime,
+    ) -> Result<Vec<u8>> {
+        let lifetime = match ty {
+            TokenType::Retry => self.retry_token_lifetime,
+
+            TokenType::NewToken => self.new_token_lifetime,
+        };
+
+        let expiry = (now + lifetime)
+            .duration_since(time::UNIX_EPOCH)
+            .map_err(|_| Error::CryptoFail)?
+            .as_millis() as u64;
+
+        let mut nonce = [0; TOKEN_NONCE_LEN];
+        crate::rand::rand_bytes(&mut nonce);
+
+        let mut token = Vec::with_capacity(
+            TOKEN_HDR_LEN + 8 + odcid.len() + aead::AES_128_GCM.tag_len(),
+        );
+
+        token.push(ty as u8);
+        token.push(self.current.id);
+        token.extend_from_slice(&nonce);
+
+        let ad = token_ad(&token, ty, peer);
+
+        let mut payload = Vec::with_capacity(8 + odcid.len());
+        payload.extend_from_slice(&expiry.to_be_bytes());
+        payload.extend_from_slice(odcid);
+
+        self.current
+            .token_key(&nonce)?
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key([0; aead::NONCE_LEN]),
+                aead::Aad::from(ad),
+                &mut payload,
+            )
+            .map_err(|_| Error::CryptoFail)?;
+
+        token.extend_from_slice(&payload);
+
+        Ok(token)
+    }
+
+    fn open_at(
+        &self, peer: &SocketAddr, token: &[u8], now: SystemTime,
+    ) -> Result<Option<ConnectionId<'static>>> {
+        if token.len() < TOKEN_HDR_LEN {
+            return Err(Error::InvalidToken);
+        }
+
+        let (hdr, payload) = token.split_at(TOKEN_HDR_LEN);
+
+        let ty = TokenType::from_u8(hdr[0])?;
+
+        let key = if hdr[1] == self.current.id {
+            &self.current
+        } else {
+            match self.previous {
+                Some(ref previous) if hdr[1] == previous.id => previous,
+
+                _ => return Err(Error::InvalidToken),
+            }
+        };
+
+        let ad = token_ad(hdr, ty, peer);
+
+        let mut payload = payload.to_vec();
+
+        let payload = key
+            .token_key(&hdr[2..])?
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key([0; aead::NONCE_LEN]),
+                aead::Aad::from(ad),
+                &mut payload,
+            )
+            .map_err(|_| Error::InvalidToken)?;
+
+        if payload.len() < 8 {
+            return Err(Error::InvalidToken);
+        }
+
+        let (expiry, odcid) = payload.split_at(8);
+
+        let mut expiry_bytes = [0; 8];
+        expiry_bytes.copy_from_slice(expiry);
+
+        let expiry = time::UNIX_EPOCH +
+            Duration::from_millis(u64::from_be_bytes(expiry_bytes));
+
+        if now > expiry {
+            return Err(Error::TokenExpired);
+        }
+
+        match ty {
+            TokenType::Retry => Ok(Some(ConnectionId::from_vec(odcid.to_vec()))),
+
+            TokenType::NewToken => Ok(None),
+        }
+    }
+}
+
+/// Builds the additional data used to authenticate a token.
+///
+/// Retry tokens are bound to the full peer address, while NEW_TOKEN tokens
+/// are only bound to the peer IP address, as clients are likely to use a
+/// different port for their next connection.
+fn token_ad(hdr: &[u8], ty: TokenType, peer: &SocketAddr) -> Vec<u8> {
+    let mut ad = hdr.to_vec();
+
+    match peer.ip() {
+        IpAddr::V4(a) => ad.extend_from_slice(&a.octets()),
+
+        IpAddr::V6(a) => ad.extend_from_slice(&a.octets()),
+    };
+
+    if ty == TokenType::Retry {
+        ad.extend_from_slice(&peer.port().to_be_bytes());
+    }
+
+    ad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:1234".parse().unwrap()
+    }
+
+    #[test]
+    fn retry_token() {
+        let key = TokenKey::new(b"secret");
+
+        let odcid = ConnectionId::from_ref(&[0xba; 16]);
+
+        let token = key.seal_retry_token(&peer(), &odcid).unwrap();
+
+        assert_eq!(key.open(&peer(), &token), Ok(Some(odcid)));
+    }
+
+    #[test]
+    fn new_token() {
+        let key = TokenKey::new(b"secret");
+
+        let token = key.seal_new_token(&peer()).unwrap();
+
+        assert_eq!(key.open(&peer(), &token), Ok(None));
+
+        // NEW_TOKEN tokens are not bound to the peer port.
+        let other_port = "127.0.0.1:4321".parse().unwrap();
+        assert_eq!(key.open(&other_port, &token), Ok(None));
+    }
+
+    #[test]
+    fn address_mismatch() {
+        let key = TokenKey::new(b"secret");
+
+        let odcid = ConnectionId::from_ref(&[0xba; 16]);
+
+        let token = key.seal_retry_token(&peer(), &odcid).unwrap();
+
+        let other_port = "127.0.0.1:4321".parse().unwrap();
+        assert_eq!(key.open(&other_port, &token), Err(Error::InvalidToken));
+
+        let other_ip = "127.0.0.2:1234".parse().unwrap();
+        assert_eq!(key.open(&other_ip, &token), Err(Error::InvalidToken));
+
+        let token = key.seal_new_token(&peer()).unwrap();
+        assert_eq!(key.open(&other_ip, &token), Err(Error::InvalidToken));
+    }
+
+    #[test]
+    fn forged() {
+        let key = TokenKey::new(b"secret");
+
+        let odcid = ConnectionId::from_ref(&[0xba; 16]);
+
+        let mut token = key.seal_retry_token(&peer(), &odcid).unwrap();
+
+        // Any modification of the token is detected.
+        for i in 0..token.len() {
+            token[i] ^= 0x01;
+            assert_eq!(key.open(&peer(), &token), Err(Error::InvalidToken));
+            token[i] ^= 0x01;
+        }
+
+        assert_eq!(key.open(&peer(), &token), Ok(Some(odcid)));
+
+        // Tokens sealed with a different secret are rejected.
+        let other = TokenKey::new(b"another secret");
+        assert_eq!(other.open(&peer(), &token), Err(Error::InvalidToken));
+
+        assert_eq!(key.open(&peer(), &[]), Err(Error::InvalidToken));
+        assert_eq!(key.open(&peer(), &token[..20]), Err(Error::InvalidToken));
+    }
+
+    #[test]
+    fn expired() {
+        let mut key = TokenKey::new(b"secret");
+        key.set_retry_token_lifetime(Duration::from_secs(5));
+
+        let odcid = ConnectionId::from_ref(&[0xba; 16]);
+
+        let now = SystemTime::now();
+
+        let token = key.seal(TokenType::Retry, &peer(), &odcid, now).unwrap();
+
+        assert_eq!(
+            key.open_at(&peer(), &token, now + Duration::from_secs(4)),
+            Ok(Some(odcid))
+        );
+
+        assert_eq!(
+            key.open_at(&peer(), &token, now + Duration::from_secs(6)),
+            Err(Error::TokenExpired)
+        );
+
+        let token = key
+            .seal(TokenType::NewToken, &peer(), &ConnectionId::default(), now)
+            .unwrap();
+
+        assert_eq!(
+            key.open_at(&peer(), &token, now + Duration::from_secs(6)),
+            Ok(None)
+        );
+
+        assert_eq!(
+            key.open_at(&peer(), &token, now + Duration::from_secs(86401)),
+            Err(Error::TokenExpired)
+        );
+    }
+
+    #[test]
+    fn rotate() {
+        let mut key = TokenKey::new(b"secret");
+
+        let odcid = ConnectionId::from_ref(&[0xba; 16]);
+
+        let token = key.seal_retry_token(&peer(), &odcid).unwrap();
+
+        key.rotate(b"new secret");
+
+        // Tokens sealed with the previous secret are still accepted.
+        assert_eq!(key.open(&peer(), &token), Ok(Some(odcid.clone())));
+
+        let new_token = key.seal_retry_token(&peer(), &odcid).unwrap();
+        assert_eq!(key.open(&peer(), &new_token), Ok(Some(odcid.clone())));
+
+        key.rotate(b"newer secret");
+
+        assert_eq!(key.open(&peer(), &token), Err(Error::InvalidToken));
+        assert_eq!(key.open(&peer(), &new_token), Ok(Some(odcid)));
+    }
+}
@@ -187,6 +187,7 @@ pub fn run(
             let recv_info = quiche::RecvInfo {
                 from,
                 to: local_addr,
+                ecn: quiche::Ecn::NotEct,
             };
 
             // Process potentially coalesced packets.